@@ -0,0 +1,830 @@
+//! Container images given with `--container`.
+//!
+//! OCI layouts, OCI archives and docker archives (e.g. `podman save` or `docker save` output)
+//! are read directly, including multi-platform image indexes. Other transports are copied
+//! with `skopeo`, which selects the platform from manifest lists itself.
+//! Paths without transport are read as OCI layouts if they are directories with `oci-layout`
+//! file and as docker or OCI archives if they are tarballs.
+//!
+//! Images built from `--containerfile` get a tag unique for the build, which is removed
+//! from the engine storage once the image is extracted.
+
+use std::fs;
+use std::io::Read;
+use std::path::{Component, Path, PathBuf};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use anyhow::{Context, Result};
+use oci_spec::image::{Descriptor, ImageConfiguration, ImageIndex, ImageManifest};
+use serde::Deserialize;
+
+use crate::builders::report;
+use crate::builders::skopeo_builder::SkopeoSyslinuxBuilder;
+
+/// Repository of images built from Containerfile, followed by a unique suffix.
+const BUILD_TAG_PREFIX: &str = "localhost/gvltctl-build";
+
+/// Annotation of image names in the index of an OCI layout.
+const REF_NAME_ANNOTATION: &str = "org.opencontainers.image.ref.name";
+
+/// Media types of manifest lists, which point to per-platform manifests.
+const INDEX_MEDIA_TYPES: [&str; 2] = [
+    "application/vnd.oci.image.index.v1+json",
+    "application/vnd.docker.distribution.manifest.list.v2+json",
+];
+
+/// Indexes may point to other indexes. Deeper nesting is treated as a broken image.
+const MAX_INDEX_DEPTH: usize = 4;
+
+/// Target platform of the image as `os/architecture[/variant]`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Platform {
+    pub os: String,
+    pub architecture: String,
+    pub variant: Option<String>,
+}
+
+impl Platform {
+    /// Platform without variant matches any variant of the architecture.
+    fn matches(&self, os: &str, architecture: &str, variant: Option<&str>) -> bool {
+        self.os == os
+            && self.architecture == architecture
+            && (self.variant.is_none() || self.variant.as_deref() == variant)
+    }
+
+    fn format(os: &str, architecture: &str, variant: Option<&str>) -> String {
+        match variant {
+            Some(variant) => format!("{}/{}/{}", os, architecture, variant),
+            None => format!("{}/{}", os, architecture),
+        }
+    }
+}
+
+impl std::str::FromStr for Platform {
+    type Err = &'static str;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let parts = s.split('/').collect::<Vec<_>>();
+        if !(2..=3).contains(&parts.len()) || parts.iter().any(|part| part.is_empty()) {
+            return Err("invalid platform: expected os/architecture[/variant]");
+        }
+        Ok(Self {
+            os: parts[0].to_string(),
+            architecture: parts[1].to_string(),
+            variant: parts.get(2).map(|variant| variant.to_string()),
+        })
+    }
+}
+
+impl std::fmt::Display for Platform {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.pad(&Self::format(
+            &self.os,
+            &self.architecture,
+            self.variant.as_deref(),
+        ))
+    }
+}
+
+/// Tool building images from `--containerfile`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum BuildEngine {
+    Podman,
+    Buildah,
+    Docker,
+}
+
+impl BuildEngine {
+    fn command(&self) -> &'static str {
+        match self {
+            Self::Podman => "podman",
+            Self::Buildah => "buildah",
+            Self::Docker => "docker",
+        }
+    }
+
+    /// Source reference `skopeo` reads the built image from.
+    /// Podman and Buildah share `containers-storage`.
+    fn source(&self, tag: &str) -> String {
+        match self {
+            Self::Podman | Self::Buildah => format!("containers-storage:{}", tag),
+            Self::Docker => format!("docker-daemon:{}", tag),
+        }
+    }
+}
+
+impl std::str::FromStr for BuildEngine {
+    type Err = &'static str;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "podman" => Ok(Self::Podman),
+            "buildah" => Ok(Self::Buildah),
+            "docker" => Ok(Self::Docker),
+            _ => Err("invalid build engine"),
+        }
+    }
+}
+
+impl std::fmt::Display for BuildEngine {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.pad(self.command())
+    }
+}
+
+/// Options of the image build from `--containerfile`.
+#[derive(Debug, Clone)]
+pub struct ContainerfileBuild {
+    pub containerfile: String,
+    pub engine: BuildEngine,
+    /// Build context directory, current directory by default.
+    pub context: Option<String>,
+    /// Target stage of multi-stage Containerfile.
+    pub target: Option<String>,
+    /// `--build-arg` values passed to the engine as is, `KEY=VALUE` or `KEY`.
+    pub build_args: Vec<String>,
+    /// `--secret` values passed to the engine as is, e.g. `id=token,src=token.txt`.
+    pub secrets: Vec<String>,
+}
+
+/// Image built from Containerfile, removed from the engine storage on drop.
+#[derive(Debug)]
+pub struct BuiltImage {
+    engine: BuildEngine,
+    tag: String,
+}
+
+impl BuiltImage {
+    /// Source to pass to [`fetch`].
+    pub fn source(&self) -> String {
+        self.engine.source(&self.tag)
+    }
+}
+
+impl Drop for BuiltImage {
+    fn drop(&mut self) {
+        match SkopeoSyslinuxBuilder::run_command(&[self.engine.command(), "rmi", &self.tag], false)
+        {
+            Ok(()) => log::debug!("removed image {}", self.tag),
+            Err(e) => log::warn!("failed to remove image {}: {:#}", self.tag, e),
+        }
+    }
+}
+
+/// Build image from Containerfile for `platform` under a tag unique for this build.
+pub fn build(options: &ContainerfileBuild, platform: &Platform) -> Result<BuiltImage> {
+    let nanos = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|duration| duration.as_nanos())
+        .unwrap_or_default();
+    let tag = format!(
+        "{}-{}-{}:latest",
+        BUILD_TAG_PREFIX,
+        std::process::id(),
+        nanos
+    );
+
+    let platform = platform.to_string();
+    let mut command = vec![
+        options.engine.command(),
+        "build",
+        "--platform",
+        &platform,
+        "-t",
+        &tag,
+        "-f",
+        &options.containerfile,
+    ];
+    if let Some(target) = &options.target {
+        command.extend(["--target", target]);
+    }
+    for build_arg in &options.build_args {
+        command.extend(["--build-arg", build_arg]);
+    }
+    for secret in &options.secrets {
+        command.extend(["--secret", secret]);
+    }
+    command.push(options.context.as_deref().unwrap_or("."));
+
+    SkopeoSyslinuxBuilder::run_command(&command, false).context(format!(
+        "Failed to build container image from Containerfile with {}",
+        options.engine
+    ))?;
+    Ok(BuiltImage {
+        engine: options.engine,
+        tag,
+    })
+}
+
+/// Image available as local files.
+#[derive(Debug)]
+pub struct LocalImage {
+    /// Digest of the image manifest. Docker archives have no manifest, their image ID
+    /// (digest of the config) is used instead.
+    pub digest: String,
+    pub config: ImageConfiguration,
+    /// Layer tarballs from the base layer up.
+    pub layers: Vec<PathBuf>,
+    /// Layers are temporary copies, which can be removed once unpacked.
+    pub temporary: bool,
+}
+
+/// Source image parsed from `transport:path[:reference]`.
+enum Source<'a> {
+    OciLayout(&'a Path, Option<&'a str>),
+    OciArchive(&'a Path, Option<&'a str>),
+    DockerArchive(&'a Path, Option<&'a str>),
+    /// Anything `skopeo` understands, e.g. `docker://` or `containers-storage:`.
+    Skopeo(&'a str),
+}
+
+impl<'a> Source<'a> {
+    fn parse(source: &'a str) -> Result<Self> {
+        let split = |rest: &'a str| match rest.split_once(':') {
+            Some((path, reference)) => (Path::new(path), Some(reference)),
+            None => (Path::new(rest), None),
+        };
+        let path = Path::new(source);
+        Ok(if let Some(rest) = source.strip_prefix("oci:") {
+            let (path, reference) = split(rest);
+            Self::OciLayout(path, reference)
+        } else if let Some(rest) = source.strip_prefix("oci-archive:") {
+            let (path, reference) = split(rest);
+            Self::OciArchive(path, reference)
+        } else if let Some(rest) = source.strip_prefix("docker-archive:") {
+            let (path, reference) = split(rest);
+            Self::DockerArchive(path, reference)
+        } else if path.is_dir() {
+            if !path.join("oci-layout").is_file() {
+                anyhow::bail!(
+                    "{} is not an OCI layout, it has no oci-layout file. Use dir:{} for directories written by skopeo.",
+                    source,
+                    source
+                );
+            }
+            Self::OciLayout(path, None)
+        } else if path.is_file() {
+            // `podman save` writes docker archives by default. Contents are checked once unpacked.
+            if !is_tarball(path)? {
+                anyhow::bail!(
+                    "{} is not a tar archive. Use a transport prefix, e.g. oci-archive: or docker-archive:.",
+                    source
+                );
+            }
+            Self::DockerArchive(path, None)
+        } else {
+            Self::Skopeo(source)
+        })
+    }
+}
+
+/// Check for tar header magic or gzip magic of a compressed tarball.
+fn is_tarball(path: &Path) -> Result<bool> {
+    let mut header = Vec::with_capacity(512);
+    fs::File::open(path)
+        .and_then(|file| file.take(512).read_to_end(&mut header))
+        .context(format!("Failed to read {}", path.display()))?;
+    Ok(header.starts_with(&[0x1f, 0x8b]) || header.get(257..262) == Some(b"ustar".as_slice()))
+}
+
+/// Make `source` available as local files, using `work_dir` for copies and unpacked archives.
+/// Fails if the image is not built for `platform`.
+pub fn fetch(source: &str, platform: &Platform, work_dir: &Path) -> Result<LocalImage> {
+    let image = match Source::parse(source)? {
+        Source::OciLayout(path, reference) => read_oci_layout(path, reference, platform, false)?,
+        Source::OciArchive(path, reference) => {
+            unpack_archive(path, work_dir)?;
+            read_oci_layout(work_dir, reference, platform, true)?
+        }
+        Source::DockerArchive(path, reference) => {
+            unpack_archive(path, work_dir)?;
+            // Archives written by `docker save` since Docker 25 are also OCI layouts,
+            // but `manifest.json` is present in both formats.
+            if work_dir.join("manifest.json").exists() {
+                read_docker_archive(work_dir, reference)?
+            } else if work_dir.join("oci-layout").exists() {
+                read_oci_layout(work_dir, reference, platform, true)?
+            } else {
+                anyhow::bail!(
+                    "{} is neither a docker archive nor an OCI archive",
+                    path.display()
+                );
+            }
+        }
+        Source::Skopeo(source) => copy_with_skopeo(source, platform, work_dir)?,
+    };
+
+    let os = image.config.os().to_string();
+    let architecture = image.config.architecture().to_string();
+    let variant = image.config.variant().as_deref();
+    if !platform.matches(&os, &architecture, variant) {
+        anyhow::bail!(
+            "Image is built for {}, but {} was requested. Use --platform to select another platform.",
+            Platform::format(&os, &architecture, variant),
+            platform
+        );
+    }
+    Ok(image)
+}
+
+fn unpack_archive(archive: &Path, target_dir: &Path) -> Result<()> {
+    SkopeoSyslinuxBuilder::run_command(
+        &[
+            "tar",
+            "-xf",
+            archive.to_str().context("Invalid archive path")?,
+            "-C",
+            target_dir.to_str().unwrap(),
+        ],
+        false,
+    )
+    .context(format!("Failed to unpack {}", archive.display()))
+}
+
+fn copy_with_skopeo(source: &str, platform: &Platform, work_dir: &Path) -> Result<LocalImage> {
+    let mut command = vec![
+        "skopeo",
+        "--override-os",
+        &platform.os,
+        "--override-arch",
+        &platform.architecture,
+    ];
+    if let Some(variant) = &platform.variant {
+        command.extend(["--override-variant", variant]);
+    }
+    let target = format!("dir:{}", work_dir.display());
+    command.extend(["copy", source, &target]);
+    SkopeoSyslinuxBuilder::run_command(&command, false)
+        .context("Failed to copy container image")?;
+
+    // `dir:` layout keeps the manifest of the selected platform and blobs named by digest.
+    let manifest_path = work_dir.join("manifest.json");
+    let manifest =
+        ImageManifest::from_file(&manifest_path).context("Failed to read image manifest")?;
+    let digest = SkopeoSyslinuxBuilder::run_command_output(
+        &["skopeo", "manifest-digest", manifest_path.to_str().unwrap()],
+        false,
+    )
+    .context("Failed to calculate image manifest digest")?
+    .trim()
+    .to_string();
+    let blob = |descriptor: &Descriptor| -> Result<PathBuf> {
+        let digest = descriptor.digest().to_string();
+        let (_, hex) = split_digest(&digest)?;
+        Ok(work_dir.join(hex))
+    };
+    Ok(LocalImage {
+        digest,
+        config: ImageConfiguration::from_file(blob(manifest.config())?)
+            .context("Failed to read image configuration")?,
+        layers: manifest.layers().iter().map(blob).collect::<Result<_>>()?,
+        temporary: true,
+    })
+}
+
+fn read_oci_layout(
+    layout: &Path,
+    reference: Option<&str>,
+    platform: &Platform,
+    temporary: bool,
+) -> Result<LocalImage> {
+    let index = ImageIndex::from_file(layout.join("index.json")).context(format!(
+        "Failed to read index of OCI layout {}",
+        layout.display()
+    ))?;
+    let mut manifests = index.manifests().clone();
+    if let Some(reference) = reference {
+        let available = ref_names(&manifests);
+        manifests.retain(|descriptor| ref_name(descriptor) == Some(reference));
+        if manifests.is_empty() {
+            anyhow::bail!(
+                "No image {} in OCI layout {}. Available: {}",
+                reference,
+                layout.display(),
+                available
+            );
+        }
+    }
+
+    for _ in 0..MAX_INDEX_DEPTH {
+        let descriptor = match manifests.as_slice() {
+            [] => anyhow::bail!("OCI layout {} contains no images", layout.display()),
+            [descriptor] if descriptor.platform().is_none() => descriptor.clone(),
+            _ => select_platform(&manifests, platform, layout)?,
+        };
+        let path = blob_path(layout, &descriptor)?;
+        if INDEX_MEDIA_TYPES.contains(&descriptor.media_type().to_string().as_str()) {
+            manifests = ImageIndex::from_file(&path)
+                .context("Failed to read image index")?
+                .manifests()
+                .clone();
+            continue;
+        }
+        let manifest = ImageManifest::from_file(&path).context("Failed to read image manifest")?;
+        return Ok(LocalImage {
+            digest: descriptor.digest().to_string(),
+            config: ImageConfiguration::from_file(blob_path(layout, manifest.config())?)
+                .context("Failed to read image configuration")?,
+            layers: manifest
+                .layers()
+                .iter()
+                .map(|layer| blob_path(layout, layer))
+                .collect::<Result<_>>()?,
+            temporary,
+        });
+    }
+    anyhow::bail!("Image indexes in {} are nested too deep", layout.display())
+}
+
+/// Select manifest for `platform` from a multi-platform index.
+fn select_platform(
+    manifests: &[Descriptor],
+    platform: &Platform,
+    layout: &Path,
+) -> Result<Descriptor> {
+    if manifests
+        .iter()
+        .any(|descriptor| descriptor.platform().is_none())
+    {
+        anyhow::bail!(
+            "OCI layout {} contains several images, select one with oci:{}:<name>. Available: {}",
+            layout.display(),
+            layout.display(),
+            ref_names(manifests)
+        );
+    }
+    let platforms = manifests
+        .iter()
+        .filter_map(|descriptor| descriptor.platform().as_ref())
+        .map(|p| {
+            (
+                p.os().to_string(),
+                p.architecture().to_string(),
+                p.variant().clone(),
+            )
+        })
+        .collect::<Vec<_>>();
+    match platforms.iter().position(|(os, architecture, variant)| {
+        platform.matches(os, architecture, variant.as_deref())
+    }) {
+        Some(i) => Ok(manifests[i].clone()),
+        None => anyhow::bail!(
+            "Image has no manifest for platform {}. Available platforms: {}",
+            platform,
+            platforms
+                .iter()
+                .map(|(os, architecture, variant)| Platform::format(
+                    os,
+                    architecture,
+                    variant.as_deref()
+                ))
+                .collect::<Vec<_>>()
+                .join(", ")
+        ),
+    }
+}
+
+fn ref_name(descriptor: &Descriptor) -> Option<&str> {
+    descriptor
+        .annotations()
+        .as_ref()
+        .and_then(|annotations| annotations.get(REF_NAME_ANNOTATION))
+        .map(String::as_str)
+}
+
+fn ref_names(manifests: &[Descriptor]) -> String {
+    let names = manifests.iter().filter_map(ref_name).collect::<Vec<_>>();
+    if names.is_empty() {
+        "no named images".to_string()
+    } else {
+        names.join(", ")
+    }
+}
+
+/// Split `algorithm:hex` digest, rejecting anything that is not safe as a file name.
+fn split_digest(digest: &str) -> Result<(&str, &str)> {
+    digest
+        .split_once(':')
+        .filter(|(algorithm, hex)| {
+            !algorithm.is_empty()
+                && algorithm.chars().all(|c| c.is_ascii_alphanumeric())
+                && !hex.is_empty()
+                && hex.chars().all(|c| c.is_ascii_hexdigit())
+        })
+        .context(format!("Invalid digest {}", digest))
+}
+
+fn blob_path(layout: &Path, descriptor: &Descriptor) -> Result<PathBuf> {
+    let digest = descriptor.digest().to_string();
+    let (algorithm, hex) = split_digest(&digest)?;
+    Ok(layout.join("blobs").join(algorithm).join(hex))
+}
+
+/// Image entry of `manifest.json` in a docker archive.
+#[derive(Debug, Deserialize)]
+#[serde(rename_all = "PascalCase")]
+struct DockerArchiveImage {
+    config: String,
+    repo_tags: Option<Vec<String>>,
+    layers: Vec<String>,
+}
+
+fn read_docker_archive(archive_dir: &Path, reference: Option<&str>) -> Result<LocalImage> {
+    let manifest = fs::read_to_string(archive_dir.join("manifest.json"))
+        .context("Failed to read docker archive manifest")?;
+    let images: Vec<DockerArchiveImage> =
+        serde_json::from_str(&manifest).context("Failed to parse docker archive manifest")?;
+    let tags = |image: &DockerArchiveImage| image.repo_tags.clone().unwrap_or_default();
+    let image = match reference {
+        Some(reference) => images.iter().find(|image| {
+            tags(image)
+                .iter()
+                .any(|tag| tag == reference || *tag == format!("{}:latest", reference))
+        }),
+        None if images.len() == 1 => images.first(),
+        None => anyhow::bail!(
+            "Docker archive contains {} images, select one with docker-archive:<path>:<name:tag>. Available: {}",
+            images.len(),
+            images.iter().flat_map(tags).collect::<Vec<_>>().join(", ")
+        ),
+    }
+    .context(format!(
+        "No image {} in docker archive",
+        reference.unwrap_or_default()
+    ))?;
+
+    // Paths are relative to the archive root.
+    let path = |relative: &str| -> Result<PathBuf> {
+        let relative = Path::new(relative);
+        if relative
+            .components()
+            .any(|component| !matches!(component, Component::Normal(_)))
+        {
+            anyhow::bail!("Invalid path {} in docker archive", relative.display());
+        }
+        Ok(archive_dir.join(relative))
+    };
+    let config_path = path(&image.config)?;
+    Ok(LocalImage {
+        digest: format!("sha256:{}", report::sha256(&config_path)?),
+        config: ImageConfiguration::from_file(&config_path)
+            .context("Failed to read image configuration")?,
+        layers: image
+            .layers
+            .iter()
+            .map(|layer| path(layer))
+            .collect::<Result<_>>()?,
+        temporary: true,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tempdir::TempDir;
+
+    const INDEX: &str = "application/vnd.oci.image.index.v1+json";
+    const MANIFEST: &str = "application/vnd.oci.image.manifest.v1+json";
+
+    fn digest(n: u32) -> String {
+        format!("sha256:{:064x}", n)
+    }
+
+    fn write_blob(layout: &Path, n: u32, content: &str) {
+        let dir = layout.join("blobs/sha256");
+        fs::create_dir_all(&dir).unwrap();
+        fs::write(dir.join(format!("{:064x}", n)), content).unwrap();
+    }
+
+    fn config(architecture: &str) -> String {
+        format!(
+            r#"{{"architecture":"{}","os":"linux","rootfs":{{"type":"layers","diff_ids":[]}},"history":[]}}"#,
+            architecture
+        )
+    }
+
+    fn descriptor(media_type: &str, n: u32, extra: &str) -> String {
+        format!(
+            r#"{{"mediaType":"{}","digest":"{}","size":1{}}}"#,
+            media_type,
+            digest(n),
+            extra
+        )
+    }
+
+    /// OCI layout with index of images for linux/amd64 and linux/arm64,
+    /// referenced from `index.json` through a nested index.
+    fn multi_platform_layout() -> TempDir {
+        let dir = TempDir::new("oci-layout").unwrap();
+        let layout = dir.path();
+        fs::write(
+            layout.join("oci-layout"),
+            r#"{"imageLayoutVersion":"1.0.0"}"#,
+        )
+        .unwrap();
+        let mut manifests = Vec::new();
+        for (n, architecture) in [(10, "amd64"), (20, "arm64")] {
+            write_blob(layout, n + 1, &config(architecture));
+            write_blob(
+                layout,
+                n,
+                &format!(
+                    r#"{{"schemaVersion":2,"mediaType":"{}","config":{},"layers":[{}]}}"#,
+                    MANIFEST,
+                    descriptor("application/vnd.oci.image.config.v1+json", n + 1, ""),
+                    descriptor("application/vnd.oci.image.layer.v1.tar+gzip", n + 2, "")
+                ),
+            );
+            manifests.push(descriptor(
+                MANIFEST,
+                n,
+                &format!(
+                    r#","platform":{{"architecture":"{}","os":"linux"}}"#,
+                    architecture
+                ),
+            ));
+        }
+        write_blob(
+            layout,
+            1,
+            &format!(
+                r#"{{"schemaVersion":2,"mediaType":"{}","manifests":[{}]}}"#,
+                INDEX,
+                manifests.join(",")
+            ),
+        );
+        fs::write(
+            layout.join("index.json"),
+            format!(
+                r#"{{"schemaVersion":2,"manifests":[{}]}}"#,
+                descriptor(
+                    INDEX,
+                    1,
+                    r#","annotations":{"org.opencontainers.image.ref.name":"latest"}"#
+                )
+            ),
+        )
+        .unwrap();
+        dir
+    }
+
+    #[test]
+    fn multi_platform_index() {
+        let dir = multi_platform_layout();
+        let work_dir = TempDir::new("work").unwrap();
+        let layout = dir.path().to_str().unwrap();
+
+        let image = fetch(layout, &"linux/amd64".parse().unwrap(), work_dir.path()).unwrap();
+        assert_eq!(image.digest, digest(10));
+        assert_eq!(image.config.architecture().to_string(), "amd64");
+        assert_eq!(
+            image.layers,
+            [dir.path().join(format!("blobs/sha256/{:064x}", 12))]
+        );
+        assert!(!image.temporary);
+
+        let image = fetch(
+            &format!("oci:{}:latest", layout),
+            &"linux/arm64".parse().unwrap(),
+            work_dir.path(),
+        )
+        .unwrap();
+        assert_eq!(image.digest, digest(20));
+        assert_eq!(image.config.architecture().to_string(), "arm64");
+    }
+
+    #[test]
+    fn missing_platform_lists_available() {
+        let dir = multi_platform_layout();
+        let work_dir = TempDir::new("work").unwrap();
+        let error = fetch(
+            dir.path().to_str().unwrap(),
+            &"linux/riscv64".parse().unwrap(),
+            work_dir.path(),
+        )
+        .unwrap_err();
+        assert_eq!(
+            error.to_string(),
+            "Image has no manifest for platform linux/riscv64. Available platforms: linux/amd64, linux/arm64"
+        );
+
+        let error = fetch(
+            &format!("oci:{}:stable", dir.path().display()),
+            &"linux/amd64".parse().unwrap(),
+            work_dir.path(),
+        )
+        .unwrap_err();
+        assert!(error.to_string().ends_with("Available: latest"));
+    }
+
+    #[test]
+    fn docker_archive_with_several_images() {
+        let dir = TempDir::new("docker-archive").unwrap();
+        let archive = dir.path();
+        fs::write(archive.join("amd64.json"), config("amd64")).unwrap();
+        fs::write(archive.join("arm64.json"), config("arm64")).unwrap();
+        fs::write(
+            archive.join("manifest.json"),
+            r#"[
+                {"Config":"amd64.json","RepoTags":["app:latest","app:1.0"],"Layers":["a/layer.tar"]},
+                {"Config":"arm64.json","RepoTags":["app:arm64"],"Layers":["b/layer.tar"]}
+            ]"#,
+        )
+        .unwrap();
+
+        let error = read_docker_archive(archive, None).unwrap_err();
+        assert_eq!(
+            error.to_string(),
+            "Docker archive contains 2 images, select one with docker-archive:<path>:<name:tag>. Available: app:latest, app:1.0, app:arm64"
+        );
+
+        // Tag defaults to latest.
+        let image = read_docker_archive(archive, Some("app")).unwrap();
+        assert_eq!(image.config.architecture().to_string(), "amd64");
+        assert_eq!(image.layers, [archive.join("a/layer.tar")]);
+        let image = read_docker_archive(archive, Some("app:arm64")).unwrap();
+        assert_eq!(image.config.architecture().to_string(), "arm64");
+        assert_eq!(image.layers, [archive.join("b/layer.tar")]);
+        assert!(read_docker_archive(archive, Some("app:2.0")).is_err());
+    }
+
+    #[test]
+    fn docker_archive_paths_stay_inside() {
+        let dir = TempDir::new("docker-archive").unwrap();
+        fs::write(dir.path().join("config.json"), config("amd64")).unwrap();
+        fs::write(
+            dir.path().join("manifest.json"),
+            r#"[{"Config":"config.json","RepoTags":null,"Layers":["../layer.tar"]}]"#,
+        )
+        .unwrap();
+        assert!(read_docker_archive(dir.path(), None).is_err());
+    }
+
+    #[test]
+    fn parse_source_without_transport() {
+        let dir = TempDir::new("sources").unwrap();
+        let layout = dir.path().join("layout");
+        fs::create_dir(&layout).unwrap();
+        let layout = layout.to_str().unwrap().to_string();
+        // Directory without oci-layout file, e.g. skopeo dir: layout.
+        assert!(Source::parse(&layout).is_err());
+        fs::write(dir.path().join("layout/oci-layout"), "{}").unwrap();
+        assert!(matches!(
+            Source::parse(&layout).unwrap(),
+            Source::OciLayout(path, None) if path == Path::new(&layout)
+        ));
+
+        let archive = dir.path().join("image.tar");
+        let mut header = vec![0; 512];
+        header[257..262].copy_from_slice(b"ustar");
+        fs::write(&archive, &header).unwrap();
+        let archive = archive.to_str().unwrap().to_string();
+        assert!(matches!(
+            Source::parse(&archive).unwrap(),
+            Source::DockerArchive(path, None) if path == Path::new(&archive)
+        ));
+        let text = dir.path().join("image.txt");
+        fs::write(&text, "debian:latest\n").unwrap();
+        assert!(Source::parse(text.to_str().unwrap()).is_err());
+
+        // Transport prefixes are not checked, paths are read once the image is fetched.
+        assert!(matches!(
+            Source::parse("docker-archive:/tmp/image.tar:app:1.0").unwrap(),
+            Source::DockerArchive(path, Some("app:1.0")) if path == Path::new("/tmp/image.tar")
+        ));
+        assert!(matches!(
+            Source::parse("oci:/tmp/missing").unwrap(),
+            Source::OciLayout(path, None) if path == Path::new("/tmp/missing")
+        ));
+        assert!(matches!(
+            Source::parse("/tmp/missing/image").unwrap(),
+            Source::Skopeo("/tmp/missing/image")
+        ));
+        assert!(matches!(
+            Source::parse("docker://docker.io/debian:latest").unwrap(),
+            Source::Skopeo("docker://docker.io/debian:latest")
+        ));
+    }
+
+    #[test]
+    fn digests() {
+        assert_eq!(split_digest("sha256:0abc").unwrap(), ("sha256", "0abc"));
+        assert!(split_digest("sha256").is_err());
+        assert!(split_digest("sha256:").is_err());
+        assert!(split_digest("sha256:../../etc").is_err());
+        assert!(split_digest("../sha256:0abc").is_err());
+    }
+
+    #[test]
+    fn platforms() {
+        let platform: Platform = "linux/arm64".parse().unwrap();
+        assert!(platform.matches("linux", "arm64", None));
+        assert!(platform.matches("linux", "arm64", Some("v8")));
+        assert!(!platform.matches("linux", "amd64", None));
+        let platform: Platform = "linux/arm/v7".parse().unwrap();
+        assert_eq!(platform.to_string(), "linux/arm/v7");
+        assert!(!platform.matches("linux", "arm", Some("v6")));
+        assert!("linux".parse::<Platform>().is_err());
+        assert!("linux//v7".parse::<Platform>().is_err());
+    }
+}
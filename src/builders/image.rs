@@ -0,0 +1,1039 @@
+//! Reading VM images built by gvltctl without mounting them.
+//!
+//! Partition table and filesystem types are parsed directly from the image file. Files are read
+//! from filesystems at their partition offsets with user space tools: mtools for the FAT boot
+//! partition, `debugfs` for ext4, `unsquashfs` for SquashFS and `dump.erofs` for EROFS.
+
+use std::env;
+use std::fs;
+use std::io::{Read, Seek, SeekFrom};
+use std::path::{Path, PathBuf};
+
+use anyhow::{Context, Result};
+use log::warn;
+use mia_installer::runtime_config::{self, RuntimeConfig};
+use serde::Serialize;
+use tempdir::TempDir;
+
+use crate::builders::report;
+use crate::builders::size::{self, VERITY_BLOCK_SIZE};
+use crate::builders::skopeo_builder::SkopeoSyslinuxBuilder;
+use crate::builders::RootfsFormat;
+
+/// Path of MIA binary installed by MIA installer inside root filesystem.
+pub const MIA_BINARY_PATH: &str = "/usr/lib/mia/mia";
+
+/// Path of the runtime config written by MIA installer inside root filesystem.
+pub const MIA_CONFIG_PATH: &str = "/usr/lib/mia/config.yaml";
+
+const SECTOR_SIZE: u64 = 512;
+
+/// Bootloader configs on the boot partition with the keyword of the kernel command line,
+/// searched in this order.
+const BOOT_CONFIGS: [(&str, &str, &str); 2] = [
+    ("/syslinux.cfg", "APPEND", "syslinux"),
+    ("/loader/entries/gevulot.conf", "options", "systemd-boot"),
+];
+
+/// Filesystem detected by its superblock magic.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Filesystem {
+    Fat,
+    Ext4,
+    Squashfs,
+    Erofs,
+}
+
+impl std::fmt::Display for Filesystem {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::Fat => write!(f, "fat"),
+            Self::Ext4 => write!(f, "ext4"),
+            Self::Squashfs => write!(f, "squashfs"),
+            Self::Erofs => write!(f, "erofs"),
+        }
+    }
+}
+
+/// Partition of the disk image.
+#[derive(Debug, Clone, Serialize)]
+pub struct Partition {
+    pub number: u32,
+    /// Offset from the start of the disk in bytes.
+    pub start: u64,
+    pub size: u64,
+    /// Partition type: MBR type code or GPT type GUID.
+    pub r#type: String,
+    /// PARTUUID as resolved by the kernel.
+    pub uuid: String,
+    /// GPT partition name.
+    pub name: Option<String>,
+    /// MBR active flag or GPT legacy BIOS bootable attribute.
+    pub bootable: bool,
+    pub filesystem: Option<Filesystem>,
+}
+
+/// Location of the root filesystem in the image.
+#[derive(Debug, Clone, Copy)]
+pub struct RootFilesystem {
+    /// Offset from the start of the image in bytes.
+    pub offset: u64,
+    /// Partition size, `None` for a bare filesystem image.
+    pub size: Option<u64>,
+    pub filesystem: Filesystem,
+}
+
+/// Disk image or a bare root filesystem image of direct kernel boot.
+#[derive(Debug)]
+pub struct Image {
+    pub path: PathBuf,
+    /// `mbr` or `gpt`, `None` for a bare filesystem image.
+    pub partition_table: Option<&'static str>,
+    pub partitions: Vec<Partition>,
+}
+
+impl Image {
+    pub fn open(path: &Path) -> Result<Self> {
+        let mut file =
+            fs::File::open(path).context(format!("Failed to open image {}", path.display()))?;
+        let (partition_table, partitions) = read_partition_table(&mut file).context(format!(
+            "Failed to read partition table of {}",
+            path.display()
+        ))?;
+        Ok(Self {
+            path: path.to_path_buf(),
+            partition_table,
+            partitions,
+        })
+    }
+
+    /// First FAT partition.
+    pub fn boot_partition(&self) -> Option<&Partition> {
+        self.partitions
+            .iter()
+            .find(|partition| partition.filesystem == Some(Filesystem::Fat))
+    }
+
+    /// Root filesystem: the first partition with a filesystem other than FAT,
+    /// or the whole file if there is no partition table.
+    pub fn root_filesystem(&self) -> Result<Option<RootFilesystem>> {
+        if self.partition_table.is_none() {
+            let mut file = fs::File::open(&self.path)
+                .context(format!("Failed to open image {}", self.path.display()))?;
+            return Ok(
+                detect_filesystem(&mut file, 0)?.map(|filesystem| RootFilesystem {
+                    offset: 0,
+                    size: None,
+                    filesystem,
+                }),
+            );
+        }
+        Ok(self.partitions.iter().find_map(|partition| {
+            partition
+                .filesystem
+                .filter(|filesystem| *filesystem != Filesystem::Fat)
+                .map(|filesystem| RootFilesystem {
+                    offset: partition.start,
+                    size: Some(partition.size),
+                    filesystem,
+                })
+        }))
+    }
+
+    /// mtools drive specification of the boot partition.
+    fn boot_drive(&self) -> Option<String> {
+        self.boot_partition()
+            .map(|partition| format!("{}@@{}", self.path.display(), partition.start))
+    }
+
+    /// Read text file from the boot partition. Returns `None` if there is no such file.
+    pub fn read_boot_file(&self, path: &str) -> Result<Option<String>> {
+        let Some(drive) = self.boot_drive() else {
+            return Ok(None);
+        };
+        let source = format!("::{}", path);
+        if !self.boot_file_exists(&drive, &source) {
+            return Ok(None);
+        }
+        SkopeoSyslinuxBuilder::run_command_output(
+            &["env", "MTOOLS_SKIP_CHECK=1", "mtype", "-i", &drive, &source],
+            false,
+        )
+        .map(Some)
+        .context(format!("Failed to read {} from boot partition", path))
+    }
+
+    /// Copy file from the boot partition to `target`. Returns `false` if there is no such file.
+    pub fn copy_boot_file(&self, path: &str, target: &Path) -> Result<bool> {
+        let Some(drive) = self.boot_drive() else {
+            return Ok(false);
+        };
+        let source = format!("::{}", path);
+        if !self.boot_file_exists(&drive, &source) {
+            return Ok(false);
+        }
+        SkopeoSyslinuxBuilder::run_command(
+            &[
+                "env",
+                "MTOOLS_SKIP_CHECK=1",
+                "mcopy",
+                "-n",
+                "-i",
+                &drive,
+                &source,
+                target.to_str().unwrap(),
+            ],
+            false,
+        )
+        .context(format!("Failed to copy {} from boot partition", path))?;
+        Ok(true)
+    }
+
+    fn boot_file_exists(&self, drive: &str, source: &str) -> bool {
+        SkopeoSyslinuxBuilder::run_command_output(
+            &[
+                "env",
+                "MTOOLS_SKIP_CHECK=1",
+                "mdir",
+                "-b",
+                "-i",
+                drive,
+                source,
+            ],
+            false,
+        )
+        .is_ok()
+    }
+
+    /// Read text file from the root filesystem. Returns `None` if there is no such file.
+    pub fn read_root_file(&self, path: &str) -> Result<Option<String>> {
+        let Some(root) = self.root_filesystem()? else {
+            return Ok(None);
+        };
+        let image = self.path.to_str().context("Invalid image path")?;
+        let offset = root.offset;
+        let output = match root.filesystem {
+            Filesystem::Ext4 => {
+                // debugfs reports missing file on stderr only and exits successfully.
+                let output = SkopeoSyslinuxBuilder::run_command_output(
+                    &[
+                        "debugfs",
+                        "-R",
+                        &format!("cat \"{}\"", path),
+                        &format!("{}?offset={}", image, offset),
+                    ],
+                    false,
+                )?;
+                if output.is_empty() {
+                    return Ok(None);
+                }
+                output
+            }
+            Filesystem::Squashfs => SkopeoSyslinuxBuilder::run_command_output(
+                &["unsquashfs", "-o", &offset.to_string(), "-cat", image, path],
+                false,
+            )?,
+            Filesystem::Erofs => SkopeoSyslinuxBuilder::run_command_output(
+                &[
+                    "dump.erofs",
+                    &format!("--offset={}", offset),
+                    "--cat",
+                    &format!("--path={}", path),
+                    image,
+                ],
+                false,
+            )?,
+            Filesystem::Fat => unreachable!("FAT is never a root filesystem"),
+        };
+        Ok(Some(output))
+    }
+
+    /// Kernel command line with the bootloader config it was read from.
+    pub fn boot_entry(&self) -> Result<Option<BootEntry>> {
+        for (config, keyword, bootloader) in BOOT_CONFIGS {
+            if let Some(content) = self.read_boot_file(config)? {
+                return Ok(Some(BootEntry {
+                    bootloader,
+                    config: config.to_string(),
+                    cmdline: config_value(&content, keyword).unwrap_or_default(),
+                    initrd: config_value(&content, "INITRD")
+                        .or_else(|| config_value(&content, "initrd")),
+                }));
+            }
+        }
+        Ok(None)
+    }
+
+    /// Check whether the root filesystem is protected by dm-verity.
+    ///
+    /// Disk images carry verity parameters in the bootloader config. A bare root filesystem
+    /// of direct kernel boot has them in `boot.json` next to it, and the hash tree appended
+    /// after the filesystem data is detected even without the descriptor.
+    pub fn verity_protected(&self) -> Result<bool> {
+        if self
+            .boot_entry()?
+            .is_some_and(|boot| boot.cmdline.contains("verity.roothash="))
+        {
+            return Ok(true);
+        }
+        if self.partition_table.is_some() {
+            return Ok(false);
+        }
+
+        let descriptor_file = self.path.with_file_name("boot.json");
+        if descriptor_file.exists() {
+            let descriptor: serde_json::Value = serde_json::from_str(
+                &fs::read_to_string(&descriptor_file)
+                    .context(format!("Failed to read {}", descriptor_file.display()))?,
+            )
+            .context(format!("Failed to parse {}", descriptor_file.display()))?;
+            let cmdline = descriptor["cmdline"].as_str().unwrap_or_default();
+            if !descriptor["verity"].is_null() || cmdline.contains("verity.roothash=") {
+                return Ok(true);
+            }
+        }
+
+        let Some(root) = self.root_filesystem()? else {
+            return Ok(false);
+        };
+        let mut file = fs::File::open(&self.path)
+            .context(format!("Failed to open image {}", self.path.display()))?;
+        let file_size = file
+            .metadata()
+            .context(format!(
+                "Failed to read metadata of {}",
+                self.path.display()
+            ))?
+            .len();
+        let filesystem_size = read_filesystem_size(&mut file, root)?;
+        Ok(file_size > size::align_up(filesystem_size, VERITY_BLOCK_SIZE))
+    }
+
+    /// Replace kernel command line in all bootloader configs with the result of `update`.
+    /// Returns `false` if there is no bootloader config.
+    pub fn update_cmdline(&self, update: impl Fn(&str) -> Result<String>) -> Result<bool> {
+        let mut found = false;
+        for (config, keyword, _) in BOOT_CONFIGS {
+            let Some(content) = self.read_boot_file(config)? else {
+                continue;
+            };
+            found = true;
+            let mut updated = String::new();
+            for line in content.lines() {
+                let trimmed = line.trim_start();
+                match trimmed
+                    .strip_prefix(keyword)
+                    .filter(|rest| rest.starts_with(char::is_whitespace))
+                {
+                    Some(cmdline) => {
+                        let indent = &line[..line.len() - trimmed.len()];
+                        let cmdline = update(cmdline.trim())?;
+                        updated.push_str(&format!("{}{} {}\n", indent, keyword, cmdline));
+                    }
+                    None => updated.push_str(&format!("{}\n", line)),
+                }
+            }
+            self.write_boot_file(config, &updated)?;
+        }
+        Ok(found)
+    }
+
+    /// Overwrite file on the boot partition.
+    fn write_boot_file(&self, path: &str, content: &str) -> Result<()> {
+        let drive = self.boot_drive().context("Image has no boot partition")?;
+        let temp_dir = TempDir::new("boot").context("Failed to create temporary directory")?;
+        let source = temp_dir.path().join("config");
+        fs::write(&source, content).context("Failed to write temporary file")?;
+        SkopeoSyslinuxBuilder::run_command(
+            &[
+                "env",
+                "MTOOLS_SKIP_CHECK=1",
+                "mcopy",
+                "-o",
+                "-i",
+                &drive,
+                source.to_str().unwrap(),
+                &format!("::{}", path),
+            ],
+            false,
+        )
+        .context(format!("Failed to write {} to boot partition", path))
+    }
+
+    /// Replace text file in the root filesystem. ext4 is modified in place,
+    /// read-only formats are unpacked and generated again.
+    pub fn write_root_file(&self, path: &str, content: &str) -> Result<()> {
+        let root = self
+            .root_filesystem()?
+            .context("Image has no root filesystem")?;
+        match root.filesystem {
+            Filesystem::Ext4 => self.write_ext4_file(root, path, content)?,
+            Filesystem::Squashfs | Filesystem::Erofs => self.repack_root(root, path, content)?,
+            Filesystem::Fat => unreachable!("FAT is never a root filesystem"),
+        }
+        if self.read_root_file(path)?.as_deref() != Some(content) {
+            anyhow::bail!("Failed to write {} to root filesystem", path);
+        }
+        Ok(())
+    }
+
+    /// Replace file in ext4 with `debugfs`, keeping its modification time.
+    fn write_ext4_file(&self, root: RootFilesystem, path: &str, content: &str) -> Result<()> {
+        let device = format!("{}?offset={}", self.path.display(), root.offset);
+        let (dir, name) = path
+            .rsplit_once('/')
+            .context(format!("Invalid path {}", path))?;
+        let stat = SkopeoSyslinuxBuilder::run_command_output(
+            &["debugfs", "-R", &format!("stat \"{}\"", path), &device],
+            false,
+        )
+        .context(format!("Failed to read {} metadata", path))?;
+        let mtime = stat.lines().find_map(|line| {
+            let hex = line.trim().strip_prefix("mtime: 0x")?;
+            let hex = hex.split(|c: char| !c.is_ascii_hexdigit()).next()?;
+            u64::from_str_radix(hex, 16).ok()
+        });
+
+        let temp_dir = TempDir::new("root").context("Failed to create temporary directory")?;
+        let source = temp_dir.path().join("file");
+        fs::write(&source, content).context("Failed to write temporary file")?;
+        // debugfs can't overwrite files, so the old one is removed first.
+        let mut commands = format!(
+            "cd \"{}\"\nrm \"{name}\"\nwrite \"{}\" \"{name}\"\n\
+             sif \"{name}\" mode 0100644\nsif \"{name}\" uid 0\nsif \"{name}\" gid 0\n",
+            if dir.is_empty() { "/" } else { dir },
+            source.display(),
+        );
+        if let Some(mtime) = mtime {
+            for field in ["atime", "mtime", "ctime", "crtime"] {
+                commands.push_str(&format!("sif \"{name}\" {} @{}\n", field, mtime));
+            }
+        }
+        let commands_file = temp_dir.path().join("commands");
+        fs::write(&commands_file, commands).context("Failed to write debugfs commands")?;
+        SkopeoSyslinuxBuilder::run_command(
+            &[
+                "debugfs",
+                "-w",
+                "-f",
+                commands_file.to_str().unwrap(),
+                &device,
+            ],
+            false,
+        )
+        .context(format!("Failed to write {} to root filesystem", path))
+    }
+
+    /// Unpack read-only root filesystem, replace the file and write regenerated filesystem back.
+    fn repack_root(&self, root: RootFilesystem, path: &str, content: &str) -> Result<()> {
+        let image = self.path.to_str().context("Invalid image path")?;
+        let rootfs_format = match root.filesystem {
+            Filesystem::Squashfs => RootfsFormat::Squashfs,
+            _ => RootfsFormat::Erofs,
+        };
+        let temp_dir = TempDir::new("root").context("Failed to create temporary directory")?;
+        let rootfs_dir = temp_dir.path().join("rootfs");
+        let rootfs_image = temp_dir.path().join("rootfs.img");
+
+        let result = (|| -> Result<()> {
+            // Extracted as root to keep file ownership.
+            match root.filesystem {
+                Filesystem::Squashfs => SkopeoSyslinuxBuilder::run_command(
+                    &[
+                        "unsquashfs",
+                        "-o",
+                        &root.offset.to_string(),
+                        "-d",
+                        rootfs_dir.to_str().unwrap(),
+                        image,
+                    ],
+                    true,
+                ),
+                _ => SkopeoSyslinuxBuilder::run_command(
+                    &[
+                        "fsck.erofs",
+                        &format!("--offset={}", root.offset),
+                        &format!("--extract={}", rootfs_dir.display()),
+                        image,
+                    ],
+                    true,
+                ),
+            }
+            .context(format!(
+                "Failed to unpack {} root filesystem",
+                root.filesystem
+            ))?;
+
+            SkopeoSyslinuxBuilder::write_file_as_root(
+                &rootfs_dir.join(path.trim_start_matches('/')),
+                content,
+            )?;
+
+            // Filesystem creation time of the original image is used as SOURCE_DATE_EPOCH,
+            // so the new file doesn't bring current time into the image.
+            let epoch = self.read_creation_time(root)?;
+            SkopeoSyslinuxBuilder::generate_rootfs_image(
+                &rootfs_dir,
+                rootfs_format,
+                &rootfs_image,
+                Some(epoch),
+            )?;
+
+            let image_size = fs::metadata(&rootfs_image)
+                .context("Failed to read root filesystem image metadata")?
+                .len();
+            if let Some(partition_size) = root.size.filter(|size| image_size > *size) {
+                anyhow::bail!(
+                    "Root filesystem ({} bytes) doesn't fit into root partition ({} bytes) anymore. Rebuild the image instead.",
+                    image_size,
+                    partition_size
+                );
+            }
+
+            // Bare filesystem image is replaced as a whole.
+            let conv = if root.size.is_some() {
+                "conv=notrunc,fsync"
+            } else {
+                "conv=fsync"
+            };
+            SkopeoSyslinuxBuilder::run_command(
+                &[
+                    "dd",
+                    "bs=4M",
+                    conv,
+                    "oflag=seek_bytes",
+                    &format!("seek={}", root.offset),
+                    &format!("if={}", rootfs_image.display()),
+                    &format!("of={}", image),
+                ],
+                false,
+            )
+            .context("Failed to write root filesystem to image")
+        })();
+
+        // Unpacked files are owned by root and can't be removed with the temp dir.
+        _ = SkopeoSyslinuxBuilder::run_command(
+            &[
+                "rm",
+                "-rf",
+                rootfs_dir.to_str().unwrap(),
+                rootfs_image.to_str().unwrap(),
+            ],
+            true,
+        );
+        result
+    }
+
+    /// Creation time stored in the superblock of a read-only root filesystem.
+    fn read_creation_time(&self, root: RootFilesystem) -> Result<u64> {
+        let mut file = fs::File::open(&self.path)
+            .context(format!("Failed to open image {}", self.path.display()))?;
+        let mut time = [0u8; 8];
+        match root.filesystem {
+            Filesystem::Squashfs => {
+                file.seek(SeekFrom::Start(root.offset + 8))?;
+                file.read_exact(&mut time[..4])?;
+            }
+            _ => {
+                file.seek(SeekFrom::Start(root.offset + 1024 + 24))?;
+                file.read_exact(&mut time)?;
+            }
+        }
+        Ok(u64::from_le_bytes(time))
+    }
+}
+
+/// Size of the filesystem in bytes as recorded in its superblock.
+fn read_filesystem_size(file: &mut fs::File, root: RootFilesystem) -> Result<u64> {
+    let mut superblock = [0u8; 1024];
+    match root.filesystem {
+        Filesystem::Squashfs => file.seek(SeekFrom::Start(root.offset))?,
+        _ => file.seek(SeekFrom::Start(root.offset + 1024))?,
+    };
+    file.read_exact(&mut superblock)
+        .context(format!("Failed to read {} superblock", root.filesystem))?;
+    let u32_at = |offset: usize| {
+        u32::from_le_bytes(superblock[offset..offset + 4].try_into().unwrap()) as u64
+    };
+    let size = match root.filesystem {
+        // Bytes used, without padding to 4 KiB added by mksquashfs.
+        Filesystem::Squashfs => u64::from_le_bytes(superblock[40..48].try_into().unwrap()),
+        Filesystem::Erofs => u32_at(0x24) << superblock[0x0c],
+        Filesystem::Ext4 => {
+            // High part of the block count is valid only with the 64bit feature.
+            let blocks_hi = if u32_at(0x60) & 0x80 != 0 {
+                u32_at(0x150)
+            } else {
+                0
+            };
+            ((blocks_hi << 32) | u32_at(0x04)) << (10 + u32_at(0x18))
+        }
+        Filesystem::Fat => unreachable!("FAT is never a root filesystem"),
+    };
+    Ok(size)
+}
+
+/// Value of the first line starting with `keyword` in a bootloader config.
+fn config_value(content: &str, keyword: &str) -> Option<String> {
+    content.lines().find_map(|line| {
+        line.trim()
+            .strip_prefix(keyword)
+            .filter(|rest| rest.starts_with(char::is_whitespace))
+            .map(|rest| rest.trim().to_string())
+    })
+}
+
+/// Kernel entry of the bootloader config.
+#[derive(Debug, Clone, Serialize)]
+pub struct BootEntry {
+    /// `syslinux` or `systemd-boot`.
+    pub bootloader: &'static str,
+    /// Path of the config file on the boot partition.
+    pub config: String,
+    pub cmdline: String,
+    pub initrd: Option<String>,
+}
+
+/// Everything `gvltctl image inspect` reports.
+#[derive(Debug, Serialize)]
+pub struct ImageInfo {
+    pub partition_table: Option<&'static str>,
+    pub partitions: Vec<Partition>,
+    pub boot: Option<BootEntry>,
+    /// Version string from the bzImage header.
+    pub kernel_version: Option<String>,
+    pub root_filesystem: Option<Filesystem>,
+    /// Runtime config of MIA, `None` with custom init.
+    pub runtime_config: Option<RuntimeConfig>,
+}
+
+/// Describe the image at `path`.
+pub fn inspect(path: &Path) -> Result<ImageInfo> {
+    let image = Image::open(path)?;
+    let root_filesystem = image.root_filesystem()?;
+    if image.partition_table.is_none() && root_filesystem.is_none() {
+        anyhow::bail!(
+            "{} is neither a partitioned disk image nor a root filesystem image",
+            path.display()
+        );
+    }
+
+    let boot = image.boot_entry()?;
+    let kernel_version = if boot.is_some() {
+        read_boot_kernel_version(&image)?
+    } else {
+        None
+    };
+
+    let runtime_config = match image.read_root_file(MIA_CONFIG_PATH) {
+        Ok(Some(config)) => Some(
+            serde_yaml::from_str(&config)
+                .context(format!("Failed to parse {}", MIA_CONFIG_PATH))?,
+        ),
+        Ok(None) => None,
+        Err(err) => {
+            warn!("Failed to read MIA config from root filesystem: {:#}", err);
+            None
+        }
+    };
+
+    Ok(ImageInfo {
+        partition_table: image.partition_table,
+        partitions: image.partitions,
+        boot,
+        kernel_version,
+        root_filesystem: root_filesystem.map(|root| root.filesystem),
+        runtime_config,
+    })
+}
+
+/// Copy kernel out of the boot partition and read its version.
+fn read_boot_kernel_version(image: &Image) -> Result<Option<String>> {
+    let kernel_file = env::temp_dir().join(format!("gvltctl-inspect-{}", std::process::id()));
+    if !image.copy_boot_file("/bzImage", &kernel_file)? {
+        return Ok(None);
+    }
+    let version = read_kernel_version(&kernel_file);
+    fs::remove_file(&kernel_file).context("Failed to remove temporary kernel file")?;
+    version
+}
+
+/// Read kernel version string from the setup header of the bzImage.
+pub fn read_kernel_version(kernel_file: &Path) -> Result<Option<String>> {
+    let mut file =
+        fs::File::open(kernel_file).context(format!("Failed to open {}", kernel_file.display()))?;
+    let mut header = [0u8; 0x210];
+    file.read_exact(&mut header)
+        .context("Failed to read kernel setup header")?;
+    if &header[0x202..0x206] != b"HdrS" {
+        return Ok(None);
+    }
+    // Offset of the version string relative to the end of the boot sector.
+    let offset = u16::from_le_bytes([header[0x20e], header[0x20f]]) as u64;
+    if offset == 0 {
+        return Ok(None);
+    }
+    let mut version = [0u8; 256];
+    file.seek(SeekFrom::Start(0x200 + offset))?;
+    let len = file.read(&mut version)?;
+    let version = &version[..len];
+    let end = version.iter().position(|b| *b == 0).unwrap_or(len);
+    Ok(Some(String::from_utf8_lossy(&version[..end]).to_string()))
+}
+
+/// Parse MBR or GPT partition table. Returns `None` as the table kind if the first sector
+/// has no boot signature.
+fn read_partition_table(file: &mut fs::File) -> Result<(Option<&'static str>, Vec<Partition>)> {
+    let mut mbr = [0u8; SECTOR_SIZE as usize];
+    if file.read_exact(&mut mbr).is_err() || mbr[510..512] != [0x55, 0xaa] {
+        return Ok((None, Vec::new()));
+    }
+    // A filesystem boot sector has the same signature, but no partition table.
+    if detect_filesystem(file, 0)?.is_some() {
+        return Ok((None, Vec::new()));
+    }
+
+    let disk_id = u32::from_le_bytes(mbr[440..444].try_into().unwrap());
+    let mut partitions = Vec::new();
+    for number in 1..=4u32 {
+        let entry = &mbr[446 + (number as usize - 1) * 16..][..16];
+        let kind = entry[4];
+        let start = u32::from_le_bytes(entry[8..12].try_into().unwrap()) as u64;
+        let sectors = u32::from_le_bytes(entry[12..16].try_into().unwrap()) as u64;
+        if kind == 0xee {
+            return Ok((Some("gpt"), read_gpt(file)?));
+        }
+        if kind == 0 || sectors == 0 {
+            continue;
+        }
+        partitions.push(Partition {
+            number,
+            start: start * SECTOR_SIZE,
+            size: sectors * SECTOR_SIZE,
+            r#type: format!("{:x}", kind),
+            uuid: format!("{:08x}-{:02x}", disk_id, number),
+            name: None,
+            bootable: entry[0] == 0x80,
+            filesystem: detect_filesystem(file, start * SECTOR_SIZE)?,
+        });
+    }
+    Ok((Some("mbr"), partitions))
+}
+
+/// Parse GPT partition entries referenced by the primary header.
+fn read_gpt(file: &mut fs::File) -> Result<Vec<Partition>> {
+    let mut header = [0u8; 92];
+    file.seek(SeekFrom::Start(SECTOR_SIZE))?;
+    file.read_exact(&mut header)?;
+    if &header[0..8] != b"EFI PART" {
+        anyhow::bail!("Protective MBR found, but GPT header is missing");
+    }
+    let entries_lba = u64::from_le_bytes(header[72..80].try_into().unwrap());
+    let entries = u32::from_le_bytes(header[80..84].try_into().unwrap());
+    let entry_size = u32::from_le_bytes(header[84..88].try_into().unwrap()) as usize;
+    if entry_size < 128 {
+        anyhow::bail!("Invalid GPT partition entry size {}", entry_size);
+    }
+
+    let mut table = vec![0u8; entries as usize * entry_size];
+    file.seek(SeekFrom::Start(entries_lba * SECTOR_SIZE))?;
+    file.read_exact(&mut table)?;
+
+    let mut partitions = Vec::new();
+    for (entry, number) in table.chunks(entry_size).zip(1u32..) {
+        if entry[0..16].iter().all(|b| *b == 0) {
+            continue;
+        }
+        let first = u64::from_le_bytes(entry[32..40].try_into().unwrap());
+        let last = u64::from_le_bytes(entry[40..48].try_into().unwrap());
+        let attributes = u64::from_le_bytes(entry[48..56].try_into().unwrap());
+        let name = entry[56..128]
+            .chunks(2)
+            .map(|c| u16::from_le_bytes([c[0], c[1]]))
+            .take_while(|c| *c != 0)
+            .collect::<Vec<_>>();
+        partitions.push(Partition {
+            number,
+            start: first * SECTOR_SIZE,
+            size: (last + 1 - first) * SECTOR_SIZE,
+            r#type: format_guid(&entry[0..16]),
+            uuid: format_guid(&entry[16..32]).to_lowercase(),
+            name: Some(String::from_utf16_lossy(&name)).filter(|name| !name.is_empty()),
+            bootable: attributes & (1 << 2) != 0,
+            filesystem: detect_filesystem(file, first * SECTOR_SIZE)?,
+        });
+    }
+    Ok(partitions)
+}
+
+/// Format mixed-endian GUID as stored on disk.
+pub(super) fn format_guid(bytes: &[u8]) -> String {
+    format!(
+        "{:08X}-{:04X}-{:04X}-{:02X}{:02X}-{}",
+        u32::from_le_bytes(bytes[0..4].try_into().unwrap()),
+        u16::from_le_bytes(bytes[4..6].try_into().unwrap()),
+        u16::from_le_bytes(bytes[6..8].try_into().unwrap()),
+        bytes[8],
+        bytes[9],
+        bytes[10..16]
+            .iter()
+            .map(|b| format!("{:02X}", b))
+            .collect::<String>()
+    )
+}
+
+/// Detect filesystem starting at `offset` by its magic numbers.
+fn detect_filesystem(file: &mut fs::File, offset: u64) -> Result<Option<Filesystem>> {
+    let mut block = [0u8; 2048];
+    file.seek(SeekFrom::Start(offset))?;
+    let mut len = 0;
+    while len < block.len() {
+        match file.read(&mut block[len..])? {
+            0 => break,
+            n => len += n,
+        }
+    }
+    if len < block.len() {
+        return Ok(None);
+    }
+
+    let filesystem = if &block[0..4] == b"hsqs" {
+        Some(Filesystem::Squashfs)
+    } else if block[1024 + 0x38..1024 + 0x3a] == [0x53, 0xef] {
+        Some(Filesystem::Ext4)
+    } else if block[1024..1028] == [0xe2, 0xe1, 0xf5, 0xe0] {
+        Some(Filesystem::Erofs)
+    } else if block[510..512] == [0x55, 0xaa]
+        && (&block[0x36..0x39] == b"FAT" || &block[0x52..0x55] == b"FAT")
+    {
+        Some(Filesystem::Fat)
+    } else {
+        None
+    };
+    Ok(filesystem)
+}
+
+/// Changes of the runtime config requested with `gvltctl image set-config`.
+#[derive(Debug, Default)]
+pub struct ConfigChanges {
+    pub command: Option<String>,
+    /// New arguments of the command, replacing the current ones.
+    pub args: Option<Vec<String>>,
+    /// Environment variables to set, replacing values of existing ones.
+    pub env: Vec<runtime_config::EnvVar>,
+    /// Remove all environment variables before setting `env`.
+    pub clear_env: bool,
+    /// Mounts to add.
+    pub mounts: Vec<runtime_config::Mount>,
+    /// Remove all mounts before adding `mounts`.
+    pub clear_mounts: bool,
+}
+
+impl ConfigChanges {
+    fn apply(&self, config: &mut RuntimeConfig) {
+        if let Some(command) = &self.command {
+            config.command = Some(command.clone());
+        }
+        if let Some(args) = &self.args {
+            config.args = args.clone();
+        }
+        if self.clear_env {
+            config.env.clear();
+        }
+        for var in &self.env {
+            match config
+                .env
+                .iter_mut()
+                .find(|existing| existing.key == var.key)
+            {
+                Some(existing) => existing.value = var.value.clone(),
+                None => config.env.push(var.clone()),
+            }
+        }
+        if self.clear_mounts {
+            config.mounts.clear();
+        }
+        config.mounts.extend(self.mounts.iter().cloned());
+    }
+
+    /// Only changes which are possible without MIA: custom init is started from kernel
+    /// command line with `init=` and arguments after `--`.
+    fn fit_cmdline(&self) -> bool {
+        self.env.is_empty() && !self.clear_env && self.mounts.is_empty() && !self.clear_mounts
+    }
+
+    /// Apply command and arguments to the kernel command line of a custom init.
+    fn apply_cmdline(&self, cmdline: &str) -> Result<String> {
+        let (kernel_args, init_args) = match cmdline.split_once(" -- ") {
+            Some((kernel_args, init_args)) => (kernel_args, Some(init_args.to_string())),
+            None => (cmdline, None),
+        };
+        let mut kernel_args = kernel_args
+            .split_whitespace()
+            .map(str::to_string)
+            .collect::<Vec<_>>();
+        if let Some(command) = &self.command {
+            kernel_args.retain(|arg| !arg.starts_with("init="));
+            kernel_args.push(format!("init={}", command));
+        }
+        let init_args = match &self.args {
+            Some(args) => {
+                if args.iter().any(|arg| arg.contains(char::is_whitespace)) {
+                    anyhow::bail!("Arguments of custom init can't contain whitespace");
+                }
+                Some(args.join(" ")).filter(|args| !args.is_empty())
+            }
+            None => init_args,
+        };
+        let mut cmdline = kernel_args.join(" ");
+        if let Some(init_args) = init_args {
+            cmdline.push_str(&format!(" -- {}", init_args));
+        }
+        Ok(cmdline)
+    }
+}
+
+/// Change runtime config of the image in place. Returns SHA-256 digest of the modified image.
+///
+/// MIA runtime config is rewritten inside root filesystem. Images with custom init have no
+/// runtime config, their command and arguments are changed in the kernel command line.
+pub fn set_config(path: &Path, changes: &ConfigChanges) -> Result<String> {
+    let image = Image::open(path)?;
+    if image.root_filesystem()?.is_none() {
+        anyhow::bail!("Root filesystem not found in {}", path.display());
+    }
+    // Any change of the root filesystem invalidates the root hash, and regenerated bare
+    // filesystem image would also drop the hash tree appended to it.
+    if image.verity_protected()? {
+        anyhow::bail!(
+            "Root filesystem is protected by dm-verity and can't be modified. Rebuild the image instead."
+        );
+    }
+
+    match image.read_root_file(MIA_CONFIG_PATH)? {
+        Some(config) => {
+            let mut config: RuntimeConfig = serde_yaml::from_str(&config)
+                .context(format!("Failed to parse {}", MIA_CONFIG_PATH))?;
+            changes.apply(&mut config);
+            let config = serde_yaml::to_string(&config)?;
+            image.write_root_file(MIA_CONFIG_PATH, &config)?;
+        }
+        None => {
+            if !changes.fit_cmdline() {
+                anyhow::bail!(
+                    "Image has no MIA runtime config at {}, only command and arguments of custom init can be changed",
+                    MIA_CONFIG_PATH
+                );
+            }
+            if !image.update_cmdline(|cmdline| changes.apply_cmdline(cmdline))? {
+                anyhow::bail!(
+                    "Image has no bootloader config. Kernel command line of direct kernel boot is in its boot.json."
+                );
+            }
+        }
+    }
+
+    report::sha256(path)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Bare SquashFS image with `bytes_used` data bytes followed by `tail` bytes.
+    fn squashfs_image(dir: &TempDir, bytes_used: u64, tail: u64) -> PathBuf {
+        let path = dir.path().join("rootfs.img");
+        let mut content = vec![0u8; size::align_up(bytes_used, VERITY_BLOCK_SIZE) as usize];
+        content[0..4].copy_from_slice(b"hsqs");
+        content[40..48].copy_from_slice(&bytes_used.to_le_bytes());
+        content.resize(content.len() + tail as usize, 0);
+        fs::write(&path, content).unwrap();
+        path
+    }
+
+    /// Bare ext4 image of `blocks` 4 KiB blocks followed by `tail` bytes.
+    fn ext4_image(dir: &TempDir, blocks: u32, tail: u64) -> PathBuf {
+        let path = dir.path().join("rootfs.img");
+        let mut content = vec![0u8; blocks as usize * 4096];
+        let superblock = &mut content[1024..2048];
+        superblock[0x04..0x08].copy_from_slice(&blocks.to_le_bytes());
+        superblock[0x18..0x1c].copy_from_slice(&2u32.to_le_bytes());
+        superblock[0x38..0x3a].copy_from_slice(&[0x53, 0xef]);
+        content.resize(content.len() + tail as usize, 0);
+        fs::write(&path, content).unwrap();
+        path
+    }
+
+    fn verity_protected(path: &Path) -> bool {
+        Image::open(path).unwrap().verity_protected().unwrap()
+    }
+
+    #[test]
+    fn filesystem_size_from_superblock() {
+        let dir = TempDir::new("image-test").unwrap();
+        let path = ext4_image(&dir, 4, 0);
+        let mut file = fs::File::open(&path).unwrap();
+        let root = Image::open(&path)
+            .unwrap()
+            .root_filesystem()
+            .unwrap()
+            .unwrap();
+        assert_eq!(root.filesystem, Filesystem::Ext4);
+        assert_eq!(read_filesystem_size(&mut file, root).unwrap(), 16384);
+
+        let path = squashfs_image(&dir, 5000, 0);
+        let mut file = fs::File::open(&path).unwrap();
+        let root = Image::open(&path)
+            .unwrap()
+            .root_filesystem()
+            .unwrap()
+            .unwrap();
+        assert_eq!(root.filesystem, Filesystem::Squashfs);
+        assert_eq!(read_filesystem_size(&mut file, root).unwrap(), 5000);
+    }
+
+    #[test]
+    fn bare_filesystem_without_hash_tree_is_not_protected() {
+        let dir = TempDir::new("image-test").unwrap();
+        // mksquashfs pads the image to 4 KiB.
+        assert!(!verity_protected(&squashfs_image(&dir, 5000, 0)));
+        assert!(!verity_protected(&ext4_image(&dir, 4, 0)));
+    }
+
+    #[test]
+    fn appended_hash_tree_is_detected() {
+        let dir = TempDir::new("image-test").unwrap();
+        assert!(verity_protected(&squashfs_image(&dir, 5000, 4096)));
+        assert!(verity_protected(&ext4_image(&dir, 4, 4096)));
+    }
+
+    #[test]
+    fn boot_descriptor_with_verity_is_detected() {
+        let dir = TempDir::new("image-test").unwrap();
+        let path = squashfs_image(&dir, 5000, 0);
+        let descriptor = dir.path().join("boot.json");
+
+        fs::write(&descriptor, r#"{"cmdline": "ro", "verity": null}"#).unwrap();
+        assert!(!verity_protected(&path));
+        fs::write(
+            &descriptor,
+            r#"{"cmdline": "ro", "verity": {"root_hash": "00", "salt": "00", "data_blocks": 2}}"#,
+        )
+        .unwrap();
+        assert!(verity_protected(&path));
+        fs::write(&descriptor, r#"{"cmdline": "ro verity.roothash=00"}"#).unwrap();
+        assert!(verity_protected(&path));
+
+        fs::write(&descriptor, "not json").unwrap();
+        assert!(Image::open(&path).unwrap().verity_protected().is_err());
+    }
+
+    #[test]
+    fn set_config_refuses_verity_protected_rootfs() {
+        let dir = TempDir::new("image-test").unwrap();
+        let path = ext4_image(&dir, 4, 4096);
+        let err = set_config(&path, &ConfigChanges::default()).unwrap_err();
+        assert!(err.to_string().contains("dm-verity"));
+    }
+}
@@ -0,0 +1,270 @@
+//! Minimal initramfs mounting the root filesystem before handing over to MIA or custom init.
+//!
+//! It consists of a static busybox, the `init` script and storage and filesystem modules
+//! of the built kernel. Root device may be given as device path, `LABEL=` or `UUID=`.
+//! With dm-verity it also contains `dmsetup` from the host to set up the verity device.
+//!
+//! MIA reads its runtime config from the root filesystem and starts the workload in it,
+//! so it can't run before the root is mounted. The `init` script is the initramfs init
+//! and execs MIA of the root filesystem as PID 1 with `switch_root`.
+
+use std::fs;
+use std::path::Path;
+
+use anyhow::{Context, Result};
+use tempdir::TempDir;
+
+use crate::builders::kernel::modules::ModulesTree;
+use crate::builders::skopeo_builder::SkopeoSyslinuxBuilder;
+use crate::builders::RootfsFormat;
+
+/// Name of the initramfs file in boot partition and direct boot output directory.
+pub const INITRAMFS_FILE: &str = "initramfs.img";
+
+const INIT_SCRIPT: &str = include_str!("initramfs/init.sh");
+
+/// Storage drivers loaded by initramfs if they are built as modules.
+const STORAGE_MODULES: [&str; 7] = [
+    "virtio_pci",
+    "virtio_blk",
+    "virtio_scsi",
+    "sd_mod",
+    "ahci",
+    "ata_piix",
+    "nvme",
+];
+
+/// Device mapper drivers loaded by initramfs for dm-verity if they are built as modules.
+const VERITY_MODULES: [&str; 2] = ["dm_mod", "dm_verity"];
+
+/// Find static busybox binary on the host.
+pub fn find_busybox(busybox_file: Option<&str>) -> Result<String> {
+    const CANDIDATES: [&str; 4] = [
+        "/usr/lib/initramfs-tools/bin/busybox",
+        "/usr/bin/busybox",
+        "/bin/busybox",
+        "/usr/sbin/busybox",
+    ];
+    let busybox = if let Some(busybox_file) = busybox_file {
+        busybox_file.to_string()
+    } else if let Some(path) = CANDIDATES
+        .into_iter()
+        .find(|candidate| Path::new(candidate).exists())
+    {
+        path.to_string()
+    } else {
+        anyhow::bail!("busybox was not found. Use --busybox-file option to specify it.");
+    };
+
+    // Initramfs has no libraries, so busybox must be linked statically.
+    if SkopeoSyslinuxBuilder::run_command_output(&["ldd", &busybox], false).is_ok() {
+        anyhow::bail!(
+            "{} is dynamically linked. Use --busybox-file option to specify static busybox.",
+            busybox
+        );
+    }
+    Ok(busybox)
+}
+
+/// Build gzip-compressed cpio initramfs into `target`.
+///
+/// Modules are taken from the kernel built in `kernel_dir`. Kernels without build tree
+/// (precompiled or prebuilt) must have storage and filesystem drivers built in.
+/// With `verity` initramfs can set up dm-verity device for the root filesystem.
+/// Without `init=` on kernel command line initramfs hands over to MIA at `mia_path`
+/// in the root filesystem.
+pub fn build(
+    target: &Path,
+    kernel_dir: Option<&Path>,
+    rootfs_format: RootfsFormat,
+    busybox: &str,
+    verity: bool,
+    mia_path: &str,
+    source_date_epoch: Option<u64>,
+) -> Result<()> {
+    // Device nodes are created in the staging directory, so it is populated as root.
+    let staging_dir = TempDir::new("initramfs").context("Failed to create temporary directory")?;
+    let root = staging_dir.path();
+    let root_str = root.to_str().unwrap();
+
+    for dir in ["bin", "dev", "proc", "sys", "newroot", "etc/initramfs"] {
+        SkopeoSyslinuxBuilder::run_command(
+            &["mkdir", "-p", &format!("{}/{}", root_str, dir)],
+            true,
+        )
+        .context("Failed to create initramfs directory")?;
+    }
+    SkopeoSyslinuxBuilder::run_command(
+        &[
+            "install",
+            "-m",
+            "0755",
+            busybox,
+            &format!("{}/bin/busybox", root_str),
+        ],
+        true,
+    )
+    .context("Failed to copy busybox to initramfs")?;
+    let init_path = root.join("init");
+    SkopeoSyslinuxBuilder::write_file_as_root(&init_path, INIT_SCRIPT)
+        .context("Failed to write initramfs init")?;
+    SkopeoSyslinuxBuilder::run_command(&["chmod", "0755", init_path.to_str().unwrap()], true)
+        .context("Failed to make initramfs init executable")?;
+    SkopeoSyslinuxBuilder::write_file_as_root(
+        &root.join("etc/initramfs/init"),
+        &format!("{}\n", mia_path),
+    )
+    .context("Failed to write initramfs init path")?;
+    // Kernel opens console for init before devtmpfs is mounted.
+    SkopeoSyslinuxBuilder::run_command(
+        &[
+            "mknod",
+            "-m",
+            "0600",
+            &format!("{}/dev/console", root_str),
+            "c",
+            "5",
+            "1",
+        ],
+        true,
+    )
+    .context("Failed to create initramfs console device")?;
+
+    if verity {
+        install_dmsetup(root)?;
+    }
+
+    if let Some(tree) = kernel_dir.map(ModulesTree::prepare).transpose()?.flatten() {
+        let mut names = STORAGE_MODULES
+            .iter()
+            .map(|name| name.to_string())
+            .collect::<Vec<_>>();
+        names.push(rootfs_format.to_string());
+        if verity {
+            names.extend(VERITY_MODULES.iter().map(|name| name.to_string()));
+        }
+        let modules = tree.load_order(&names)?;
+        for module in &modules {
+            let target = root.join("lib/modules").join(&tree.release).join(module);
+            SkopeoSyslinuxBuilder::run_command(
+                &[
+                    "install",
+                    "-D",
+                    "-m",
+                    "0644",
+                    tree.dir.join(module).to_str().unwrap(),
+                    target.to_str().unwrap(),
+                ],
+                true,
+            )
+            .context(format!(
+                "Failed to copy kernel module {} to initramfs",
+                module
+            ))?;
+        }
+        let list = modules
+            .iter()
+            .map(|module| format!("{}/{}\n", tree.release, module))
+            .collect::<String>();
+        SkopeoSyslinuxBuilder::write_file_as_root(&root.join("etc/initramfs/modules"), &list)
+            .context("Failed to write initramfs modules list")?;
+    }
+
+    // Archive entries in stable order with fixed owner and timestamps.
+    let epoch = source_date_epoch.unwrap_or(0);
+    SkopeoSyslinuxBuilder::run_command(
+        &[
+            "find",
+            root_str,
+            "-exec",
+            "touch",
+            "--no-dereference",
+            "--date",
+            &format!("@{}", epoch),
+            "{}",
+            "+",
+        ],
+        true,
+    )
+    .context("Failed to normalize initramfs timestamps")?;
+    SkopeoSyslinuxBuilder::run_command(
+        &[
+            "sh",
+            "-c",
+            &format!(
+                "cd {} && find . -print0 | LC_ALL=C sort -z | cpio --null --create --format=newc --owner=0:0 --reproducible --quiet | gzip -9 -n > {}",
+                root_str,
+                target.display()
+            ),
+        ],
+        true,
+    )
+    .context("Failed to create initramfs archive")?;
+
+    // Files are owned by root, so temp dir can't remove them on dropping.
+    _ = SkopeoSyslinuxBuilder::run_command(&["rm", "-rf", root_str], true);
+
+    Ok(())
+}
+
+/// Copy `dmsetup` from the host into initramfs at `root` along with its shared libraries.
+fn install_dmsetup(root: &Path) -> Result<()> {
+    const CANDIDATES: [&str; 3] = ["/usr/sbin/dmsetup", "/sbin/dmsetup", "/usr/bin/dmsetup"];
+    let dmsetup = CANDIDATES
+        .into_iter()
+        .find(|candidate| Path::new(candidate).exists())
+        .context("dmsetup was not found. It is required for --verity.")?;
+    let root_str = root.to_str().unwrap();
+    SkopeoSyslinuxBuilder::run_command(
+        &[
+            "install",
+            "-m",
+            "0755",
+            dmsetup,
+            &format!("{}/bin/dmsetup", root_str),
+        ],
+        true,
+    )
+    .context("Failed to copy dmsetup to initramfs")?;
+
+    // Libraries are placed into /lib, which is always searched by the dynamic loader.
+    // The loader itself must stay at the path recorded in the binary.
+    let ldd = SkopeoSyslinuxBuilder::run_command_output(&["ldd", dmsetup], false)
+        .context("Failed to list dmsetup libraries")?;
+    for line in ldd.lines() {
+        let (library, target) = match line.split_once("=>") {
+            Some((_, path)) => {
+                let Some(library) = path.split_whitespace().next() else {
+                    continue;
+                };
+                let name = Path::new(library)
+                    .file_name()
+                    .context("Invalid library path in ldd output")?;
+                (library, root.join("lib").join(name))
+            }
+            // Dynamic loader has an absolute path, vDSO has none.
+            None => match line.split_whitespace().next() {
+                Some(loader) if loader.starts_with('/') => {
+                    (loader, root.join(loader.trim_start_matches('/')))
+                }
+                _ => continue,
+            },
+        };
+        if fs::symlink_metadata(&target).is_ok() {
+            continue;
+        }
+        SkopeoSyslinuxBuilder::run_command(
+            &[
+                "install",
+                "-D",
+                "-m",
+                "0755",
+                library,
+                target.to_str().unwrap(),
+            ],
+            true,
+        )
+        .context(format!("Failed to copy {} to initramfs", library))?;
+    }
+    Ok(())
+}
@@ -0,0 +1,105 @@
+#!/bin/busybox sh
+# Gevulot initramfs init.
+# Loads storage and filesystem modules, finds the root filesystem, sets up dm-verity
+# if requested and switches to MIA in it. Custom init is given with init= on kernel command line.
+
+/bin/busybox --install -s /bin
+export PATH=/bin
+
+mount -t proc proc /proc
+mount -t sysfs sysfs /sys
+mount -t devtmpfs devtmpfs /dev
+
+panic() {
+    echo "initramfs: $*"
+    exec sh
+}
+
+root=""
+rootfstype=""
+mode="ro"
+# MIA binary path inside the root filesystem, written by gvltctl.
+init=""
+[ -f /etc/initramfs/init ] && read -r init < /etc/initramfs/init
+verity_roothash=""
+verity_salt=""
+verity_blocks=""
+for arg in $(cat /proc/cmdline); do
+    case "$arg" in
+        root=*) root="${arg#root=}" ;;
+        rootfstype=*) rootfstype="${arg#rootfstype=}" ;;
+        init=*) init="${arg#init=}" ;;
+        verity.roothash=*) verity_roothash="${arg#verity.roothash=}" ;;
+        verity.salt=*) verity_salt="${arg#verity.salt=}" ;;
+        verity.blocks=*) verity_blocks="${arg#verity.blocks=}" ;;
+        ro) mode="ro" ;;
+        rw) mode="rw" ;;
+        --) break ;;
+    esac
+done
+[ -n "$root" ] || panic "no root= on kernel command line"
+[ -n "$init" ] || panic "no init= on kernel command line"
+# Busybox findfs resolves only LABEL= and UUID=.
+case "$root" in
+    PARTUUID=*|PARTLABEL=*) panic "root=$root is not supported by initramfs, use LABEL=, UUID= or device path" ;;
+esac
+
+if [ -f /etc/initramfs/modules ]; then
+    while read -r module; do
+        insmod "/lib/modules/$module" || echo "initramfs: failed to load $module"
+    done < /etc/initramfs/modules
+fi
+
+# Same image can be attached as SCSI/SATA (sdX) or virtio-blk (vdX) disk.
+resolve_root() {
+    case "$root" in
+        LABEL=*|UUID=*)
+            findfs "$root" 2>/dev/null
+            ;;
+        /dev/sd*)
+            for device in "$root" "/dev/vd${root#/dev/sd}"; do
+                [ -b "$device" ] && echo "$device" && return
+            done
+            ;;
+        /dev/vd*)
+            for device in "$root" "/dev/sd${root#/dev/vd}"; do
+                [ -b "$device" ] && echo "$device" && return
+            done
+            ;;
+        *)
+            [ -b "$root" ] && echo "$root"
+            ;;
+    esac
+}
+
+device=""
+attempts=0
+while [ -z "$device" ]; do
+    device="$(resolve_root)"
+    [ -n "$device" ] && break
+    attempts=$((attempts + 1))
+    [ "$attempts" -le 100 ] || panic "root device $root not found"
+    sleep 0.1
+done
+
+# Hash tree is stored on the same device right after the data blocks.
+if [ -n "$verity_roothash" ]; then
+    [ -n "$verity_salt" ] && [ -n "$verity_blocks" ] ||
+        panic "incomplete verity parameters on kernel command line"
+    table="0 $((verity_blocks * 8)) verity 1 $device $device 4096 4096 $verity_blocks $verity_blocks sha256 $verity_roothash $verity_salt"
+    dmsetup create root --readonly --noudevsync --table "$table" ||
+        panic "failed to set up dm-verity for $device"
+    dmsetup mknodes root
+    device="/dev/mapper/root"
+    mode="ro"
+fi
+
+mount ${rootfstype:+-t "$rootfstype"} -o "$mode" "$device" /newroot ||
+    panic "failed to mount $device"
+
+# Init may be an absolute symlink, which can only be resolved inside the root filesystem.
+[ -e "/newroot$init" ] || [ -L "/newroot$init" ] ||
+    panic "init $init not found in root filesystem"
+
+umount /proc /sys /dev
+exec switch_root /newroot "$init" "$@"
@@ -0,0 +1,588 @@
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use anyhow::{Context, Result};
+use log::{debug, warn};
+use serde::{Deserialize, Serialize};
+use tempdir::TempDir;
+
+use crate::builders::skopeo_builder::SkopeoSyslinuxBuilder;
+use crate::builders::Arch;
+
+pub mod modules;
+pub mod prebuilt;
+
+/// Version of the built-in Gevulot config fragment, recorded in cache manifests.
+/// Cached builds are keyed by the hash of the resulting config, so changes of the fragment
+/// options don't need a new version.
+pub const GEVULOT_FRAGMENT_VERSION: u32 = 1;
+
+/// Built-in config fragment merged into every kernel config.
+const GEVULOT_FRAGMENT: &str = include_str!("kernel/gevulot-v1.config");
+
+/// Number of hex characters of config hash used in cache directory names.
+const CONFIG_HASH_LENGTH: usize = 16;
+
+/// Number of hex characters of source commit used in cache directory names.
+const COMMIT_LENGTH: usize = 12;
+
+/// Name of the manifest file stored in every build tree.
+pub const MANIFEST_FILE: &str = "gvltctl-manifest.json";
+
+/// User customizations of the kernel configuration.
+#[derive(Debug, Clone, Default)]
+pub struct KernelConfig {
+    pub arch: Arch,
+    /// Toolchain prefix, e.g. `aarch64-linux-gnu-`. If not set, the default one for `arch`
+    /// is used when the host has another architecture.
+    pub cross_compile: Option<String>,
+    /// Base config file used instead of the defconfig of `arch`.
+    pub defconfig: Option<String>,
+    /// Config fragments merged on top of the built-in Gevulot fragment in given order.
+    pub fragments: Vec<String>,
+}
+
+impl KernelConfig {
+    /// Toolchain prefix used for the build, `None` for native builds.
+    fn toolchain(&self) -> Option<String> {
+        self.arch.toolchain(self.cross_compile.as_deref())
+    }
+}
+
+/// State of the kernel build stored in cache entry manifest.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum BuildStatus {
+    /// Build is in progress or was interrupted.
+    Building,
+    /// Build failed, tree is kept for inspection until pruned.
+    Failed,
+    /// Kernel image is built and can be reused.
+    Ready,
+}
+
+/// Description of a kernel cache entry.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct CacheManifest {
+    pub version: String,
+    /// Kernel repository URL or prebuilt kernel image URL.
+    pub url: String,
+    /// Source commit of kernels built locally.
+    #[serde(default)]
+    pub commit: Option<String>,
+    /// Config hash of kernels built locally.
+    #[serde(default)]
+    pub config_hash: Option<String>,
+    /// Kernel image checksum of prebuilt kernels.
+    #[serde(default)]
+    pub sha256: Option<String>,
+    pub fragment_version: u32,
+    /// Entries created before multi-architecture support are x86_64 kernels.
+    #[serde(default)]
+    pub arch: Arch,
+    /// Toolchain prefix of cross-compiled kernels.
+    #[serde(default)]
+    pub cross_compile: Option<String>,
+    pub status: BuildStatus,
+    /// Unix timestamp of the last manifest update.
+    pub updated_at: u64,
+}
+
+/// Kernel cache entry found on disk.
+#[derive(Debug, Clone, Serialize)]
+pub struct CacheEntry {
+    pub name: String,
+    pub path: PathBuf,
+    /// `None` if manifest is missing or unreadable.
+    pub manifest: Option<CacheManifest>,
+}
+
+impl CacheEntry {
+    /// Entry can be used for building images.
+    pub fn is_ready(&self) -> bool {
+        self.manifest.as_ref().is_some_and(|manifest| {
+            manifest.status == BuildStatus::Ready && image_path(&self.path, manifest.arch).exists()
+        })
+    }
+}
+
+/// Root directory of kernel cache: `~/.linux-builds`.
+///
+/// Layout:
+/// - `sources/<url>/<version>`: pristine clones, never built in.
+/// - `builds/<version>-<commit>-<config hash>`: finished builds with manifest.
+/// - `builds/prebuilt-<version>-<checksum>`: downloaded prebuilt kernels with manifest.
+/// - `tmp/`: clones and builds in progress, promoted by rename when complete.
+pub fn cache_dir() -> Result<PathBuf> {
+    let home_dir = std::env::var("HOME").context("Failed to get HOME environment variable")?;
+    Ok(Path::new(&home_dir).join(".linux-builds"))
+}
+
+fn sources_dir() -> Result<PathBuf> {
+    Ok(cache_dir()?.join("sources"))
+}
+
+fn builds_dir() -> Result<PathBuf> {
+    Ok(cache_dir()?.join("builds"))
+}
+
+fn tmp_dir() -> Result<PathBuf> {
+    Ok(cache_dir()?.join("tmp"))
+}
+
+/// Path of the kernel image inside the kernel build tree.
+pub fn image_path(kernel_dir: &Path, arch: Arch) -> PathBuf {
+    kernel_dir
+        .join("arch")
+        .join(arch.kernel_arch())
+        .join("boot")
+        .join(arch.kernel_file())
+}
+
+/// Architecture of the kernel in the cache entry at `kernel_dir`.
+pub fn entry_arch(kernel_dir: &Path) -> Arch {
+    read_manifest(kernel_dir)
+        .map(|manifest| manifest.arch)
+        .unwrap_or_default()
+}
+
+/// `ARCH` and `CROSS_COMPILE` arguments of `make` for the kernel tree.
+fn make_variables(arch: Arch, cross_compile: Option<&str>) -> Vec<String> {
+    let mut variables = vec![format!("ARCH={}", arch.kernel_arch())];
+    if let Some(cross_compile) = cross_compile {
+        variables.push(format!("CROSS_COMPILE={}", cross_compile));
+    }
+    variables
+}
+
+/// Kernel release string (`uname -r`) of the kernel in `kernel_dir`.
+pub fn kernel_release(kernel_dir: &Path) -> Result<String> {
+    let release = fs::read_to_string(kernel_dir.join("include/config/kernel.release"))
+        .context("Failed to read kernel release")?;
+    Ok(release.trim().to_string())
+}
+
+/// Build kernel with given configuration or reuse cached build.
+/// Returns path to the kernel build tree.
+///
+/// Builds are keyed on kernel version, source commit and resulting `.config` hash,
+/// so changing kernel URL, version or configuration never reuses a wrong kernel.
+pub fn build(version: &str, kernel_url: &str, config: &KernelConfig) -> Result<PathBuf> {
+    let source_dir = prepare_source(version, kernel_url)?;
+    let commit = source_commit(&source_dir)?;
+
+    // Generate config out of the source tree first to find out its hash.
+    let config_dir =
+        TempDir::new("kernel-config").context("Failed to create temporary directory")?;
+    configure(&source_dir, config_dir.path(), config)?;
+    let config_hash = config_hash(&config_dir.path().join(".config"))?;
+    debug!("kernel config hash: {}", config_hash);
+
+    let name = format!(
+        "{}-{}-{}",
+        version,
+        &commit[..COMMIT_LENGTH.min(commit.len())],
+        config_hash
+    );
+    let kernel_dir = builds_dir()?.join(&name);
+    if read_entry(&kernel_dir)?.is_ready() {
+        debug!("using cached kernel build {}", kernel_dir.display());
+        return Ok(kernel_dir);
+    }
+
+    // Build in temporary directory unique for this process and promote it when finished.
+    let staging_dir = tmp_dir()?.join(format!("{}.{}", name, std::process::id()));
+    remove_dir(&staging_dir)?;
+    fs::create_dir_all(tmp_dir()?).context("Failed to create kernel cache directory")?;
+    SkopeoSyslinuxBuilder::run_command(
+        &[
+            "cp",
+            "-a",
+            "--reflink=auto",
+            source_dir.to_str().unwrap(),
+            staging_dir.to_str().unwrap(),
+        ],
+        false,
+    )
+    .context("Failed to copy kernel sources")?;
+    fs::copy(
+        config_dir.path().join(".config"),
+        staging_dir.join(".config"),
+    )
+    .context("Failed to copy kernel config")?;
+
+    let mut manifest = CacheManifest {
+        version: version.to_string(),
+        url: kernel_url.to_string(),
+        commit: Some(commit),
+        config_hash: Some(config_hash),
+        sha256: None,
+        fragment_version: GEVULOT_FRAGMENT_VERSION,
+        arch: config.arch,
+        cross_compile: config.toolchain(),
+        status: BuildStatus::Building,
+        updated_at: 0,
+    };
+    write_manifest(&staging_dir, &mut manifest)?;
+
+    let make_variables = make_variables(config.arch, config.toolchain().as_deref());
+    let mut make_args = vec![
+        "make".to_string(),
+        "-C".to_string(),
+        staging_dir.to_str().unwrap().to_string(),
+        format!("-j{}", num_cpus::get()),
+    ];
+    make_args.extend(make_variables);
+    let result = SkopeoSyslinuxBuilder::run_command(
+        &make_args.iter().map(String::as_str).collect::<Vec<_>>(),
+        false,
+    );
+    if let Err(err) = result {
+        manifest.status = BuildStatus::Failed;
+        write_manifest(&staging_dir, &mut manifest)?;
+        return Err(err).context(format!(
+            "Failed to build kernel (build tree kept in {})",
+            staging_dir.display()
+        ));
+    }
+
+    manifest.status = BuildStatus::Ready;
+    write_manifest(&staging_dir, &mut manifest)?;
+
+    promote(&staging_dir, &kernel_dir)?;
+
+    Ok(kernel_dir)
+}
+
+/// Clone kernel sources of given version. Nothing is ever built in this tree,
+/// so it can be used for out-of-tree configuration.
+/// Sources of `latest` are cloned again when the remote branch moves on.
+pub fn prepare_source(version: &str, kernel_url: &str) -> Result<PathBuf> {
+    let source_dir = sources_dir()?.join(url_slug(kernel_url)).join(version);
+    if source_dir.exists() {
+        if version != "latest" || !is_outdated(&source_dir, kernel_url) {
+            debug!("Kernel source directory already exists");
+            return Ok(source_dir);
+        }
+        debug!("Kernel source directory is outdated");
+    }
+
+    // Clone into temporary directory, so interrupted clone is never reused.
+    let clone_dir = tmp_dir()?.join(format!("source-{}.{}", version, std::process::id()));
+    remove_dir(&clone_dir)?;
+    fs::create_dir_all(tmp_dir()?).context("Failed to create kernel cache directory")?;
+    let clone_dir_str = clone_dir.to_str().unwrap();
+    let clone_args = if version == "latest" {
+        vec!["git", "clone", "--depth", "1", kernel_url, clone_dir_str]
+    } else {
+        vec![
+            "git",
+            "clone",
+            "--depth",
+            "1",
+            "--branch",
+            version,
+            kernel_url,
+            clone_dir_str,
+        ]
+    };
+    SkopeoSyslinuxBuilder::run_command(&clone_args, false)
+        .context("Failed to clone kernel repository")?;
+
+    fs::create_dir_all(source_dir.parent().unwrap())
+        .context("Failed to create kernel source directory")?;
+    // Outdated sources are moved away first, rename can't replace non-empty directory.
+    if source_dir.exists() {
+        let old_dir = tmp_dir()?.join(format!("source-{}-old.{}", version, std::process::id()));
+        remove_dir(&old_dir)?;
+        fs::rename(&source_dir, &old_dir).context("Failed to replace kernel sources")?;
+        remove_dir(&old_dir)?;
+    }
+    fs::rename(&clone_dir, &source_dir).context("Failed to promote kernel sources")?;
+
+    Ok(source_dir)
+}
+
+/// Generate `.config` in `build_dir` from base config, built-in Gevulot fragment
+/// and user fragments.
+pub fn configure(source_dir: &Path, build_dir: &Path, config: &KernelConfig) -> Result<()> {
+    let source_dir_str = source_dir.to_str().unwrap();
+    let output_arg = format!("O={}", build_dir.display());
+    // Compiler is probed during configuration, so cross toolchain is needed already here.
+    let make_variables = make_variables(config.arch, config.toolchain().as_deref());
+    let make = |target: &str| -> Result<()> {
+        let mut args: Vec<&str> = vec!["make", "-C", source_dir_str, &output_arg];
+        args.extend(make_variables.iter().map(String::as_str));
+        args.push(target);
+        SkopeoSyslinuxBuilder::run_command(&args, false)
+    };
+
+    if let Some(defconfig) = &config.defconfig {
+        fs::copy(defconfig, build_dir.join(".config"))
+            .context(format!("Failed to copy kernel defconfig {}", defconfig))?;
+    } else {
+        make(config.arch.defconfig()).context("Failed to configure kernel")?;
+    }
+
+    let gevulot_fragment = build_dir.join(format!("gevulot-v{}.config", GEVULOT_FRAGMENT_VERSION));
+    fs::write(&gevulot_fragment, GEVULOT_FRAGMENT)
+        .context("Failed to write Gevulot kernel config fragment")?;
+
+    let merge_script = source_dir.join("scripts/kconfig/merge_config.sh");
+    let base_config = build_dir.join(".config");
+    let mut merge_args = vec![
+        merge_script.to_str().unwrap(),
+        "-m",
+        "-O",
+        build_dir.to_str().unwrap(),
+        base_config.to_str().unwrap(),
+        gevulot_fragment.to_str().unwrap(),
+    ];
+    merge_args.extend(config.fragments.iter().map(String::as_str));
+    SkopeoSyslinuxBuilder::run_command(&merge_args, false)
+        .context("Failed to merge kernel config fragments")?;
+
+    // Resolve dependencies of merged options.
+    make("olddefconfig").context("Failed to configure kernel")?;
+
+    Ok(())
+}
+
+/// Hash of the kernel config file used as part of the cache key.
+pub fn config_hash(config_path: &Path) -> Result<String> {
+    let output = SkopeoSyslinuxBuilder::run_command_output(
+        &["sha256sum", config_path.to_str().unwrap()],
+        false,
+    )
+    .context("Failed to hash kernel config")?;
+    let hash = output
+        .split_whitespace()
+        .next()
+        .context("Empty sha256sum output")?;
+    Ok(hash[..CONFIG_HASH_LENGTH].to_string())
+}
+
+/// Commit checked out in kernel source tree.
+fn source_commit(source_dir: &Path) -> Result<String> {
+    let output = SkopeoSyslinuxBuilder::run_command_output(
+        &[
+            "git",
+            "-C",
+            source_dir.to_str().unwrap(),
+            "rev-parse",
+            "HEAD",
+        ],
+        false,
+    )
+    .context("Failed to get kernel source commit")?;
+    Ok(output.trim().to_string())
+}
+
+/// Remote HEAD of `kernel_url` differs from the commit checked out in `source_dir`.
+/// Cached sources are considered up to date if the remote can't be reached.
+fn is_outdated(source_dir: &Path, kernel_url: &str) -> bool {
+    let remote =
+        SkopeoSyslinuxBuilder::run_command_output(&["git", "ls-remote", kernel_url, "HEAD"], false);
+    let remote = match remote {
+        Ok(output) => output.split_whitespace().next().map(str::to_string),
+        Err(err) => {
+            warn!(
+                "Failed to check latest kernel commit, using cached sources: {:#}",
+                err
+            );
+            return false;
+        }
+    };
+    match (remote, source_commit(source_dir)) {
+        (Some(remote), Ok(local)) => remote != local,
+        // Broken clone is replaced by fresh one.
+        (Some(_), Err(_)) => true,
+        (None, _) => false,
+    }
+}
+
+/// Directory name derived from repository URL, e.g. `github.com_torvalds_linux.git`.
+fn url_slug(url: &str) -> String {
+    let url = url.split_once("://").map_or(url, |(_, rest)| rest);
+    url.trim_end_matches('/')
+        .chars()
+        .map(|c| {
+            if c.is_ascii_alphanumeric() || c == '.' || c == '-' {
+                c
+            } else {
+                '_'
+            }
+        })
+        .collect()
+}
+
+fn write_manifest(kernel_dir: &Path, manifest: &mut CacheManifest) -> Result<()> {
+    manifest.updated_at = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|duration| duration.as_secs())
+        .unwrap_or_default();
+    let content =
+        serde_json::to_string_pretty(manifest).context("Failed to serialize kernel manifest")?;
+    fs::write(kernel_dir.join(MANIFEST_FILE), content).context("Failed to write kernel manifest")
+}
+
+fn read_entry(kernel_dir: &Path) -> Result<CacheEntry> {
+    let manifest = fs::read_to_string(kernel_dir.join(MANIFEST_FILE))
+        .ok()
+        .and_then(|content| serde_json::from_str(&content).ok());
+    Ok(CacheEntry {
+        name: kernel_dir
+            .file_name()
+            .context("Invalid kernel cache entry path")?
+            .to_string_lossy()
+            .to_string(),
+        path: kernel_dir.to_path_buf(),
+        manifest,
+    })
+}
+
+/// Manifest of the kernel cache entry at `kernel_dir`, if it is readable.
+pub fn read_manifest(kernel_dir: &Path) -> Option<CacheManifest> {
+    read_entry(kernel_dir).ok().and_then(|entry| entry.manifest)
+}
+
+fn read_entries(dir: &Path) -> Result<Vec<CacheEntry>> {
+    if !dir.exists() {
+        return Ok(Vec::new());
+    }
+    let mut entries = Vec::new();
+    for entry in fs::read_dir(dir).context("Failed to read kernel cache directory")? {
+        let entry = entry.context("Failed to read kernel cache directory")?;
+        if entry.file_type()?.is_dir() {
+            entries.push(read_entry(&entry.path())?);
+        }
+    }
+    entries.sort_by(|a, b| a.name.cmp(&b.name));
+    Ok(entries)
+}
+
+/// List finished and incomplete kernel builds.
+pub fn list() -> Result<Vec<CacheEntry>> {
+    let mut entries = read_entries(&builds_dir()?)?;
+    entries.extend(
+        read_entries(&tmp_dir()?)?
+            .into_iter()
+            // Source clones in progress are not builds.
+            .filter(|entry| entry.manifest.is_some()),
+    );
+    Ok(entries)
+}
+
+/// Remove incomplete and failed builds and interrupted clones.
+/// Entries in `tmp/` of running processes are kept.
+/// With `all` the whole cache including sources is removed.
+/// Returns paths of removed entries.
+pub fn prune(all: bool) -> Result<Vec<PathBuf>> {
+    let mut removed = Vec::new();
+    if all {
+        let dir = cache_dir()?;
+        if dir.exists() {
+            remove_dir(&dir)?;
+            removed.push(dir);
+        }
+        return Ok(removed);
+    }
+
+    let stale = read_entries(&tmp_dir()?)?
+        .into_iter()
+        .filter(|entry| {
+            let in_use = owner_pid(&entry.name).is_some_and(is_running);
+            if in_use {
+                debug!("kernel cache entry {} is in use", entry.name);
+            }
+            !in_use
+        })
+        .chain(
+            read_entries(&builds_dir()?)?
+                .into_iter()
+                .filter(|entry| !entry.is_ready()),
+        );
+    for entry in stale {
+        remove_dir(&entry.path)?;
+        removed.push(entry.path);
+    }
+    Ok(removed)
+}
+
+/// Process which created `tmp/` entry, names end with `.<pid>`.
+fn owner_pid(name: &str) -> Option<u32> {
+    name.rsplit_once('.').and_then(|(_, pid)| pid.parse().ok())
+}
+
+fn is_running(pid: u32) -> bool {
+    Path::new("/proc").join(pid.to_string()).exists()
+}
+
+/// Move finished entry from `tmp/` to `builds/`.
+fn promote(staging_dir: &Path, kernel_dir: &Path) -> Result<()> {
+    // Another process may have finished the same build in the meantime.
+    if read_entry(kernel_dir)?.is_ready() {
+        return remove_dir(staging_dir);
+    }
+    remove_dir(kernel_dir)?;
+    fs::create_dir_all(builds_dir()?).context("Failed to create kernel cache directory")?;
+    fs::rename(staging_dir, kernel_dir).context("Failed to promote kernel build")
+}
+
+fn remove_dir(path: &Path) -> Result<()> {
+    if path.exists() {
+        fs::remove_dir_all(path).context(format!("Failed to remove {}", path.display()))?;
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn url_slug_is_flat_directory_name() {
+        assert_eq!(
+            url_slug("https://github.com/torvalds/linux.git"),
+            "github.com_torvalds_linux.git"
+        );
+        assert_eq!(
+            url_slug("https://git.kernel.org/pub/scm/linux/kernel/git/stable/linux.git/"),
+            "git.kernel.org_pub_scm_linux_kernel_git_stable_linux.git"
+        );
+        assert_eq!(
+            url_slug("git@github.com:me/linux-fork"),
+            "git_github.com_me_linux-fork"
+        );
+        assert_eq!(url_slug("/home/me/linux"), "_home_me_linux");
+    }
+
+    #[test]
+    fn config_hash_is_sha256_prefix() {
+        let dir = TempDir::new("kernel-test").unwrap();
+        let config = dir.path().join(".config");
+        fs::write(&config, "CONFIG_GEVULOT=y\n").unwrap();
+        assert_eq!(config_hash(&config).unwrap(), "c6dd10736cdad66f");
+        assert!(config_hash(&dir.path().join("missing")).is_err());
+    }
+
+    #[test]
+    fn owner_pid_of_tmp_entries() {
+        assert_eq!(
+            owner_pid("v6.12-0123456789ab-0123456789abcdef.1234"),
+            Some(1234)
+        );
+        assert_eq!(owner_pid("source-v6.12.42"), Some(42));
+        assert_eq!(owner_pid("source-latest-old.7"), Some(7));
+        assert_eq!(owner_pid("v6.12-interrupted"), None);
+        assert_eq!(owner_pid("latest"), None);
+    }
+
+    #[test]
+    fn running_processes_are_detected() {
+        assert!(is_running(std::process::id()));
+        // Above the maximum PID of Linux.
+        assert!(!is_running(u32::MAX));
+    }
+}
@@ -0,0 +1,321 @@
+//! Kernel modules installation into the VM root filesystem.
+
+use std::collections::{HashMap, HashSet};
+use std::fs;
+use std::path::{Path, PathBuf};
+
+use anyhow::{Context, Result};
+use log::debug;
+use tempdir::TempDir;
+
+use crate::builders::skopeo_builder::SkopeoSyslinuxBuilder;
+use crate::builders::ModulesInstall;
+
+/// Metadata files copied along with selected modules, so `depmod` knows about built-in ones.
+const METADATA_FILES: [&str; 3] = [
+    "modules.builtin",
+    "modules.builtin.modinfo",
+    "modules.order",
+];
+
+/// Module name as used by `modprobe`: `-` and `_` are interchangeable.
+fn normalize_name(name: &str) -> String {
+    name.replace('-', "_")
+}
+
+/// Module name from its path, e.g. `kernel/fs/fuse/fuse.ko.xz` -> `fuse`.
+fn module_name(path: &str) -> String {
+    let file_name = path.rsplit('/').next().unwrap_or(path);
+    normalize_name(file_name.split(".ko").next().unwrap_or(file_name))
+}
+
+/// Parse `modules.dep` into module name -> (module path, dependency paths).
+fn read_modules_dep(modules_dir: &Path) -> Result<HashMap<String, (String, Vec<String>)>> {
+    let content = fs::read_to_string(modules_dir.join("modules.dep"))
+        .context("Failed to read modules.dep")?;
+    Ok(content
+        .lines()
+        .filter_map(|line| line.split_once(':'))
+        .map(|(path, deps)| {
+            (
+                module_name(path),
+                (
+                    path.to_string(),
+                    deps.split_whitespace().map(str::to_string).collect(),
+                ),
+            )
+        })
+        .collect())
+}
+
+/// Names of modules built into the kernel.
+fn read_modules_builtin(modules_dir: &Path) -> HashSet<String> {
+    fs::read_to_string(modules_dir.join("modules.builtin"))
+        .map(|content| content.lines().map(module_name).collect())
+        .unwrap_or_default()
+}
+
+/// Modules of a built kernel installed into a temporary directory.
+pub struct ModulesTree {
+    _staging_dir: TempDir,
+    /// `lib/modules/<release>` inside the temporary directory.
+    pub dir: PathBuf,
+    pub release: String,
+}
+
+impl ModulesTree {
+    /// Run `make modules_install` for the kernel built in `kernel_dir`.
+    /// Returns `None` if the kernel has no loadable modules.
+    pub fn prepare(kernel_dir: &Path) -> Result<Option<Self>> {
+        if !kernel_dir.join("modules.order").exists() {
+            debug!("kernel in {} has no loadable modules", kernel_dir.display());
+            return Ok(None);
+        }
+        let release = super::kernel_release(kernel_dir)?;
+
+        // Install as a regular user first, so the kernel build tree is never touched by root.
+        let staging_dir =
+            TempDir::new("kernel-modules").context("Failed to create temporary directory")?;
+        // Modules are stripped with the toolchain the kernel was built with.
+        let manifest = super::read_manifest(kernel_dir);
+        let make_variables = super::make_variables(
+            manifest
+                .as_ref()
+                .map(|manifest| manifest.arch)
+                .unwrap_or_default(),
+            manifest
+                .as_ref()
+                .and_then(|manifest| manifest.cross_compile.as_deref()),
+        );
+        let mod_path_arg = format!("INSTALL_MOD_PATH={}", staging_dir.path().display());
+        let mut args = vec!["make", "-C", kernel_dir.to_str().unwrap()];
+        args.extend(make_variables.iter().map(String::as_str));
+        args.extend([
+            mod_path_arg.as_str(),
+            "INSTALL_MOD_STRIP=1",
+            "modules_install",
+        ]);
+        SkopeoSyslinuxBuilder::run_command(&args, false)
+            .context("Failed to install kernel modules")?;
+        let dir = staging_dir.path().join("lib/modules").join(&release);
+        // These point to the kernel build tree on the host.
+        for link in ["build", "source"] {
+            let _ = fs::remove_file(dir.join(link));
+        }
+
+        Ok(Some(Self {
+            _staging_dir: staging_dir,
+            dir,
+            release,
+        }))
+    }
+
+    /// Paths of loadable modules relative to `dir` in load order, dependencies first.
+    /// Built-in and missing modules are skipped.
+    pub fn load_order(&self, names: &[String]) -> Result<Vec<String>> {
+        let modules_dep = read_modules_dep(&self.dir)?;
+        let mut order = Vec::new();
+        for name in names {
+            if let Some((path, deps)) = modules_dep.get(&normalize_name(name)) {
+                // `modprobe` loads dependencies listed in `modules.dep` from the last one.
+                for file in deps.iter().rev().chain(std::iter::once(path)) {
+                    if !order.contains(file) {
+                        order.push(file.clone());
+                    }
+                }
+            }
+        }
+        Ok(order)
+    }
+}
+
+/// Install modules of the kernel built in `kernel_dir` into `root_dir`.
+pub fn install(
+    kernel_dir: &Path,
+    root_dir: &Path,
+    mode: ModulesInstall,
+    requested: &[String],
+) -> Result<()> {
+    if mode == ModulesInstall::None {
+        return Ok(());
+    }
+    let Some(tree) = ModulesTree::prepare(kernel_dir)? else {
+        return Ok(());
+    };
+    let source_dir = &tree.dir;
+    let release = &tree.release;
+
+    let target_dir = root_dir.join("lib/modules").join(release);
+    SkopeoSyslinuxBuilder::run_command(&["mkdir", "-p", target_dir.to_str().unwrap()], true)
+        .context("Failed to create modules directory")?;
+
+    let files = match mode {
+        ModulesInstall::All => {
+            let mut files = vec!["kernel".to_string()];
+            files.extend(METADATA_FILES.iter().map(|file| file.to_string()));
+            files
+        }
+        ModulesInstall::Requested => {
+            // Missing and built-in modules are reported by verification.
+            let mut files = tree.load_order(requested)?;
+            files.extend(METADATA_FILES.iter().map(|file| file.to_string()));
+            files
+        }
+        ModulesInstall::None => unreachable!(),
+    };
+
+    for file in files {
+        let source = source_dir.join(&file);
+        if !source.exists() {
+            continue;
+        }
+        let target = target_dir.join(&file);
+        SkopeoSyslinuxBuilder::run_command(
+            &["mkdir", "-p", target.parent().unwrap().to_str().unwrap()],
+            true,
+        )
+        .context("Failed to create modules directory")?;
+        SkopeoSyslinuxBuilder::run_command(
+            &[
+                "cp",
+                "-a",
+                source.to_str().unwrap(),
+                target.parent().unwrap().to_str().unwrap(),
+            ],
+            true,
+        )
+        .context(format!("Failed to copy kernel module {}", file))?;
+    }
+
+    SkopeoSyslinuxBuilder::run_command(
+        &[
+            "depmod",
+            "--basedir",
+            root_dir.to_str().unwrap(),
+            "-a",
+            release,
+        ],
+        true,
+    )
+    .context("Failed to build module dependencies")?;
+
+    Ok(())
+}
+
+/// Check that every module can be loaded with `modprobe` in the VM:
+/// it is either listed in `modules.dep` in `root_dir` or built into the kernel from `kernel_dir`.
+pub fn verify(kernel_dir: &Path, root_dir: &Path, modules: &[String]) -> Result<()> {
+    let release = super::kernel_release(kernel_dir)?;
+    let modules_dir = root_dir.join("lib/modules").join(&release);
+    let mut available = if modules_dir.join("modules.dep").exists() {
+        read_modules_dep(&modules_dir)?.into_keys().collect()
+    } else {
+        HashSet::new()
+    };
+    available.extend(read_modules_builtin(kernel_dir));
+    available.extend(read_modules_builtin(&modules_dir));
+
+    let missing = modules
+        .iter()
+        .filter(|name| !available.contains(&normalize_name(name)))
+        .map(String::as_str)
+        .collect::<Vec<_>>();
+    if !missing.is_empty() {
+        anyhow::bail!(
+            "Kernel modules not found for kernel {}: {}. Check kernel config or --kernel-modules-install.",
+            release,
+            missing.join(", ")
+        );
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const MODULES_DEP: &str = "\
+kernel/drivers/block/virtio_blk.ko: kernel/drivers/virtio/virtio_ring.ko
+kernel/drivers/virtio/virtio_ring.ko:
+kernel/drivers/md/dm-verity.ko: kernel/drivers/md/dm-bufio.ko kernel/drivers/md/dm-mod.ko
+kernel/drivers/md/dm-bufio.ko: kernel/drivers/md/dm-mod.ko
+kernel/drivers/md/dm-mod.ko:
+kernel/fs/erofs/erofs.ko.xz: kernel/lib/lz4/lz4_decompress.ko.xz
+kernel/lib/lz4/lz4_decompress.ko.xz:
+";
+
+    fn tree(dir: &TempDir) -> ModulesTree {
+        fs::write(dir.path().join("modules.dep"), MODULES_DEP).unwrap();
+        ModulesTree {
+            _staging_dir: TempDir::new("modules-test").unwrap(),
+            dir: dir.path().to_path_buf(),
+            release: "6.12.0".to_string(),
+        }
+    }
+
+    fn names(names: &[&str]) -> Vec<String> {
+        names.iter().map(|name| name.to_string()).collect()
+    }
+
+    #[test]
+    fn module_names_from_paths() {
+        assert_eq!(module_name("kernel/fs/fuse/fuse.ko"), "fuse");
+        assert_eq!(module_name("kernel/fs/fuse/fuse.ko.xz"), "fuse");
+        assert_eq!(
+            module_name("kernel/drivers/md/dm-verity.ko.zst"),
+            "dm_verity"
+        );
+        assert_eq!(module_name("virtio_blk.ko"), "virtio_blk");
+    }
+
+    #[test]
+    fn load_order_puts_dependencies_first() {
+        let dir = TempDir::new("modules-test").unwrap();
+        let tree = tree(&dir);
+        assert_eq!(
+            tree.load_order(&names(&["virtio_blk", "dm_verity"]))
+                .unwrap(),
+            [
+                "kernel/drivers/virtio/virtio_ring.ko",
+                "kernel/drivers/block/virtio_blk.ko",
+                "kernel/drivers/md/dm-mod.ko",
+                "kernel/drivers/md/dm-bufio.ko",
+                "kernel/drivers/md/dm-verity.ko",
+            ]
+        );
+    }
+
+    #[test]
+    fn load_order_skips_duplicates_and_missing_modules() {
+        let dir = TempDir::new("modules-test").unwrap();
+        let tree = tree(&dir);
+        assert_eq!(
+            tree.load_order(&names(&[
+                "dm-mod",
+                "virtio_pci",
+                "erofs",
+                "dm_bufio",
+                "erofs"
+            ]))
+            .unwrap(),
+            [
+                "kernel/drivers/md/dm-mod.ko",
+                "kernel/lib/lz4/lz4_decompress.ko.xz",
+                "kernel/fs/erofs/erofs.ko.xz",
+                "kernel/drivers/md/dm-bufio.ko",
+            ]
+        );
+        assert!(tree.load_order(&[]).unwrap().is_empty());
+    }
+
+    #[test]
+    fn load_order_requires_modules_dep() {
+        let dir = TempDir::new("modules-test").unwrap();
+        let tree = ModulesTree {
+            _staging_dir: TempDir::new("modules-test").unwrap(),
+            dir: dir.path().to_path_buf(),
+            release: "6.12.0".to_string(),
+        };
+        assert!(tree.load_order(&names(&["virtio_blk"])).is_err());
+    }
+}
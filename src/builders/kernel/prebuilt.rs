@@ -0,0 +1,475 @@
+//! Prebuilt Gevulot kernels downloaded from a release index.
+//!
+//! Release index is a JSON document signed with detached signature stored next to it
+//! (`<index URL>.sig`). The signature is created with
+//! `openssl dgst -sha256 -sign private.pem -out index.json.sig index.json`.
+//!
+//! ```json
+//! {
+//!   "kernels": [
+//!     {
+//!       "version": "v6.12",
+//!       "arch": "x86_64",
+//!       "bzimage": { "url": "v6.12/bzImage", "sha256": "..." },
+//!       "headers": { "url": "v6.12/headers.tar.gz", "sha256": "..." }
+//!     }
+//!   ]
+//! }
+//! ```
+//!
+//! Artifact URLs may be relative to the index URL. `headers` is a tarball of kernel build tree
+//! prepared for building external modules. It is required for NVIDIA drivers only.
+//! Kernels for `aarch64` are listed with the same fields, `bzimage` then points to arm64 `Image`.
+
+use std::fs;
+use std::path::{Path, PathBuf};
+
+use anyhow::{Context, Result};
+use log::debug;
+use serde::Deserialize;
+use tempdir::TempDir;
+
+use super::{
+    builds_dir, image_path, promote, read_entry, remove_dir, tmp_dir, write_manifest, BuildStatus,
+    CacheManifest,
+};
+use crate::builders::skopeo_builder::SkopeoSyslinuxBuilder;
+use crate::builders::Arch;
+
+/// Prefix of kernel version selecting prebuilt kernel, e.g. `prebuilt:v6.12`.
+pub const VERSION_PREFIX: &str = "prebuilt:";
+
+/// Number of hex characters of kernel checksum used in cache directory names.
+const CHECKSUM_LENGTH: usize = 16;
+
+#[derive(Debug, Deserialize)]
+struct ReleaseIndex {
+    kernels: Vec<Release>,
+}
+
+#[derive(Debug, Deserialize)]
+struct Release {
+    version: String,
+    arch: String,
+    bzimage: Artifact,
+    headers: Option<Artifact>,
+}
+
+#[derive(Debug, Deserialize)]
+struct Artifact {
+    url: String,
+    sha256: String,
+}
+
+/// Fetch prebuilt kernel of given version and architecture into kernel cache or reuse cached one.
+/// Returns path to the cache entry, which has the same layout as kernel build tree.
+///
+/// Release index signature is verified with `public_key` (PEM file).
+/// With `with_headers` the entry also contains kernel headers for building external modules.
+pub fn fetch(
+    version: &str,
+    index_url: &str,
+    public_key: &str,
+    with_headers: bool,
+    arch: Arch,
+) -> Result<PathBuf> {
+    let download_dir =
+        TempDir::new("kernel-download").context("Failed to create temporary directory")?;
+    let release = fetch_release(version, arch, index_url, public_key, download_dir.path())?;
+
+    let name = format!(
+        "prebuilt-{}-{}",
+        version,
+        &release.bzimage.sha256[..CHECKSUM_LENGTH.min(release.bzimage.sha256.len())]
+    );
+    let kernel_dir = builds_dir()?.join(&name);
+    if read_entry(&kernel_dir)?.is_ready() && (!with_headers || has_headers(&kernel_dir)) {
+        debug!("using cached prebuilt kernel {}", kernel_dir.display());
+        return Ok(kernel_dir);
+    }
+
+    let staging_dir = tmp_dir()?.join(format!("{}.{}", name, std::process::id()));
+    remove_dir(&staging_dir)?;
+    let bzimage = image_path(&staging_dir, arch);
+    fs::create_dir_all(bzimage.parent().unwrap())
+        .context("Failed to create kernel cache directory")?;
+
+    let bzimage_url = resolve_url(index_url, &release.bzimage.url);
+    let mut manifest = CacheManifest {
+        version: format!("{}{}", VERSION_PREFIX, version),
+        url: bzimage_url.clone(),
+        commit: None,
+        config_hash: None,
+        sha256: Some(release.bzimage.sha256.clone()),
+        fragment_version: 0,
+        arch,
+        cross_compile: None,
+        status: BuildStatus::Building,
+        updated_at: 0,
+    };
+    write_manifest(&staging_dir, &mut manifest)?;
+
+    download_verified(&bzimage_url, &bzimage, &release.bzimage.sha256)?;
+
+    if with_headers {
+        let headers = release.headers.as_ref().context(format!(
+            "Prebuilt kernel {} doesn't provide headers required for NVIDIA drivers",
+            version
+        ))?;
+        let headers_file = download_dir.path().join("headers.tar");
+        download_verified(
+            &resolve_url(index_url, &headers.url),
+            &headers_file,
+            &headers.sha256,
+        )?;
+        SkopeoSyslinuxBuilder::run_command(
+            &[
+                "tar",
+                "-xf",
+                headers_file.to_str().unwrap(),
+                "-C",
+                staging_dir.to_str().unwrap(),
+            ],
+            false,
+        )
+        .context("Failed to extract kernel headers")?;
+        if !has_headers(&staging_dir) {
+            anyhow::bail!("Kernel headers tarball doesn't contain include/config/kernel.release");
+        }
+        // Cached entry without headers would prevent promotion.
+        remove_dir(&kernel_dir)?;
+    }
+
+    manifest.status = BuildStatus::Ready;
+    write_manifest(&staging_dir, &mut manifest)?;
+    promote(&staging_dir, &kernel_dir)?;
+
+    Ok(kernel_dir)
+}
+
+/// Download release index, verify its signature and find release of given version.
+fn fetch_release(
+    version: &str,
+    arch: Arch,
+    index_url: &str,
+    public_key: &str,
+    download_dir: &Path,
+) -> Result<Release> {
+    let index_file = download_dir.join("index.json");
+    let signature_file = download_dir.join("index.json.sig");
+    download(index_url, &index_file)?;
+    download(&format!("{}.sig", index_url), &signature_file)?;
+
+    SkopeoSyslinuxBuilder::run_command(
+        &[
+            "openssl",
+            "dgst",
+            "-sha256",
+            "-verify",
+            public_key,
+            "-signature",
+            signature_file.to_str().unwrap(),
+            index_file.to_str().unwrap(),
+        ],
+        false,
+    )
+    .context("Failed to verify kernel release index signature")?;
+
+    let index: ReleaseIndex = serde_json::from_str(
+        &fs::read_to_string(&index_file).context("Failed to read kernel release index")?,
+    )
+    .context("Failed to parse kernel release index")?;
+
+    let available = index
+        .kernels
+        .iter()
+        .filter(|release| release.arch == arch.to_string())
+        .map(|release| release.version.clone())
+        .collect::<Vec<_>>();
+    index
+        .kernels
+        .into_iter()
+        .find(|release| release.version == version && release.arch == arch.to_string())
+        .context(format!(
+            "Prebuilt kernel {} for {} not found in release index (available: {})",
+            version,
+            arch,
+            if available.is_empty() {
+                "none".to_string()
+            } else {
+                available.join(", ")
+            }
+        ))
+}
+
+/// Kernel tree contains files required for building external modules.
+fn has_headers(kernel_dir: &Path) -> bool {
+    kernel_dir.join("include/config/kernel.release").exists()
+}
+
+/// Resolve artifact URL relative to the index URL.
+fn resolve_url(index_url: &str, url: &str) -> String {
+    if url.contains("://") {
+        url.to_string()
+    } else {
+        let base = index_url
+            .rsplit_once('/')
+            .map_or(index_url, |(base, _)| base);
+        format!("{}/{}", base, url.trim_start_matches('/'))
+    }
+}
+
+fn download(url: &str, target: &Path) -> Result<()> {
+    SkopeoSyslinuxBuilder::run_command(
+        &["curl", "-fsSL", "--output", target.to_str().unwrap(), url],
+        false,
+    )
+    .context(format!("Failed to download {}", url))
+}
+
+/// Download artifact and check its SHA-256 checksum.
+fn download_verified(url: &str, target: &Path, sha256: &str) -> Result<()> {
+    download(url, target)?;
+    verify_checksum(target, sha256)
+}
+
+fn verify_checksum(file: &Path, expected: &str) -> Result<()> {
+    let output =
+        SkopeoSyslinuxBuilder::run_command_output(&["sha256sum", file.to_str().unwrap()], false)
+            .context("Failed to calculate checksum")?;
+    let actual = output
+        .split_whitespace()
+        .next()
+        .context("Empty sha256sum output")?;
+    if !actual.eq_ignore_ascii_case(expected) {
+        anyhow::bail!(
+            "Checksum mismatch for {}: expected {}, got {}",
+            file.display(),
+            expected,
+            actual
+        );
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::{BufRead, BufReader, Write};
+    use std::net::TcpListener;
+
+    use super::*;
+
+    /// Serve `files` over HTTP on 127.0.0.1 and return base URL.
+    fn serve(files: Vec<(&'static str, Vec<u8>)>) -> String {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let url = format!("http://{}", listener.local_addr().unwrap());
+        std::thread::spawn(move || {
+            for stream in listener.incoming() {
+                let mut stream = stream.unwrap();
+                let mut request = String::new();
+                BufReader::new(&stream).read_line(&mut request).unwrap();
+                let path = request.split_whitespace().nth(1).unwrap_or_default();
+                let response = match files.iter().find(|(name, _)| *name == path) {
+                    Some((_, content)) => {
+                        let mut response = format!(
+                            "HTTP/1.1 200 OK\r\nContent-Length: {}\r\nConnection: close\r\n\r\n",
+                            content.len()
+                        )
+                        .into_bytes();
+                        response.extend(content);
+                        response
+                    }
+                    None => {
+                        b"HTTP/1.1 404 Not Found\r\nContent-Length: 0\r\nConnection: close\r\n\r\n"
+                            .to_vec()
+                    }
+                };
+                stream.write_all(&response).unwrap();
+            }
+        });
+        url
+    }
+
+    fn openssl(args: &[&str]) {
+        SkopeoSyslinuxBuilder::run_command(&[&["openssl"], args].concat(), false).unwrap();
+    }
+
+    /// Generate key pair in `dir`, returns paths of private and public key.
+    fn generate_key(dir: &Path, name: &str) -> (String, String) {
+        let private = dir.join(format!("{}.pem", name));
+        let public = dir.join(format!("{}.pub.pem", name));
+        let private = private.to_str().unwrap().to_string();
+        let public = public.to_str().unwrap().to_string();
+        openssl(&["genrsa", "-out", &private, "2048"]);
+        openssl(&["rsa", "-in", &private, "-pubout", "-out", &public]);
+        (private, public)
+    }
+
+    fn sign(dir: &Path, private_key: &str, content: &[u8]) -> Vec<u8> {
+        let file = dir.join("content");
+        let signature = dir.join("content.sig");
+        fs::write(&file, content).unwrap();
+        openssl(&[
+            "dgst",
+            "-sha256",
+            "-sign",
+            private_key,
+            "-out",
+            signature.to_str().unwrap(),
+            file.to_str().unwrap(),
+        ]);
+        fs::read(signature).unwrap()
+    }
+
+    /// Checksum of kernel served in tests, its content is `kernel`.
+    const KERNEL_SHA256: &str = "6923dd1bc0460082c5d55a831908c24a282860b7f1cd6c2b79cf1bc8857c639c";
+
+    const INDEX: &str = r#"{
+        "kernels": [
+            {
+                "version": "v6.12",
+                "arch": "x86_64",
+                "bzimage": {
+                    "url": "v6.12/bzImage",
+                    "sha256": "6923dd1bc0460082c5d55a831908c24a282860b7f1cd6c2b79cf1bc8857c639c"
+                }
+            },
+            {
+                "version": "v6.12",
+                "arch": "aarch64",
+                "bzimage": { "url": "https://example.com/Image", "sha256": "00" },
+                "headers": { "url": "v6.12/headers.tar.gz", "sha256": "00" }
+            }
+        ]
+    }"#;
+
+    #[test]
+    fn release_index_with_valid_signature() {
+        let dir = TempDir::new("prebuilt-test").unwrap();
+        let (private_key, public_key) = generate_key(dir.path(), "key");
+        let signature = sign(dir.path(), &private_key, INDEX.as_bytes());
+        let url = serve(vec![
+            ("/kernels/index.json", INDEX.as_bytes().to_vec()),
+            ("/kernels/index.json.sig", signature),
+        ]);
+        let index_url = format!("{}/kernels/index.json", url);
+
+        let release =
+            fetch_release("v6.12", Arch::X86_64, &index_url, &public_key, dir.path()).unwrap();
+        assert_eq!(release.version, "v6.12");
+        assert_eq!(
+            resolve_url(&index_url, &release.bzimage.url),
+            format!("{}/kernels/v6.12/bzImage", url)
+        );
+        assert!(release.headers.is_none());
+
+        let release =
+            fetch_release("v6.12", Arch::Aarch64, &index_url, &public_key, dir.path()).unwrap();
+        assert_eq!(
+            resolve_url(&index_url, &release.bzimage.url),
+            "https://example.com/Image"
+        );
+
+        let err =
+            fetch_release("v6.6", Arch::X86_64, &index_url, &public_key, dir.path()).unwrap_err();
+        assert!(
+            format!("{:#}", err).contains("available: v6.12"),
+            "{:#}",
+            err
+        );
+    }
+
+    #[test]
+    fn release_index_with_bad_signature_is_rejected() {
+        let dir = TempDir::new("prebuilt-test").unwrap();
+        let (private_key, _) = generate_key(dir.path(), "attacker");
+        let (_, public_key) = generate_key(dir.path(), "trusted");
+        let signature = sign(dir.path(), &private_key, INDEX.as_bytes());
+        let url = serve(vec![
+            ("/index.json", INDEX.as_bytes().to_vec()),
+            ("/index.json.sig", signature),
+        ]);
+
+        let err = fetch_release(
+            "v6.12",
+            Arch::X86_64,
+            &format!("{}/index.json", url),
+            &public_key,
+            dir.path(),
+        )
+        .unwrap_err();
+        assert!(
+            format!("{:#}", err).contains("Failed to verify kernel release index signature"),
+            "{:#}",
+            err
+        );
+    }
+
+    #[test]
+    fn release_index_without_signature_is_rejected() {
+        let dir = TempDir::new("prebuilt-test").unwrap();
+        let (_, public_key) = generate_key(dir.path(), "key");
+        let url = serve(vec![("/index.json", INDEX.as_bytes().to_vec())]);
+
+        let err = fetch_release(
+            "v6.12",
+            Arch::X86_64,
+            &format!("{}/index.json", url),
+            &public_key,
+            dir.path(),
+        )
+        .unwrap_err();
+        assert!(format!("{:#}", err).contains("index.json.sig"), "{:#}", err);
+    }
+
+    #[test]
+    fn artifact_with_checksum_mismatch_is_rejected() {
+        let dir = TempDir::new("prebuilt-test").unwrap();
+        let url = serve(vec![("/bzImage", b"kernel".to_vec())]);
+        let target = dir.path().join("bzImage");
+
+        let sha256 = KERNEL_SHA256.replace('6', "7");
+        let err = download_verified(&format!("{}/bzImage", url), &target, &sha256).unwrap_err();
+        assert!(
+            format!("{:#}", err).contains("Checksum mismatch"),
+            "{:#}",
+            err
+        );
+    }
+
+    #[test]
+    fn artifact_with_matching_checksum() {
+        let dir = TempDir::new("prebuilt-test").unwrap();
+        let url = serve(vec![("/bzImage", b"kernel".to_vec())]);
+        let target = dir.path().join("bzImage");
+
+        download_verified(&format!("{}/bzImage", url), &target, KERNEL_SHA256).unwrap();
+        assert_eq!(fs::read(&target).unwrap(), b"kernel");
+        // Checksums are compared case-insensitively.
+        download_verified(
+            &format!("{}/bzImage", url),
+            &target,
+            &KERNEL_SHA256.to_uppercase(),
+        )
+        .unwrap();
+    }
+
+    #[test]
+    fn relative_artifact_urls() {
+        assert_eq!(
+            resolve_url("https://example.com/kernels/index.json", "v6.12/bzImage"),
+            "https://example.com/kernels/v6.12/bzImage"
+        );
+        assert_eq!(
+            resolve_url("https://example.com/kernels/index.json", "/v6.12/bzImage"),
+            "https://example.com/kernels/v6.12/bzImage"
+        );
+        assert_eq!(
+            resolve_url(
+                "https://example.com/index.json",
+                "https://cdn.example.com/bzImage"
+            ),
+            "https://cdn.example.com/bzImage"
+        );
+    }
+}
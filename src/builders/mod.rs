@@ -1,23 +1,396 @@
 use anyhow::Result;
 
+pub mod container;
+pub mod image;
+pub mod initramfs;
+pub mod kernel;
 pub mod nvidia;
+pub mod partition;
+pub mod random;
+pub mod report;
+pub mod rootfs;
+pub mod sbom;
+pub mod size;
 pub mod skopeo_builder;
+pub mod slim;
+pub mod tarball;
+pub mod verity;
+pub mod workspace;
+
+use container::{BuildEngine, Platform};
+use partition::ExtraPartition;
+use rootfs::OwnerMap;
+use serde::{Deserialize, Serialize};
+use size::ImageSize;
+use slim::SlimProfile;
+
+/// CPU architecture of the VM image.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Arch {
+    #[default]
+    X86_64,
+    Aarch64,
+}
+
+impl Arch {
+    /// Architecture of the host running the build, if it is supported.
+    pub fn host() -> Option<Self> {
+        std::env::consts::ARCH.parse().ok()
+    }
+
+    /// Container platform selected when `--platform` is not given.
+    pub fn platform(&self) -> Platform {
+        Platform {
+            os: "linux".to_string(),
+            architecture: self.container_architecture().to_string(),
+            variant: None,
+        }
+    }
+
+    /// Architecture name used in container image configs.
+    pub fn container_architecture(&self) -> &'static str {
+        match self {
+            Self::X86_64 => "amd64",
+            Self::Aarch64 => "arm64",
+        }
+    }
+
+    /// Value of `ARCH` passed to kernel makefiles.
+    pub fn kernel_arch(&self) -> &'static str {
+        match self {
+            Self::X86_64 => "x86",
+            Self::Aarch64 => "arm64",
+        }
+    }
+
+    /// Kernel config target used when no `--kernel-defconfig` is given.
+    pub fn defconfig(&self) -> &'static str {
+        match self {
+            Self::X86_64 => "x86_64_defconfig",
+            Self::Aarch64 => "defconfig",
+        }
+    }
+
+    /// File name of the kernel image, both in the kernel build tree and on the boot partition.
+    pub fn kernel_file(&self) -> &'static str {
+        match self {
+            Self::X86_64 => "bzImage",
+            Self::Aarch64 => "Image",
+        }
+    }
+
+    /// Target triple of the MIA binary.
+    pub fn mia_platform(&self) -> &'static str {
+        match self {
+            Self::X86_64 => "x86_64-unknown-linux-gnu",
+            Self::Aarch64 => "aarch64-unknown-linux-gnu",
+        }
+    }
+
+    /// Cross compiler prefix used when building on another host architecture
+    /// and `--cross-compile` is not given.
+    pub fn default_cross_compile(&self) -> &'static str {
+        match self {
+            Self::X86_64 => "x86_64-linux-gnu-",
+            Self::Aarch64 => "aarch64-linux-gnu-",
+        }
+    }
+
+    /// Toolchain prefix for binaries of this architecture: `cross_compile` if given,
+    /// otherwise the default one on another host, `None` for native tools.
+    pub fn toolchain(&self, cross_compile: Option<&str>) -> Option<String> {
+        cross_compile.map(str::to_string).or_else(|| {
+            (Arch::host() != Some(*self)).then(|| self.default_cross_compile().to_string())
+        })
+    }
+
+    /// Suffix of EFI binaries, e.g. `BOOTX64.EFI` or `systemd-bootaa64.efi`.
+    pub fn efi_suffix(&self) -> &'static str {
+        match self {
+            Self::X86_64 => "x64",
+            Self::Aarch64 => "aa64",
+        }
+    }
+
+    /// Serial console of QEMU `pc` and `virt` machines.
+    pub fn console(&self) -> &'static str {
+        match self {
+            Self::X86_64 => "ttyS0",
+            Self::Aarch64 => "ttyAMA0",
+        }
+    }
+
+    /// Boot mode used when `--boot` is not given. There is no BIOS on aarch64.
+    pub fn default_boot_mode(&self) -> BootMode {
+        match self {
+            Self::X86_64 => BootMode::Bios,
+            Self::Aarch64 => BootMode::Uefi,
+        }
+    }
+}
+
+impl std::str::FromStr for Arch {
+    type Err = &'static str;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "x86_64" => Ok(Self::X86_64),
+            "aarch64" => Ok(Self::Aarch64),
+            _ => Err("invalid architecture"),
+        }
+    }
+}
+
+impl std::fmt::Display for Arch {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::X86_64 => write!(f, "x86_64"),
+            Self::Aarch64 => write!(f, "aarch64"),
+        }
+    }
+}
+
+/// Firmware interface the VM image is bootable with.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum BootMode {
+    /// MBR partition table and SYSLINUX.
+    Bios,
+    /// GPT partition table with EFI system partition and systemd-boot.
+    Uefi,
+    /// GPT partition table with both SYSLINUX and systemd-boot.
+    Hybrid,
+}
+
+impl BootMode {
+    pub fn uses_gpt(&self) -> bool {
+        !matches!(self, Self::Bios)
+    }
+
+    pub fn supports_bios(&self) -> bool {
+        !matches!(self, Self::Uefi)
+    }
+
+    pub fn supports_uefi(&self) -> bool {
+        !matches!(self, Self::Bios)
+    }
+}
+
+impl std::str::FromStr for BootMode {
+    type Err = &'static str;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "bios" => Ok(Self::Bios),
+            "uefi" => Ok(Self::Uefi),
+            "hybrid" => Ok(Self::Hybrid),
+            _ => Err("invalid boot mode"),
+        }
+    }
+}
+
+impl std::fmt::Display for BootMode {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::Bios => write!(f, "bios"),
+            Self::Uefi => write!(f, "uefi"),
+            Self::Hybrid => write!(f, "hybrid"),
+        }
+    }
+}
+
+/// Kind of artifacts produced by the build.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum OutputKind {
+    /// Partitioned disk image with bootloader.
+    Disk,
+    /// Kernel, root filesystem image and kernel command line for direct kernel boot.
+    KernelRootfs,
+}
+
+impl OutputKind {
+    /// Output path used when `--output` is not given.
+    pub fn default_output(&self) -> &'static str {
+        match self {
+            Self::Disk => "disk.img",
+            Self::KernelRootfs => "image",
+        }
+    }
+}
+
+impl std::str::FromStr for OutputKind {
+    type Err = &'static str;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "disk" => Ok(Self::Disk),
+            "kernel+rootfs" => Ok(Self::KernelRootfs),
+            _ => Err("invalid output kind"),
+        }
+    }
+}
+
+impl std::fmt::Display for OutputKind {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::Disk => write!(f, "disk"),
+            Self::KernelRootfs => write!(f, "kernel+rootfs"),
+        }
+    }
+}
+
+/// Kernel modules installed from the built kernel into the root filesystem.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ModulesInstall {
+    /// All modules built with the kernel.
+    All,
+    /// Modules requested with `--kernel-module` and their dependencies.
+    Requested,
+    /// No modules.
+    None,
+}
+
+impl std::str::FromStr for ModulesInstall {
+    type Err = &'static str;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "all" => Ok(Self::All),
+            "requested" => Ok(Self::Requested),
+            "none" => Ok(Self::None),
+            _ => Err("invalid kernel modules install mode"),
+        }
+    }
+}
+
+impl std::fmt::Display for ModulesInstall {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::All => write!(f, "all"),
+            Self::Requested => write!(f, "requested"),
+            Self::None => write!(f, "none"),
+        }
+    }
+}
+
+/// Format of the software bill of materials written next to the output.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SbomFormat {
+    /// SPDX 2.3 JSON.
+    Spdx,
+    /// CycloneDX 1.5 JSON.
+    CycloneDx,
+}
+
+impl SbomFormat {
+    /// Suffix of the SBOM file name.
+    pub fn extension(&self) -> &'static str {
+        match self {
+            Self::Spdx => "spdx.json",
+            Self::CycloneDx => "cdx.json",
+        }
+    }
+}
+
+impl std::str::FromStr for SbomFormat {
+    type Err = &'static str;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "spdx" => Ok(Self::Spdx),
+            "cyclonedx" => Ok(Self::CycloneDx),
+            _ => Err("invalid SBOM format"),
+        }
+    }
+}
+
+impl std::fmt::Display for SbomFormat {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::Spdx => write!(f, "spdx"),
+            Self::CycloneDx => write!(f, "cyclonedx"),
+        }
+    }
+}
+
+/// Timestamp used for reproducible builds if `SOURCE_DATE_EPOCH` is not set:
+/// 1980-01-01, the earliest date representable in FAT.
+pub const DEFAULT_SOURCE_DATE_EPOCH: u64 = 315532800;
 
 pub trait ImageBuilder {
     fn build(&self, options: &BuildOptions) -> Result<()>;
 }
 
+/// Filesystem used for the root partition of the VM image.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum RootfsFormat {
+    Ext4,
+    Squashfs,
+    Erofs,
+}
+
+impl RootfsFormat {
+    /// Read-only formats are generated directly from the rootfs directory and can't be mounted
+    /// as read-write.
+    pub fn is_read_only(&self) -> bool {
+        !matches!(self, Self::Ext4)
+    }
+}
+
+impl std::str::FromStr for RootfsFormat {
+    type Err = &'static str;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "ext4" => Ok(Self::Ext4),
+            "squashfs" => Ok(Self::Squashfs),
+            "erofs" => Ok(Self::Erofs),
+            _ => Err("invalid rootfs format"),
+        }
+    }
+}
+
+impl std::fmt::Display for RootfsFormat {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::Ext4 => write!(f, "ext4"),
+            Self::Squashfs => write!(f, "squashfs"),
+            Self::Erofs => write!(f, "erofs"),
+        }
+    }
+}
+
 #[derive(Debug, Clone)]
 pub struct BuildOptions {
     pub container_source: Option<String>,
     pub rootfs_dir: Option<String>,
+    pub rootfs_excludes: Vec<String>,
+    pub owner_map: Vec<OwnerMap>,
+    pub rootfs_tar: Option<String>,
     pub containerfile: Option<String>,
-    pub image_size: String,
+    pub build_engine: BuildEngine,
+    pub build_context: Option<String>,
+    pub build_target: Option<String>,
+    pub build_args: Vec<String>,
+    pub build_secrets: Vec<String>,
+    pub arch: Arch,
+    pub platform: Platform,
+    pub image_size: ImageSize,
+    pub boot_size: u64,
+    pub extra_partitions: Vec<ExtraPartition>,
+    pub rootfs_format: RootfsFormat,
+    pub slim: Option<SlimProfile>,
     pub kernel_version: String,
     pub kernel_url: Option<String>,
     pub kernel_file: Option<String>,
+    pub kernel_index: Option<String>,
+    pub kernel_index_key: Option<String>,
+    pub kernel_defconfig: Option<String>,
+    pub kernel_config_fragments: Vec<String>,
+    pub cross_compile: Option<String>,
     pub nvidia_drivers: bool,
     pub kernel_modules: Vec<String>,
+    pub modules_install: ModulesInstall,
     pub mounts: Vec<String>,
     pub mia_version: Option<String>,
     pub no_gevulot_runtime: bool,
@@ -25,8 +398,19 @@ pub struct BuildOptions {
     pub init: Option<String>,
     pub init_args: Option<String>,
     pub rw_root: bool,
+    pub initramfs: bool,
+    pub busybox_file: Option<String>,
+    pub verity: bool,
+    pub root_device: Option<String>,
+    pub boot_mode: BootMode,
     pub mbr_file: Option<String>,
+    pub efi_loader_file: Option<String>,
     pub output_file: String,
+    pub output_kind: OutputKind,
+    pub report_file: Option<String>,
+    pub sbom: Option<SbomFormat>,
+    pub reproducible: bool,
+    pub source_date_epoch: u64,
     pub force: bool,
     pub quiet: bool,
 }
@@ -59,6 +443,35 @@ impl std::fmt::Display for BuildOptions {
                 .as_deref()
                 .unwrap_or("None (will use container or Containerfile)")
         )?;
+        if self.rootfs_dir.is_some() {
+            writeln!(
+                f,
+                "| Excludes         | {:<42} |",
+                if self.rootfs_excludes.is_empty() {
+                    "None".to_string()
+                } else {
+                    self.rootfs_excludes.join(" ")
+                }
+            )?;
+            writeln!(
+                f,
+                "| Owner Map        | {:<42} |",
+                if self.owner_map.is_empty() {
+                    "None".to_string()
+                } else {
+                    self.owner_map
+                        .iter()
+                        .map(|map| map.to_string())
+                        .collect::<Vec<_>>()
+                        .join(" ")
+                }
+            )?;
+        }
+        writeln!(
+            f,
+            "| Rootfs Tarball   | {:<42} |",
+            self.rootfs_tar.as_deref().unwrap_or("None")
+        )?;
         writeln!(
             f,
             "| Containerfile    | {:<42} |",
@@ -66,7 +479,59 @@ impl std::fmt::Display for BuildOptions {
                 .as_deref()
                 .unwrap_or("None (will use rootfs or container)")
         )?;
+        if self.containerfile.is_some() {
+            writeln!(f, "| Build Engine     | {:<42} |", self.build_engine)?;
+            writeln!(
+                f,
+                "| Build Context    | {:<42} |",
+                self.build_context.as_deref().unwrap_or(".")
+            )?;
+            writeln!(
+                f,
+                "| Build Target     | {:<42} |",
+                self.build_target.as_deref().unwrap_or("None (last stage)")
+            )?;
+            writeln!(
+                f,
+                "| Build Args       | {:<42} |",
+                if self.build_args.is_empty() {
+                    "None".to_string()
+                } else {
+                    self.build_args.join(" ")
+                }
+            )?;
+            // Secret values are not printed, only their number.
+            writeln!(f, "| Build Secrets    | {:<42} |", self.build_secrets.len())?;
+        }
+        writeln!(f, "| Architecture     | {:<42} |", self.arch)?;
+        writeln!(f, "| Platform         | {:<42} |", self.platform)?;
         writeln!(f, "| Image Size       | {:<42} |", self.image_size)?;
+        writeln!(
+            f,
+            "| Boot Size        | {:<42} |",
+            size::format_size(self.boot_size)
+        )?;
+        writeln!(
+            f,
+            "| Extra Partitions | {:<42} |",
+            if self.extra_partitions.is_empty() {
+                "None".to_string()
+            } else {
+                self.extra_partitions
+                    .iter()
+                    .map(|partition| partition.to_string())
+                    .collect::<Vec<_>>()
+                    .join(" ")
+            }
+        )?;
+        writeln!(f, "| Rootfs Format    | {:<42} |", self.rootfs_format)?;
+        writeln!(
+            f,
+            "| Slim             | {:<42} |",
+            self.slim
+                .map(|profile| profile.to_string())
+                .unwrap_or("None".to_string())
+        )?;
         writeln!(f, "| Kernel Version   | {:<42} |", self.kernel_version)?;
         writeln!(
             f,
@@ -80,6 +545,32 @@ impl std::fmt::Display for BuildOptions {
                 .as_deref()
                 .unwrap_or("None (will download and build)")
         )?;
+        writeln!(
+            f,
+            "| Kernel Index     | {:<42} |",
+            self.kernel_index.as_deref().unwrap_or("None")
+        )?;
+        writeln!(
+            f,
+            "| Kernel Defconfig | {:<42} |",
+            self.kernel_defconfig
+                .clone()
+                .unwrap_or(format!("None (will use {})", self.arch.defconfig()))
+        )?;
+        writeln!(
+            f,
+            "| Kernel Config    | {:<42} |",
+            if self.kernel_config_fragments.is_empty() {
+                "None".to_string()
+            } else {
+                self.kernel_config_fragments.join(" ")
+            }
+        )?;
+        writeln!(
+            f,
+            "| Cross Compile    | {:<42} |",
+            self.cross_compile.as_deref().unwrap_or("None")
+        )?;
         writeln!(f, "| NVIDIA drivers   | {:<42} |", self.nvidia_drivers)?;
         writeln!(
             f,
@@ -90,6 +581,7 @@ impl std::fmt::Display for BuildOptions {
                 self.kernel_modules.join(" ")
             }
         )?;
+        writeln!(f, "| Modules Install  | {:<42} |", self.modules_install)?;
         writeln!(
             f,
             "| Mounts           | {:<42} |",
@@ -127,6 +619,14 @@ impl std::fmt::Display for BuildOptions {
                 .unwrap_or("None (will use ENTRYPOINT and CMD)")
         )?;
         writeln!(f, "| Read-only root   | {:<42} |", !self.rw_root)?;
+        writeln!(f, "| Initramfs        | {:<42} |", self.initramfs)?;
+        writeln!(f, "| dm-verity        | {:<42} |", self.verity)?;
+        writeln!(
+            f,
+            "| Root Device      | {:<42} |",
+            self.root_device.as_deref().unwrap_or("auto")
+        )?;
+        writeln!(f, "| Boot Mode        | {:<42} |", self.boot_mode)?;
         writeln!(
             f,
             "| MBR File         | {:<42} |",
@@ -134,7 +634,36 @@ impl std::fmt::Display for BuildOptions {
                 .as_deref()
                 .unwrap_or("None (will check for local one)")
         )?;
+        writeln!(
+            f,
+            "| EFI Loader File  | {:<42} |",
+            self.efi_loader_file
+                .as_deref()
+                .unwrap_or("None (will check for local one)")
+        )?;
         writeln!(f, "| Output File      | {:<42} |", self.output_file)?;
+        writeln!(f, "| Output Kind      | {:<42} |", self.output_kind)?;
+        writeln!(
+            f,
+            "| Report           | {:<42} |",
+            self.report_file.as_deref().unwrap_or("None")
+        )?;
+        writeln!(
+            f,
+            "| SBOM             | {:<42} |",
+            self.sbom
+                .map(|format| format.to_string())
+                .unwrap_or("None".to_string())
+        )?;
+        writeln!(
+            f,
+            "| Reproducible     | {:<42} |",
+            if self.reproducible {
+                format!("true (SOURCE_DATE_EPOCH={})", self.source_date_epoch)
+            } else {
+                "false".to_string()
+            }
+        )?;
         writeln!(f, "| Force            | {:<42} |", self.force)?;
         writeln!(f, "| Quiet            | {:<42} |", self.quiet)?;
         writeln!(
@@ -149,26 +678,98 @@ impl TryFrom<&clap::ArgMatches> for BuildOptions {
     type Error = &'static str;
 
     fn try_from(matches: &clap::ArgMatches) -> Result<Self, Self::Error> {
+        let arch: Arch = matches
+            .get_one::<String>("arch")
+            .ok_or("need architecture")?
+            .parse()?;
+        let output_kind: OutputKind = matches
+            .get_one::<String>("output_kind")
+            .ok_or("need output kind")?
+            .parse()?;
         Ok(BuildOptions {
             container_source: matches.get_one::<String>("container_source").cloned(),
             rootfs_dir: matches.get_one::<String>("rootfs_dir").cloned(),
+            rootfs_excludes: matches
+                .get_many::<String>("exclude")
+                .unwrap_or_default()
+                .cloned()
+                .collect::<Vec<_>>(),
+            owner_map: matches
+                .get_many::<String>("owner_map")
+                .unwrap_or_default()
+                .map(|map| map.parse())
+                .collect::<Result<Vec<_>, _>>()?,
+            rootfs_tar: matches.get_one::<String>("rootfs_tar").cloned(),
             containerfile: matches.get_one::<String>("containerfile").cloned(),
+            build_engine: matches
+                .get_one::<String>("build_engine")
+                .ok_or("need build engine")?
+                .parse()?,
+            build_context: matches.get_one::<String>("build_context").cloned(),
+            build_target: matches.get_one::<String>("build_target").cloned(),
+            build_args: matches
+                .get_many::<String>("build_arg")
+                .unwrap_or_default()
+                .cloned()
+                .collect::<Vec<_>>(),
+            build_secrets: matches
+                .get_many::<String>("build_secret")
+                .unwrap_or_default()
+                .cloned()
+                .collect::<Vec<_>>(),
+            arch,
+            platform: matches
+                .get_one::<String>("platform")
+                .map(|platform| platform.parse())
+                .transpose()?
+                .unwrap_or_else(|| arch.platform()),
             image_size: matches
                 .get_one::<String>("image_size")
                 .ok_or("need image size")?
-                .to_string(),
+                .parse()?,
+            boot_size: size::parse_size(
+                matches
+                    .get_one::<String>("boot_size")
+                    .ok_or("need boot partition size")?,
+            )?,
+            extra_partitions: matches
+                .get_many::<String>("extra_partition")
+                .unwrap_or_default()
+                .map(|partition| partition.parse())
+                .collect::<Result<Vec<_>, _>>()?,
+            rootfs_format: matches
+                .get_one::<String>("rootfs_format")
+                .ok_or("need rootfs format")?
+                .parse()?,
+            slim: matches
+                .get_one::<String>("slim")
+                .map(|profile| profile.parse())
+                .transpose()?,
             kernel_version: matches
                 .get_one::<String>("kernel_version")
                 .ok_or("need kernel version")?
                 .to_string(),
             kernel_url: matches.get_one::<String>("kernel_url").cloned(),
             kernel_file: matches.get_one::<String>("kernel_file").cloned(),
+            kernel_index: matches.get_one::<String>("kernel_index").cloned(),
+            kernel_index_key: matches.get_one::<String>("kernel_index_key").cloned(),
+            kernel_defconfig: matches.get_one::<String>("kernel_defconfig").cloned(),
+            kernel_config_fragments: matches
+                .get_many::<String>("kernel_config")
+                .unwrap_or_default()
+                .cloned()
+                .collect::<Vec<_>>(),
+            cross_compile: matches.get_one::<String>("cross_compile").cloned(),
             nvidia_drivers: matches.get_flag("nvidia_drivers"),
             kernel_modules: matches
                 .get_many::<String>("kernel_module")
                 .unwrap_or_default()
                 .cloned()
                 .collect::<Vec<_>>(),
+            modules_install: matches
+                .get_one::<String>("modules_install")
+                .ok_or("need modules install mode")?
+                .parse()?,
             mounts: matches
                 .get_many::<String>("mount")
                 .unwrap_or_default()
@@ -180,11 +781,32 @@ impl TryFrom<&clap::ArgMatches> for BuildOptions {
             init: matches.get_one::<String>("init").cloned(),
             init_args: matches.get_one::<String>("init_args").cloned(),
             rw_root: matches.get_flag("rw_root"),
+            initramfs: matches.get_flag("initramfs"),
+            busybox_file: matches.get_one::<String>("busybox_file").cloned(),
+            verity: matches.get_flag("verity"),
+            root_device: matches.get_one::<String>("root_device").cloned(),
+            boot_mode: matches
+                .get_one::<String>("boot_mode")
+                .map(|mode| mode.parse())
+                .transpose()?
+                .unwrap_or(arch.default_boot_mode()),
             mbr_file: matches.get_one::<String>("mbr_file").cloned(),
+            efi_loader_file: matches.get_one::<String>("efi_loader_file").cloned(),
             output_file: matches
                 .get_one::<String>("output_file")
-                .unwrap()
-                .to_string(),
+                .cloned()
+                .unwrap_or_else(|| output_kind.default_output().to_string()),
+            output_kind,
+            report_file: matches.get_one::<String>("report_file").cloned(),
+            sbom: matches
+                .get_one::<String>("sbom")
+                .map(|format| format.parse())
+                .transpose()?,
+            reproducible: matches.get_flag("reproducible"),
+            source_date_epoch: matches
+                .get_one::<u64>("source_date_epoch")
+                .copied()
+                .unwrap_or(DEFAULT_SOURCE_DATE_EPOCH),
             force: matches.get_flag("force"),
             quiet: matches.get_flag("quiet"),
         })
@@ -0,0 +1,783 @@
+//! Partition table written directly into the disk image.
+//!
+//! MBR and GPT are generated from a [`Layout`] instead of scripting `fdisk` or `sfdisk`,
+//! so the layout is configurable and errors are reported with their cause.
+
+use std::fs;
+use std::io::{Read, Seek, SeekFrom, Write};
+use std::path::Path;
+
+use anyhow::{Context, Result};
+
+use super::image::format_guid;
+use super::random;
+use super::size::{self, align_up};
+
+const SECTOR_SIZE: u64 = 512;
+
+/// Number of GPT partition entries and size of one entry. 128 entries take 32 sectors.
+const GPT_ENTRIES: u64 = 128;
+const GPT_ENTRY_SIZE: u64 = 128;
+const GPT_ENTRIES_SECTORS: u64 = GPT_ENTRIES * GPT_ENTRY_SIZE / SECTOR_SIZE;
+const GPT_HEADER_SIZE: usize = 92;
+
+/// GPT partition attribute read by SYSLINUX `gptmbr.bin`.
+const GPT_LEGACY_BIOS_BOOTABLE: u64 = 1 << 2;
+
+/// Kind of the partition table.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TableKind {
+    Mbr,
+    Gpt,
+}
+
+/// Content of the partition, which determines its type code.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PartitionType {
+    /// FAT boot partition, EFI system partition on GPT.
+    Boot,
+    /// FAT data partition, ignored by EFI firmware.
+    Fat,
+    /// Linux filesystem.
+    Linux,
+}
+
+impl PartitionType {
+    fn mbr_code(&self) -> u8 {
+        match self {
+            // W95 FAT32 (LBA).
+            Self::Boot | Self::Fat => 0x0c,
+            Self::Linux => 0x83,
+        }
+    }
+
+    fn gpt_guid(&self) -> &'static str {
+        match self {
+            Self::Boot => "C12A7328-F81F-11D2-BA4B-00A0C93EC93B",
+            Self::Fat => "EBD0A0A2-B9E5-4433-87C0-68B6B72699C7",
+            Self::Linux => "0FC63DAF-8483-4772-8E79-3D69D8477DE4",
+        }
+    }
+}
+
+/// Requested partition.
+#[derive(Debug, Clone)]
+pub struct PartitionSpec {
+    /// GPT partition name, ignored on MBR.
+    pub name: String,
+    /// Size in bytes. `None` takes the space left by other partitions, at most one partition
+    /// can have it.
+    pub size: Option<u64>,
+    pub r#type: PartitionType,
+    /// MBR active flag or GPT legacy BIOS bootable attribute.
+    pub bootable: bool,
+    /// GPT partition GUID, random if not given.
+    pub uuid: Option<String>,
+}
+
+/// Partition placed on the disk.
+#[derive(Debug, Clone)]
+pub struct Partition {
+    /// 1-based partition number.
+    pub number: u32,
+    /// Offset from the start of the disk in bytes.
+    pub start: u64,
+    pub size: u64,
+    /// PARTUUID as resolved by the kernel.
+    pub uuid: String,
+}
+
+/// Partition table layout.
+#[derive(Debug, Clone)]
+pub struct Layout {
+    pub table: TableKind,
+    /// Partitions start at multiples of this size in bytes.
+    pub alignment: u64,
+    /// GPT disk GUID or MBR disk signature in hex, random if not given.
+    pub disk_id: Option<String>,
+    pub partitions: Vec<PartitionSpec>,
+}
+
+impl Layout {
+    /// Write partition table into the disk image, which must already have its final size.
+    /// Boot code in the first 440 bytes of the image is kept.
+    pub fn write(&self, image: &Path) -> Result<Vec<Partition>> {
+        let mut file = fs::OpenOptions::new()
+            .read(true)
+            .write(true)
+            .open(image)
+            .context(format!("Failed to open disk image {}", image.display()))?;
+        let disk_size = file
+            .metadata()
+            .context("Failed to read disk image metadata")?
+            .len();
+        let placed = self.place(disk_size)?;
+
+        let partitions = match self.table {
+            TableKind::Mbr => self.write_mbr(&mut file, &placed)?,
+            TableKind::Gpt => self.write_gpt(&mut file, disk_size, &placed)?,
+        };
+        file.sync_all().context("Failed to sync disk image")?;
+        Ok(partitions)
+    }
+
+    /// First and last usable sectors of the disk.
+    fn usable_sectors(&self, disk_size: u64) -> Result<(u64, u64)> {
+        let sectors = disk_size / SECTOR_SIZE;
+        let (first, last) = match self.table {
+            TableKind::Mbr => (1, sectors.saturating_sub(1)),
+            // Primary header and entries at the start, their backup copies at the end.
+            TableKind::Gpt => (
+                2 + GPT_ENTRIES_SECTORS,
+                sectors.saturating_sub(2 + GPT_ENTRIES_SECTORS),
+            ),
+        };
+        if last <= first {
+            anyhow::bail!("Disk image of {} bytes is too small", disk_size);
+        }
+        Ok((first, last))
+    }
+
+    /// Start and size in sectors of every partition.
+    fn place(&self, disk_size: u64) -> Result<Vec<(u64, u64)>> {
+        if self.alignment == 0 || self.alignment % SECTOR_SIZE != 0 {
+            anyhow::bail!(
+                "Partition alignment must be a multiple of {} bytes",
+                SECTOR_SIZE
+            );
+        }
+        if self.table == TableKind::Mbr && self.partitions.len() > 4 {
+            anyhow::bail!("MBR partition table supports at most 4 partitions");
+        }
+        if self.partitions.iter().filter(|p| p.size.is_none()).count() > 1 {
+            anyhow::bail!("Only one partition can take the rest of the disk");
+        }
+        let alignment = self.alignment / SECTOR_SIZE;
+        let (first_usable, last_usable) = self.usable_sectors(disk_size)?;
+
+        let place = |rest: u64| -> Result<Vec<(u64, u64)>> {
+            let mut next = first_usable;
+            let mut placed = Vec::new();
+            for partition in &self.partitions {
+                let start = align_up(next, alignment);
+                let size = match partition.size {
+                    Some(size) => size.div_ceil(SECTOR_SIZE),
+                    None => rest,
+                };
+                if size == 0 && partition.size.is_some() {
+                    anyhow::bail!("Partition {} has zero size", partition.name);
+                }
+                placed.push((start, size));
+                next = start + size;
+            }
+            Ok(placed)
+        };
+
+        // Place the flexible partition with zero size first to find out how much space is left.
+        let end = place(0)?
+            .last()
+            .map(|(start, size)| start + size)
+            .unwrap_or(first_usable);
+        let available = (last_usable + 1).saturating_sub(end);
+        let rest = available / alignment * alignment;
+        if self.partitions.iter().any(|p| p.size.is_none()) && rest == 0 {
+            anyhow::bail!(
+                "No space left for the last partition on {} bytes disk",
+                disk_size
+            );
+        }
+        let placed = place(rest)?;
+        if let Some((start, size)) = placed.last() {
+            if start + size > last_usable + 1 {
+                anyhow::bail!(
+                    "Partitions ({} bytes) don't fit into disk image of {} bytes",
+                    (start + size) * SECTOR_SIZE,
+                    disk_size
+                );
+            }
+        }
+        Ok(placed)
+    }
+
+    fn write_mbr(&self, file: &mut fs::File, placed: &[(u64, u64)]) -> Result<Vec<Partition>> {
+        let disk_id = match &self.disk_id {
+            Some(disk_id) => u32::from_str_radix(disk_id.trim_start_matches("0x"), 16)
+                .context(format!("Invalid MBR disk identifier {}", disk_id))?,
+            None => u32::from_le_bytes(
+                random::bytes::<4>().context("Failed to generate disk identifier")?,
+            ),
+        };
+
+        let mut mbr = read_sector(file, 0)?;
+        mbr[440..444].copy_from_slice(&disk_id.to_le_bytes());
+        mbr[444..446].fill(0);
+        mbr[446..510].fill(0);
+        let mut partitions = Vec::new();
+        for (i, (spec, (start, size))) in self.partitions.iter().zip(placed).enumerate() {
+            if start + size > u32::MAX as u64 {
+                anyhow::bail!("Disk image is too big for MBR partition table, use GPT");
+            }
+            let entry = mbr_entry(spec.bootable, spec.r#type.mbr_code(), *start, *size);
+            mbr[446 + i * 16..446 + (i + 1) * 16].copy_from_slice(&entry);
+            partitions.push(Partition {
+                number: i as u32 + 1,
+                start: start * SECTOR_SIZE,
+                size: size * SECTOR_SIZE,
+                uuid: format!("{:08x}-{:02x}", disk_id, i + 1),
+            });
+        }
+        mbr[510..512].copy_from_slice(&[0x55, 0xaa]);
+        write_at(file, 0, &mbr).context("Failed to write MBR")?;
+        Ok(partitions)
+    }
+
+    fn write_gpt(
+        &self,
+        file: &mut fs::File,
+        disk_size: u64,
+        placed: &[(u64, u64)],
+    ) -> Result<Vec<Partition>> {
+        let last_lba = disk_size / SECTOR_SIZE - 1;
+        let (first_usable, last_usable) = self.usable_sectors(disk_size)?;
+        let disk_guid = match &self.disk_id {
+            Some(disk_id) => parse_guid(disk_id)?,
+            None => random_guid()?,
+        };
+
+        // Protective MBR covering the whole disk.
+        let mut mbr = read_sector(file, 0)?;
+        mbr[440..510].fill(0);
+        let protected = last_lba.min(u32::MAX as u64);
+        mbr[446..462].copy_from_slice(&mbr_entry(false, 0xee, 1, protected));
+        mbr[510..512].copy_from_slice(&[0x55, 0xaa]);
+        write_at(file, 0, &mbr).context("Failed to write protective MBR")?;
+
+        let mut entries = vec![0u8; (GPT_ENTRIES * GPT_ENTRY_SIZE) as usize];
+        let mut partitions = Vec::new();
+        for (i, (spec, (start, size))) in self.partitions.iter().zip(placed).enumerate() {
+            let uuid = match &spec.uuid {
+                Some(uuid) => parse_guid(uuid)?,
+                None => random_guid()?,
+            };
+            let entry = &mut entries[i * GPT_ENTRY_SIZE as usize..][..GPT_ENTRY_SIZE as usize];
+            entry[0..16].copy_from_slice(&parse_guid(spec.r#type.gpt_guid())?);
+            entry[16..32].copy_from_slice(&uuid);
+            entry[32..40].copy_from_slice(&start.to_le_bytes());
+            entry[40..48].copy_from_slice(&(start + size - 1).to_le_bytes());
+            let attributes = if spec.bootable {
+                GPT_LEGACY_BIOS_BOOTABLE
+            } else {
+                0
+            };
+            entry[48..56].copy_from_slice(&attributes.to_le_bytes());
+            let name = spec.name.encode_utf16().collect::<Vec<_>>();
+            if name.len() > 36 {
+                anyhow::bail!(
+                    "GPT partition name {} is longer than 36 characters",
+                    spec.name
+                );
+            }
+            for (j, c) in name.iter().enumerate() {
+                entry[56 + j * 2..58 + j * 2].copy_from_slice(&c.to_le_bytes());
+            }
+            partitions.push(Partition {
+                number: i as u32 + 1,
+                start: start * SECTOR_SIZE,
+                size: size * SECTOR_SIZE,
+                uuid: format_guid(&uuid).to_lowercase(),
+            });
+        }
+        let entries_crc = crc32(&entries);
+
+        let backup_entries_lba = last_lba - GPT_ENTRIES_SECTORS;
+        for (header_lba, backup_lba, entries_lba) in
+            [(1, last_lba, 2), (last_lba, 1, backup_entries_lba)]
+        {
+            let mut header = [0u8; GPT_HEADER_SIZE];
+            header[0..8].copy_from_slice(b"EFI PART");
+            header[8..12].copy_from_slice(&0x0001_0000u32.to_le_bytes());
+            header[12..16].copy_from_slice(&(GPT_HEADER_SIZE as u32).to_le_bytes());
+            header[24..32].copy_from_slice(&header_lba.to_le_bytes());
+            header[32..40].copy_from_slice(&backup_lba.to_le_bytes());
+            header[40..48].copy_from_slice(&first_usable.to_le_bytes());
+            header[48..56].copy_from_slice(&last_usable.to_le_bytes());
+            header[56..72].copy_from_slice(&disk_guid);
+            header[72..80].copy_from_slice(&entries_lba.to_le_bytes());
+            header[80..84].copy_from_slice(&(GPT_ENTRIES as u32).to_le_bytes());
+            header[84..88].copy_from_slice(&(GPT_ENTRY_SIZE as u32).to_le_bytes());
+            header[88..92].copy_from_slice(&entries_crc.to_le_bytes());
+            let header_crc = crc32(&header);
+            header[16..20].copy_from_slice(&header_crc.to_le_bytes());
+
+            let mut sector = [0u8; SECTOR_SIZE as usize];
+            sector[..GPT_HEADER_SIZE].copy_from_slice(&header);
+            write_at(file, entries_lba * SECTOR_SIZE, &entries)
+                .context("Failed to write GPT partition entries")?;
+            write_at(file, header_lba * SECTOR_SIZE, &sector)
+                .context("Failed to write GPT header")?;
+        }
+        Ok(partitions)
+    }
+}
+
+/// Filesystem created on an extra partition.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ExtraFilesystem {
+    Ext4,
+    Vfat,
+}
+
+impl ExtraFilesystem {
+    /// Longest filesystem label supported by `mkfs`.
+    fn max_label_len(&self) -> usize {
+        match self {
+            Self::Ext4 => 16,
+            Self::Vfat => 11,
+        }
+    }
+
+    pub fn partition_type(&self) -> PartitionType {
+        match self {
+            Self::Ext4 => PartitionType::Linux,
+            Self::Vfat => PartitionType::Fat,
+        }
+    }
+}
+
+impl std::str::FromStr for ExtraFilesystem {
+    type Err = &'static str;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "ext4" => Ok(Self::Ext4),
+            "vfat" | "fat" => Ok(Self::Vfat),
+            _ => Err("invalid extra partition filesystem: expected ext4 or vfat"),
+        }
+    }
+}
+
+impl std::fmt::Display for ExtraFilesystem {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::Ext4 => write!(f, "ext4"),
+            Self::Vfat => write!(f, "vfat"),
+        }
+    }
+}
+
+/// Data partition placed after the root partition, given as `label:size:fs`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ExtraPartition {
+    /// Filesystem label and GPT partition name.
+    pub label: String,
+    /// Size in bytes.
+    pub size: u64,
+    pub filesystem: ExtraFilesystem,
+}
+
+impl std::str::FromStr for ExtraPartition {
+    type Err = &'static str;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let mut parts = s.split(':');
+        let (Some(label), Some(size), Some(filesystem), None) =
+            (parts.next(), parts.next(), parts.next(), parts.next())
+        else {
+            return Err("invalid extra partition: expected label:size:fs");
+        };
+        let filesystem: ExtraFilesystem = filesystem.parse()?;
+        if label.is_empty()
+            || !label
+                .chars()
+                .all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_')
+        {
+            return Err("invalid extra partition label: expected letters, digits, '-' or '_'");
+        }
+        if label.len() > filesystem.max_label_len() {
+            return Err(
+                "extra partition label is too long: at most 16 characters for ext4 and 11 for vfat",
+            );
+        }
+        if ["BOOT", "ROOTFS"].contains(&label.to_ascii_uppercase().as_str()) {
+            return Err("extra partition labels BOOT and ROOTFS are reserved");
+        }
+        let size = size::parse_size(size)?;
+        if size == 0 {
+            return Err("extra partition size must not be zero");
+        }
+        Ok(Self {
+            label: label.to_string(),
+            size,
+            filesystem,
+        })
+    }
+}
+
+impl ExtraPartition {
+    /// Label written to the filesystem. vfat labels are stored in upper case.
+    pub fn filesystem_label(&self) -> String {
+        match self.filesystem {
+            ExtraFilesystem::Ext4 => self.label.clone(),
+            ExtraFilesystem::Vfat => self.label.to_ascii_uppercase(),
+        }
+    }
+}
+
+impl std::fmt::Display for ExtraPartition {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "{}:{}:{}",
+            self.label,
+            size::format_size(self.size).replace(' ', ""),
+            self.filesystem
+        )
+    }
+}
+
+/// MBR partition entry addressed by LBA. CHS fields get the maximum value when the
+/// address doesn't fit into them.
+fn mbr_entry(bootable: bool, code: u8, start: u64, size: u64) -> [u8; 16] {
+    let mut entry = [0u8; 16];
+    entry[0] = if bootable { 0x80 } else { 0 };
+    entry[1..4].copy_from_slice(&chs(start));
+    entry[4] = code;
+    entry[5..8].copy_from_slice(&chs(start + size - 1));
+    entry[8..12].copy_from_slice(&(start as u32).to_le_bytes());
+    entry[12..16].copy_from_slice(&(size.min(u32::MAX as u64) as u32).to_le_bytes());
+    entry
+}
+
+/// CHS address with 255 heads and 63 sectors per track.
+fn chs(lba: u64) -> [u8; 3] {
+    const HEADS: u64 = 255;
+    const SECTORS: u64 = 63;
+    let cylinder = lba / (HEADS * SECTORS);
+    if cylinder > 1023 {
+        return [0xfe, 0xff, 0xff];
+    }
+    let head = (lba / SECTORS) % HEADS;
+    let sector = lba % SECTORS + 1;
+    [
+        head as u8,
+        (sector as u8) | (((cylinder >> 8) as u8) << 6),
+        cylinder as u8,
+    ]
+}
+
+fn read_sector(file: &mut fs::File, lba: u64) -> Result<[u8; SECTOR_SIZE as usize]> {
+    let mut sector = [0u8; SECTOR_SIZE as usize];
+    file.seek(SeekFrom::Start(lba * SECTOR_SIZE))?;
+    file.read_exact(&mut sector)
+        .context("Failed to read disk image")?;
+    Ok(sector)
+}
+
+fn write_at(file: &mut fs::File, offset: u64, data: &[u8]) -> Result<()> {
+    file.seek(SeekFrom::Start(offset))?;
+    file.write_all(data)?;
+    Ok(())
+}
+
+/// Parse GUID string into its mixed-endian on-disk form.
+fn parse_guid(guid: &str) -> Result<[u8; 16]> {
+    let hex = guid.replace('-', "");
+    if hex.len() != 32 {
+        anyhow::bail!("Invalid GUID {}", guid);
+    }
+    let mut bytes = [0u8; 16];
+    for (i, byte) in bytes.iter_mut().enumerate() {
+        *byte = u8::from_str_radix(&hex[i * 2..i * 2 + 2], 16)
+            .context(format!("Invalid GUID {}", guid))?;
+    }
+    bytes[0..4].reverse();
+    bytes[4..6].reverse();
+    bytes[6..8].reverse();
+    Ok(bytes)
+}
+
+/// Random version 4 GUID in its on-disk form.
+fn random_guid() -> Result<[u8; 16]> {
+    let mut bytes = random::bytes::<16>().context("Failed to generate GUID")?;
+    // Version and variant live in the big-endian part of the on-disk form.
+    bytes[7] = (bytes[7] & 0x0f) | 0x40;
+    bytes[8] = (bytes[8] & 0x3f) | 0x80;
+    Ok(bytes)
+}
+
+/// CRC-32 (IEEE 802.3) used by GPT headers.
+fn crc32(data: &[u8]) -> u32 {
+    let mut crc = 0xffff_ffffu32;
+    for byte in data {
+        crc ^= *byte as u32;
+        for _ in 0..8 {
+            crc = if crc & 1 != 0 {
+                (crc >> 1) ^ 0xedb8_8320
+            } else {
+                crc >> 1
+            };
+        }
+    }
+    !crc
+}
+
+#[cfg(test)]
+mod tests {
+    use std::path::PathBuf;
+
+    use super::*;
+    use crate::builders::image::Image;
+    use crate::builders::size::MIB;
+    use tempdir::TempDir;
+
+    const ROOT_GUID: &str = "47564c54-0000-4000-8000-000000000002";
+
+    fn spec(name: &str, size: Option<u64>, r#type: PartitionType, bootable: bool) -> PartitionSpec {
+        PartitionSpec {
+            name: name.to_string(),
+            size,
+            r#type,
+            bootable,
+            uuid: None,
+        }
+    }
+
+    fn layout(table: TableKind) -> Layout {
+        Layout {
+            table,
+            alignment: MIB,
+            disk_id: None,
+            partitions: vec![
+                spec("BOOT", Some(16 * MIB), PartitionType::Boot, true),
+                spec("ROOTFS", None, PartitionType::Linux, false),
+            ],
+        }
+    }
+
+    fn disk(dir: &TempDir, size: u64) -> PathBuf {
+        let path = dir.path().join("disk.img");
+        fs::File::create(&path).unwrap().set_len(size).unwrap();
+        path
+    }
+
+    fn read_at(path: &Path, offset: u64, len: usize) -> Vec<u8> {
+        let mut file = fs::File::open(path).unwrap();
+        let mut data = vec![0u8; len];
+        file.seek(SeekFrom::Start(offset)).unwrap();
+        file.read_exact(&mut data).unwrap();
+        data
+    }
+
+    /// Check GPT header at `lba` and its CRCs, returns LBA of the partition entries.
+    fn check_gpt_header(path: &Path, lba: u64, backup_lba: u64) -> u64 {
+        let mut header = read_at(path, lba * SECTOR_SIZE, GPT_HEADER_SIZE);
+        assert_eq!(&header[0..8], b"EFI PART");
+        let u64_at = |header: &[u8], offset: usize| {
+            u64::from_le_bytes(header[offset..offset + 8].try_into().unwrap())
+        };
+        assert_eq!(u64_at(&header, 24), lba);
+        assert_eq!(u64_at(&header, 32), backup_lba);
+
+        let header_crc = u32::from_le_bytes(header[16..20].try_into().unwrap());
+        header[16..20].fill(0);
+        assert_eq!(crc32(&header), header_crc);
+
+        let entries_lba = u64_at(&header, 72);
+        let entries = read_at(
+            path,
+            entries_lba * SECTOR_SIZE,
+            (GPT_ENTRIES * GPT_ENTRY_SIZE) as usize,
+        );
+        let entries_crc = u32::from_le_bytes(header[88..92].try_into().unwrap());
+        assert_eq!(crc32(&entries), entries_crc);
+        entries_lba
+    }
+
+    #[test]
+    fn crc32_check_value() {
+        assert_eq!(crc32(b"123456789"), 0xcbf4_3926);
+        assert_eq!(crc32(b""), 0);
+    }
+
+    #[test]
+    fn guid_round_trip() {
+        let guid = parse_guid(ROOT_GUID).unwrap();
+        assert_eq!(guid[0..4], [0x54, 0x4c, 0x56, 0x47]);
+        assert_eq!(format_guid(&guid).to_lowercase(), ROOT_GUID);
+        assert!(parse_guid("47564c54").is_err());
+        assert!(parse_guid("47564c54-0000-4000-8000-00000000000g").is_err());
+    }
+
+    #[test]
+    fn mbr_layout_is_read_back() {
+        let dir = TempDir::new("partition-test").unwrap();
+        let path = disk(&dir, 64 * MIB);
+        let mut layout = layout(TableKind::Mbr);
+        layout.disk_id = Some("47564c54".to_string());
+
+        let written = layout.write(&path).unwrap();
+        assert_eq!(written[1].uuid, "47564c54-02");
+
+        let image = Image::open(&path).unwrap();
+        assert_eq!(image.partition_table, Some("mbr"));
+        let partitions = image
+            .partitions
+            .iter()
+            .map(|p| {
+                (
+                    p.start,
+                    p.size,
+                    p.r#type.as_str(),
+                    p.bootable,
+                    p.uuid.as_str(),
+                )
+            })
+            .collect::<Vec<_>>();
+        assert_eq!(
+            partitions,
+            [
+                (MIB, 16 * MIB, "c", true, "47564c54-01"),
+                (17 * MIB, 47 * MIB, "83", false, "47564c54-02"),
+            ]
+        );
+        for (written, read) in written.iter().zip(&image.partitions) {
+            assert_eq!((written.start, written.size), (read.start, read.size));
+        }
+    }
+
+    #[test]
+    fn mbr_keeps_boot_code() {
+        let dir = TempDir::new("partition-test").unwrap();
+        let path = disk(&dir, 64 * MIB);
+        let boot_code = [0xeb; 440];
+        write_at(
+            &mut fs::OpenOptions::new().write(true).open(&path).unwrap(),
+            0,
+            &boot_code,
+        )
+        .unwrap();
+        layout(TableKind::Mbr).write(&path).unwrap();
+        assert_eq!(read_at(&path, 0, 440), boot_code);
+    }
+
+    #[test]
+    fn gpt_layout_is_read_back() {
+        let dir = TempDir::new("partition-test").unwrap();
+        let size = 64 * MIB;
+        let path = disk(&dir, size);
+        let mut layout = layout(TableKind::Gpt);
+        layout.partitions[1].uuid = Some(ROOT_GUID.to_string());
+
+        let written = layout.write(&path).unwrap();
+        assert_eq!(written[1].uuid, ROOT_GUID);
+
+        let image = Image::open(&path).unwrap();
+        assert_eq!(image.partition_table, Some("gpt"));
+        let partitions = image
+            .partitions
+            .iter()
+            .map(|p| {
+                (
+                    p.start,
+                    p.size,
+                    p.r#type.as_str(),
+                    p.bootable,
+                    p.name.as_deref(),
+                )
+            })
+            .collect::<Vec<_>>();
+        // Backup entries at the end leave less than 47 MiB for the root partition.
+        assert_eq!(
+            partitions,
+            [
+                (
+                    MIB,
+                    16 * MIB,
+                    PartitionType::Boot.gpt_guid(),
+                    true,
+                    Some("BOOT")
+                ),
+                (
+                    17 * MIB,
+                    46 * MIB,
+                    PartitionType::Linux.gpt_guid(),
+                    false,
+                    Some("ROOTFS")
+                ),
+            ]
+        );
+        assert_eq!(image.partitions[1].uuid, ROOT_GUID);
+        assert_eq!(image.partitions[0].uuid, written[0].uuid);
+
+        let last_lba = size / SECTOR_SIZE - 1;
+        assert_eq!(check_gpt_header(&path, 1, last_lba), 2);
+        assert_eq!(
+            check_gpt_header(&path, last_lba, 1),
+            last_lba - GPT_ENTRIES_SECTORS
+        );
+    }
+
+    #[test]
+    fn too_many_mbr_partitions() {
+        let dir = TempDir::new("partition-test").unwrap();
+        let path = disk(&dir, 64 * MIB);
+        let mut layout = layout(TableKind::Mbr);
+        layout.partitions = (0..5)
+            .map(|i| spec(&i.to_string(), Some(MIB), PartitionType::Linux, false))
+            .collect();
+        let err = layout.write(&path).unwrap_err();
+        assert!(err.to_string().contains("at most 4 partitions"));
+
+        layout.table = TableKind::Gpt;
+        assert_eq!(layout.write(&path).unwrap().len(), 5);
+    }
+
+    #[test]
+    fn only_one_flexible_partition() {
+        let dir = TempDir::new("partition-test").unwrap();
+        let path = disk(&dir, 64 * MIB);
+        let mut layout = layout(TableKind::Gpt);
+        layout.partitions[0].size = None;
+        let err = layout.write(&path).unwrap_err();
+        assert!(err.to_string().contains("Only one partition"));
+    }
+
+    #[test]
+    fn disk_too_small() {
+        let dir = TempDir::new("partition-test").unwrap();
+        for table in [TableKind::Mbr, TableKind::Gpt] {
+            let path = disk(&dir, 8 * MIB);
+            let mut fixed = layout(table);
+            fixed.partitions[1].size = Some(MIB);
+            let err = fixed.write(&path).unwrap_err();
+            assert!(err.to_string().contains("don't fit"), "{}", err);
+
+            // Nothing is left for the flexible partition.
+            let path = disk(&dir, 17 * MIB);
+            let err = layout(table).write(&path).unwrap_err();
+            assert!(err.to_string().contains("No space left"), "{}", err);
+
+            let path = disk(&dir, SECTOR_SIZE);
+            let err = layout(table).write(&path).unwrap_err();
+            assert!(err.to_string().contains("too small"), "{}", err);
+        }
+    }
+
+    #[test]
+    fn gpt_partition_name_length() {
+        let dir = TempDir::new("partition-test").unwrap();
+        let path = disk(&dir, 64 * MIB);
+        let mut layout = layout(TableKind::Gpt);
+        // 36 UTF-16 code units fit, characters outside BMP take two of them.
+        layout.partitions[1].name = "R".repeat(36);
+        layout.write(&path).unwrap();
+        assert_eq!(
+            Image::open(&path).unwrap().partitions[1].name.as_deref(),
+            Some("R".repeat(36).as_str())
+        );
+
+        layout.partitions[1].name = format!("{}\u{1f980}", "R".repeat(35));
+        let err = layout.write(&path).unwrap_err();
+        assert!(err.to_string().contains("longer than 36"));
+    }
+}
@@ -0,0 +1,15 @@
+//! Random identifiers and salts of builds which are not reproducible.
+
+use std::fs;
+use std::io::Read;
+
+use anyhow::{Context, Result};
+
+/// Read `N` random bytes from `/dev/urandom`.
+pub fn bytes<const N: usize>() -> Result<[u8; N]> {
+    let mut bytes = [0u8; N];
+    fs::File::open("/dev/urandom")
+        .and_then(|mut urandom| urandom.read_exact(&mut bytes))
+        .context("Failed to read /dev/urandom")?;
+    Ok(bytes)
+}
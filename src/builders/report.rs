@@ -0,0 +1,314 @@
+//! Machine-readable report of the image build written with `--report`.
+
+use std::cell::RefCell;
+use std::fs;
+use std::path::Path;
+use std::time::Instant;
+
+use anyhow::{Context, Result};
+use mia_installer::runtime_config::RuntimeConfig;
+use serde::Serialize;
+
+use crate::builders::image::Image;
+use crate::builders::kernel;
+use crate::builders::rootfs::CopyStats;
+use crate::builders::skopeo_builder::SkopeoSyslinuxBuilder;
+use crate::builders::verity::Verity;
+
+/// Everything known about the build and its result.
+#[derive(Debug, Default, Serialize)]
+pub struct BuildReport {
+    /// Version of gvltctl which built the image.
+    pub gvltctl_version: String,
+    pub succeeded: bool,
+    pub error: Option<String>,
+    pub source: Option<SourceReport>,
+    /// Statistics of the `--rootfs-dir` copy.
+    pub rootfs_copy: Option<CopyStats>,
+    pub slim: Option<SlimReport>,
+    pub kernel: Option<KernelReport>,
+    /// Installed MIA version, `latest` is resolved to the release number.
+    pub mia_version: Option<String>,
+    /// Runtime config installed for MIA, `None` with custom init.
+    pub runtime_config: Option<RuntimeConfig>,
+    pub root_device: Option<String>,
+    pub verity: Option<Verity>,
+    /// Disk image size in bytes, calculated or requested with `--size`.
+    pub image_size: Option<u64>,
+    /// Partitions of the disk image. Empty for kernel+rootfs output.
+    pub partitions: Vec<PartitionReport>,
+    pub outputs: Vec<OutputFile>,
+    pub steps: Vec<StepReport>,
+}
+
+/// Source of the root filesystem.
+#[derive(Debug, Serialize)]
+pub struct SourceReport {
+    /// `container`, `containerfile` or `rootfs-dir`.
+    pub kind: &'static str,
+    /// Image reference or path as given on the command line.
+    pub reference: String,
+    /// Manifest digest of the container image.
+    pub digest: Option<String>,
+}
+
+/// Files removed from the root filesystem with `--slim`.
+#[derive(Debug, Serialize)]
+pub struct SlimReport {
+    pub profile: String,
+    /// Disk usage of the root filesystem content in bytes.
+    pub bytes_before: u64,
+    pub bytes_after: u64,
+}
+
+/// Kernel installed into the image.
+#[derive(Debug, Serialize)]
+pub struct KernelReport {
+    /// Kernel version, `None` for precompiled kernel file.
+    pub version: Option<String>,
+    /// Precompiled kernel file given with `--kernel-file`.
+    pub file: Option<String>,
+    pub commit: Option<String>,
+    pub config_hash: Option<String>,
+    pub release: Option<String>,
+    /// Checksum of the kernel image.
+    pub sha256: String,
+}
+
+impl KernelReport {
+    /// Describe kernel from the cache entry at `kernel_dir`.
+    pub fn from_cache(kernel_dir: &Path) -> Result<Self> {
+        let manifest = kernel::read_manifest(kernel_dir);
+        Ok(Self {
+            version: manifest.as_ref().map(|manifest| manifest.version.clone()),
+            file: None,
+            commit: manifest
+                .as_ref()
+                .and_then(|manifest| manifest.commit.clone()),
+            config_hash: manifest.and_then(|manifest| manifest.config_hash),
+            release: kernel::kernel_release(kernel_dir).ok(),
+            sha256: sha256(&kernel::image_path(
+                kernel_dir,
+                kernel::entry_arch(kernel_dir),
+            ))?,
+        })
+    }
+
+    /// Describe precompiled kernel file.
+    pub fn from_file(kernel_file: &str) -> Result<Self> {
+        Ok(Self {
+            version: None,
+            file: Some(kernel_file.to_string()),
+            commit: None,
+            config_hash: None,
+            release: None,
+            sha256: sha256(Path::new(kernel_file))?,
+        })
+    }
+}
+
+/// Partition of the disk image.
+#[derive(Debug, Serialize)]
+pub struct PartitionReport {
+    pub number: u32,
+    /// Offset from the start of the disk in bytes.
+    pub start: u64,
+    pub size: u64,
+    /// Partition type: MBR type code or GPT type GUID.
+    pub r#type: String,
+    pub uuid: Option<String>,
+    pub name: Option<String>,
+    pub filesystem: String,
+}
+
+/// Read partition table of the disk image. `filesystems` are given in partition order.
+pub fn read_partitions(disk_image: &str, filesystems: &[String]) -> Result<Vec<PartitionReport>> {
+    let image = Image::open(Path::new(disk_image)).context("Failed to read partition table")?;
+    Ok(image
+        .partitions
+        .into_iter()
+        .map(|partition| PartitionReport {
+            number: partition.number,
+            start: partition.start,
+            size: partition.size,
+            r#type: partition.r#type,
+            uuid: Some(partition.uuid),
+            name: partition.name,
+            filesystem: filesystems
+                .get(partition.number as usize - 1)
+                .cloned()
+                .unwrap_or_default(),
+        })
+        .collect())
+}
+
+/// Output file with its checksums.
+#[derive(Debug, Serialize)]
+pub struct OutputFile {
+    pub path: String,
+    pub size: u64,
+    pub sha256: String,
+    /// CIDv1 of the whole file as a single raw block with SHA-256 multihash.
+    pub cid: String,
+}
+
+impl OutputFile {
+    pub fn new(path: &Path) -> Result<Self> {
+        let sha256 = sha256(path)?;
+        Ok(Self {
+            path: path.display().to_string(),
+            size: fs::metadata(path)
+                .context(format!("Failed to read {} metadata", path.display()))?
+                .len(),
+            cid: cid_from_sha256(&sha256)?,
+            sha256,
+        })
+    }
+}
+
+/// Duration of a build step.
+#[derive(Debug, Clone, Serialize)]
+pub struct StepReport {
+    pub name: String,
+    pub duration_ms: u64,
+    pub failed: bool,
+}
+
+/// Records durations of build steps, at most one step is in progress.
+#[derive(Debug, Default)]
+pub struct Steps {
+    finished: RefCell<Vec<StepReport>>,
+    current: RefCell<Option<(String, Instant)>>,
+}
+
+impl Steps {
+    /// Start a new step finishing the current one.
+    pub fn begin(&self, name: &str) {
+        self.end();
+        *self.current.borrow_mut() = Some((name.to_string(), Instant::now()));
+    }
+
+    /// Finish the current step.
+    pub fn end(&self) {
+        self.finish(false);
+    }
+
+    /// Finish the current step as failed.
+    pub fn fail(&self) {
+        self.finish(true);
+    }
+
+    fn finish(&self, failed: bool) {
+        if let Some((name, start)) = self.current.borrow_mut().take() {
+            self.finished.borrow_mut().push(StepReport {
+                name,
+                duration_ms: start.elapsed().as_millis() as u64,
+                failed,
+            });
+        }
+    }
+
+    /// Finished steps in order.
+    pub fn reports(&self) -> Vec<StepReport> {
+        self.finished.borrow().clone()
+    }
+}
+
+impl BuildReport {
+    pub fn new() -> Self {
+        Self {
+            gvltctl_version: env!("CARGO_PKG_VERSION").to_string(),
+            ..Default::default()
+        }
+    }
+
+    pub fn write(&self, path: &Path) -> Result<()> {
+        fs::write(path, serde_json::to_string_pretty(self)?)
+            .context(format!("Failed to write build report {}", path.display()))
+    }
+}
+
+/// SHA-256 checksum of the file as lowercase hex.
+pub fn sha256(path: &Path) -> Result<String> {
+    let output =
+        SkopeoSyslinuxBuilder::run_command_output(&["sha256sum", path.to_str().unwrap()], false)
+            .context(format!(
+                "Failed to calculate checksum of {}",
+                path.display()
+            ))?;
+    Ok(output
+        .split_whitespace()
+        .next()
+        .context("Empty sha256sum output")?
+        .to_string())
+}
+
+/// CIDv1 with `raw` codec and SHA-256 multihash in base32 multibase.
+fn cid_from_sha256(sha256: &str) -> Result<String> {
+    if sha256.len() != 64 {
+        anyhow::bail!("Invalid checksum");
+    }
+    // Version 1, raw codec, sha2-256, 32 bytes of digest.
+    let mut bytes = vec![0x01, 0x55, 0x12, 0x20];
+    for i in (0..sha256.len()).step_by(2) {
+        bytes.push(
+            u8::from_str_radix(sha256.get(i..i + 2).context("Invalid checksum")?, 16)
+                .context("Invalid checksum")?,
+        );
+    }
+    Ok(format!("b{}", base32(&bytes)))
+}
+
+/// RFC 4648 base32 in lowercase without padding.
+fn base32(bytes: &[u8]) -> String {
+    const ALPHABET: &[u8; 32] = b"abcdefghijklmnopqrstuvwxyz234567";
+    let mut result = String::new();
+    let mut buffer = 0u32;
+    let mut bits = 0;
+    for byte in bytes {
+        buffer = (buffer << 8) | *byte as u32;
+        bits += 8;
+        while bits >= 5 {
+            bits -= 5;
+            result.push(ALPHABET[((buffer >> bits) & 0x1f) as usize] as char);
+        }
+    }
+    if bits > 0 {
+        result.push(ALPHABET[((buffer << (5 - bits)) & 0x1f) as usize] as char);
+    }
+    result
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn base32_test_vectors() {
+        // RFC 4648, section 10.
+        assert_eq!(base32(b""), "");
+        assert_eq!(base32(b"f"), "my");
+        assert_eq!(base32(b"fo"), "mzxq");
+        assert_eq!(base32(b"foo"), "mzxw6");
+        assert_eq!(base32(b"foob"), "mzxw6yq");
+        assert_eq!(base32(b"fooba"), "mzxw6ytb");
+        assert_eq!(base32(b"foobar"), "mzxw6ytboi");
+    }
+
+    #[test]
+    fn cid_of_empty_file() {
+        assert_eq!(
+            cid_from_sha256("e3b0c44298fc1c149afbf4c8996fb92427ae41e4649b934ca495991b7852b855")
+                .unwrap(),
+            "bafkreihdwdcefgh4dqkjv67uzcmw7ojee6xedzdetojuzjevtenxquvyku"
+        );
+    }
+
+    #[test]
+    fn cid_rejects_invalid_checksums() {
+        assert!(cid_from_sha256("").is_err());
+        assert!(cid_from_sha256("e3b0c442").is_err());
+        assert!(cid_from_sha256(&"zz".repeat(32)).is_err());
+        assert!(cid_from_sha256(&"e".repeat(63)).is_err());
+    }
+}
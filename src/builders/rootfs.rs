@@ -0,0 +1,425 @@
+//! Copy of the root filesystem directory given with `--rootfs-dir` into the image.
+//!
+//! The copy runs as root in the hidden `gvltctl copy-rootfs` command, which the build executes
+//! with sudo. It includes hidden files and keeps modes, owners, symlinks, hardlinks, device
+//! nodes, extended attributes and modification times.
+
+use std::collections::HashMap;
+use std::ffi::{CStr, CString};
+use std::fs;
+use std::io;
+use std::os::unix::ffi::OsStrExt;
+use std::os::unix::fs::{MetadataExt, PermissionsExt};
+use std::path::{Path, PathBuf};
+
+use anyhow::{Context, Result};
+use serde::{Deserialize, Serialize};
+
+/// Owner mapping `FROM:TO` given with `--owner-map`, applied to both user and group IDs.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct OwnerMap {
+    /// `None` (`*`) matches any ID.
+    pub from: Option<u32>,
+    pub to: u32,
+}
+
+impl OwnerMap {
+    /// Map `id` with the first matching mapping.
+    fn apply(maps: &[OwnerMap], id: u32) -> u32 {
+        maps.iter()
+            .find(|map| map.from.is_none() || map.from == Some(id))
+            .map_or(id, |map| map.to)
+    }
+}
+
+impl std::str::FromStr for OwnerMap {
+    type Err = &'static str;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        const ERROR: &str = "invalid owner map: expected FROM:TO, e.g. 1000:0 or *:0";
+        let (from, to) = s.split_once(':').ok_or(ERROR)?;
+        Ok(Self {
+            from: match from {
+                "*" => None,
+                from => Some(from.parse().map_err(|_| ERROR)?),
+            },
+            to: to.parse().map_err(|_| ERROR)?,
+        })
+    }
+}
+
+impl std::fmt::Display for OwnerMap {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self.from {
+            Some(from) => write!(f, "{}:{}", from, self.to),
+            None => write!(f, "*:{}", self.to),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct CopyOptions {
+    /// Glob patterns of excluded paths, see [`is_excluded`].
+    pub excludes: Vec<String>,
+    pub owner_map: Vec<OwnerMap>,
+}
+
+/// Summary of the copy printed by the build.
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct CopyStats {
+    pub files: u64,
+    pub directories: u64,
+    pub symlinks: u64,
+    pub hardlinks: u64,
+    /// Device nodes, FIFOs and sockets.
+    pub special_files: u64,
+    pub excluded: u64,
+    /// Content of regular files in bytes.
+    pub bytes: u64,
+}
+
+/// Copy content of `source` directory into `target` directory, which gets the attributes
+/// of `source`. Existing files in `target` are replaced.
+pub fn copy_tree(source: &Path, target: &Path, options: &CopyOptions) -> Result<CopyStats> {
+    let metadata =
+        fs::symlink_metadata(source).context(format!("Failed to read {}", source.display()))?;
+    if !metadata.is_dir() {
+        anyhow::bail!("{} is not a directory", source.display());
+    }
+    fs::create_dir_all(target).context(format!("Failed to create {}", target.display()))?;
+
+    let mut copier = Copier {
+        options,
+        stats: CopyStats::default(),
+        links: HashMap::new(),
+    };
+    copier.copy_dir_contents(source, target, Path::new(""))?;
+    copier.apply_metadata(source, target, &metadata)?;
+    Ok(copier.stats)
+}
+
+/// Path `relative` to the rootfs directory matches any of `excludes`.
+///
+/// Pattern without `/` matches the file name at any depth, e.g. `*.pyc`. Other patterns match
+/// the whole relative path, e.g. `/var/cache/**` or `usr/share/doc`. `*` and `?` don't match
+/// `/`, `**` matches any number of path components. Content of excluded directories is skipped.
+pub fn is_excluded(excludes: &[String], relative: &Path) -> bool {
+    excludes.iter().any(|pattern| {
+        if pattern.contains('/') {
+            glob_match(
+                pattern.trim_start_matches('/').as_bytes(),
+                relative.as_os_str().as_bytes(),
+            )
+        } else {
+            relative
+                .file_name()
+                .is_some_and(|name| glob_match(pattern.as_bytes(), name.as_bytes()))
+        }
+    })
+}
+
+fn glob_match(pattern: &[u8], text: &[u8]) -> bool {
+    match pattern {
+        [] => text.is_empty(),
+        [b'*', b'*', rest @ ..] => {
+            (0..=text.len()).any(|i| glob_match(rest, &text[i..]))
+                // `**/` also matches no directory at all.
+                || rest.strip_prefix(b"/").is_some_and(|rest| glob_match(rest, text))
+        }
+        [b'*', rest @ ..] => (0..=text.len())
+            .take_while(|&i| i == 0 || text[i - 1] != b'/')
+            .any(|i| glob_match(rest, &text[i..])),
+        [b'?', rest @ ..] => {
+            matches!(text, [c, tail @ ..] if *c != b'/' && glob_match(rest, tail))
+        }
+        [c, rest @ ..] => matches!(text, [t, tail @ ..] if t == c && glob_match(rest, tail)),
+    }
+}
+
+struct Copier<'a> {
+    options: &'a CopyOptions,
+    stats: CopyStats,
+    /// Copies of files with several links by device and inode of the source.
+    links: HashMap<(u64, u64), PathBuf>,
+}
+
+impl Copier<'_> {
+    fn copy_dir_contents(&mut self, source: &Path, target: &Path, relative: &Path) -> Result<()> {
+        // Stable order keeps the first copy of hardlinked files the same between builds.
+        let mut entries = fs::read_dir(source)
+            .and_then(|entries| entries.collect::<io::Result<Vec<_>>>())
+            .context(format!("Failed to read directory {}", source.display()))?;
+        entries.sort_by_key(|entry| entry.file_name());
+
+        for entry in entries {
+            let relative = relative.join(entry.file_name());
+            if is_excluded(&self.options.excludes, &relative) {
+                log::debug!("excluded {}", relative.display());
+                self.stats.excluded += 1;
+                continue;
+            }
+            self.copy_entry(&entry.path(), &target.join(entry.file_name()), &relative)?;
+        }
+        Ok(())
+    }
+
+    fn copy_entry(&mut self, source: &Path, target: &Path, relative: &Path) -> Result<()> {
+        let metadata =
+            fs::symlink_metadata(source).context(format!("Failed to read {}", source.display()))?;
+        let file_type = metadata.file_type();
+
+        if file_type.is_dir() {
+            match fs::create_dir(target) {
+                Err(e) if e.kind() == io::ErrorKind::AlreadyExists && target.is_dir() => {}
+                result => result.context(format!("Failed to create {}", target.display()))?,
+            }
+            self.copy_dir_contents(source, target, relative)?;
+            self.stats.directories += 1;
+            // Directory is modified by its content, so its attributes are applied last.
+            return self.apply_metadata(source, target, &metadata);
+        }
+
+        remove_existing(target)?;
+        if metadata.nlink() > 1 {
+            let key = (metadata.dev(), metadata.ino());
+            if let Some(first) = self.links.get(&key) {
+                fs::hard_link(first, target)
+                    .context(format!("Failed to create hardlink {}", target.display()))?;
+                self.stats.hardlinks += 1;
+                return Ok(());
+            }
+            self.links.insert(key, target.to_path_buf());
+        }
+
+        if file_type.is_file() {
+            let mut reader =
+                fs::File::open(source).context(format!("Failed to open {}", source.display()))?;
+            let mut writer = fs::File::create(target)
+                .context(format!("Failed to create {}", target.display()))?;
+            self.stats.bytes += io::copy(&mut reader, &mut writer)
+                .context(format!("Failed to copy {}", source.display()))?;
+            self.stats.files += 1;
+        } else if file_type.is_symlink() {
+            let link = fs::read_link(source)
+                .context(format!("Failed to read symlink {}", source.display()))?;
+            std::os::unix::fs::symlink(link, target)
+                .context(format!("Failed to create symlink {}", target.display()))?;
+            self.stats.symlinks += 1;
+        } else {
+            // Device nodes, FIFOs and sockets.
+            let path = c_path(target)?;
+            // SAFETY: `path` is a NUL-terminated string alive for the duration of the call.
+            if unsafe { libc::mknod(path.as_ptr(), metadata.mode(), metadata.rdev()) } != 0 {
+                return Err(io::Error::last_os_error())
+                    .context(format!("Failed to create {}", target.display()));
+            }
+            self.stats.special_files += 1;
+        }
+        self.apply_metadata(source, target, &metadata)
+    }
+
+    /// Set owner, mode, extended attributes and times of `target` from `source`.
+    fn apply_metadata(&self, source: &Path, target: &Path, metadata: &fs::Metadata) -> Result<()> {
+        let target_path = c_path(target)?;
+        let uid = OwnerMap::apply(&self.options.owner_map, metadata.uid());
+        let gid = OwnerMap::apply(&self.options.owner_map, metadata.gid());
+
+        // Changing owner clears setuid and setgid bits, so mode goes after it.
+        // SAFETY: `target_path` is a NUL-terminated string alive for the duration of the call.
+        if unsafe { libc::lchown(target_path.as_ptr(), uid, gid) } != 0 {
+            return Err(io::Error::last_os_error())
+                .context(format!("Failed to change owner of {}", target.display()));
+        }
+        if !metadata.file_type().is_symlink() {
+            fs::set_permissions(target, fs::Permissions::from_mode(metadata.mode() & 0o7777))
+                .context(format!("Failed to change mode of {}", target.display()))?;
+        }
+
+        copy_xattrs(&c_path(source)?, &target_path).context(format!(
+            "Failed to copy extended attributes of {}",
+            source.display()
+        ))?;
+
+        let times = [
+            libc::timespec {
+                tv_sec: metadata.atime() as libc::time_t,
+                tv_nsec: metadata.atime_nsec() as _,
+            },
+            libc::timespec {
+                tv_sec: metadata.mtime() as libc::time_t,
+                tv_nsec: metadata.mtime_nsec() as _,
+            },
+        ];
+        // SAFETY: `target_path` is a NUL-terminated string and `times` holds the two
+        // timespecs `utimensat` reads, both alive for the duration of the call.
+        let result = unsafe {
+            libc::utimensat(
+                libc::AT_FDCWD,
+                target_path.as_ptr(),
+                times.as_ptr(),
+                libc::AT_SYMLINK_NOFOLLOW,
+            )
+        };
+        if result != 0 {
+            return Err(io::Error::last_os_error())
+                .context(format!("Failed to set times of {}", target.display()));
+        }
+        Ok(())
+    }
+}
+
+/// Remove file or directory at `path` if there is one.
+fn remove_existing(path: &Path) -> Result<()> {
+    let result = match fs::symlink_metadata(path) {
+        Ok(metadata) if metadata.is_dir() => fs::remove_dir_all(path),
+        Ok(_) => fs::remove_file(path),
+        Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(()),
+        Err(e) => Err(e),
+    };
+    result.context(format!("Failed to remove existing {}", path.display()))
+}
+
+fn c_path(path: &Path) -> Result<CString> {
+    CString::new(path.as_os_str().as_bytes()).context(format!("Invalid path {}", path.display()))
+}
+
+fn copy_xattrs(source: &CStr, target: &CStr) -> io::Result<()> {
+    for name in list_xattrs(source)? {
+        let value = get_xattr(source, &name)?;
+        // SAFETY: `target` and `name` are NUL-terminated strings and `value` is valid for
+        // reads of `value.len()` bytes, all alive for the duration of the call.
+        let result = unsafe {
+            libc::lsetxattr(
+                target.as_ptr(),
+                name.as_ptr(),
+                value.as_ptr() as *const libc::c_void,
+                value.len(),
+                0,
+            )
+        };
+        if result != 0 {
+            let err = io::Error::last_os_error();
+            if err.raw_os_error() != Some(libc::EOPNOTSUPP) {
+                return Err(err);
+            }
+            log::warn!(
+                "extended attribute {} is not supported by the image filesystem",
+                name.to_string_lossy()
+            );
+        }
+    }
+    Ok(())
+}
+
+fn list_xattrs(path: &CStr) -> io::Result<Vec<CString>> {
+    // SAFETY: `path` is a NUL-terminated string and `read_xattr_buffer` passes either a null
+    // buffer with zero size or a buffer valid for writes of `size` bytes.
+    let names = read_xattr_buffer(|buffer, size| unsafe {
+        libc::llistxattr(path.as_ptr(), buffer as *mut libc::c_char, size)
+    });
+    let names = match names {
+        Err(e) if e.raw_os_error() == Some(libc::EOPNOTSUPP) => return Ok(Vec::new()),
+        names => names?,
+    };
+    names
+        .split(|byte| *byte == 0)
+        .filter(|name| !name.is_empty())
+        .map(|name| CString::new(name).map_err(io::Error::from))
+        .collect()
+}
+
+fn get_xattr(path: &CStr, name: &CStr) -> io::Result<Vec<u8>> {
+    // SAFETY: `path` and `name` are NUL-terminated strings and `read_xattr_buffer` passes
+    // either a null buffer with zero size or a buffer valid for writes of `size` bytes.
+    read_xattr_buffer(|buffer, size| unsafe {
+        libc::lgetxattr(path.as_ptr(), name.as_ptr(), buffer, size)
+    })
+}
+
+/// Call `read` first to get the size and then to fill the buffer.
+/// Attributes may change in between, so it is retried on `ERANGE`.
+fn read_xattr_buffer(read: impl Fn(*mut libc::c_void, usize) -> isize) -> io::Result<Vec<u8>> {
+    loop {
+        let size = read(std::ptr::null_mut(), 0);
+        if size < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        let mut buffer = vec![0u8; size as usize];
+        if buffer.is_empty() {
+            return Ok(buffer);
+        }
+        let size = read(buffer.as_mut_ptr() as *mut libc::c_void, buffer.len());
+        if size < 0 {
+            let err = io::Error::last_os_error();
+            if err.raw_os_error() == Some(libc::ERANGE) {
+                continue;
+            }
+            return Err(err);
+        }
+        buffer.truncate(size as usize);
+        return Ok(buffer);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn excluded(pattern: &str, relative: &str) -> bool {
+        is_excluded(&[pattern.to_string()], Path::new(relative))
+    }
+
+    #[test]
+    fn star_does_not_match_slash() {
+        assert!(glob_match(b"usr/*/doc", b"usr/share/doc"));
+        assert!(!glob_match(b"usr/*/doc", b"usr/local/share/doc"));
+        assert!(!glob_match(b"usr/*", b"usr/share/doc"));
+        assert!(glob_match(b"usr/*", b"usr/"));
+        assert!(glob_match(b"*.pyc", b"module.pyc"));
+        assert!(!glob_match(b"*.pyc", b"lib/module.pyc"));
+    }
+
+    #[test]
+    fn question_mark_matches_one_character_except_slash() {
+        assert!(glob_match(b"lib?4", b"lib64"));
+        assert!(!glob_match(b"lib?4", b"lib4"));
+        assert!(!glob_match(b"usr?lib", b"usr/lib"));
+    }
+
+    #[test]
+    fn double_star_matches_any_number_of_directories() {
+        assert!(glob_match(b"var/cache/**", b"var/cache/apt/archives/x.deb"));
+        assert!(glob_match(b"var/cache/**", b"var/cache/"));
+        // `**/` also matches zero directories.
+        assert!(glob_match(b"**/__pycache__", b"__pycache__"));
+        assert!(glob_match(
+            b"**/__pycache__",
+            b"usr/lib/python3/__pycache__"
+        ));
+        assert!(glob_match(b"usr/**/doc", b"usr/doc"));
+        assert!(glob_match(b"usr/**/doc", b"usr/share/local/doc"));
+        assert!(!glob_match(b"usr/**/doc", b"usr/share/docs"));
+    }
+
+    #[test]
+    fn pattern_without_slash_matches_basename() {
+        assert!(excluded("*.pyc", "module.pyc"));
+        assert!(excluded("*.pyc", "usr/lib/python3/module.pyc"));
+        assert!(excluded("doc", "usr/share/doc"));
+        assert!(!excluded("doc", "usr/share/doc/readme"));
+    }
+
+    #[test]
+    fn pattern_with_slash_matches_whole_path() {
+        assert!(excluded("usr/share/doc", "usr/share/doc"));
+        assert!(excluded("/usr/share/doc", "usr/share/doc"));
+        assert!(!excluded("share/doc", "usr/share/doc"));
+        assert!(excluded("/var/cache/**", "var/cache/apt"));
+        assert!(!excluded("/var/cache/**", "var/lib/cache"));
+    }
+
+    #[test]
+    fn no_patterns_exclude_nothing() {
+        assert!(!is_excluded(&[], Path::new("usr/share/doc")));
+    }
+}
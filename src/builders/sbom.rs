@@ -0,0 +1,671 @@
+//! Software bill of materials of the built image.
+//!
+//! Packages are collected from the assembled root filesystem: system package databases
+//! (dpkg, apk, rpm), installed Python and Node.js packages and their lockfiles.
+//! Kernel and MIA are added as separate components.
+
+use std::collections::BTreeMap;
+use std::fs;
+use std::path::Path;
+
+use anyhow::{Context, Result};
+use log::{debug, warn};
+
+use crate::builders::random;
+use crate::builders::skopeo_builder::SkopeoSyslinuxBuilder;
+use crate::builders::SbomFormat;
+
+/// Top-level directories of the root filesystem never scanned for packages.
+const SKIPPED_DIRS: [&str; 4] = ["proc", "sys", "dev", "boot"];
+
+/// Role of the component in the image.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ComponentKind {
+    OperatingSystem,
+    Application,
+    Library,
+}
+
+/// Software component found in the image.
+#[derive(Debug, Clone)]
+pub struct Package {
+    pub name: String,
+    pub version: String,
+    /// Package URL identifying the package in its ecosystem.
+    pub purl: String,
+    pub kind: ComponentKind,
+}
+
+impl Package {
+    fn library(purl_type: &str, name: &str, version: &str, qualifiers: &str) -> Self {
+        Self {
+            name: name.to_string(),
+            version: version.to_string(),
+            purl: format!(
+                "pkg:{}/{}@{}{}",
+                purl_type,
+                name.split('/')
+                    .map(purl_encode)
+                    .collect::<Vec<_>>()
+                    .join("/"),
+                purl_encode(version),
+                qualifiers
+            ),
+            kind: ComponentKind::Library,
+        }
+    }
+
+    /// Linux kernel of given version.
+    pub fn kernel(version: &str) -> Self {
+        Self {
+            name: "linux".to_string(),
+            version: version.to_string(),
+            purl: format!("pkg:generic/linux@{}", purl_encode(version)),
+            kind: ComponentKind::OperatingSystem,
+        }
+    }
+
+    /// MIA init of given version.
+    pub fn mia(version: &str) -> Self {
+        Self {
+            name: "mia".to_string(),
+            version: version.to_string(),
+            purl: format!("pkg:github/gevulotnetwork/mia@{}", purl_encode(version)),
+            kind: ComponentKind::Application,
+        }
+    }
+}
+
+/// Collect packages installed in the root filesystem at `root`.
+pub fn scan_rootfs(root: &Path) -> Vec<Package> {
+    let distro = os_release_id(root);
+    let mut packages = Vec::new();
+    packages.extend(scan_dpkg(root, distro.as_deref().unwrap_or("debian")));
+    packages.extend(scan_apk(root, distro.as_deref().unwrap_or("alpine")));
+    packages.extend(scan_rpm(root, distro.as_deref().unwrap_or("redhat")));
+    scan_dir(root, root, &mut packages);
+    packages.sort_by(|a, b| a.purl.cmp(&b.purl));
+    packages.dedup_by(|a, b| a.purl == b.purl);
+    packages
+}
+
+/// Write SBOM in given format describing image `name` with `packages`.
+///
+/// Reproducible builds pass `SOURCE_DATE_EPOCH` and sha256 digest of the image
+/// in `reproducible`. Creation time and document UUID are then derived from them
+/// instead of the current time and random UUID.
+pub fn write(
+    target: &Path,
+    format: SbomFormat,
+    name: &str,
+    packages: &[Package],
+    reproducible: Option<(u64, &str)>,
+) -> Result<()> {
+    let mut date = vec!["date", "-u", "+%Y-%m-%dT%H:%M:%SZ"];
+    let date_arg;
+    if let Some((source_date_epoch, _)) = reproducible {
+        date_arg = format!("--date=@{}", source_date_epoch);
+        date.push(&date_arg);
+    }
+    let created = SkopeoSyslinuxBuilder::run_command_output(&date, false)
+        .context("Failed to get SBOM creation time")?
+        .trim()
+        .to_string();
+    let uuid = match reproducible {
+        Some((_, image_digest)) => digest_uuid(image_digest)?,
+        None => format_uuid(random_uuid()?),
+    };
+    let document = match format {
+        SbomFormat::Spdx => spdx(name, packages, &created, &uuid),
+        SbomFormat::CycloneDx => cyclonedx(name, packages, &created, &uuid),
+    };
+    fs::write(target, serde_json::to_string_pretty(&document)?)
+        .context(format!("Failed to write SBOM {}", target.display()))
+}
+
+fn spdx(name: &str, packages: &[Package], created: &str, uuid: &str) -> serde_json::Value {
+    let image_id = "SPDXRef-Image";
+    let mut spdx_packages = vec![serde_json::json!({
+        "name": name,
+        "SPDXID": image_id,
+        "downloadLocation": "NOASSERTION",
+        "filesAnalyzed": false,
+        "primaryPackagePurpose": "OPERATING-SYSTEM",
+    })];
+    let mut relationships = vec![serde_json::json!({
+        "spdxElementId": "SPDXRef-DOCUMENT",
+        "relationshipType": "DESCRIBES",
+        "relatedSpdxElement": image_id,
+    })];
+    for (index, package) in packages.iter().enumerate() {
+        let id = format!("SPDXRef-Package-{}", index + 1);
+        spdx_packages.push(serde_json::json!({
+            "name": package.name,
+            "SPDXID": id,
+            "versionInfo": package.version,
+            "downloadLocation": "NOASSERTION",
+            "filesAnalyzed": false,
+            "primaryPackagePurpose": match package.kind {
+                ComponentKind::OperatingSystem => "OPERATING-SYSTEM",
+                ComponentKind::Application => "APPLICATION",
+                ComponentKind::Library => "LIBRARY",
+            },
+            "externalRefs": [{
+                "referenceCategory": "PACKAGE-MANAGER",
+                "referenceType": "purl",
+                "referenceLocator": package.purl,
+            }],
+        }));
+        relationships.push(serde_json::json!({
+            "spdxElementId": image_id,
+            "relationshipType": "CONTAINS",
+            "relatedSpdxElement": id,
+        }));
+    }
+    serde_json::json!({
+        "spdxVersion": "SPDX-2.3",
+        "dataLicense": "CC0-1.0",
+        "SPDXID": "SPDXRef-DOCUMENT",
+        "name": name,
+        "documentNamespace": format!("https://gevulot.com/spdx/{}-{}", name, uuid),
+        "creationInfo": {
+            "created": created,
+            "creators": [format!("Tool: gvltctl-{}", env!("CARGO_PKG_VERSION"))],
+        },
+        "packages": spdx_packages,
+        "relationships": relationships,
+    })
+}
+
+fn cyclonedx(name: &str, packages: &[Package], created: &str, uuid: &str) -> serde_json::Value {
+    let components = packages
+        .iter()
+        .map(|package| {
+            serde_json::json!({
+                "type": match package.kind {
+                    ComponentKind::OperatingSystem => "operating-system",
+                    ComponentKind::Application => "application",
+                    ComponentKind::Library => "library",
+                },
+                "bom-ref": package.purl,
+                "name": package.name,
+                "version": package.version,
+                "purl": package.purl,
+            })
+        })
+        .collect::<Vec<_>>();
+    serde_json::json!({
+        "bomFormat": "CycloneDX",
+        "specVersion": "1.5",
+        "serialNumber": format!("urn:uuid:{}", uuid),
+        "version": 1,
+        "metadata": {
+            "timestamp": created,
+            "tools": {
+                "components": [{
+                    "type": "application",
+                    "name": "gvltctl",
+                    "version": env!("CARGO_PKG_VERSION"),
+                }],
+            },
+            "component": {
+                "type": "operating-system",
+                "bom-ref": "image",
+                "name": name,
+            },
+        },
+        "components": components,
+        "dependencies": [{
+            "ref": "image",
+            "dependsOn": packages.iter().map(|package| &package.purl).collect::<Vec<_>>(),
+        }],
+    })
+}
+
+/// Distribution ID from `/etc/os-release`, used as purl namespace of system packages.
+fn os_release_id(root: &Path) -> Option<String> {
+    // Symbolic links may be absolute and point to the host file.
+    ["etc/os-release", "usr/lib/os-release"]
+        .iter()
+        .map(|path| root.join(path))
+        .filter(|path| fs::symlink_metadata(path).is_ok_and(|metadata| metadata.is_file()))
+        .find_map(|path| fs::read_to_string(path).ok())?
+        .lines()
+        .find_map(|line| line.strip_prefix("ID="))
+        .map(|id| id.trim_matches('"').to_string())
+}
+
+/// Paragraphs of `Key: value` records separated by empty lines.
+fn paragraphs(content: &str) -> Vec<BTreeMap<String, String>> {
+    content
+        .split("\n\n")
+        .map(|paragraph| {
+            paragraph
+                .lines()
+                .filter_map(|line| line.split_once(':'))
+                .map(|(key, value)| (key.to_string(), value.trim().to_string()))
+                .collect()
+        })
+        .filter(|fields: &BTreeMap<_, _>| !fields.is_empty())
+        .collect()
+}
+
+fn scan_dpkg(root: &Path, distro: &str) -> Vec<Package> {
+    let mut status = fs::read_to_string(root.join("var/lib/dpkg/status")).unwrap_or_default();
+    // Distroless images have a status file per package instead.
+    if let Ok(entries) = fs::read_dir(root.join("var/lib/dpkg/status.d")) {
+        for entry in entries.flatten() {
+            if let Ok(content) = fs::read_to_string(entry.path()) {
+                status.push_str("\n\n");
+                status.push_str(&content);
+            }
+        }
+    }
+    paragraphs(&status)
+        .into_iter()
+        .filter(|fields| {
+            // Files in status.d have no Status field, they are always installed.
+            match fields.get("Status") {
+                Some(status) => status.ends_with(" installed"),
+                None => true,
+            }
+        })
+        .filter_map(|fields| {
+            let name = fields.get("Package")?;
+            let version = fields.get("Version")?;
+            let qualifiers = fields
+                .get("Architecture")
+                .map(|arch| format!("?arch={}", arch))
+                .unwrap_or_default();
+            Some(Package::library(
+                &format!("deb/{}", distro),
+                name,
+                version,
+                &qualifiers,
+            ))
+        })
+        .collect()
+}
+
+fn scan_apk(root: &Path, distro: &str) -> Vec<Package> {
+    let Ok(installed) = fs::read_to_string(root.join("lib/apk/db/installed")) else {
+        return Vec::new();
+    };
+    paragraphs(&installed)
+        .into_iter()
+        .filter_map(|fields| {
+            let qualifiers = fields
+                .get("A")
+                .map(|arch| format!("?arch={}", arch))
+                .unwrap_or_default();
+            Some(Package::library(
+                &format!("apk/{}", distro),
+                fields.get("P")?,
+                fields.get("V")?,
+                &qualifiers,
+            ))
+        })
+        .collect()
+}
+
+fn scan_rpm(root: &Path, distro: &str) -> Vec<Package> {
+    if !["var/lib/rpm", "usr/lib/sysimage/rpm"]
+        .iter()
+        .any(|path| root.join(path).exists())
+    {
+        return Vec::new();
+    }
+    // RPM database format depends on the distribution, so it is read with rpm on the host.
+    let output = root
+        .to_str()
+        .context("Invalid rootfs path")
+        .and_then(|root| {
+            SkopeoSyslinuxBuilder::run_command_output(
+                &[
+                    "rpm",
+                    "--root",
+                    root,
+                    "-qa",
+                    "--qf",
+                    "%{NAME}\\t%{VERSION}-%{RELEASE}\\t%{ARCH}\\n",
+                ],
+                true,
+            )
+        });
+    let output = match output {
+        Ok(output) => output,
+        Err(e) => {
+            warn!("RPM packages are not included in SBOM: {:#}", e);
+            return Vec::new();
+        }
+    };
+    output
+        .lines()
+        .filter_map(|line| {
+            let mut fields = line.split('\t');
+            let name = fields.next()?;
+            let version = fields.next()?;
+            let qualifiers = fields
+                .next()
+                .filter(|arch| *arch != "(none)")
+                .map(|arch| format!("?arch={}", arch))
+                .unwrap_or_default();
+            // GPG public keys are stored in the database as packages.
+            (name != "gpg-pubkey")
+                .then(|| Package::library(&format!("rpm/{}", distro), name, version, &qualifiers))
+        })
+        .collect()
+}
+
+/// Walk `dir` looking for installed Python and Node.js packages and lockfiles.
+/// Symbolic links are not followed and unreadable directories are skipped.
+fn scan_dir(root: &Path, dir: &Path, packages: &mut Vec<Package>) {
+    let Ok(entries) = fs::read_dir(dir) else {
+        debug!("skipping unreadable directory {}", dir.display());
+        return;
+    };
+    for entry in entries.flatten() {
+        let path = entry.path();
+        let Ok(file_type) = entry.file_type() else {
+            continue;
+        };
+        let file_name = entry.file_name().to_string_lossy().to_string();
+        if file_type.is_dir() {
+            if dir == root && SKIPPED_DIRS.contains(&file_name.as_str()) {
+                continue;
+            }
+            scan_dir(root, &path, packages);
+        } else if file_type.is_file() {
+            let parent = dir
+                .file_name()
+                .map(|name| name.to_string_lossy().to_string())
+                .unwrap_or_default();
+            match file_name.as_str() {
+                "METADATA" if parent.ends_with(".dist-info") => {
+                    packages.extend(python_metadata(&path))
+                }
+                "PKG-INFO" if parent.ends_with(".egg-info") => {
+                    packages.extend(python_metadata(&path))
+                }
+                "requirements.txt" => packages.extend(python_requirements(&path)),
+                "poetry.lock" => packages.extend(poetry_lock(&path)),
+                "Pipfile.lock" => packages.extend(pipfile_lock(&path)),
+                "package-lock.json" => packages.extend(npm_lock(&path)),
+                "package.json" if is_installed_node_package(dir) => {
+                    packages.extend(node_package(&path))
+                }
+                _ => {}
+            }
+        }
+    }
+}
+
+/// Python package name normalized as required by purl `pypi` type.
+fn pypi_package(name: &str, version: &str) -> Package {
+    Package::library("pypi", &name.to_lowercase().replace('_', "-"), version, "")
+}
+
+fn python_metadata(path: &Path) -> Option<Package> {
+    let content = fs::read_to_string(path).ok()?;
+    // Headers end at the first empty line, the description follows.
+    let headers = content.split("\n\n").next()?;
+    let field = |key: &str| {
+        headers
+            .lines()
+            .find_map(|line| line.strip_prefix(key))
+            .map(str::trim)
+    };
+    Some(pypi_package(field("Name:")?, field("Version:")?))
+}
+
+/// Pinned requirements (`name==version`) from `requirements.txt`.
+fn python_requirements(path: &Path) -> Vec<Package> {
+    let Ok(content) = fs::read_to_string(path) else {
+        return Vec::new();
+    };
+    content
+        .lines()
+        .filter_map(|line| {
+            let line = line.split('#').next()?.split(';').next()?.trim();
+            let (name, version) = line.split_once("==")?;
+            let name = name.split('[').next()?.trim();
+            let version = version.split_whitespace().next()?;
+            Some(pypi_package(name, version))
+        })
+        .collect()
+}
+
+fn poetry_lock(path: &Path) -> Vec<Package> {
+    let lock = fs::read_to_string(path)
+        .ok()
+        .and_then(|content| content.parse::<toml::Table>().ok());
+    let Some(toml::Value::Array(lock_packages)) = lock.and_then(|mut lock| lock.remove("package"))
+    else {
+        return Vec::new();
+    };
+    lock_packages
+        .iter()
+        .filter_map(|package| {
+            Some(pypi_package(
+                package.get("name")?.as_str()?,
+                package.get("version")?.as_str()?,
+            ))
+        })
+        .collect()
+}
+
+fn pipfile_lock(path: &Path) -> Vec<Package> {
+    let Some(lock) = read_json(path) else {
+        return Vec::new();
+    };
+    ["default", "develop"]
+        .iter()
+        .filter_map(|section| lock[section].as_object())
+        .flatten()
+        .filter_map(|(name, package)| {
+            let version = package["version"].as_str()?.trim_start_matches("==");
+            Some(pypi_package(name, version))
+        })
+        .collect()
+}
+
+fn npm_lock(path: &Path) -> Vec<Package> {
+    let Some(lock) = read_json(path) else {
+        return Vec::new();
+    };
+    let mut packages = Vec::new();
+    // Lockfile v2 and v3 list packages by their path in `node_modules`.
+    if let Some(lock_packages) = lock["packages"].as_object() {
+        for (location, package) in lock_packages {
+            let Some((_, name)) = location.rsplit_once("node_modules/") else {
+                continue;
+            };
+            if let Some(version) = package["version"].as_str() {
+                packages.push(npm_package(name, version));
+            }
+        }
+    } else if let Some(dependencies) = lock["dependencies"].as_object() {
+        npm_lock_v1_dependencies(dependencies, &mut packages);
+    }
+    packages
+}
+
+fn npm_lock_v1_dependencies(
+    dependencies: &serde_json::Map<String, serde_json::Value>,
+    packages: &mut Vec<Package>,
+) {
+    for (name, package) in dependencies {
+        if let Some(version) = package["version"].as_str() {
+            packages.push(npm_package(name, version));
+        }
+        if let Some(nested) = package["dependencies"].as_object() {
+            npm_lock_v1_dependencies(nested, packages);
+        }
+    }
+}
+
+/// Directory is `node_modules/<name>` or `node_modules/@<scope>/<name>`.
+fn is_installed_node_package(dir: &Path) -> bool {
+    let parent = dir.parent();
+    let is_node_modules = |dir: Option<&Path>| {
+        dir.and_then(Path::file_name)
+            .is_some_and(|name| name == "node_modules")
+    };
+    is_node_modules(parent)
+        || (parent
+            .and_then(Path::file_name)
+            .is_some_and(|name| name.to_string_lossy().starts_with('@'))
+            && is_node_modules(parent.and_then(Path::parent)))
+}
+
+fn node_package(path: &Path) -> Option<Package> {
+    let package = read_json(path)?;
+    Some(npm_package(
+        package["name"].as_str()?,
+        package["version"].as_str()?,
+    ))
+}
+
+fn npm_package(name: &str, version: &str) -> Package {
+    Package::library("npm", name, version, "")
+}
+
+fn read_json(path: &Path) -> Option<serde_json::Value> {
+    serde_json::from_str(&fs::read_to_string(path).ok()?).ok()
+}
+
+/// Percent-encode purl component, keeping characters allowed by the purl spec unencoded.
+fn purl_encode(component: &str) -> String {
+    component
+        .bytes()
+        .map(|byte| match byte {
+            b'a'..=b'z' | b'A'..=b'Z' | b'0'..=b'9' | b'.' | b'-' | b'_' | b'~' | b':' => {
+                (byte as char).to_string()
+            }
+            _ => format!("%{:02X}", byte),
+        })
+        .collect()
+}
+
+/// Random version 4 UUID for document identifiers.
+fn random_uuid() -> Result<[u8; 16]> {
+    let mut bytes = random::bytes::<16>().context("Failed to generate SBOM identifier")?;
+    bytes[6] = (bytes[6] & 0x0f) | 0x40;
+    bytes[8] = (bytes[8] & 0x3f) | 0x80;
+    Ok(bytes)
+}
+
+/// Version 8 (custom) UUID made of the first 16 bytes of sha256 `digest`.
+fn digest_uuid(digest: &str) -> Result<String> {
+    let mut bytes = [0u8; 16];
+    for (i, byte) in bytes.iter_mut().enumerate() {
+        *byte = digest
+            .get(i * 2..i * 2 + 2)
+            .and_then(|hex| u8::from_str_radix(hex, 16).ok())
+            .context("Invalid image digest")?;
+    }
+    bytes[6] = (bytes[6] & 0x0f) | 0x80;
+    bytes[8] = (bytes[8] & 0x3f) | 0x80;
+    Ok(format_uuid(bytes))
+}
+
+fn format_uuid(bytes: [u8; 16]) -> String {
+    let hex = bytes
+        .iter()
+        .map(|byte| format!("{:02x}", byte))
+        .collect::<String>();
+    format!(
+        "{}-{}-{}-{}-{}",
+        &hex[..8],
+        &hex[8..12],
+        &hex[12..16],
+        &hex[16..20],
+        &hex[20..]
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use tempdir::TempDir;
+
+    use super::*;
+
+    const DIGEST: &str = "e3b0c44298fc1c149afbf4c8996fb92427ae41e4649b934ca495991b7852b855";
+
+    fn packages() -> Vec<Package> {
+        vec![
+            Package::kernel("6.12.0"),
+            Package::mia("0.2.5"),
+            Package::library("deb/debian", "libc6", "2.36-9+deb12u4", "?arch=amd64"),
+        ]
+    }
+
+    #[test]
+    fn purls_are_encoded() {
+        let packages = packages();
+        assert_eq!(packages[0].purl, "pkg:generic/linux@6.12.0");
+        assert_eq!(packages[1].purl, "pkg:github/gevulotnetwork/mia@0.2.5");
+        assert_eq!(
+            packages[2].purl,
+            "pkg:deb/debian/libc6@2.36-9%2Bdeb12u4?arch=amd64"
+        );
+        assert_eq!(
+            Package::library("npm", "@types/node", "20.0.0", "").purl,
+            "pkg:npm/%40types/node@20.0.0"
+        );
+    }
+
+    #[test]
+    fn digest_uuid_is_version_8() {
+        assert_eq!(
+            digest_uuid(DIGEST).unwrap(),
+            "e3b0c442-98fc-8c14-9afb-f4c8996fb924"
+        );
+        assert!(digest_uuid("e3b0").is_err());
+        assert!(digest_uuid(&"x".repeat(64)).is_err());
+    }
+
+    #[test]
+    fn random_uuid_is_version_4() {
+        let uuid = format_uuid(random_uuid().unwrap());
+        assert_eq!(uuid.len(), 36);
+        assert_eq!(&uuid[14..15], "4");
+        assert!("89ab".contains(&uuid[19..20]));
+        assert_ne!(uuid, format_uuid(random_uuid().unwrap()));
+    }
+
+    #[test]
+    fn reproducible_sbom_is_deterministic() {
+        let dir = TempDir::new("sbom-test").unwrap();
+        for format in [SbomFormat::Spdx, SbomFormat::CycloneDx] {
+            let first = dir.path().join("first.json");
+            let second = dir.path().join("second.json");
+            let reproducible = Some((315532800, DIGEST));
+            write(&first, format, "image", &packages(), reproducible).unwrap();
+            write(&second, format, "image", &packages(), reproducible).unwrap();
+            let content = fs::read_to_string(&first).unwrap();
+            assert_eq!(content, fs::read_to_string(&second).unwrap());
+            assert!(content.contains("\"1980-01-01T00:00:00Z\""), "{}", content);
+            assert!(
+                content.contains("e3b0c442-98fc-8c14-9afb-f4c8996fb924"),
+                "{}",
+                content
+            );
+        }
+    }
+
+    #[test]
+    fn sbom_identifiers_are_unique() {
+        let dir = TempDir::new("sbom-test").unwrap();
+        for format in [SbomFormat::Spdx, SbomFormat::CycloneDx] {
+            let first = dir.path().join("first.json");
+            let second = dir.path().join("second.json");
+            write(&first, format, "image", &packages(), None).unwrap();
+            write(&second, format, "image", &packages(), None).unwrap();
+            assert_ne!(
+                fs::read_to_string(&first).unwrap(),
+                fs::read_to_string(&second).unwrap()
+            );
+        }
+    }
+}
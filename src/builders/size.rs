@@ -0,0 +1,320 @@
+use crate::builders::RootfsFormat;
+
+pub const KIB: u64 = 1024;
+pub const MIB: u64 = 1024 * KIB;
+pub const GIB: u64 = 1024 * MIB;
+pub const TIB: u64 = 1024 * GIB;
+
+/// Offset of the first partition (sector 2048).
+pub const PARTITION_OFFSET: u64 = MIB;
+
+/// Default size of the FAT boot partition.
+pub const BOOT_PARTITION_SIZE: u64 = 200 * MIB;
+
+/// Space left at the end of the disk (e.g. for backup GPT header).
+pub const DISK_TAIL_SIZE: u64 = MIB;
+
+/// Space reserved in root filesystem for MIA binary and its config.
+pub const MIA_RESERVED_SIZE: u64 = 32 * MIB;
+
+/// Space reserved in root filesystem for NVIDIA drivers and libraries.
+pub const NVIDIA_RESERVED_SIZE: u64 = 512 * MIB;
+
+/// Space reserved in root filesystem for kernel modules.
+pub const MODULES_RESERVED_SIZE: u64 = 64 * MIB;
+
+/// Space reserved in boot partition for SYSLINUX files.
+pub const BOOTLOADER_RESERVED_SIZE: u64 = MIB;
+
+/// Space reserved in boot partition for initramfs.
+pub const INITRAMFS_RESERVED_SIZE: u64 = 16 * MIB;
+
+/// Kernel size assumed when it is not built yet.
+pub const DEFAULT_KERNEL_SIZE: u64 = 32 * MIB;
+
+/// Bytes per inode used by `mkfs.ext4` for default filesystem type.
+const EXT4_BYTES_PER_INODE: u64 = 16 * KIB;
+
+/// Journal and metadata of small filesystems take a fixed amount of space.
+const EXT4_FIXED_OVERHEAD: u64 = 64 * MIB;
+
+/// Data and hash block size of dm-verity devices.
+pub const VERITY_BLOCK_SIZE: u64 = 4 * KIB;
+
+/// Number of SHA-256 digests in one dm-verity hash block.
+const VERITY_HASHES_PER_BLOCK: u64 = VERITY_BLOCK_SIZE / 32;
+
+/// Requested size of the disk image.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ImageSize {
+    /// Exact size in bytes.
+    Fixed(u64),
+    /// Size calculated from the content with extra free space in percents.
+    Auto { extra_percent: u64 },
+}
+
+impl std::str::FromStr for ImageSize {
+    type Err = &'static str;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        if let Some(rest) = s.strip_prefix("auto") {
+            if rest.is_empty() {
+                return Ok(Self::Auto { extra_percent: 0 });
+            }
+            let extra_percent = rest
+                .strip_prefix('+')
+                .and_then(|rest| rest.strip_suffix('%'))
+                .and_then(|percent| percent.parse().ok())
+                .ok_or("invalid image size: expected auto or auto+N%")?;
+            Ok(Self::Auto { extra_percent })
+        } else {
+            parse_size(s).map(Self::Fixed)
+        }
+    }
+}
+
+impl std::fmt::Display for ImageSize {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::Fixed(size) => write!(f, "{}", format_size(*size)),
+            Self::Auto { extra_percent: 0 } => write!(f, "auto"),
+            Self::Auto { extra_percent } => write!(f, "auto+{}%", extra_percent),
+        }
+    }
+}
+
+/// Parse size with optional binary suffix (e.g. 10G, 1024M, 512K, 4096).
+/// Suffixes are powers of 1024, same as in `truncate`.
+pub fn parse_size(s: &str) -> Result<u64, &'static str> {
+    let s = s.trim();
+    let s = s
+        .strip_suffix("iB")
+        .or_else(|| s.strip_suffix('B'))
+        .unwrap_or(s);
+    let (number, multiplier) = match s.chars().last() {
+        Some('K') | Some('k') => (&s[..s.len() - 1], KIB),
+        Some('M') | Some('m') => (&s[..s.len() - 1], MIB),
+        Some('G') | Some('g') => (&s[..s.len() - 1], GIB),
+        Some('T') | Some('t') => (&s[..s.len() - 1], TIB),
+        _ => (s, 1),
+    };
+    number
+        .parse::<u64>()
+        .ok()
+        .and_then(|number| number.checked_mul(multiplier))
+        .ok_or("invalid size: expected number with optional K, M, G or T suffix")
+}
+
+/// Format size in bytes as human-readable string.
+pub fn format_size(size: u64) -> String {
+    const UNITS: [(u64, &str); 4] = [(TIB, "TiB"), (GIB, "GiB"), (MIB, "MiB"), (KIB, "KiB")];
+    for (unit, name) in UNITS {
+        if size >= unit {
+            return if size % unit == 0 {
+                format!("{} {}", size / unit, name)
+            } else {
+                format!("{:.1} {}", size as f64 / unit as f64, name)
+            };
+        }
+    }
+    format!("{} B", size)
+}
+
+/// Round size up to the multiple of `alignment`.
+pub fn align_up(size: u64, alignment: u64) -> u64 {
+    size.div_ceil(alignment) * alignment
+}
+
+/// Disk usage of the root filesystem content.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct ContentUsage {
+    /// Allocated size of all files in bytes.
+    pub bytes: u64,
+    /// Number of files, directories and other filesystem objects.
+    pub inodes: u64,
+}
+
+/// Calculate size of the root partition required to hold `usage` plus `extra_payload` bytes.
+pub fn root_partition_size(
+    usage: ContentUsage,
+    extra_payload: u64,
+    extra_percent: u64,
+    rootfs_format: RootfsFormat,
+) -> u64 {
+    let content = usage.bytes + extra_payload;
+    let required = match rootfs_format {
+        // Inode tables, block group descriptors, journal and block rounding.
+        RootfsFormat::Ext4 => {
+            (content + content / 10).max(usage.inodes * EXT4_BYTES_PER_INODE) + EXT4_FIXED_OVERHEAD
+        }
+        // Compressed formats are never bigger than uncompressed content plus metadata.
+        RootfsFormat::Squashfs | RootfsFormat::Erofs => content + content / 20 + MIB,
+    };
+    align_up(required + required * extra_percent / 100, MIB)
+}
+
+/// Size of dm-verity hash tree (without superblock) protecting `data_size` bytes.
+pub fn verity_hash_size(data_size: u64) -> u64 {
+    let mut blocks = data_size.div_ceil(VERITY_BLOCK_SIZE);
+    let mut hash_blocks = 0;
+    // Every level hashes the level below it until a single block is left.
+    loop {
+        blocks = blocks.div_ceil(VERITY_HASHES_PER_BLOCK);
+        hash_blocks += blocks;
+        if blocks <= 1 {
+            break;
+        }
+    }
+    hash_blocks * VERITY_BLOCK_SIZE
+}
+
+/// Calculate total disk image size from the partition sizes.
+/// Every partition starts at a multiple of `PARTITION_OFFSET`.
+pub fn disk_size(boot_partition_size: u64, root_partition_size: u64, extra_sizes: &[u64]) -> u64 {
+    [boot_partition_size, root_partition_size]
+        .iter()
+        .chain(extra_sizes)
+        .map(|size| align_up(*size, PARTITION_OFFSET))
+        .sum::<u64>()
+        + PARTITION_OFFSET
+        + DISK_TAIL_SIZE
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parse_size_accepts_binary_suffixes() {
+        assert_eq!(parse_size("4096"), Ok(4096));
+        assert_eq!(parse_size("512k"), Ok(512 * KIB));
+        assert_eq!(parse_size("1024M"), Ok(GIB));
+        assert_eq!(parse_size("10G"), Ok(10 * GIB));
+        assert_eq!(parse_size("2T"), Ok(2 * TIB));
+        assert_eq!(parse_size("1GiB"), Ok(GIB));
+        assert_eq!(parse_size("2GB"), Ok(2 * GIB));
+        assert_eq!(parse_size(" 8M "), Ok(8 * MIB));
+    }
+
+    #[test]
+    fn parse_size_rejects_invalid_values() {
+        assert!(parse_size("").is_err());
+        assert!(parse_size("G").is_err());
+        assert!(parse_size("ten").is_err());
+        assert!(parse_size("-1M").is_err());
+        assert!(parse_size("1.5G").is_err());
+        assert!(parse_size("18446744073709551615T").is_err());
+    }
+
+    #[test]
+    fn image_size_parses_auto_with_extra_space() {
+        assert_eq!("auto".parse(), Ok(ImageSize::Auto { extra_percent: 0 }));
+        assert_eq!(
+            "auto+20%".parse(),
+            Ok(ImageSize::Auto { extra_percent: 20 })
+        );
+        assert_eq!("10G".parse(), Ok(ImageSize::Fixed(10 * GIB)));
+        assert!("auto+20".parse::<ImageSize>().is_err());
+        assert!("auto20%".parse::<ImageSize>().is_err());
+        assert_eq!(ImageSize::Auto { extra_percent: 0 }.to_string(), "auto");
+        assert_eq!(ImageSize::Auto { extra_percent: 5 }.to_string(), "auto+5%");
+        assert_eq!(ImageSize::Fixed(10 * GIB).to_string(), "10 GiB");
+    }
+
+    #[test]
+    fn format_size_uses_largest_unit() {
+        assert_eq!(format_size(0), "0 B");
+        assert_eq!(format_size(1023), "1023 B");
+        assert_eq!(format_size(KIB), "1 KiB");
+        assert_eq!(format_size(1536), "1.5 KiB");
+        assert_eq!(format_size(200 * MIB), "200 MiB");
+        assert_eq!(format_size(10 * GIB), "10 GiB");
+        assert_eq!(format_size(3 * TIB / 2), "1.5 TiB");
+    }
+
+    #[test]
+    fn root_partition_size_covers_ext4_overhead() {
+        let usage = ContentUsage {
+            bytes: 100 * MIB,
+            inodes: 10,
+        };
+        // Content plus 10 % and fixed journal overhead.
+        assert_eq!(
+            root_partition_size(usage, 0, 0, RootfsFormat::Ext4),
+            174 * MIB
+        );
+        // Extra payload counts as content.
+        assert_eq!(
+            root_partition_size(usage, 10 * MIB, 0, RootfsFormat::Ext4),
+            185 * MIB
+        );
+        // Extra space is added on top and rounded up to MiB.
+        assert_eq!(
+            root_partition_size(usage, 0, 20, RootfsFormat::Ext4),
+            209 * MIB
+        );
+    }
+
+    #[test]
+    fn root_partition_size_reserves_inodes_for_many_small_files() {
+        let usage = ContentUsage {
+            bytes: 0,
+            inodes: 100_000,
+        };
+        assert_eq!(
+            root_partition_size(usage, 0, 0, RootfsFormat::Ext4),
+            1627 * MIB
+        );
+    }
+
+    #[test]
+    fn root_partition_size_of_compressed_formats() {
+        let usage = ContentUsage {
+            bytes: 100 * MIB,
+            inodes: 100_000,
+        };
+        assert_eq!(
+            root_partition_size(usage, 0, 0, RootfsFormat::Squashfs),
+            106 * MIB
+        );
+        assert_eq!(
+            root_partition_size(usage, 0, 0, RootfsFormat::Erofs),
+            106 * MIB
+        );
+    }
+
+    #[test]
+    fn verity_hash_size_sums_all_levels() {
+        assert_eq!(verity_hash_size(VERITY_BLOCK_SIZE), VERITY_BLOCK_SIZE);
+        // 128 data blocks fit into a single hash block.
+        assert_eq!(verity_hash_size(128 * VERITY_BLOCK_SIZE), VERITY_BLOCK_SIZE);
+        // 129 data blocks need two hash blocks and a root block above them.
+        assert_eq!(
+            verity_hash_size(129 * VERITY_BLOCK_SIZE),
+            3 * VERITY_BLOCK_SIZE
+        );
+        // Partial data block is hashed too.
+        assert_eq!(
+            verity_hash_size(128 * VERITY_BLOCK_SIZE + 1),
+            3 * VERITY_BLOCK_SIZE
+        );
+        // 262144 data blocks -> 2048 + 16 + 1 hash blocks.
+        assert_eq!(verity_hash_size(GIB), 2065 * VERITY_BLOCK_SIZE);
+    }
+
+    #[test]
+    fn disk_size_aligns_partitions() {
+        assert_eq!(align_up(0, MIB), 0);
+        assert_eq!(align_up(1, MIB), MIB);
+        assert_eq!(align_up(MIB, MIB), MIB);
+        // Partition table, boot, root and tail.
+        assert_eq!(
+            disk_size(BOOT_PARTITION_SIZE, 174 * MIB, &[]),
+            MIB + 200 * MIB + 174 * MIB + MIB
+        );
+        assert_eq!(
+            disk_size(BOOT_PARTITION_SIZE, 174 * MIB, &[MIB + 1]),
+            MIB + 200 * MIB + 174 * MIB + 2 * MIB + MIB
+        );
+    }
+}
@@ -1,339 +1,1397 @@
 use anyhow::{Context, Result};
 use log::debug;
 use mia_installer::runtime_config::{self, RuntimeConfig};
-use oci_spec::image::{ImageConfiguration, ImageManifest};
 use std::io::{self, BufRead, BufReader, Write};
-use std::{env, fs, path::Path, process::Command};
+use std::{
+    fs,
+    path::{Path, PathBuf},
+    process::Command,
+};
 use tempdir::TempDir;
 
-use crate::builders::{BuildOptions, ImageBuilder};
-
-use super::nvidia;
+use crate::builders::size::{self, ImageSize};
+use crate::builders::{
+    Arch, BootMode, BuildOptions, ImageBuilder, ModulesInstall, OutputKind, RootfsFormat,
+    SbomFormat,
+};
+
+use super::container::{self, Platform};
+use super::image::{self, Image};
+use super::partition::{
+    ExtraFilesystem, ExtraPartition, Layout, PartitionSpec, PartitionType, TableKind,
+};
+use super::verity::{self, Verity};
+use super::workspace::{self, LoopDevice, Workspace};
+use super::{initramfs, kernel, nvidia, report, rootfs, sbom, slim, tarball};
+
+/// Identifiers used instead of random ones in reproducible builds.
+const REPRODUCIBLE_DISK_ID: &str = "0x47564c54";
+const REPRODUCIBLE_DISK_GUID: &str = "47564c54-0000-4000-8000-000000000000";
+const REPRODUCIBLE_BOOT_PARTITION_GUID: &str = "47564c54-0000-4000-8000-000000000001";
+const REPRODUCIBLE_ROOT_PARTITION_GUID: &str = "47564c54-0000-4000-8000-000000000003";
+const REPRODUCIBLE_BOOT_VOLUME_ID: &str = "47564c54";
+const REPRODUCIBLE_ROOTFS_UUID: &str = "47564c54-0000-4000-8000-000000000002";
+/// Extra partitions get these prefixes followed by the partition number.
+const REPRODUCIBLE_EXTRA_PARTITION_GUID_PREFIX: &str = "47564c54-0000-4000-8001-";
+const REPRODUCIBLE_EXTRA_FILESYSTEM_UUID_PREFIX: &str = "47564c54-0000-4000-8002-";
+
+/// GitHub releases of MIA, used to resolve `latest` version.
+const MIA_RELEASES_URL: &str =
+    "https://api.github.com/repos/gevulotnetwork/mia/releases?per_page=100";
+
+/// Files written into output directory with `--output-kind kernel+rootfs`,
+/// next to the kernel named after the architecture (`bzImage` or `Image`).
+const DIRECT_BOOT_ROOTFS_FILE: &str = "rootfs.img";
+const DIRECT_BOOT_DESCRIPTOR_FILE: &str = "boot.json";
+
+/// Root device of the disk image and of the rootfs image attached as a single virtio disk.
+/// Used when root filesystem can't be referenced by partition UUID or label.
+const DISK_ROOT_DEVICE: &str = "/dev/sda2";
+const DIRECT_BOOT_ROOT_DEVICE: &str = "/dev/vda";
+
+/// Number of the first extra partition, following boot and root partitions.
+const FIRST_EXTRA_PARTITION: u32 = 3;
+
+/// Label of ext4 and EROFS root filesystems.
+const ROOTFS_LABEL: &str = "ROOTFS";
 
 pub struct SkopeoSyslinuxBuilder {}
 
 impl ImageBuilder for SkopeoSyslinuxBuilder {
     fn build(&self, options: &BuildOptions) -> Result<()> {
-        // Handle printing messages with regard to `quiet` option.
-        let print = |line: &str| -> Result<()> {
-            if !options.quiet {
-                print!("{}", line);
-                io::stdout().flush().context("Failed to flush stdout")?;
-            }
-            Ok(())
+        let progress = Progress {
+            quiet: options.quiet,
+            steps: report::Steps::default(),
         };
-
-        print(&format!(
+        progress.print(&format!(
             "Building image {} with options:\n",
             options.output_file
         ))?;
 
-        print(&format!("{}", options))?;
+        progress.print(&format!("{}", options))?;
 
-        // Check if the output file already exists
-        if Path::new(&options.output_file).exists() {
-            if !options.force {
-                anyhow::bail!("Output file '{}' already exists. Please choose a different filename or remove the existing file.", &options.output_file);
-            } else {
-                fs::remove_file(&options.output_file)
-                    .context("Failed to remove existing output file")?;
-            }
+        Self::check_options(options)?;
+
+        let busybox = if options.initramfs {
+            Some(initramfs::find_busybox(options.busybox_file.as_deref())?)
+        } else {
+            None
+        };
+
+        Self::prepare_output(options, &progress)?;
+
+        let mut build = Build {
+            options,
+            progress,
+            report: report::BuildReport::new(),
+            // Container images are extracted here before disk image is created,
+            // so the image size can be calculated from the content.
+            staging_dir: TempDir::new("rootfs").context("Failed to create temporary directory")?,
+            // Mounts and loop device are released when it is dropped, also on early return.
+            workspace: Workspace::new()?,
+            busybox,
+            // In reproducible mode filesystems are generated from staging directories with fixed
+            // identifiers and timestamps instead of being populated through loop mounts.
+            reproducible: options.reproducible.then_some(options.source_date_epoch),
+            // Verity hash tree is appended to the generated filesystem, so it is always staged.
+            staged_root: options.rootfs_format.is_read_only()
+                || options.reproducible
+                || options.verity,
+            staged_boot: options.reproducible,
+            sbom_packages: None,
+        };
+
+        let result = build.run();
+        if let Err(e) = &result {
+            log::error!("error: {:#}", e);
+            build.progress.steps.fail();
         }
+        // Always call cleanup, even if there was an error or the build was interrupted.
+        build.cleanup()?;
+        let result = result.and_then(|built| build.finish_sbom().map(|()| built));
+        build.finish_report(&result)?;
 
-        if options.force {
-            print(&format!("Cleaning up old attempts... "))?;
-            if Self::cleanup().is_ok() {
-                print(&format!("✅\n"))?;
-            } else {
-                print(&format!("❌\n"))?;
-            }
+        // Check if there was an error and return it
+        let (root_device, verity) = result?;
+        Self::print_instructions(options, &build.progress, &root_device, verity.as_ref())
+    }
+}
+
+/// Progress messages printed unless `--quiet` and timing of build steps for the report.
+struct Progress {
+    quiet: bool,
+    steps: report::Steps,
+}
+
+impl Progress {
+    fn print(&self, line: &str) -> Result<()> {
+        if !self.quiet {
+            print!("{}", line);
+            io::stdout().flush().context("Failed to flush stdout")?;
         }
+        Ok(())
+    }
 
-        // Execute the main steps to create the bootable disk image
-        let result = (|| -> Result<()> {
-            print(&format!("Creating disk image... "))?;
-            Self::create_disk_image(&options.image_size, &options.output_file)?;
-            print(&format!("✅\n"))?;
+    /// Start the next step, unless the build was interrupted.
+    fn begin(&self, name: &str) -> Result<()> {
+        workspace::check_interrupted()?;
+        self.steps.begin(name);
+        self.print(&format!("{}... ", name))
+    }
 
-            print(&format!("Creating partitions... "))?;
-            Self::create_partitions(&options.output_file)?;
-            print(&format!("✅\n"))?;
+    fn done(&self) -> Result<()> {
+        self.steps.end();
+        self.print("✅\n")
+    }
 
-            print(&format!("Setting up loop device... "))?;
-            Self::setup_loop_device(&options.output_file)?;
-            print(&format!("✅\n"))?;
+    /// Finish the step printing its result before the check mark.
+    fn done_with(&self, result: &str) -> Result<()> {
+        self.steps.end();
+        self.print(&format!("{} ✅\n", result))
+    }
 
-            print(&format!("Creating filesystems... "))?;
-            Self::create_filesystems(&options.output_file)?;
-            print(&format!("✅\n"))?;
+    fn fail(&self) -> Result<()> {
+        self.steps.fail();
+        self.print("❌\n")
+    }
+}
 
-            print(&format!("Mounting filesystems... "))?;
-            Self::mount_filesystems(&options.output_file)?;
-            print(&format!("✅\n"))?;
+/// State of a single build shared by its stages.
+struct Build<'a> {
+    options: &'a BuildOptions,
+    progress: Progress,
+    report: report::BuildReport,
+    staging_dir: TempDir,
+    workspace: Workspace,
+    busybox: Option<String>,
+    reproducible: Option<u64>,
+    staged_root: bool,
+    staged_boot: bool,
+    /// Packages are collected from the root filesystem, SBOM is written when the image is done.
+    sbom_packages: Option<Vec<sbom::Package>>,
+}
 
-            let mut container_rt_config = RuntimeConfig::default();
-            let mut kernel_modules = options.kernel_modules.clone();
+impl Build<'_> {
+    /// Execute the main steps to create the bootable image.
+    /// Returns the root device and verity parameters of the root filesystem.
+    fn run(&mut self) -> Result<(String, Option<Verity>)> {
+        let mut container_rt_config = RuntimeConfig::default();
+        let rootfs_source = self.extract_source(&mut container_rt_config)?;
+        let image_size = self.measure_rootfs(rootfs_source.as_deref())?;
+        self.create_target(image_size)?;
+        self.populate_rootfs()?;
+
+        let mut kernel_modules = self.options.kernel_modules.clone();
+        let kernel_dir = self.provide_kernel(&mut kernel_modules)?;
+        self.install_init(&container_rt_config, &kernel_modules, kernel_dir.as_deref())?;
+        if self.options.sbom.is_some() {
+            self.collect_packages()?;
+        }
 
-            if let Some(container_source) = &options.container_source {
-                print(&format!("Installing rootfs from container... "))?;
-                Self::install_rootfs_from_container(container_source, &mut container_rt_config)?;
-                print(&format!("✅\n"))?;
-            } else if let Some(rootfs_dir) = &options.rootfs_dir {
-                print(&format!("Installing rootfs from directory... "))?;
-                Self::install_rootfs_from_directory(rootfs_dir)?;
-                print(&format!("✅\n"))?;
-            } else if let Some(containerfile) = &options.containerfile {
-                print(&format!(
-                    "Building and installing rootfs from Containerfile... "
-                ))?;
-                Self::build_and_install_rootfs_from_containerfile(
-                    containerfile,
-                    &mut container_rt_config,
+        let root_device = SkopeoSyslinuxBuilder::root_device(self.options)?;
+        let verity = match self.options.output_kind {
+            OutputKind::Disk => self.finish_disk_image(&root_device)?,
+            OutputKind::KernelRootfs => self.finish_direct_boot(&root_device, image_size)?,
+        };
+        Ok((root_device, verity))
+    }
+
+    /// Extract container image into the staging directory and return it.
+    /// Root filesystem directory is returned as it is, tarballs are never staged.
+    fn extract_source(&mut self, rt_config: &mut RuntimeConfig) -> Result<Option<PathBuf>> {
+        let options = self.options;
+        let staging_dir = self.staging_dir.path();
+        if let Some(container_source) = &options.container_source {
+            self.progress.begin("Extracting rootfs from container")?;
+            let digest = SkopeoSyslinuxBuilder::extract_container(
+                container_source,
+                &options.platform,
+                staging_dir,
+                rt_config,
+            )?;
+            self.report.source = Some(report::SourceReport {
+                kind: "container",
+                reference: container_source.clone(),
+                digest: Some(digest),
+            });
+            self.progress.done()?;
+            Ok(Some(staging_dir.to_path_buf()))
+        } else if let Some(rootfs_dir) = &options.rootfs_dir {
+            self.report.source = Some(report::SourceReport {
+                kind: "rootfs-dir",
+                reference: rootfs_dir.clone(),
+                digest: None,
+            });
+            Ok(Some(PathBuf::from(rootfs_dir)))
+        } else if let Some(rootfs_tar) = &options.rootfs_tar {
+            self.report.source = Some(report::SourceReport {
+                kind: "rootfs-tar",
+                reference: rootfs_tar.clone(),
+                digest: None,
+            });
+            // Tarball is extracted straight into the image filesystem.
+            Ok(None)
+        } else if let Some(containerfile) = &options.containerfile {
+            self.progress
+                .begin("Building and extracting rootfs from Containerfile")?;
+            let digest = SkopeoSyslinuxBuilder::build_and_extract_containerfile(
+                &container::ContainerfileBuild {
+                    containerfile: containerfile.clone(),
+                    engine: options.build_engine,
+                    context: options.build_context.clone(),
+                    target: options.build_target.clone(),
+                    build_args: options.build_args.clone(),
+                    secrets: options.build_secrets.clone(),
+                },
+                &options.platform,
+                staging_dir,
+                rt_config,
+            )?;
+            self.report.source = Some(report::SourceReport {
+                kind: "containerfile",
+                reference: containerfile.clone(),
+                digest: Some(digest),
+            });
+            self.progress.done()?;
+            Ok(Some(staging_dir.to_path_buf()))
+        } else {
+            anyhow::bail!("No rootfs source specified");
+        }
+    }
+
+    /// Calculate size of the disk image or ext4 rootfs image from the source content.
+    fn measure_rootfs(&mut self, rootfs_source: Option<&Path>) -> Result<u64> {
+        self.progress.begin("Calculating image size")?;
+        let usage = if let Some(rootfs_source) = rootfs_source {
+            SkopeoSyslinuxBuilder::get_content_usage(rootfs_source)?
+        } else {
+            match self.options.rootfs_tar.as_deref() {
+                Some(rootfs_tar) if rootfs_tar != tarball::STDIN => {
+                    tarball::content_usage(Path::new(rootfs_tar))?
+                }
+                // Tarball from stdin is read only once, explicit --size is not checked.
+                _ => size::ContentUsage::default(),
+            }
+        };
+        log::debug!("rootfs content usage: {:?}", usage);
+        let image_size = SkopeoSyslinuxBuilder::calculate_image_size(self.options, usage)?;
+        self.progress.done_with(&size::format_size(image_size))?;
+        self.report.image_size = Some(image_size);
+        Ok(image_size)
+    }
+
+    /// Create and mount partitions of the disk image or staging directories
+    /// of direct kernel boot output.
+    fn create_target(&mut self, image_size: u64) -> Result<()> {
+        let options = self.options;
+        match options.output_kind {
+            OutputKind::Disk => {
+                self.progress.begin("Creating disk image")?;
+                SkopeoSyslinuxBuilder::create_disk_image(image_size, &options.output_file)?;
+                self.progress.done()?;
+
+                self.progress.begin("Creating partitions")?;
+                SkopeoSyslinuxBuilder::create_partitions(
+                    &options.output_file,
+                    options.boot_mode,
+                    options.boot_size,
+                    &options.extra_partitions,
+                    self.reproducible.is_some(),
+                )?;
+                self.progress.done()?;
+
+                self.progress.begin("Setting up loop device")?;
+                self.workspace.attach(&options.output_file)?;
+                self.progress.done()?;
+
+                self.progress.begin("Creating filesystems")?;
+                SkopeoSyslinuxBuilder::create_filesystems(
+                    self.workspace.loop_device()?,
+                    options.boot_mode,
+                    &options.extra_partitions,
+                    self.staged_root,
+                    self.reproducible,
                 )?;
-                print(&format!("✅\n"))?;
+                self.progress.done()?;
+
+                self.progress.begin("Mounting filesystems")?;
+                SkopeoSyslinuxBuilder::mount_filesystems(
+                    &mut self.workspace,
+                    self.staged_root,
+                    self.staged_boot,
+                )?;
+                self.progress.done()?;
+            }
+            OutputKind::KernelRootfs => {
+                self.progress.begin("Creating staging directories")?;
+                fs::create_dir_all(&options.output_file)
+                    .context("Failed to create output directory")?;
+                SkopeoSyslinuxBuilder::prepare_staging_root(&self.workspace.root_dir())?;
+                SkopeoSyslinuxBuilder::run_command(
+                    &["mkdir", "-p", self.workspace.boot_dir().to_str().unwrap()],
+                    true,
+                )
+                .context("Failed to create boot directory")?;
+                self.progress.done()?;
             }
+        }
+        Ok(())
+    }
 
-            print("Creating input/output context directories...")?;
-            Self::create_mount_dirs()?;
-            print("✅\n")?;
+    /// Install the root filesystem content, remove what `--slim` selects and create
+    /// mountpoints of MIA.
+    fn populate_rootfs(&mut self) -> Result<()> {
+        let options = self.options;
+        let root_dir = self.workspace.root_dir();
+        if let Some(rootfs_dir) = &options.rootfs_dir {
+            self.progress.begin("Installing rootfs from directory")?;
+            let stats = SkopeoSyslinuxBuilder::install_rootfs_from_directory(
+                options, rootfs_dir, &root_dir,
+            )?;
+            self.progress.done_with(&format!(
+                "{} in {} files",
+                size::format_size(stats.bytes),
+                stats.files
+            ))?;
+            self.report.rootfs_copy = Some(stats);
+        } else if let Some(rootfs_tar) = &options.rootfs_tar {
+            self.progress.begin("Installing rootfs from tarball")?;
+            SkopeoSyslinuxBuilder::install_rootfs_from_tarball(rootfs_tar, &root_dir)?;
+            self.progress.done()?;
+        } else {
+            self.progress.begin("Installing rootfs from container")?;
+            SkopeoSyslinuxBuilder::install_rootfs_from_staging(self.staging_dir.path(), &root_dir)?;
+            self.progress.done()?;
+        }
 
-            if let Some(kernel_path) = &options.kernel_file {
-                if options.nvidia_drivers {
-                    print("WARNING: Installing NVIDIA drivers for precompiled kernel is not supported yet!")?;
-                }
-                print(&format!("Installing precompiled kernel... "))?;
-                Self::install_precompiled_kernel(kernel_path)?;
-                print(&format!("✅\n"))?;
+        if let Some(profile) = options.slim {
+            self.progress
+                .begin(&format!("Slimming rootfs ({})", profile))?;
+            let before = SkopeoSyslinuxBuilder::get_content_usage(&root_dir)?;
+            let toolchain = options.arch.toolchain(options.cross_compile.as_deref());
+            slim::slim(
+                &root_dir,
+                profile,
+                &format!("{}strip", toolchain.unwrap_or_default()),
+            )?;
+            let after = SkopeoSyslinuxBuilder::get_content_usage(&root_dir)?;
+            self.progress.done_with(&format!(
+                "{} → {}",
+                size::format_size(before.bytes),
+                size::format_size(after.bytes)
+            ))?;
+            self.report.slim = Some(report::SlimReport {
+                profile: profile.to_string(),
+                bytes_before: before.bytes,
+                bytes_after: after.bytes,
+            });
+        }
+
+        self.progress
+            .begin("Creating input/output context directories")?;
+        SkopeoSyslinuxBuilder::create_mount_dirs(&root_dir)?;
+        self.progress.done()
+    }
+
+    /// Install precompiled, prebuilt or built kernel and its modules.
+    /// Returns the kernel build tree, which precompiled kernels don't have.
+    fn provide_kernel(&mut self, kernel_modules: &mut Vec<String>) -> Result<Option<PathBuf>> {
+        let options = self.options;
+        let kernel_dir = if let Some(kernel_path) = &options.kernel_file {
+            if options.nvidia_drivers {
+                self.progress.print(
+                    "WARNING: Installing NVIDIA drivers for precompiled kernel is not supported yet!",
+                )?;
+            }
+            self.progress.begin("Installing precompiled kernel")?;
+            SkopeoSyslinuxBuilder::install_precompiled_kernel(
+                kernel_path,
+                &self.workspace.boot_dir(),
+                options.arch,
+            )?;
+            self.report.kernel = Some(report::KernelReport::from_file(kernel_path)?);
+            self.progress.done()?;
+            None
+        } else {
+            self.progress.begin("Installing kernel")?;
+            let prebuilt_kernel = options
+                .kernel_version
+                .strip_prefix(kernel::prebuilt::VERSION_PREFIX);
+            let kernel_dir = if let Some(version) = prebuilt_kernel {
+                kernel::prebuilt::fetch(
+                    version,
+                    options.kernel_index.as_ref().unwrap(),
+                    options.kernel_index_key.as_ref().unwrap(),
+                    options.nvidia_drivers,
+                    options.arch,
+                )?
             } else {
-                print(&format!("Installing kernel... "))?;
-                Self::install_kernel(
+                kernel::build(
                     &options.kernel_version,
                     options
                         .kernel_url
                         .as_ref()
                         .context("Kernel URL is required")?,
-                    options.nvidia_drivers,
-                    &mut kernel_modules,
-                )?;
-                print(&format!("✅\n"))?;
+                    &kernel::KernelConfig {
+                        arch: options.arch,
+                        cross_compile: options.cross_compile.clone(),
+                        defconfig: options.kernel_defconfig.clone(),
+                        fragments: options.kernel_config_fragments.clone(),
+                    },
+                )?
+            };
+            SkopeoSyslinuxBuilder::install_kernel(
+                &kernel_dir,
+                options.arch,
+                &self.workspace.root_dir(),
+                options.modules_install,
+                options.nvidia_drivers,
+                kernel_modules,
+            )?;
+            self.report.kernel = Some(report::KernelReport::from_cache(&kernel_dir)?);
+            self.progress.done()?;
+            Some(kernel_dir)
+        };
+
+        if !kernel_modules.is_empty() {
+            // Kernel release is unknown for precompiled kernels and prebuilt ones without headers.
+            match kernel_dir
+                .as_deref()
+                .filter(|dir| kernel::kernel_release(dir).is_ok())
+            {
+                Some(kernel_dir) => {
+                    self.progress.begin("Verifying kernel modules")?;
+                    kernel::modules::verify(
+                        kernel_dir,
+                        &self.workspace.root_dir(),
+                        kernel_modules,
+                    )?;
+                    self.progress.done()?;
+                }
+                None => self
+                    .progress
+                    .print("WARNING: Kernel modules can't be verified for this kernel!\n")?,
             }
+        }
+        Ok(kernel_dir)
+    }
 
-            // Without explicit init, mia will be used.
-            if options.init.is_none() {
-                print(&format!("Installing MIA (Minimal Init Application)... "))?;
-                Self::install_mia(
-                    options
-                        .mia_version
-                        .as_ref()
-                        .context("MIA version is required")?,
-                    &container_rt_config,
-                    &kernel_modules,
+    /// Install MIA, unless custom init is used, and build initramfs.
+    fn install_init(
+        &mut self,
+        container_rt_config: &RuntimeConfig,
+        kernel_modules: &[String],
+        kernel_dir: Option<&Path>,
+    ) -> Result<()> {
+        let options = self.options;
+        // Without explicit init, mia will be used.
+        if options.init.is_none() {
+            self.progress
+                .begin("Installing MIA (Minimal Init Application)")?;
+            let mia_version = SkopeoSyslinuxBuilder::resolve_mia_version(
+                options
+                    .mia_version
+                    .as_ref()
+                    .context("MIA version is required")?,
+            )?;
+            let rt_config = SkopeoSyslinuxBuilder::install_mia(
+                &mia_version,
+                options.arch,
+                &self.workspace.root_dir(),
+                container_rt_config,
+                kernel_modules,
+                SkopeoSyslinuxBuilder::parse_mounts(
                     &options.mounts,
-                    !options.no_gevulot_runtime,
-                    !options.no_default_mounts,
-                )?;
-                print(&format!("✅\n"))?;
-            } else {
-                print("WARNING: Using custom init system is considered unstable for now!")?;
+                    &options.extra_partitions,
+                    &self.workspace.root_dir(),
+                )?,
+                !options.no_gevulot_runtime,
+                !options.no_default_mounts,
+            )?;
+            self.report.mia_version = Some(mia_version);
+            self.report.runtime_config = Some(rt_config);
+            self.progress.done()?;
+        } else {
+            self.progress
+                .print("WARNING: Using custom init system is considered unstable for now!")?;
+        }
+
+        if let Some(busybox) = &self.busybox {
+            self.progress.begin("Creating initramfs")?;
+            initramfs::build(
+                &self.workspace.boot_dir().join(initramfs::INITRAMFS_FILE),
+                kernel_dir,
+                options.rootfs_format,
+                busybox,
+                options.verity,
+                image::MIA_BINARY_PATH,
+                self.reproducible,
+            )?;
+            self.progress.done()?;
+        }
+        Ok(())
+    }
+
+    /// Collect packages of the root filesystem, kernel and MIA for the SBOM.
+    fn collect_packages(&mut self) -> Result<()> {
+        self.progress.begin("Collecting SBOM packages")?;
+        let mut packages = sbom::scan_rootfs(&self.workspace.root_dir());
+        if let Some(kernel) = &self.report.kernel {
+            if let Some(version) = kernel.release.as_ref().or(kernel.version.as_ref()) {
+                packages.push(sbom::Package::kernel(version));
             }
+        }
+        if let Some(mia_version) = &self.report.mia_version {
+            packages.push(sbom::Package::mia(mia_version));
+        }
+        self.sbom_packages = Some(packages);
+        self.progress.done()
+    }
 
-            print(&format!("Installing bootloader... "))?;
-            Self::install_bootloader(
-                options.init.as_deref(),
-                options.init_args.as_deref(),
-                &options.output_file,
-                options.rw_root,
-                options.mbr_file.as_deref(),
+    /// Generate staged filesystems of the disk image and install the bootloader.
+    fn finish_disk_image(&mut self, root_device: &str) -> Result<Option<Verity>> {
+        let options = self.options;
+        // Root filesystem goes first, verity root hash is a part of bootloader config.
+        let verity = if self.staged_root {
+            self.progress.begin(&format!(
+                "Creating {} root filesystem",
+                options.rootfs_format
+            ))?;
+            let verity = SkopeoSyslinuxBuilder::create_rootfs_from_staging(
+                &mut self.workspace,
+                options.rootfs_format,
+                self.staged_boot,
+                self.reproducible,
+                options.verity,
             )?;
-            print(&format!("✅\n"))?;
+            self.progress.done()?;
+            verity
+        } else {
+            None
+        };
 
-            print(&format!("Setting bootable flag... "))?;
-            Self::set_bootable_flag(&options.output_file)?;
-            print(&format!("✅\n"))?;
+        self.progress.begin("Installing bootloader")?;
+        SkopeoSyslinuxBuilder::install_bootloader(
+            &SkopeoSyslinuxBuilder::kernel_cmdline(options, root_device, verity.as_ref()),
+            options.initramfs,
+            &self.workspace,
+            options.arch,
+            options.boot_mode,
+            options.mbr_file.as_deref(),
+            options.efi_loader_file.as_deref(),
+            self.staged_boot,
+        )?;
+        self.progress.done()?;
+
+        if self.staged_boot {
+            self.progress.begin("Creating boot filesystem")?;
+            SkopeoSyslinuxBuilder::create_boot_from_staging(
+                &self.workspace,
+                options.boot_mode,
+                options.source_date_epoch,
+            )?;
+            self.progress.done()?;
+        }
 
-            Ok(())
-        })();
+        Ok(verity)
+    }
 
-        if let Err(e) = &result {
-            log::error!("error: {:#}", e);
+    /// Export kernel and initramfs, generate rootfs image and write boot descriptor
+    /// into the output directory.
+    fn finish_direct_boot(&mut self, root_device: &str, image_size: u64) -> Result<Option<Verity>> {
+        let options = self.options;
+        let output_dir = Path::new(&options.output_file);
+        let boot_dir = self.workspace.boot_dir();
+
+        self.progress.begin("Exporting kernel")?;
+        SkopeoSyslinuxBuilder::export_boot_file(
+            &boot_dir,
+            options.arch.kernel_file(),
+            &output_dir.join(options.arch.kernel_file()),
+        )?;
+        if options.initramfs {
+            SkopeoSyslinuxBuilder::export_boot_file(
+                &boot_dir,
+                initramfs::INITRAMFS_FILE,
+                &output_dir.join(initramfs::INITRAMFS_FILE),
+            )?;
         }
+        self.progress.done()?;
 
-        // Always call cleanup, even if there was an error
-        print(&format!("Cleaning up... "))?;
-        Self::cleanup()?;
-        print(&format!("✅\n"))?;
+        self.progress.begin(&format!(
+            "Creating {} root filesystem image",
+            options.rootfs_format
+        ))?;
+        let rootfs_path = output_dir.join(DIRECT_BOOT_ROOTFS_FILE);
+        if options.rootfs_format == RootfsFormat::Ext4 {
+            SkopeoSyslinuxBuilder::create_disk_image(image_size, rootfs_path.to_str().unwrap())?;
+        } else {
+            fs::File::create(&rootfs_path).context("Failed to create root filesystem image")?;
+        }
+        SkopeoSyslinuxBuilder::generate_rootfs_image(
+            &self.workspace.root_dir(),
+            options.rootfs_format,
+            &rootfs_path,
+            self.reproducible,
+        )?;
+        self.progress.done()?;
+
+        let verity = if options.verity {
+            self.progress.begin("Creating verity hash tree")?;
+            let verity = verity::format(
+                &rootfs_path,
+                self.reproducible.map(|_| verity::REPRODUCIBLE_SALT),
+            )?;
+            self.progress.done()?;
+            Some(verity)
+        } else {
+            None
+        };
 
-        // Check if there was an error and return it
-        result?;
+        self.progress.begin("Writing boot descriptor")?;
+        SkopeoSyslinuxBuilder::write_boot_descriptor(
+            options,
+            output_dir,
+            root_device,
+            verity.as_ref(),
+        )?;
+        self.progress.done()?;
 
-        // Print success message and instructions for running the image
+        Ok(verity)
+    }
+
+    /// Unmount filesystems, detach loop device and remove staging directories.
+    fn cleanup(&mut self) -> Result<()> {
+        self.progress.steps.begin("Cleaning up");
+        self.progress.print("Cleaning up... ")?;
+        let cleaned = self.workspace.cleanup();
+        // Extracted files are owned by root, so temp dir can't remove them on dropping.
+        _ = SkopeoSyslinuxBuilder::run_command(
+            &["rm", "-rf", self.staging_dir.path().to_str().unwrap()],
+            true,
+        );
+        match cleaned {
+            Ok(()) => self.progress.done(),
+            Err(e) => {
+                log::warn!("{:#}", e);
+                self.progress.fail()
+            }
+        }
+    }
+
+    /// Write SBOM of the built image with `--sbom`.
+    /// SBOM of reproducible build is identified by the image digest,
+    /// so it is written after the image is detached.
+    fn finish_sbom(&mut self) -> Result<()> {
+        let (Some(sbom_format), Some(packages)) = (self.options.sbom, &self.sbom_packages) else {
+            return Ok(());
+        };
+        self.progress
+            .begin(&format!("Writing {} SBOM", sbom_format))?;
+        let written = SkopeoSyslinuxBuilder::write_sbom(
+            self.options,
+            sbom_format,
+            packages,
+            self.reproducible,
+        );
+        match written {
+            Ok(()) => self.progress.done(),
+            Err(e) => {
+                log::error!("error: {:#}", e);
+                self.progress.steps.fail();
+                Err(e)
+            }
+        }
+    }
+
+    /// Write build report with `--report-file`.
+    fn finish_report(&mut self, result: &Result<(String, Option<Verity>)>) -> Result<()> {
+        let Some(report_file) = &self.options.report_file else {
+            return Ok(());
+        };
+        self.report.steps = self.progress.steps.reports();
+        let written =
+            SkopeoSyslinuxBuilder::complete_report(&mut self.report, self.options, result)
+                .and_then(|_| self.report.write(Path::new(report_file)));
+        // Build error is more important than the report one.
+        match written {
+            Err(e) if result.is_err() => {
+                log::error!("failed to write build report: {:#}", e);
+                Ok(())
+            }
+            written => written,
+        }
+    }
+}
+
+impl SkopeoSyslinuxBuilder {
+    /// Reject option combinations the build can't handle before anything is created.
+    fn check_options(options: &BuildOptions) -> Result<()> {
+        if options.platform.architecture != options.arch.container_architecture() {
+            anyhow::bail!(
+                "Platform {} doesn't match architecture {}. Use --platform linux/{} or omit it.",
+                options.platform,
+                options.arch,
+                options.arch.container_architecture()
+            );
+        }
+        if options.output_kind == OutputKind::Disk
+            && options.arch != Arch::X86_64
+            && options.boot_mode.supports_bios()
+        {
+            anyhow::bail!(
+                "SYSLINUX is x86-only, {} disk images can only be booted with --boot uefi.",
+                options.arch
+            );
+        }
+        if options.nvidia_drivers && options.arch != Arch::X86_64 {
+            anyhow::bail!("NVIDIA drivers are only supported for x86_64 images.");
+        }
+        // Initramfs tools are copied from the host.
+        if options.initramfs && Arch::host() != Some(options.arch) {
+            if options.busybox_file.is_none() {
+                anyhow::bail!(
+                    "Initramfs for {} requires static busybox of that architecture. Use --busybox-file option to specify it.",
+                    options.arch
+                );
+            }
+            if options.verity {
+                anyhow::bail!(
+                    "dm-verity initramfs uses dmsetup of the host and can't be built for {} on this host.",
+                    options.arch
+                );
+            }
+        }
+
+        if options.rootfs_tar.as_deref() == Some(tarball::STDIN)
+            && matches!(options.image_size, ImageSize::Auto { .. })
+        {
+            anyhow::bail!(
+                "Tarball from stdin can't be measured for --size auto. Use explicit --size."
+            );
+        }
+
+        if options.rw_root && options.rootfs_format.is_read_only() {
+            anyhow::bail!(
+                "Root filesystem in {} format can't be mounted as read-write.",
+                options.rootfs_format
+            );
+        }
+        if options.rw_root && options.verity {
+            anyhow::bail!(
+                "Root filesystem protected with dm-verity can't be mounted as read-write."
+            );
+        }
+
+        if !options.extra_partitions.is_empty() {
+            if options.output_kind != OutputKind::Disk {
+                anyhow::bail!("Extra partitions can only be added to disk images.");
+            }
+            if !options.boot_mode.uses_gpt() && options.extra_partitions.len() > 2 {
+                anyhow::bail!(
+                    "MBR partition table supports at most 2 extra partitions. Use --boot uefi or --boot hybrid for more."
+                );
+            }
+            for (i, partition) in options.extra_partitions.iter().enumerate() {
+                // Filesystem labels are resolved case-insensitively for vfat.
+                if options.extra_partitions[..i]
+                    .iter()
+                    .any(|other| other.label.eq_ignore_ascii_case(&partition.label))
+                {
+                    anyhow::bail!("Extra partition label {} is used twice.", partition.label);
+                }
+            }
+        }
+        for mount in &options.mounts {
+            Self::parse_partition_mount(mount, &options.extra_partitions)?;
+        }
+
+        if options
+            .kernel_version
+            .starts_with(kernel::prebuilt::VERSION_PREFIX)
+            && options.kernel_file.is_none()
+        {
+            if options.kernel_index.is_none() || options.kernel_index_key.is_none() {
+                anyhow::bail!(
+                    "Prebuilt kernels require --kernel-index and --kernel-index-key options."
+                );
+            }
+            if options.kernel_defconfig.is_some() || !options.kernel_config_fragments.is_empty() {
+                anyhow::bail!("Kernel config can't be customized for prebuilt kernels.");
+            }
+        }
+        Ok(())
+    }
+
+    /// Release loop devices of killed builds and remove output files with `--force`.
+    fn prepare_output(options: &BuildOptions, progress: &Progress) -> Result<()> {
+        // Loop device of a killed build keeps the old disk image attached.
+        if options.force
+            && options.output_kind == OutputKind::Disk
+            && Path::new(&options.output_file).exists()
+        {
+            progress.begin("Cleaning up old attempts")?;
+            if workspace::release_stale(&options.output_file).is_ok() {
+                progress.done()?;
+            } else {
+                progress.fail()?;
+            }
+        }
+
+        // Check if the output file already exists
+        match options.output_kind {
+            OutputKind::Disk => {
+                if Path::new(&options.output_file).exists() {
+                    if !options.force {
+                        anyhow::bail!("Output file '{}' already exists. Please choose a different filename or remove the existing file.", &options.output_file);
+                    } else {
+                        fs::remove_file(&options.output_file)
+                            .context("Failed to remove existing output file")?;
+                    }
+                }
+                if let Some(sbom_path) = Self::sbom_path(options).filter(|path| path.exists()) {
+                    if !options.force {
+                        anyhow::bail!("SBOM file '{}' already exists. Please choose a different filename or remove the existing file.", sbom_path.display());
+                    } else {
+                        fs::remove_file(&sbom_path)
+                            .context("Failed to remove existing SBOM file")?;
+                    }
+                }
+            }
+            OutputKind::KernelRootfs => {
+                for path in [
+                    options.arch.kernel_file(),
+                    DIRECT_BOOT_ROOTFS_FILE,
+                    DIRECT_BOOT_DESCRIPTOR_FILE,
+                ]
+                .iter()
+                .map(|file| Path::new(&options.output_file).join(file))
+                .chain(Self::sbom_path(options))
+                {
+                    if path.exists() {
+                        if !options.force {
+                            anyhow::bail!("Output file '{}' already exists. Please choose a different output directory or remove the existing file.", path.display());
+                        } else {
+                            fs::remove_file(&path)
+                                .context("Failed to remove existing output file")?;
+                        }
+                    }
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// Print success message and instructions for running the image.
+    fn print_instructions(
+        options: &BuildOptions,
+        progress: &Progress,
+        root_device: &str,
+        verity: Option<&Verity>,
+    ) -> Result<()> {
+        let print = |line: &str| progress.print(line);
         print(&format!("Image created successfully ✅"))?;
+        print(&format!("\nRoot device: {}", root_device))?;
+        if let Some(verity) = verity {
+            print(&format!("\nRoot hash: {}", verity.root_hash))?;
+        }
+        let image_path = Self::image_path(options);
+        if options.reproducible {
+            print(&format!(
+                "\nImage digest: sha256:{}\n",
+                report::sha256(&image_path)?
+            ))?;
+        }
         print(&format!("\nYou can run the image with qemu like this:\n"))?;
-        print(&format!("qemu-system-x86_64 \\\n"))?;
+        print(&format!("qemu-system-{} \\\n", options.arch))?;
         print(&format!("   -m 1024 \\\n"))?;
-        print(&format!("   -enable-kvm \\\n"))?;
+        match options.arch {
+            Arch::X86_64 => print(&format!("   -enable-kvm \\\n"))?,
+            Arch::Aarch64 => print(&format!("   -machine virt -cpu max \\\n"))?,
+        }
         print(&format!("   -nographic \\\n"))?;
-        print(&format!("   --hda ./{}\n", options.output_file))?;
+        match options.output_kind {
+            OutputKind::Disk if options.arch == Arch::Aarch64 => {
+                // virt machine has no IDE controller.
+                print(&format!("   -bios /usr/share/AAVMF/AAVMF_CODE.fd \\\n"))?;
+                print(&format!(
+                    "   -drive file=./{},if=virtio,format=raw\n",
+                    options.output_file
+                ))?;
+            }
+            OutputKind::Disk => {
+                if options.boot_mode == BootMode::Uefi {
+                    print(&format!("   -bios /usr/share/ovmf/OVMF.fd \\\n"))?;
+                }
+                print(&format!("   --hda ./{}\n", options.output_file))?;
+            }
+            OutputKind::KernelRootfs => {
+                let output_dir = Path::new(&options.output_file);
+                print(&format!(
+                    "   -kernel {} \\\n",
+                    output_dir.join(options.arch.kernel_file()).display()
+                ))?;
+                if options.initramfs {
+                    print(&format!(
+                        "   -initrd {} \\\n",
+                        output_dir.join(initramfs::INITRAMFS_FILE).display()
+                    ))?;
+                }
+                print(&format!(
+                    "   -append \"{}\" \\\n",
+                    Self::kernel_cmdline(options, root_device, verity)
+                ))?;
+                print(&format!(
+                    "   -drive file={},if=virtio,format=raw{}\n",
+                    image_path.display(),
+                    if options.rw_root { "" } else { ",readonly=on" }
+                ))?;
+            }
+        }
         Ok(())
     }
-}
 
-impl SkopeoSyslinuxBuilder {
-    // Create an empty disk image file of the specified size
-    fn create_disk_image(size: &str, output_file: &str) -> Result<()> {
-        Self::run_command(&["truncate", "-s", size, output_file], false)
-            .context("Failed to create disk image")
+    /// Fill in build result, partition layout and checksums of output files.
+    fn complete_report(
+        report: &mut report::BuildReport,
+        options: &BuildOptions,
+        result: &Result<(String, Option<Verity>)>,
+    ) -> Result<()> {
+        let (root_device, verity) = match result {
+            Ok(result) => result,
+            Err(e) => {
+                report.error = Some(format!("{:#}", e));
+                return Ok(());
+            }
+        };
+        report.succeeded = true;
+        report.root_device = Some(root_device.clone());
+        report.verity = verity.clone();
+
+        match options.output_kind {
+            OutputKind::Disk => {
+                let filesystems = ["vfat".to_string(), options.rootfs_format.to_string()]
+                    .into_iter()
+                    .chain(
+                        options
+                            .extra_partitions
+                            .iter()
+                            .map(|partition| partition.filesystem.to_string()),
+                    )
+                    .collect::<Vec<_>>();
+                report.partitions = report::read_partitions(&options.output_file, &filesystems)?;
+                report
+                    .outputs
+                    .push(report::OutputFile::new(Path::new(&options.output_file))?);
+            }
+            OutputKind::KernelRootfs => {
+                let output_dir = Path::new(&options.output_file);
+                let mut files = vec![options.arch.kernel_file()];
+                if options.initramfs {
+                    files.push(initramfs::INITRAMFS_FILE);
+                }
+                files.extend([DIRECT_BOOT_ROOTFS_FILE, DIRECT_BOOT_DESCRIPTOR_FILE]);
+                for file in files {
+                    report
+                        .outputs
+                        .push(report::OutputFile::new(&output_dir.join(file))?);
+                }
+            }
+        }
+        if let Some(sbom_path) = Self::sbom_path(options) {
+            report.outputs.push(report::OutputFile::new(&sbom_path)?);
+        }
+        Ok(())
     }
 
-    // Create partitions on the disk image using fdisk
-    fn create_partitions(output_file: &str) -> Result<()> {
-        let fdisk_commands = "o\nn\np\n1\n2048\n+200M\nn\np\n2\n\n\nt\n2\n83\nw\n";
-        let mut child = Command::new("fdisk")
-            .arg(output_file)
-            .stdin(std::process::Stdio::piped())
-            .stdout(std::process::Stdio::null())
-            .stderr(std::process::Stdio::null())
-            .spawn()
-            .context("Failed to spawn fdisk command")?;
+    /// Disk image or root filesystem image of direct kernel boot.
+    fn image_path(options: &BuildOptions) -> PathBuf {
+        match options.output_kind {
+            OutputKind::Disk => Path::new(&options.output_file).to_path_buf(),
+            OutputKind::KernelRootfs => {
+                Path::new(&options.output_file).join(DIRECT_BOOT_ROOTFS_FILE)
+            }
+        }
+    }
 
-        child
-            .stdin
-            .as_mut()
-            .unwrap()
-            .write_all(fdisk_commands.as_bytes())
-            .context("Failed to write fdisk commands")?;
+    fn write_sbom(
+        options: &BuildOptions,
+        format: SbomFormat,
+        packages: &[sbom::Package],
+        reproducible: Option<u64>,
+    ) -> Result<()> {
+        let image_digest = reproducible
+            .map(|_| report::sha256(&Self::image_path(options)))
+            .transpose()?;
+        sbom::write(
+            &Self::sbom_path(options).context("SBOM format is not set")?,
+            format,
+            &Self::image_name(options),
+            packages,
+            reproducible.zip(image_digest.as_deref()),
+        )
+    }
 
-        let status = child.wait().context("Failed to wait for fdisk command")?;
+    /// SBOM is written next to the disk image or into the output directory.
+    fn sbom_path(options: &BuildOptions) -> Option<PathBuf> {
+        let format = options.sbom?;
+        Some(match options.output_kind {
+            OutputKind::Disk => {
+                PathBuf::from(format!("{}.{}", options.output_file, format.extension()))
+            }
+            OutputKind::KernelRootfs => {
+                Path::new(&options.output_file).join(format!("sbom.{}", format.extension()))
+            }
+        })
+    }
 
-        if status.success() {
-            Ok(())
+    /// Name of the image used in SBOM: file name of the disk image or output directory.
+    fn image_name(options: &BuildOptions) -> String {
+        Path::new(&options.output_file)
+            .file_name()
+            .map(|name| name.to_string_lossy().to_string())
+            .unwrap_or_else(|| options.output_file.clone())
+    }
+
+    /// Calculate disk image size for the build.
+    /// Fails if explicitly requested size is too small for the content.
+    fn calculate_image_size(options: &BuildOptions, usage: size::ContentUsage) -> Result<u64> {
+        let mut extra_payload = 0;
+        if options.init.is_none() {
+            extra_payload += size::MIA_RESERVED_SIZE;
+        }
+        if options.nvidia_drivers {
+            extra_payload += size::NVIDIA_RESERVED_SIZE;
+        }
+        if options.kernel_file.is_none()
+            && (options.modules_install == ModulesInstall::All
+                || (options.modules_install == ModulesInstall::Requested
+                    && !options.kernel_modules.is_empty()))
+        {
+            extra_payload += size::MODULES_RESERVED_SIZE;
+        }
+
+        // Kernel is exported as a separate file for direct kernel boot.
+        if options.output_kind == OutputKind::KernelRootfs {
+            let extra_percent = match options.image_size {
+                ImageSize::Auto { extra_percent } => extra_percent,
+                ImageSize::Fixed(_) => 0,
+            };
+            let required = size::root_partition_size(
+                usage,
+                extra_payload,
+                extra_percent,
+                options.rootfs_format,
+            );
+            return match options.image_size {
+                ImageSize::Fixed(image_size) if image_size < required => anyhow::bail!(
+                    "Root filesystem image size {} is too small: at least {} is required for {} of root filesystem content. Use --size auto or increase --size.",
+                    size::format_size(image_size),
+                    size::format_size(required),
+                    size::format_size(usage.bytes)
+                ),
+                ImageSize::Fixed(image_size) => Ok(image_size),
+                ImageSize::Auto { .. } => Ok(required),
+            };
+        }
+
+        // Kernel is installed into boot partition, which has fixed size.
+        let kernel_size = if let Some(kernel_file) = &options.kernel_file {
+            fs::metadata(kernel_file)
+                .context("Failed to read kernel file metadata")?
+                .len()
         } else {
-            Err(anyhow::anyhow!(
-                "fdisk command failed with status {}",
-                status
-            ))
+            // Cached build may not match requested config, assume the usual size instead.
+            size::DEFAULT_KERNEL_SIZE
+        };
+        let initramfs_size = if options.initramfs {
+            size::INITRAMFS_RESERVED_SIZE
+        } else {
+            0
+        };
+        if kernel_size + initramfs_size + size::BOOTLOADER_RESERVED_SIZE > options.boot_size {
+            anyhow::bail!(
+                "Kernel ({}) doesn't fit into boot partition ({}). Increase --boot-size.",
+                size::format_size(kernel_size),
+                size::format_size(options.boot_size)
+            );
         }
-    }
+        let extra_sizes = options
+            .extra_partitions
+            .iter()
+            .map(|partition| partition.size)
+            .collect::<Vec<_>>();
+        let disk_size = |root_partition_size: u64| {
+            size::disk_size(options.boot_size, root_partition_size, &extra_sizes)
+        };
 
-    // Set the bootable flag on the first partition
-    fn set_bootable_flag(output_file: &str) -> Result<()> {
-        let fdisk_bootable = "a\n1\nw\n";
-        let mut child = Command::new("fdisk")
-            .arg(output_file)
-            .stdin(std::process::Stdio::piped())
-            .stdout(std::process::Stdio::null())
-            .stderr(std::process::Stdio::null())
-            .spawn()
-            .context("Failed to spawn fdisk command for setting bootable flag")?;
+        // Verity hash tree is stored in the root partition after the filesystem.
+        let root_partition_size = |filesystem_size: u64| {
+            if options.verity {
+                filesystem_size + size::align_up(size::verity_hash_size(filesystem_size), size::MIB)
+            } else {
+                filesystem_size
+            }
+        };
 
-        child
-            .stdin
-            .as_mut()
-            .unwrap()
-            .write_all(fdisk_bootable.as_bytes())
-            .context("Failed to write fdisk bootable commands")?;
+        match options.image_size {
+            ImageSize::Auto { extra_percent } => {
+                Ok(disk_size(root_partition_size(size::root_partition_size(
+                    usage,
+                    extra_payload,
+                    extra_percent,
+                    options.rootfs_format,
+                ))))
+            }
+            ImageSize::Fixed(image_size) => {
+                let required = disk_size(root_partition_size(size::root_partition_size(
+                    usage,
+                    extra_payload,
+                    0,
+                    options.rootfs_format,
+                )));
+                if image_size < required {
+                    anyhow::bail!(
+                        "Image size {} is too small: at least {} is required for {} of root filesystem content. Use --size auto or increase --size.",
+                        size::format_size(image_size),
+                        size::format_size(required),
+                        size::format_size(usage.bytes)
+                    );
+                }
+                Ok(image_size)
+            }
+        }
+    }
+
+    // Measure disk usage of directory content (as root, because some files may be unreadable)
+    fn get_content_usage(dir: &Path) -> Result<size::ContentUsage> {
+        let dir = dir.to_str().context("Invalid rootfs path")?;
+        let parse_du = |output: String| -> Result<u64> {
+            output
+                .split_whitespace()
+                .next()
+                .context("Empty du output")?
+                .parse()
+                .context("Failed to parse du output")
+        };
+        let bytes = parse_du(Self::run_command_output(
+            &["du", "-s", "--block-size=1", dir],
+            true,
+        )?)?;
+        let inodes = parse_du(Self::run_command_output(
+            &["du", "-s", "--inodes", dir],
+            true,
+        )?)?;
+        Ok(size::ContentUsage { bytes, inodes })
+    }
 
-        let status = child.wait().context("Failed to wait for fdisk command")?;
+    // Create an empty disk image file of the specified size
+    fn create_disk_image(size: u64, output_file: &str) -> Result<()> {
+        Self::run_command(&["truncate", "-s", &size.to_string(), output_file], false)
+            .context("Failed to create disk image")
+    }
 
-        if status.success() {
-            Ok(())
+    // Create boot partition, root partition taking the rest of the disk image
+    // and extra partitions at the end. Boot partition is marked bootable for SYSLINUX MBR code.
+    fn create_partitions(
+        output_file: &str,
+        boot_mode: BootMode,
+        boot_size: u64,
+        extra_partitions: &[ExtraPartition],
+        reproducible: bool,
+    ) -> Result<()> {
+        let (table, disk_id) = if boot_mode.uses_gpt() {
+            (TableKind::Gpt, REPRODUCIBLE_DISK_GUID)
         } else {
-            Err(anyhow::anyhow!(
-                "fdisk command failed with status {}",
-                status
-            ))
+            (TableKind::Mbr, REPRODUCIBLE_DISK_ID)
+        };
+        let uuid = |uuid: &str| reproducible.then(|| uuid.to_string());
+        let mut partitions = vec![
+            PartitionSpec {
+                name: "BOOT".to_string(),
+                size: Some(boot_size),
+                r#type: PartitionType::Boot,
+                bootable: boot_mode.supports_bios(),
+                uuid: uuid(REPRODUCIBLE_BOOT_PARTITION_GUID),
+            },
+            PartitionSpec {
+                name: "ROOTFS".to_string(),
+                size: None,
+                r#type: PartitionType::Linux,
+                bootable: false,
+                uuid: uuid(REPRODUCIBLE_ROOT_PARTITION_GUID),
+            },
+        ];
+        for (partition, number) in extra_partitions.iter().zip(FIRST_EXTRA_PARTITION..) {
+            partitions.push(PartitionSpec {
+                name: partition.label.clone(),
+                size: Some(partition.size),
+                r#type: partition.filesystem.partition_type(),
+                bootable: false,
+                uuid: uuid(&format!(
+                    "{}{:012x}",
+                    REPRODUCIBLE_EXTRA_PARTITION_GUID_PREFIX, number
+                )),
+            });
+        }
+        let layout = Layout {
+            table,
+            alignment: size::PARTITION_OFFSET,
+            disk_id: reproducible.then(|| disk_id.to_string()),
+            partitions,
+        };
+        let partitions = layout
+            .write(Path::new(output_file))
+            .context("Failed to create partitions")?;
+        for partition in partitions {
+            debug!(
+                "partition {}: start {}, size {}, PARTUUID {}",
+                partition.number, partition.start, partition.size, partition.uuid
+            );
         }
+        Ok(())
     }
 
-    // Set up a loop device for the disk image
-    fn setup_loop_device(output_file: &str) -> Result<()> {
-        Self::run_command(&["losetup", "-fP", output_file], true)
-            .context("Failed to set up loop device")
+    // Create filesystems on the partitions.
+    // Staged root filesystems are generated later by `create_rootfs_from_staging`.
+    fn create_filesystems(
+        loop_device: &LoopDevice,
+        boot_mode: BootMode,
+        extra_partitions: &[ExtraPartition],
+        staged_root: bool,
+        reproducible: Option<u64>,
+    ) -> Result<()> {
+        let boot_device = loop_device.partition(1);
+        let epoch_env = format!("SOURCE_DATE_EPOCH={}", reproducible.unwrap_or_default());
+        let mut command = vec!["mkfs.vfat", "-n", "BOOT"];
+        if boot_mode.uses_gpt() {
+            // FAT32 is the filesystem required for EFI system partition.
+            command.extend(["-F", "32"]);
+        }
+        if reproducible.is_some() {
+            command.splice(0..0, ["env", &epoch_env]);
+            command.extend(["--invariant", "-i", REPRODUCIBLE_BOOT_VOLUME_ID]);
+        }
+        command.push(&boot_device);
+        Self::run_command(&command, true).context("Failed to create VFAT filesystem")?;
+        if !staged_root {
+            Self::run_command(
+                &["mkfs.ext4", "-L", ROOTFS_LABEL, &loop_device.partition(2)],
+                true,
+            )
+            .context("Failed to create EXT4 filesystem")?;
+        }
+        for (partition, number) in extra_partitions.iter().zip(FIRST_EXTRA_PARTITION..) {
+            Self::create_extra_filesystem(
+                partition,
+                &loop_device.partition(number),
+                number,
+                reproducible,
+            )?;
+        }
+        Ok(())
     }
 
-    // Create filesystems on the partitions
-    fn create_filesystems(output_file: &str) -> Result<()> {
-        let loop_device = Self::get_loop_device(output_file)?;
-        Self::run_command(
-            &["mkfs.vfat", "-n", "BOOT", &format!("{}p1", loop_device)],
-            true,
-        )
-        .context("Failed to create VFAT filesystem")?;
-        Self::run_command(
-            &["mkfs.ext4", "-L", "ROOTFS", &format!("{}p2", loop_device)],
-            true,
-        )
-        .context("Failed to create EXT4 filesystem")?;
+    // Create empty filesystem on the extra partition.
+    // In reproducible builds its identifiers are derived from the partition number.
+    fn create_extra_filesystem(
+        partition: &ExtraPartition,
+        device: &str,
+        number: u32,
+        reproducible: Option<u64>,
+    ) -> Result<()> {
+        let epoch = reproducible
+            .map(|epoch| epoch.to_string())
+            .unwrap_or_default();
+        let epoch_env = format!("SOURCE_DATE_EPOCH={}", epoch);
+        let fake_time_env = format!("E2FSPROGS_FAKE_TIME={}", epoch);
+        let uuid = format!(
+            "{}{:012x}",
+            REPRODUCIBLE_EXTRA_FILESYSTEM_UUID_PREFIX, number
+        );
+        let hash_seed = format!("hash_seed={}", uuid);
+        let volume_id = format!("{}{:02x}", &REPRODUCIBLE_BOOT_VOLUME_ID[..6], number);
+        let label = partition.filesystem_label();
+
+        let mut command = match partition.filesystem {
+            ExtraFilesystem::Ext4 => {
+                let mut command = vec!["mkfs.ext4", "-L", &label];
+                if reproducible.is_some() {
+                    command.splice(0..0, ["env", &epoch_env, &fake_time_env]);
+                    command.extend(["-U", &uuid, "-E", &hash_seed]);
+                }
+                command
+            }
+            ExtraFilesystem::Vfat => {
+                let mut command = vec!["mkfs.vfat", "-n", &label];
+                if reproducible.is_some() {
+                    command.splice(0..0, ["env", &epoch_env]);
+                    command.extend(["--invariant", "-i", &volume_id]);
+                }
+                command
+            }
+        };
+        command.push(device);
+        Self::run_command(&command, true).context(format!(
+            "Failed to create {} filesystem on extra partition {}",
+            partition.filesystem, partition.label
+        ))
+    }
+
+    // Mount the filesystems.
+    // Staged filesystems are plain directories instead of mounts.
+    fn mount_filesystems(
+        workspace: &mut Workspace,
+        staged_root: bool,
+        staged_boot: bool,
+    ) -> Result<()> {
+        let root_dir = workspace.root_dir();
+        let boot_dir = workspace.boot_dir();
+        fs::create_dir_all(&root_dir).context("Failed to create mount directory")?;
+        if !staged_root {
+            let root_device = workspace.loop_device()?.partition(2);
+            workspace
+                .mount(&root_device, &root_dir)
+                .context("Failed to mount root filesystem")?;
+        } else {
+            Self::prepare_staging_root(&root_dir)?;
+        }
+        Self::run_command(&["mkdir", "-p", boot_dir.to_str().unwrap()], true)
+            .context("Failed to create boot directory")?;
+        if !staged_boot {
+            let boot_device = workspace.loop_device()?.partition(1);
+            workspace
+                .mount(&boot_device, &boot_dir)
+                .context("Failed to mount boot filesystem")?;
+        }
         Ok(())
     }
 
-    // Mount the filesystems
-    fn mount_filesystems(output_file: &str) -> Result<()> {
-        let loop_device = Self::get_loop_device(output_file)?;
-        fs::create_dir_all(env::temp_dir().join("mnt"))
-            .context("Failed to create mount directory")?;
-        Self::run_command(
-            &[
-                "mount",
-                &format!("{}p2", loop_device),
-                env::temp_dir().join("mnt").to_str().unwrap(),
-            ],
-            true,
-        )
-        .context("Failed to mount root filesystem")?;
-        Self::run_command(
-            &[
-                "mkdir",
-                "-p",
-                env::temp_dir().join("mnt").join("boot").to_str().unwrap(),
-            ],
-            true,
-        )
-        .context("Failed to create boot directory")?;
-        Self::run_command(
-            &[
-                "mount",
-                &format!("{}p1", loop_device),
-                env::temp_dir().join("mnt").join("boot").to_str().unwrap(),
-            ],
-            true,
-        )
-        .context("Failed to mount boot filesystem")?;
+    // Prepare staging directory, which becomes root directory of the generated filesystem
+    fn prepare_staging_root(staging_root: &Path) -> Result<()> {
+        fs::create_dir_all(staging_root).context("Failed to create staging directory")?;
+        Self::run_command(&["chown", "0:0", staging_root.to_str().unwrap()], true)
+            .context("Failed to change owner of staging directory")?;
+        Self::run_command(&["chmod", "755", staging_root.to_str().unwrap()], true)
+            .context("Failed to change mode of staging directory")?;
         Ok(())
     }
 
-    // Install the root filesystem from a container image
-    fn install_rootfs_from_container(
+    // Extract the root filesystem from a container image into `target_dir`
+    // and return manifest digest of the image.
+    fn extract_container(
         container_source: &str,
+        platform: &Platform,
+        target_dir: &Path,
         rt_config: &mut RuntimeConfig,
-    ) -> Result<()> {
+    ) -> Result<String> {
         // This temp dir will be removed on dropping.
-        let target_dir = TempDir::new("").context("Failed to create temporary directory")?;
-
-        // Copy the container image to a directory
-        Self::run_command(
-            &[
-                "skopeo",
-                "copy",
-                container_source,
-                &format!("dir:{}", target_dir.path().display()),
-            ],
-            false,
-        )
-        .context("Failed to copy container image")?;
-
-        // Read image manifest
-        let manifest = ImageManifest::from_file(target_dir.path().join("manifest.json"))
-            .context("Failed to read image manifest")?;
+        let image_dir = TempDir::new("image").context("Failed to create temporary directory")?;
+        let image = container::fetch(container_source, platform, image_dir.path())?;
 
         // Extract all layers of image into target dir
-        for layer in manifest.layers() {
-            let layer_path = target_dir.path().join(layer.digest().digest());
-            log::debug!(
-                "unpack layer {} from {}",
-                layer.digest(),
-                layer_path.display()
-            );
+        for layer_path in &image.layers {
+            log::debug!("unpack layer {}", layer_path.display());
             // Unpack with root permissions
             match Self::run_command(
                 &[
@@ -341,15 +1399,16 @@ impl SkopeoSyslinuxBuilder {
                     "-xf",
                     layer_path.to_str().unwrap(),
                     "-C",
-                    target_dir.path().to_str().unwrap(),
+                    target_dir.to_str().unwrap(),
                 ],
                 true,
             ) {
-                Ok(_) => {
+                Ok(_) if image.temporary => {
                     log::debug!("remove layer {}", layer_path.display());
-                    fs::remove_file(&layer_path).context("Failed to remove layer file")?;
+                    fs::remove_file(layer_path).context("Failed to remove layer file")?;
                     log::debug!("removed layer {}", layer_path.display());
                 }
+                Ok(_) => {}
                 Err(_) => {
                     log::warn!("Failed to unpack layer"); // TODO: Investigate why this happens.
                 }
@@ -358,30 +1417,7 @@ impl SkopeoSyslinuxBuilder {
 
         log::debug!("unpacked all layers");
 
-        let config_path = target_dir.path().join(manifest.config().digest().digest());
-        let config = ImageConfiguration::from_file(&config_path)
-            .context("Failed to read image configuration")?;
-        log::debug!("unpacked config {}", config_path.display());
-        fs::remove_file(&config_path).context("Failed to remove config file")?;
-        log::debug!("removed config {}", config_path.display());
-
-        // Copy the extracted rootfs to the mounted filesystem
-        Self::run_command(
-            &[
-                "sh",
-                "-c",
-                &format!(
-                    "cp -a {}/. {}", // NOTE: It preserves all attributes, symlinks and includes hidden files.
-                    target_dir.path().display(),
-                    env::temp_dir().join("mnt").to_str().unwrap()
-                ),
-            ],
-            true,
-        )
-        .context("Failed to copy rootfs to mounted filesystem")?;
-
-        // Ensure all changes are written to disk
-        Self::run_command(&["sync"], true).context("Failed to sync filesystem")?;
+        let config = image.config;
 
         // Extract runtime config from the container manifest.
         if let Some(exec_params) = config.config() {
@@ -418,60 +1454,91 @@ impl SkopeoSyslinuxBuilder {
             }
         }
 
-        Ok(())
+        Ok(image.digest)
     }
 
-    // Install the root filesystem from a directory
-    fn install_rootfs_from_directory(rootfs_dir: &str) -> Result<()> {
-        // Copy the rootfs directory to the mounted filesystem
+    // Install the root filesystem extracted from a container image
+    fn install_rootfs_from_staging(staging_dir: &Path, root_dir: &Path) -> Result<()> {
+        // Copy the extracted rootfs to the mounted filesystem
         Self::run_command(
             &[
                 "sh",
                 "-c",
                 &format!(
-                    "cp -r {}/* {}",
-                    rootfs_dir,
-                    env::temp_dir().join("mnt").to_str().unwrap()
+                    "cp -a {}/. {}", // NOTE: It preserves all attributes, symlinks and includes hidden files.
+                    staging_dir.display(),
+                    root_dir.display()
                 ),
             ],
             true,
         )
-        .context("Failed to copy rootfs from directory")?;
+        .context("Failed to copy rootfs to mounted filesystem")?;
 
         // Ensure all changes are written to disk
         Self::run_command(&["sync"], true).context("Failed to sync filesystem")?;
         Ok(())
     }
 
-    // Build and install the root filesystem from a Containerfile
-    fn build_and_install_rootfs_from_containerfile(
-        containerfile: &str,
-        rt_config: &mut RuntimeConfig,
-    ) -> Result<()> {
-        let container_source = "containers-storage:localhost/custom_image:latest";
+    // Install the root filesystem from a tarball
+    fn install_rootfs_from_tarball(rootfs_tar: &str, root_dir: &Path) -> Result<()> {
+        tarball::extract(rootfs_tar, root_dir)?;
 
-        // Build the container image from the Containerfile
-        Self::run_command(
-            &[
-                "podman",
-                "build",
-                "-t",
-                "localhost/custom_image:latest",
-                "-f",
-                containerfile,
-            ],
-            false,
-        )
-        .context("Failed to build container image from Containerfile")?;
+        // Ensure all changes are written to disk
+        Self::run_command(&["sync"], true).context("Failed to sync filesystem")?;
+        Ok(())
+    }
+
+    /// Install the root filesystem from a directory.
+    ///
+    /// Copy runs as root in the hidden `copy-rootfs` command of this executable, see [`rootfs`].
+    fn install_rootfs_from_directory(
+        options: &BuildOptions,
+        rootfs_dir: &str,
+        root_dir: &Path,
+    ) -> Result<rootfs::CopyStats> {
+        let exe = std::env::current_exe().context("Failed to find gvltctl executable")?;
+        let mut command = vec![exe.to_str().unwrap(), "copy-rootfs"];
+        for exclude in &options.rootfs_excludes {
+            command.extend(["--exclude", exclude]);
+        }
+        let owner_map = options
+            .owner_map
+            .iter()
+            .map(|map| map.to_string())
+            .collect::<Vec<_>>();
+        for map in &owner_map {
+            command.extend(["--owner-map", map]);
+        }
+        command.extend(["--", rootfs_dir, root_dir.to_str().unwrap()]);
+
+        let output = Self::run_command_output(&command, true)
+            .context("Failed to copy rootfs from directory")?;
+        let stats =
+            serde_json::from_str(&output).context("Failed to parse rootfs copy statistics")?;
+
+        // Ensure all changes are written to disk
+        Self::run_command(&["sync"], true).context("Failed to sync filesystem")?;
+        Ok(stats)
+    }
 
-        Self::install_rootfs_from_container(container_source, rt_config)
-            .context("Failed to install rootfs from built container")
+    // Build the container image from a Containerfile and extract its root filesystem
+    fn build_and_extract_containerfile(
+        build: &container::ContainerfileBuild,
+        platform: &Platform,
+        target_dir: &Path,
+        rt_config: &mut RuntimeConfig,
+    ) -> Result<String> {
+        // Built image is removed when it goes out of scope.
+        let image = container::build(build, platform)?;
+
+        Self::extract_container(&image.source(), platform, target_dir, rt_config)
+            .context("Failed to extract rootfs from built container")
     }
 
     /// Create `/mnt/input` and `/mnt/output` directories in the VM, which will be used as input and
     /// output context mountpoints.
-    fn create_mount_dirs() -> Result<()> {
-        let mnt = env::temp_dir().join("mnt").join("mnt");
+    fn create_mount_dirs(root_dir: &Path) -> Result<()> {
+        let mnt = root_dir.join("mnt");
         for path in [mnt.join("input"), mnt.join("output")] {
             if !path.exists() {
                 Self::run_command(&["mkdir", "-p", &format!("{}", path.display())], true)
@@ -483,194 +1550,30 @@ impl SkopeoSyslinuxBuilder {
 
     // Install the Linux kernel
     fn install_kernel(
-        version: &str,
-        kernel_url: &str,
+        kernel_dir: &Path,
+        arch: Arch,
+        root_dir: &Path,
+        modules_install: ModulesInstall,
         nvidia_drivers: bool,
         kernel_modules: &mut Vec<String>,
     ) -> Result<()> {
-        let home_dir = std::env::var("HOME").context("Failed to get HOME environment variable")?;
-        let kernel_dir = format!("{}/.linux-builds/{}", home_dir, version);
-        let bzimage_path = format!("{}/arch/x86/boot/bzImage", kernel_dir);
-
-        // Check if the bzImage already exists
-        if Path::new(&bzimage_path).exists() {
-            // println!("Kernel bzImage already exists, skipping build");
-        } else {
-            // Clone the specific version from the remote repository
-
-            // Check if the kernel directory already exists
-            if Path::new(&kernel_dir).exists() {
-                // If it exists, do a git pull
-                debug!("Kernel directory already exists");
-            } else {
-                // If it doesn't exist, clone the repository
-                let clone_args = if version == "latest" {
-                    vec!["git", "clone", "--depth", "1", kernel_url, &kernel_dir]
-                } else {
-                    vec![
-                        "git",
-                        "clone",
-                        "--depth",
-                        "1",
-                        "--branch",
-                        version,
-                        kernel_url,
-                        &kernel_dir,
-                    ]
-                };
-                Self::run_command(&clone_args, false)
-                    .context("Failed to clone kernel repository")?;
-            }
-
-            let current_dir = std::env::current_dir().context("Failed to get current directory")?;
-            std::env::set_current_dir(&kernel_dir)
-                .context("Failed to change to kernel directory")?;
-
-            // Configure the kernel
-            Self::run_command(&["make", "x86_64_defconfig"], false)
-                .context("Failed to configure kernel")?;
-
-            // SQUASHFS support
-            Self::run_command(&["scripts/config", "--enable", "CONFIG_SQUASHFS"], false)
-                .context("Failed to enable CONFIG_SQUASHFS flag to kernel config ")?;
-
-            Self::run_command(
-                &["scripts/config", "--disable", "CONFIG_SQUASHFS_FILE_CACHE"],
-                false,
-            )
-            .context("Failed to disable CONFIG_SQUASHFS_FILE_CACHE flag to kernel config ")?;
-
-            Self::run_command(
-                &["scripts/config", "--enable", "CONFIG_SQUASHFS_FILE_DIRECT"],
-                false,
-            )
-            .context("Failed to enable CONFIG_SQUASHFS_FILE_DIRECT flag to kernel config")?;
-
-            Self::run_command(
-                &[
-                    "scripts/config",
-                    "--enable",
-                    "CONFIG_SQUASHFS_DECOMP_SINGLE",
-                ],
-                false,
-            )
-            .context("Failed to enable CONFIG_SQUASHFS_DECOMP_SINGLE flag to kernel config")?;
-
-            Self::run_command(
-                &["scripts/config", "--enable", "CONFIG_SQUASHFS_DECOMP_MULTI"],
-                false,
-            )
-            .context("Failed to enable CONFIG_SQUASHFS_DECOMP_MULTI flag to kernel config")?;
-
-            Self::run_command(
-                &[
-                    "scripts/config",
-                    "--enable",
-                    "CONFIG_SQUASHFS_DECOMP_MULTI_PERCPU",
-                ],
-                false,
-            )
-            .context(
-                "Failed to enable CONFIG_SQUASHFS_DECOMP_MULTI_PERCPU flag to kernel config",
-            )?;
-
-            Self::run_command(
-                &[
-                    "scripts/config",
-                    "--enable",
-                    "CONFIG_SQUASHFS_CHOICE_DECOMP_BY_MOUNT",
-                ],
-                false,
-            )
-            .context(
-                "Failed to enable CONFIG_SQUASHFS_CHOICE_DECOMP_BY_MOUNT flag to kernel config",
-            )?;
-            Self::run_command(
-                &[
-                    "scripts/config",
-                    "--enable",
-                    "CONFIG_SQUASHFS_MOUNT_DECOMP_THREADS",
-                ],
-                false,
-            )
-            .context(
-                "Failed to enable CONFIG_SQUASHFS_MOUNT_DECOMP_THREADS flag to kernel config",
-            )?;
-            Self::run_command(
-                &["scripts/config", "--enable", "CONFIG_SQUASHFS_XATTR"],
-                false,
-            )
-            .context("Failed to enable CONFIG_SQUASHFS_XATTR flag to kernel config")?;
-            Self::run_command(
-                &["scripts/config", "--enable", "CONFIG_SQUASHFS_ZLIB"],
-                false,
-            )
-            .context("Failed to enable CONFIG_SQUASHFS_ZLIB flag to kernel config")?;
-            Self::run_command(
-                &["scripts/config", "--enable", "CONFIG_SQUASHFS_LZ4"],
-                false,
-            )
-            .context("Failed to enable CONFIG_SQUASHFS_LZ4 flag to kernel config")?;
-            Self::run_command(
-                &["scripts/config", "--enable", "CONFIG_SQUASHFS_LZO"],
-                false,
-            )
-            .context("Failed to enable CONFIG_SQUASHFS_LZO flag to kernel config")?;
-            Self::run_command(&["scripts/config", "--enable", "CONFIG_SQUASHFS_XZ"], false)
-                .context("Failed to enable CONFIG_SQUASHFS_XZ flag to kernel config")?;
-            Self::run_command(
-                &["scripts/config", "--enable", "CONFIG_SQUASHFS_ZSTD"],
-                false,
-            )
-            .context("Failed to enable CONFIG_SQUASHFS_ZSTD flag to kernel config")?;
-            Self::run_command(
-                &[
-                    "scripts/config",
-                    "--enable",
-                    "CONFIG_SQUASHFS_4K_DEVBLK_SIZE",
-                ],
-                false,
-            )
-            .context("Failed to enable CONFIG_SQUASHFS_4K_DEVBLK_SIZE flag to kernel config")?;
-            Self::run_command(
-                &[
-                    "scripts/config",
-                    "--set-val",
-                    "3",
-                    "CONFIG_SQUASHFS_FRAGMENT_CACHE_SIZE",
-                ],
-                false,
-            )
-            .context("Failed to set CONFIG_SQUASHFS_FRAGMENT_CACHE_SIZE value to kernel config")?;
-            Self::run_command(
-                &["scripts/config", "--disable", "CONFIG_SQUASHFS_EMBEDDED"],
-                false,
-            )
-            .context("Failed to disable CONFIG_SQUASHFS_EMBEDDED flag to kernel config ")?;
-
-            // Build the kernel
-            Self::run_command(&["make", &format!("-j{}", num_cpus::get())], false)
-                .context("Failed to build kernel")?;
-
-            std::env::set_current_dir(current_dir)
-                .context("Failed to change back to original directory")?;
-        }
+        let image_path = kernel::image_path(kernel_dir, arch);
 
         // Copy the built kernel to the boot partition
         Self::run_command(
             &[
                 "cp",
-                &bzimage_path,
-                env::temp_dir().join("mnt").join("boot").to_str().unwrap(),
+                image_path.to_str().unwrap(),
+                root_dir.join("boot").to_str().unwrap(),
             ],
             true,
         )
         .context("Failed to copy kernel to boot partition")?;
 
+        kernel::modules::install(kernel_dir, root_dir, modules_install, kernel_modules)?;
+
         if nvidia_drivers {
-            let kernel_source_dir = std::path::PathBuf::from(&kernel_dir);
-            let vm_root_path = env::temp_dir().join("mnt");
-            nvidia::install_drivers(kernel_source_dir, vm_root_path)
+            nvidia::install_drivers(kernel_dir, root_dir)
                 .context("Unable to install NVIDIA drivers")?;
 
             kernel_modules.push("nvidia".to_string());
@@ -682,55 +1585,159 @@ impl SkopeoSyslinuxBuilder {
     }
 
     // Install a precompiled kernel
-    fn install_precompiled_kernel(kernel_path: &str) -> Result<()> {
+    fn install_precompiled_kernel(kernel_path: &str, boot_dir: &Path, arch: Arch) -> Result<()> {
         Self::run_command(
             &[
                 "cp",
                 kernel_path,
-                env::temp_dir()
-                    .join("mnt")
-                    .join("boot")
-                    .join("bzImage")
-                    .to_str()
-                    .unwrap(),
+                boot_dir.join(arch.kernel_file()).to_str().unwrap(),
             ],
             true,
         )
         .context("Failed to copy precompiled kernel")
     }
 
+    /// Parse `source:target[:fstype[:options]]` given with `--mount`.
+    /// virtio 9p is used if filesystem type is not specified.
+    pub fn parse_mount(mount: &str) -> runtime_config::Mount {
+        let parts: Vec<&str> = mount.split(':').collect();
+        let source = parts.first().unwrap().to_string();
+        let target = parts.get(1).unwrap_or(&"").to_string();
+        let fstype = parts.get(2).unwrap_or(&"9p").to_string();
+        let data = parts
+            .get(3)
+            .unwrap_or(&"trans=virtio,version=9p2000.L")
+            .to_string();
+        runtime_config::Mount {
+            source,
+            target,
+            fstype: Some(fstype),
+            flags: None,
+            data: Some(data),
+        }
+    }
+
+    /// Parse `LABEL=label:target[:fstype[:options]]` referring to an extra partition.
+    /// Partition is mounted by its filesystem label, so it is found whether the disk
+    /// is attached as `sda` or `vda`. Filesystem of the partition is used if filesystem
+    /// type is not specified. Returns `None` for other mounts.
+    fn parse_partition_mount(
+        mount: &str,
+        extra_partitions: &[ExtraPartition],
+    ) -> Result<Option<runtime_config::Mount>> {
+        let Some(mount) = mount.strip_prefix("LABEL=") else {
+            return Ok(None);
+        };
+        let parts: Vec<&str> = mount.split(':').collect();
+        let label = parts[0];
+        let partition = extra_partitions
+            .iter()
+            .find(|partition| partition.label == label)
+            .context(format!(
+                "Mount source LABEL={} doesn't match any --extra-partition",
+                label
+            ))?;
+        let target = parts
+            .get(1)
+            .filter(|target| target.starts_with('/'))
+            .context(format!(
+                "Mount of LABEL={} needs absolute target path",
+                label
+            ))?;
+        Ok(Some(runtime_config::Mount {
+            source: format!("LABEL={}", partition.filesystem_label()),
+            target: target.to_string(),
+            fstype: Some(
+                parts
+                    .get(2)
+                    .map(|fstype| fstype.to_string())
+                    .unwrap_or(partition.filesystem.to_string()),
+            ),
+            flags: None,
+            data: parts.get(3).map(|data| data.to_string()),
+        }))
+    }
+
+    /// Parse `--mount` options. Mountpoints of extra partitions are created in the image.
+    fn parse_mounts(
+        mounts: &[String],
+        extra_partitions: &[ExtraPartition],
+        root_dir: &Path,
+    ) -> Result<Vec<runtime_config::Mount>> {
+        let mut parsed = Vec::new();
+        for mount in mounts {
+            let mount = match Self::parse_partition_mount(mount, extra_partitions)? {
+                Some(mount) => {
+                    // Read-only root filesystem can't get the mountpoint at runtime.
+                    let target = root_dir.join(mount.target.trim_start_matches('/'));
+                    Self::run_command(&["mkdir", "-p", target.to_str().unwrap()], true)
+                        .context(format!("Failed to create {} directory", target.display()))?;
+                    mount
+                }
+                None => Self::parse_mount(mount),
+            };
+            parsed.push(mount);
+        }
+        Ok(parsed)
+    }
+
+    /// Resolve `latest` MIA version to the newest release, so the installed version is known.
+    /// Other versions are returned as they are.
+    fn resolve_mia_version(mia_version: &str) -> Result<String> {
+        if mia_version != "latest" {
+            return Ok(mia_version.to_string());
+        }
+        let releases = Self::run_command_output(&["curl", "-fsSL", MIA_RELEASES_URL], false)
+            .context("Failed to fetch MIA releases")?;
+        let version = Self::latest_mia_version(&releases)?;
+        debug!("latest MIA version: {}", version);
+        Ok(version)
+    }
+
+    /// Newest stable MIA version in GitHub releases of MIA repository.
+    /// Releases of MIA installer from the same repository are skipped.
+    fn latest_mia_version(releases: &str) -> Result<String> {
+        #[derive(serde::Deserialize)]
+        struct Release {
+            tag_name: String,
+            #[serde(default)]
+            draft: bool,
+            #[serde(default)]
+            prerelease: bool,
+        }
+
+        let releases: Vec<Release> =
+            serde_json::from_str(releases).context("Failed to parse MIA releases")?;
+        releases
+            .iter()
+            .filter(|release| !release.draft && !release.prerelease)
+            .filter_map(|release| {
+                let version = release.tag_name.strip_prefix("mia-")?;
+                let numbers = version
+                    .split('.')
+                    .map(|number| number.parse::<u64>().ok())
+                    .collect::<Option<Vec<_>>>()?;
+                Some((numbers, version))
+            })
+            .max()
+            .map(|(_, version)| version.to_string())
+            .context("No MIA release found")
+    }
+
     /// Prepare MIA installation config and run installer.
+    /// Returns the runtime config installed into the image.
     fn install_mia(
         mia_version: &str,
+        arch: Arch,
+        root_dir: &Path,
         container_rt_config: &RuntimeConfig,
-        kernel_modules: &Vec<String>,
-        mounts: &Vec<String>,
+        kernel_modules: &[String],
+        mut mounts: Vec<runtime_config::Mount>,
         gevulot_runtime: bool,
         default_mounts: bool,
-    ) -> Result<()> {
-        let mut mounts = mounts
-            .iter()
-            .map(|m| {
-                let parts: Vec<&str> = m.split(':').collect();
-                let source = parts.first().unwrap().to_string();
-                let target = parts.get(1).unwrap_or(&"").to_string();
-                let fstype = parts.get(2).unwrap_or(&"9p").to_string();
-                let data = parts
-                    .get(3)
-                    .unwrap_or(&"trans=virtio,version=9p2000.L")
-                    .to_string();
-                runtime_config::Mount {
-                    source,
-                    target,
-                    fstype: Some(fstype),
-                    flags: None,
-                    data: Some(data),
-                }
-            })
-            .collect::<Vec<_>>();
-
+    ) -> Result<RuntimeConfig> {
         let follow_config = if gevulot_runtime {
-            let gevulot_mnt_dir = env::temp_dir().join("mnt").join("mnt").join("gevulot");
+            let gevulot_mnt_dir = root_dir.join("mnt").join("gevulot");
             for dirname in ["rt-config", "input", "output"] {
                 let dirpath = gevulot_mnt_dir.join(dirname);
                 Self::run_command(&["mkdir", "-p", dirpath.to_str().unwrap()], true)
@@ -766,171 +1773,633 @@ impl SkopeoSyslinuxBuilder {
             working_dir: container_rt_config.working_dir.clone(),
             mounts,
             default_mounts,
-            kernel_modules: kernel_modules.clone(),
+            kernel_modules: kernel_modules.to_vec(),
             follow_config,
             ..Default::default()
         };
 
-        let mut install_config = mia_installer::InstallConfig::default();
-        install_config.mia_version = mia_version.to_string();
-        install_config.mia_platform = "x86_64-unknown-linux-gnu".to_string();
-        install_config.prefix = env::temp_dir().join("mnt");
-        install_config.as_root = true;
+        let mut install_config = mia_installer::InstallConfig::default();
+        install_config.mia_version = mia_version.to_string();
+        install_config.mia_platform = arch.mia_platform().to_string();
+        install_config.prefix = root_dir.to_path_buf();
+        install_config.as_root = true;
+
+        // In case there is an init system installed in the container
+        install_config.overwrite_symlink = true;
+
+        install_config.rt_config = Some(rt_config.clone());
+
+        mia_installer::install(&install_config)?;
+        Ok(rt_config)
+    }
+
+    /// Root device for the kernel command line, unless overridden with `--root-device`.
+    ///
+    /// Kernel itself resolves `PARTUUID=`, so it is used for disk images, which then boot
+    /// whether the disk is attached as `sda` or `vda`. Initramfs resolves filesystem labels,
+    /// which also work for the rootfs image of direct kernel boot. SquashFS has no label,
+    /// so device path is used there and initramfs tries both `sdX` and `vdX` names.
+    /// Busybox `findfs` of initramfs can't resolve `PARTUUID=`, so it is rejected there.
+    fn root_device(options: &BuildOptions) -> Result<String> {
+        if let Some(root_device) = &options.root_device {
+            if options.initramfs && root_device.starts_with("PARTUUID=") {
+                anyhow::bail!(
+                    "PARTUUID= root device is not supported with --initramfs. Use LABEL=, UUID= or device path."
+                );
+            }
+            return Ok(root_device.clone());
+        }
+        let labeled = options.rootfs_format != RootfsFormat::Squashfs;
+        if options.initramfs && labeled {
+            return Ok(format!("LABEL={}", ROOTFS_LABEL));
+        }
+        match options.output_kind {
+            OutputKind::Disk if options.initramfs => Ok(DISK_ROOT_DEVICE.to_string()),
+            OutputKind::Disk => {
+                let image = Image::open(Path::new(&options.output_file))
+                    .context("Failed to read root partition UUID")?;
+                let root = image
+                    .partitions
+                    .get(1)
+                    .context("Disk image has no root partition")?;
+                Ok(format!("PARTUUID={}", root.uuid))
+            }
+            OutputKind::KernelRootfs => Ok(DIRECT_BOOT_ROOT_DEVICE.to_string()),
+        }
+    }
+
+    /// Kernel command line passed by all bootloaders and used for direct kernel boot.
+    fn kernel_cmdline(
+        options: &BuildOptions,
+        root_device: &str,
+        verity: Option<&Verity>,
+    ) -> String {
+        let init = if let Some(init) = &options.init {
+            format!(" init={}", init)
+        } else {
+            "".to_string()
+        };
+
+        let init_args = if let Some(init_args) = &options.init_args {
+            format!(" -- {}", init_args)
+        } else {
+            "".to_string()
+        };
+
+        let root_dev_mode = if options.rw_root { "rw" } else { "ro" };
+
+        // Kernel can't probe read-only formats as reliably as ext4, so tell it explicitly.
+        let rootfstype = if options.rootfs_format.is_read_only() {
+            format!(" rootfstype={}", options.rootfs_format)
+        } else {
+            "".to_string()
+        };
+
+        let verity = if let Some(verity) = verity {
+            format!(" {}", verity.cmdline())
+        } else {
+            "".to_string()
+        };
+
+        format!(
+            "root={} {}{}{} console={}{}{}",
+            root_device,
+            root_dev_mode,
+            rootfstype,
+            verity,
+            options.arch.console(),
+            init,
+            init_args
+        )
+    }
+
+    // Install the bootloader (SYSLINUX and/or systemd-boot)
+    fn install_bootloader(
+        cmdline: &str,
+        initramfs: bool,
+        workspace: &Workspace,
+        arch: Arch,
+        boot_mode: BootMode,
+        mbr_file: Option<&str>,
+        efi_loader_file: Option<&str>,
+        staged_boot: bool,
+    ) -> Result<()> {
+        let boot_dir = workspace.boot_dir();
+
+        if boot_mode.supports_bios() {
+            Self::write_file_as_root(
+                &boot_dir.join("syslinux.cfg"),
+                &Self::syslinux_config(cmdline, initramfs),
+            )
+            .context("Failed to write SYSLINUX configuration")?;
+
+            // Install SYSLINUX.
+            // Staged boot partition gets it in `create_boot_from_staging`.
+            if !staged_boot {
+                Self::run_command(&["extlinux", "--install", boot_dir.to_str().unwrap()], true)
+                    .context("Failed to install SYSLINUX")?;
+            }
+
+            // Install MBR
+            let mbr_name = if boot_mode.uses_gpt() {
+                "gptmbr.bin"
+            } else {
+                "mbr.bin"
+            };
+            let mbr_path = if let Some(mbr_file) = mbr_file {
+                mbr_file.to_string()
+            } else if let Some(path) = Self::find_syslinux_file(mbr_name) {
+                path
+            } else {
+                anyhow::bail!(
+                    "MBR file {} was not found. Use --mbr-file option to specify it.",
+                    mbr_name
+                );
+            };
+            Self::run_command(
+                &[
+                    "dd",
+                    "bs=440",
+                    "count=1",
+                    "conv=notrunc",
+                    &format!("if={}", mbr_path),
+                    &format!("of={}", workspace.loop_device()?.device()),
+                ],
+                true,
+            )
+            .context("Failed to install MBR")?;
+        }
+
+        if boot_mode.supports_uefi() {
+            const DIRS: [&str; 2] = ["/usr/lib/systemd/boot/efi", "/usr/share/systemd/boot/efi"];
+            let efi_loader_path = if let Some(efi_loader_file) = efi_loader_file {
+                efi_loader_file.to_string()
+            } else if let Some(path) = DIRS
+                .into_iter()
+                .map(|dir| format!("{}/systemd-boot{}.efi", dir, arch.efi_suffix()))
+                .find(|candidate| Path::new(candidate).exists())
+            {
+                path
+            } else {
+                anyhow::bail!(
+                    "systemd-boot EFI binary was not found. Use --efi-loader-file option to specify it."
+                );
+            };
 
-        // In case there is an init system installed in the container
-        install_config.overwrite_symlink = true;
+            // Firmware boots the fallback path when there are no boot entries in NVRAM.
+            let efi_boot_dir = boot_dir.join("EFI").join("BOOT");
+            let entries_dir = boot_dir.join("loader").join("entries");
+            for dir in [&efi_boot_dir, &entries_dir] {
+                Self::run_command(&["mkdir", "-p", dir.to_str().unwrap()], true)
+                    .context(format!("Failed to create {} directory", dir.display()))?;
+            }
+            Self::run_command(
+                &[
+                    "cp",
+                    &efi_loader_path,
+                    efi_boot_dir
+                        .join(format!("BOOT{}.EFI", arch.efi_suffix().to_uppercase()))
+                        .to_str()
+                        .unwrap(),
+                ],
+                true,
+            )
+            .context("Failed to install systemd-boot")?;
 
-        install_config.rt_config = Some(rt_config);
+            Self::write_file_as_root(
+                &boot_dir.join("loader").join("loader.conf"),
+                "default gevulot.conf\ntimeout 0\n",
+            )
+            .context("Failed to write systemd-boot configuration")?;
+            Self::write_file_as_root(
+                &entries_dir.join("gevulot.conf"),
+                &format!(
+                    "title Gevulot\nlinux /{}\n{}options {}\n",
+                    arch.kernel_file(),
+                    if initramfs {
+                        format!("initrd /{}\n", initramfs::INITRAMFS_FILE)
+                    } else {
+                        "".to_string()
+                    },
+                    cmdline
+                ),
+            )
+            .context("Failed to write systemd-boot entry")?;
+        }
 
-        mia_installer::install(&install_config)
-    }
+        // Ensure all changes are written to disk
+        Self::run_command(&["sync"], true).context("Failed to sync filesystem")?;
 
-    // Install the bootloader (SYSLINUX)
-    fn install_bootloader(
-        init: Option<&str>,
-        init_args: Option<&str>,
-        output_file: &str,
-        rw_root: bool,
-        mbr_file: Option<&str>,
-    ) -> Result<()> {
-        let init = if let Some(init) = init {
-            format!(" init={}", init)
-        } else {
-            "".to_string()
-        };
+        Ok(())
+    }
 
-        let init_args = if let Some(init_args) = init_args {
-            format!(" -- {}", init_args)
+    /// SYSLINUX configuration booting the kernel from the boot partition.
+    fn syslinux_config(cmdline: &str, initramfs: bool) -> String {
+        let initrd = if initramfs {
+            format!("    INITRD /{}\n", initramfs::INITRAMFS_FILE)
         } else {
             "".to_string()
         };
-
-        let root_dev_mode = if rw_root { "rw" } else { "ro" };
-
-        // Create SYSLINUX configuration
-        let syslinux_cfg = format!(
+        format!(
             r#"DEFAULT linux
 PROMPT 0
 TIMEOUT 50
 
 LABEL linux
     LINUX /bzImage
-    APPEND root=/dev/sda2 {} console=ttyS0{}{}
+{}    APPEND {}
 "#,
-            root_dev_mode, init, init_args
-        );
+            initrd, cmdline
+        )
+    }
+
+    // Helper function to find SYSLINUX file installed on the host
+    fn find_syslinux_file(name: &str) -> Option<String> {
+        const DIRS: [&str; 3] = [
+            "/usr/share/syslinux",
+            "/usr/lib/syslinux/mbr",
+            "/usr/lib/syslinux/bios",
+        ];
+        DIRS.into_iter()
+            .map(|dir| format!("{}/{}", dir, name))
+            .find(|candidate| Path::new(candidate).exists())
+    }
 
-        // Write SYSLINUX configuration to file
+    // Write file with root permissions
+    pub fn write_file_as_root(path: &Path, content: &str) -> Result<()> {
         let mut child = Command::new("sudo")
             .stdout(std::process::Stdio::null())
             .stderr(std::process::Stdio::null())
-            .args([
-                "tee",
-                env::temp_dir()
-                    .join("mnt")
-                    .join("boot")
-                    .join("syslinux.cfg")
-                    .to_str()
-                    .unwrap(),
-            ])
+            .args(["tee", path.to_str().unwrap()])
             .stdin(std::process::Stdio::piped())
             .spawn()
-            .context("Failed to spawn tee command for SYSLINUX configuration")?;
+            .context("Failed to spawn tee command")?;
         child
             .stdin
-            .as_mut()
+            .take()
             .unwrap()
-            .write_all(syslinux_cfg.as_bytes())
-            .context("Failed to write SYSLINUX configuration")?;
-        child.wait().context("Failed to wait for tee command")?;
+            .write_all(content.as_bytes())
+            .context("Failed to write file content")?;
+        let status = child.wait().context("Failed to wait for tee command")?;
+        if status.success() {
+            Ok(())
+        } else {
+            Err(anyhow::anyhow!("tee command failed with status {}", status))
+        }
+    }
 
-        // Install SYSLINUX
-        Self::run_command(
-            &[
-                "extlinux",
-                "--install",
-                env::temp_dir().join("mnt").join("boot").to_str().unwrap(),
-            ],
-            true,
-        )
-        .context("Failed to install SYSLINUX")?;
+    /// Populate boot partition from the staging directory using mtools, so no timestamps
+    /// of the build time end up in FAT directory entries.
+    fn create_boot_from_staging(
+        workspace: &Workspace,
+        boot_mode: BootMode,
+        source_date_epoch: u64,
+    ) -> Result<()> {
+        let boot_device = workspace.loop_device()?.partition(1);
+        let boot_dir = workspace.boot_dir();
+        let epoch_env = format!("SOURCE_DATE_EPOCH={}", source_date_epoch);
+
+        Self::normalize_mtimes(&boot_dir, source_date_epoch, false)?;
+
+        // Copy files in stable order.
+        let mut entries = fs::read_dir(&boot_dir)
+            .context("Failed to read boot directory")?
+            .map(|entry| entry.map(|entry| entry.path()))
+            .collect::<io::Result<Vec<_>>>()
+            .context("Failed to read boot directory")?;
+        entries.sort();
+        for entry in &entries {
+            Self::run_command(
+                &[
+                    "env",
+                    &epoch_env,
+                    "MTOOLS_SKIP_CHECK=1",
+                    "mcopy",
+                    "-s",
+                    "-m",
+                    "-i",
+                    &boot_device,
+                    entry.to_str().unwrap(),
+                    "::/",
+                ],
+                true,
+            )
+            .context(format!(
+                "Failed to copy {} to boot partition",
+                entry.display()
+            ))?;
+        }
+
+        if boot_mode.supports_bios() {
+            Self::run_command(
+                &[
+                    "env",
+                    &epoch_env,
+                    "MTOOLS_SKIP_CHECK=1",
+                    "syslinux",
+                    "--install",
+                    &boot_device,
+                ],
+                true,
+            )
+            .context("Failed to install SYSLINUX")?;
+        }
+
+        // Boot files must not end up inside root filesystem.
+        for entry in &entries {
+            Self::run_command(&["rm", "-rf", entry.to_str().unwrap()], true)
+                .context("Failed to remove staged boot files")?;
+        }
+
+        Ok(())
+    }
+
+    /// Generate root filesystem from the staging directory and write it into the root partition.
+    /// With `verity` hash tree is appended to the filesystem and verity parameters are returned.
+    fn create_rootfs_from_staging(
+        workspace: &mut Workspace,
+        rootfs_format: RootfsFormat,
+        staged_boot: bool,
+        reproducible: Option<u64>,
+        verity: bool,
+    ) -> Result<Option<Verity>> {
+        let root_device = workspace.loop_device()?.partition(2);
+        let boot_device = workspace.loop_device()?.partition(1);
+        let rootfs_dir = workspace.root_dir();
+        let boot_dir = workspace.boot_dir();
+        let boot_aside_dir = workspace.boot_aside_dir();
+
+        // Boot partition must not end up inside root filesystem.
+        // Its mountpoint will remain as an empty directory.
+        // Boot files are restored afterwards, so the bootloader can be installed.
+        if staged_boot {
+            Self::run_command(
+                &[
+                    "mv",
+                    boot_dir.to_str().unwrap(),
+                    boot_aside_dir.to_str().unwrap(),
+                ],
+                true,
+            )
+            .context("Failed to move staged boot files")?;
+            Self::run_command(&["mkdir", boot_dir.to_str().unwrap()], true)
+                .context("Failed to create boot directory")?;
+        } else if workspace.is_mounted(&boot_dir) {
+            workspace
+                .unmount(&boot_dir)
+                .context("Failed to unmount boot filesystem")?;
+        }
 
-        // Install MBR
-        let mbr_path = if let Some(mbr_file) = mbr_file {
-            mbr_file
+        let verity = Self::write_rootfs_to_partition(
+            &rootfs_dir,
+            &root_device,
+            rootfs_format,
+            reproducible,
+            verity,
+        )?;
+
+        if staged_boot {
+            Self::run_command(&["rmdir", boot_dir.to_str().unwrap()], true)
+                .context("Failed to remove boot directory")?;
+            Self::run_command(
+                &[
+                    "mv",
+                    boot_aside_dir.to_str().unwrap(),
+                    boot_dir.to_str().unwrap(),
+                ],
+                true,
+            )
+            .context("Failed to restore staged boot files")?;
         } else {
-            const CANDIDATES: [&str; 3] = [
-                "/usr/share/syslinux/mbr.bin",
-                "/usr/lib/syslinux/mbr/mbr.bin",
-                "/usr/lib/syslinux/bios/mbr.bin",
-            ];
-            if let Some(path) = CANDIDATES
-                .into_iter()
-                .find(|candidate| std::path::Path::new(candidate).exists())
-            {
-                path
-            } else {
-                anyhow::bail!("MBR file was not found. Use --mbr-file option to specify it.");
-            }
+            workspace
+                .mount(&boot_device, &boot_dir)
+                .context("Failed to mount boot filesystem")?;
+        }
+
+        Ok(verity)
+    }
+
+    /// Generate root filesystem image, optionally with verity hash tree,
+    /// and write it to `root_device`.
+    fn write_rootfs_to_partition(
+        rootfs_dir: &Path,
+        root_device: &str,
+        rootfs_format: RootfsFormat,
+        reproducible: Option<u64>,
+        verity: bool,
+    ) -> Result<Option<Verity>> {
+        // ext4 is created right on the partition.
+        if rootfs_format == RootfsFormat::Ext4 && !verity {
+            Self::generate_rootfs_image(
+                rootfs_dir,
+                rootfs_format,
+                Path::new(root_device),
+                reproducible,
+            )?;
+            return Ok(None);
+        }
+
+        let partition_size = Self::get_device_size(root_device)?;
+
+        // This temp dir will be removed on dropping.
+        let image_dir = TempDir::new("rootfs").context("Failed to create temporary directory")?;
+        let image_path = image_dir.path().join("rootfs.img");
+        if rootfs_format == RootfsFormat::Ext4 {
+            // Filesystem takes the rest of the partition left after the hash tree.
+            let filesystem_size = (partition_size - size::verity_hash_size(partition_size))
+                / size::VERITY_BLOCK_SIZE
+                * size::VERITY_BLOCK_SIZE;
+            Self::create_disk_image(filesystem_size, image_path.to_str().unwrap())?;
+        }
+        Self::generate_rootfs_image(rootfs_dir, rootfs_format, &image_path, reproducible)?;
+
+        let verity = if verity {
+            Some(verity::format(
+                &image_path,
+                reproducible.map(|_| verity::REPRODUCIBLE_SALT),
+            )?)
+        } else {
+            None
         };
-        let loop_device = Self::get_loop_device(output_file)?;
+
+        let image_size = fs::metadata(&image_path)
+            .context("Failed to read root filesystem image metadata")?
+            .len();
+        if image_size > partition_size {
+            anyhow::bail!(
+                "Root filesystem ({} bytes) doesn't fit into root partition ({} bytes). Use --size option to increase image size.",
+                image_size,
+                partition_size
+            );
+        }
+
         Self::run_command(
             &[
                 "dd",
-                "bs=440",
-                "count=1",
-                "conv=notrunc",
-                &format!("if={}", mbr_path),
-                &format!("of={}", loop_device),
+                "bs=4M",
+                "conv=notrunc,fsync",
+                &format!("if={}", image_path.display()),
+                &format!("of={}", root_device),
             ],
             true,
         )
-        .context("Failed to install MBR")?;
+        .context("Failed to write root filesystem to partition")?;
 
-        // Ensure all changes are written to disk
-        Self::run_command(&["sync"], true).context("Failed to sync filesystem")?;
+        Ok(verity)
+    }
 
-        Ok(())
+    /// Generate filesystem image at `target` (file or block device) from `rootfs_dir`.
+    /// For ext4 `target` must already have the size of the filesystem.
+    pub fn generate_rootfs_image(
+        rootfs_dir: &Path,
+        rootfs_format: RootfsFormat,
+        target: &Path,
+        reproducible: Option<u64>,
+    ) -> Result<()> {
+        let target = target
+            .to_str()
+            .context("Invalid root filesystem image path")?;
+
+        if let Some(source_date_epoch) = reproducible {
+            Self::normalize_mtimes(rootfs_dir, source_date_epoch, true)?;
+        }
+        let epoch = reproducible
+            .map(|epoch| epoch.to_string())
+            .unwrap_or_default();
+        let epoch_env = format!("SOURCE_DATE_EPOCH={}", epoch);
+
+        match rootfs_format {
+            RootfsFormat::Ext4 => {
+                let fake_time_env = format!("E2FSPROGS_FAKE_TIME={}", epoch);
+                let hash_seed = format!("hash_seed={}", REPRODUCIBLE_ROOTFS_UUID);
+                let mut command = vec!["mkfs.ext4", "-L", ROOTFS_LABEL];
+                if reproducible.is_some() {
+                    command.splice(0..0, ["env", &epoch_env, &fake_time_env]);
+                    command.extend(["-U", REPRODUCIBLE_ROOTFS_UUID, "-E", &hash_seed]);
+                }
+                command.extend(["-d", rootfs_dir.to_str().unwrap(), target]);
+                Self::run_command(&command, true).context("Failed to create EXT4 filesystem")
+            }
+            RootfsFormat::Squashfs => {
+                let mut command = vec![
+                    "mksquashfs",
+                    rootfs_dir.to_str().unwrap(),
+                    target,
+                    "-noappend",
+                    "-comp",
+                    "zstd",
+                ];
+                if reproducible.is_some() {
+                    command.splice(0..0, ["env", &epoch_env]);
+                    command.extend(["-mkfs-time", &epoch]);
+                }
+                Self::run_command(&command, true).context("Failed to create SquashFS filesystem")
+            }
+            RootfsFormat::Erofs => {
+                let mut command = vec!["mkfs.erofs", "-L", ROOTFS_LABEL, "-zlz4hc"];
+                if reproducible.is_some() {
+                    command.splice(0..0, ["env", &epoch_env]);
+                    command.extend(["-T", &epoch, "-U", REPRODUCIBLE_ROOTFS_UUID]);
+                }
+                command.extend([target, rootfs_dir.to_str().unwrap()]);
+                Self::run_command(&command, true).context("Failed to create EROFS filesystem")
+            }
+        }
     }
 
-    // Clean up: unmount filesystems and detach loop device
-    fn cleanup() -> Result<()> {
-        _ = Self::run_command(
-            &[
-                "umount",
-                "-R",
-                env::temp_dir().join("mnt").to_str().unwrap(),
-            ],
-            true,
-        );
-        // Detach all unused loop devices
-        _ = Self::run_command(&["losetup", "-D"], true);
-        log::debug!(
-            "removing temp dir {}",
-            env::temp_dir().join("mnt").display()
-        );
-        _ = fs::remove_dir(env::temp_dir().join("mnt"));
-        log::debug!("removed temp dir {}", env::temp_dir().join("mnt").display());
+    /// Move file from staged `/boot` out of the root filesystem.
+    fn export_boot_file(boot_dir: &Path, name: &str, target: &Path) -> Result<()> {
+        fs::copy(boot_dir.join(name), target).context(format!("Failed to export {}", name))?;
+        // Boot files are not needed inside root filesystem.
+        Self::run_command(&["rm", "-f", boot_dir.join(name).to_str().unwrap()], true)
+            .context(format!("Failed to remove staged {}", name))?;
         Ok(())
     }
 
-    // Helper function to get the current loop device
-    fn get_loop_device(output_file: &str) -> Result<String> {
-        let loop_device = String::from_utf8(
-            Command::new("sh")
-                .arg("-c")
-                .arg(&format!(
-                    "losetup -a | grep {} | awk -F: '{{print $1}}' | head -1",
-                    output_file
-                ))
-                .output()
-                .context("Failed to execute losetup command")?
-                .stdout,
+    /// Write JSON descriptor with everything needed to launch the VM with direct kernel boot.
+    fn write_boot_descriptor(
+        options: &BuildOptions,
+        output_dir: &Path,
+        root_device: &str,
+        verity: Option<&Verity>,
+    ) -> Result<()> {
+        let descriptor = serde_json::json!({
+            "kernel": options.arch.kernel_file(),
+            "initrd": options.initramfs.then_some(initramfs::INITRAMFS_FILE),
+            "rootfs": DIRECT_BOOT_ROOTFS_FILE,
+            "rootfs_format": options.rootfs_format.to_string(),
+            "root_device": root_device,
+            "read_only": !options.rw_root,
+            "init": options.init,
+            "init_args": options.init_args,
+            "verity": verity,
+            "cmdline": Self::kernel_cmdline(options, root_device, verity),
+        });
+        fs::write(
+            output_dir.join(DIRECT_BOOT_DESCRIPTOR_FILE),
+            serde_json::to_string_pretty(&descriptor)?,
         )
-        .context("Failed to parse losetup output")?
-        .trim()
-        .to_string();
-        Ok(loop_device)
+        .context("Failed to write boot descriptor")
+    }
+
+    /// Set modification time of files under `dir` to `source_date_epoch`.
+    /// With `clamp` only files newer than `source_date_epoch` are changed.
+    fn normalize_mtimes(dir: &Path, source_date_epoch: u64, clamp: bool) -> Result<()> {
+        let dir = dir.to_str().context("Invalid directory path")?;
+        let command = Self::normalize_mtimes_command(dir, source_date_epoch, clamp);
+        let command = command.iter().map(String::as_str).collect::<Vec<_>>();
+        Self::run_command(&command, true).context("Failed to normalize modification times")
+    }
+
+    /// Command run by [`Self::normalize_mtimes`].
+    fn normalize_mtimes_command(dir: &str, source_date_epoch: u64, clamp: bool) -> Vec<String> {
+        let date = format!("@{}", source_date_epoch);
+        let mut command = vec!["find", dir];
+        if clamp {
+            command.extend(["-newermt", &date]);
+        }
+        command.extend([
+            "-exec",
+            "touch",
+            "--no-dereference",
+            "--date",
+            &date,
+            "{}",
+            "+",
+        ]);
+        command.into_iter().map(str::to_string).collect()
+    }
+
+    // Helper function to get size of the block device in bytes
+    fn get_device_size(device: &str) -> Result<u64> {
+        Self::run_command_output(&["blockdev", "--getsize64", device], true)?
+            .trim()
+            .parse()
+            .context("Failed to parse device size")
+    }
+
+    /// Same as [`Self::run_command`], but captures and returns stdout of the command.
+    pub fn run_command_output(commands: &[&str], as_root: bool) -> Result<String> {
+        let program = if as_root { "sudo" } else { commands[0] };
+        let args = if as_root { commands } else { &commands[1..] };
+
+        log::debug!("running command: {program} {:?}", args);
+
+        let output = Command::new(program)
+            .args(args)
+            .output()
+            .context("Failed to spawn command")?;
+        if output.status.success() {
+            String::from_utf8(output.stdout).context("Failed to parse command stdout")
+        } else {
+            String::from_utf8_lossy(&output.stderr)
+                .lines()
+                .for_each(|line| debug!(target: commands[0], "{}", line));
+            Err(anyhow::anyhow!(
+                "Command failed with status {}",
+                output.status
+            ))
+        }
     }
 
     pub fn run_command(commands: &[&str], as_root: bool) -> Result<()> {
@@ -974,3 +2443,438 @@ LABEL linux
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Check GUID is in canonical form of random (version 4, RFC 4122 variant) GUIDs.
+    fn assert_guid(guid: &str) {
+        assert_eq!(guid.len(), 36, "{}", guid);
+        for (i, c) in guid.chars().enumerate() {
+            if [8, 13, 18, 23].contains(&i) {
+                assert_eq!(c, '-', "{}", guid);
+            } else {
+                assert!(c.is_ascii_hexdigit() && !c.is_ascii_uppercase(), "{}", guid);
+            }
+        }
+        assert_eq!(&guid[14..15], "4", "{}", guid);
+        assert!("89ab".contains(&guid[19..20]), "{}", guid);
+    }
+
+    #[test]
+    fn reproducible_identifiers_are_valid() {
+        let guids = [
+            REPRODUCIBLE_DISK_GUID,
+            REPRODUCIBLE_BOOT_PARTITION_GUID,
+            REPRODUCIBLE_ROOT_PARTITION_GUID,
+            REPRODUCIBLE_ROOTFS_UUID,
+        ];
+        for guid in guids {
+            assert_guid(guid);
+        }
+        for (i, guid) in guids.iter().enumerate() {
+            assert!(!guids[i + 1..].contains(guid), "duplicate {}", guid);
+        }
+        for prefix in [
+            REPRODUCIBLE_EXTRA_PARTITION_GUID_PREFIX,
+            REPRODUCIBLE_EXTRA_FILESYSTEM_UUID_PREFIX,
+        ] {
+            assert_guid(&format!("{}{:012x}", prefix, FIRST_EXTRA_PARTITION));
+        }
+
+        // MBR disk identifier and FAT volume ID are 32-bit hex numbers.
+        let disk_id = REPRODUCIBLE_DISK_ID.strip_prefix("0x").unwrap();
+        assert_eq!(disk_id.len(), 8);
+        assert!(u32::from_str_radix(disk_id, 16).is_ok());
+        assert_eq!(REPRODUCIBLE_BOOT_VOLUME_ID.len(), 8);
+        assert!(u32::from_str_radix(REPRODUCIBLE_BOOT_VOLUME_ID, 16).is_ok());
+
+        assert_eq!(verity::REPRODUCIBLE_SALT.len(), 64);
+        assert!(verity::REPRODUCIBLE_SALT
+            .chars()
+            .all(|c| c.is_ascii_hexdigit()));
+    }
+
+    #[test]
+    fn default_source_date_epoch_is_representable_in_fat() {
+        // 1980-01-01T00:00:00Z
+        assert_eq!(
+            crate::builders::DEFAULT_SOURCE_DATE_EPOCH,
+            10 * 365 * 86400 + 2 * 86400
+        );
+    }
+
+    /// Run [`SkopeoSyslinuxBuilder::normalize_mtimes_command`] as the current user.
+    fn normalize_mtimes(dir: &Path, source_date_epoch: u64, clamp: bool) {
+        let command = SkopeoSyslinuxBuilder::normalize_mtimes_command(
+            dir.to_str().unwrap(),
+            source_date_epoch,
+            clamp,
+        );
+        let command = command.iter().map(String::as_str).collect::<Vec<_>>();
+        SkopeoSyslinuxBuilder::run_command(&command, false).unwrap();
+    }
+
+    fn mtime(path: &Path) -> u64 {
+        fs::symlink_metadata(path)
+            .unwrap()
+            .modified()
+            .unwrap()
+            .duration_since(std::time::UNIX_EPOCH)
+            .unwrap()
+            .as_secs()
+    }
+
+    /// Directory with a file and a dangling symlink modified now and a file from 1975.
+    fn mtimes_tree() -> TempDir {
+        let dir = TempDir::new("mtimes").unwrap();
+        fs::create_dir(dir.path().join("etc")).unwrap();
+        fs::write(dir.path().join("etc/new"), "").unwrap();
+        fs::write(dir.path().join("old"), "").unwrap();
+        std::os::unix::fs::symlink("/missing", dir.path().join("link")).unwrap();
+        SkopeoSyslinuxBuilder::run_command(
+            &[
+                "touch",
+                "--date",
+                "@157766400",
+                dir.path().join("old").to_str().unwrap(),
+            ],
+            false,
+        )
+        .unwrap();
+        dir
+    }
+
+    #[test]
+    fn normalize_mtimes_sets_all_files() {
+        let dir = mtimes_tree();
+        normalize_mtimes(dir.path(), 315532800, false);
+        for path in ["", "etc", "etc/new", "old", "link"] {
+            assert_eq!(mtime(&dir.path().join(path)), 315532800, "{}", path);
+        }
+    }
+
+    #[test]
+    fn normalize_mtimes_clamps_newer_files() {
+        let dir = mtimes_tree();
+        normalize_mtimes(dir.path(), 315532800, true);
+        for path in ["", "etc", "etc/new", "link"] {
+            assert_eq!(mtime(&dir.path().join(path)), 315532800, "{}", path);
+        }
+        // Files older than SOURCE_DATE_EPOCH are kept as they are.
+        assert_eq!(mtime(&dir.path().join("old")), 157766400);
+    }
+
+    fn options() -> BuildOptions {
+        BuildOptions {
+            container_source: Some("docker://docker.io/debian:latest".to_string()),
+            rootfs_dir: None,
+            rootfs_excludes: Vec::new(),
+            owner_map: Vec::new(),
+            rootfs_tar: None,
+            containerfile: None,
+            build_engine: container::BuildEngine::Podman,
+            build_context: None,
+            build_target: None,
+            build_args: Vec::new(),
+            build_secrets: Vec::new(),
+            arch: Arch::X86_64,
+            platform: Arch::X86_64.platform(),
+            image_size: ImageSize::Auto { extra_percent: 0 },
+            boot_size: size::BOOT_PARTITION_SIZE,
+            extra_partitions: Vec::new(),
+            rootfs_format: RootfsFormat::Ext4,
+            slim: None,
+            kernel_version: "v6.12".to_string(),
+            kernel_url: None,
+            kernel_file: None,
+            kernel_index: None,
+            kernel_index_key: None,
+            kernel_defconfig: None,
+            kernel_config_fragments: Vec::new(),
+            cross_compile: None,
+            nvidia_drivers: false,
+            kernel_modules: Vec::new(),
+            modules_install: ModulesInstall::Requested,
+            mounts: Vec::new(),
+            mia_version: Some("latest".to_string()),
+            no_gevulot_runtime: false,
+            no_default_mounts: false,
+            init: None,
+            init_args: None,
+            rw_root: false,
+            initramfs: false,
+            busybox_file: None,
+            verity: false,
+            root_device: None,
+            boot_mode: BootMode::Bios,
+            mbr_file: None,
+            efi_loader_file: None,
+            output_file: "disk.img".to_string(),
+            output_kind: OutputKind::Disk,
+            report_file: None,
+            sbom: None,
+            reproducible: false,
+            source_date_epoch: crate::builders::DEFAULT_SOURCE_DATE_EPOCH,
+            force: false,
+            quiet: false,
+        }
+    }
+
+    /// Create partitioned disk image with fixed identifiers.
+    fn disk_image(dir: &Path, boot_mode: BootMode) -> String {
+        let path = dir.join("disk.img");
+        fs::File::create(&path)
+            .unwrap()
+            .set_len(64 * size::MIB)
+            .unwrap();
+        let path = path.to_str().unwrap().to_string();
+        SkopeoSyslinuxBuilder::create_partitions(&path, boot_mode, 16 * size::MIB, &[], true)
+            .unwrap();
+        path
+    }
+
+    #[test]
+    fn root_device_is_partuuid_of_disk_image() {
+        let dir = TempDir::new("root-device").unwrap();
+        let mut options = options();
+        options.output_file = disk_image(dir.path(), BootMode::Bios);
+        assert_eq!(
+            SkopeoSyslinuxBuilder::root_device(&options).unwrap(),
+            "PARTUUID=47564c54-02"
+        );
+
+        let dir = TempDir::new("root-device").unwrap();
+        options.boot_mode = BootMode::Uefi;
+        options.output_file = disk_image(dir.path(), BootMode::Uefi);
+        assert_eq!(
+            SkopeoSyslinuxBuilder::root_device(&options).unwrap(),
+            format!("PARTUUID={}", REPRODUCIBLE_ROOT_PARTITION_GUID)
+        );
+    }
+
+    #[test]
+    fn root_device_with_initramfs() {
+        let mut options = options();
+        options.initramfs = true;
+        assert_eq!(
+            SkopeoSyslinuxBuilder::root_device(&options).unwrap(),
+            format!("LABEL={}", ROOTFS_LABEL)
+        );
+        options.output_kind = OutputKind::KernelRootfs;
+        options.rootfs_format = RootfsFormat::Erofs;
+        assert_eq!(
+            SkopeoSyslinuxBuilder::root_device(&options).unwrap(),
+            format!("LABEL={}", ROOTFS_LABEL)
+        );
+
+        // Squashfs has no label.
+        options.rootfs_format = RootfsFormat::Squashfs;
+        assert_eq!(
+            SkopeoSyslinuxBuilder::root_device(&options).unwrap(),
+            DIRECT_BOOT_ROOT_DEVICE
+        );
+        options.output_kind = OutputKind::Disk;
+        assert_eq!(
+            SkopeoSyslinuxBuilder::root_device(&options).unwrap(),
+            DISK_ROOT_DEVICE
+        );
+
+        // Initramfs can't resolve PARTUUID.
+        options.root_device = Some("PARTUUID=47564c54-02".to_string());
+        assert!(SkopeoSyslinuxBuilder::root_device(&options).is_err());
+        options.root_device = Some("UUID=0d2b5e4c-0d0a-4d4b-9a3e-2b6f7c1d8e9f".to_string());
+        assert_eq!(
+            SkopeoSyslinuxBuilder::root_device(&options).unwrap(),
+            "UUID=0d2b5e4c-0d0a-4d4b-9a3e-2b6f7c1d8e9f"
+        );
+        options.initramfs = false;
+        options.root_device = Some("PARTUUID=47564c54-02".to_string());
+        assert_eq!(
+            SkopeoSyslinuxBuilder::root_device(&options).unwrap(),
+            "PARTUUID=47564c54-02"
+        );
+    }
+
+    #[test]
+    fn root_device_without_initramfs_for_direct_boot() {
+        let mut options = options();
+        options.output_kind = OutputKind::KernelRootfs;
+        assert_eq!(
+            SkopeoSyslinuxBuilder::root_device(&options).unwrap(),
+            DIRECT_BOOT_ROOT_DEVICE
+        );
+    }
+
+    #[test]
+    fn root_device_override() {
+        let mut options = options();
+        options.root_device = Some("/dev/nvme0n1p2".to_string());
+        assert_eq!(
+            SkopeoSyslinuxBuilder::root_device(&options).unwrap(),
+            "/dev/nvme0n1p2"
+        );
+        options.initramfs = true;
+        options.output_kind = OutputKind::KernelRootfs;
+        assert_eq!(
+            SkopeoSyslinuxBuilder::root_device(&options).unwrap(),
+            "/dev/nvme0n1p2"
+        );
+    }
+
+    #[test]
+    fn partition_mounts_use_filesystem_label() {
+        let extra_partitions = [
+            "scratch:1G:ext4".parse().unwrap(),
+            "data:64M:vfat".parse().unwrap(),
+        ];
+        let mount = SkopeoSyslinuxBuilder::parse_partition_mount(
+            "LABEL=scratch:/scratch",
+            &extra_partitions,
+        )
+        .unwrap()
+        .unwrap();
+        assert_eq!(mount.source, "LABEL=scratch");
+        assert_eq!(mount.target, "/scratch");
+        assert_eq!(mount.fstype.as_deref(), Some("ext4"));
+        assert_eq!(mount.data, None);
+
+        // vfat labels are upper case on disk.
+        let mount = SkopeoSyslinuxBuilder::parse_partition_mount(
+            "LABEL=data:/data:vfat:ro,uid=1000",
+            &extra_partitions,
+        )
+        .unwrap()
+        .unwrap();
+        assert_eq!(mount.source, "LABEL=DATA");
+        assert_eq!(mount.fstype.as_deref(), Some("vfat"));
+        assert_eq!(mount.data.as_deref(), Some("ro,uid=1000"));
+
+        assert!(SkopeoSyslinuxBuilder::parse_partition_mount(
+            "LABEL=other:/other",
+            &extra_partitions
+        )
+        .is_err());
+        assert!(
+            SkopeoSyslinuxBuilder::parse_partition_mount("LABEL=scratch", &extra_partitions)
+                .is_err()
+        );
+        assert!(SkopeoSyslinuxBuilder::parse_partition_mount(
+            "input:/mnt/input",
+            &extra_partitions
+        )
+        .unwrap()
+        .is_none());
+    }
+
+    #[test]
+    fn kernel_cmdline_defaults() {
+        let options = options();
+        assert_eq!(
+            SkopeoSyslinuxBuilder::kernel_cmdline(&options, "PARTUUID=47564c54-02", None),
+            "root=PARTUUID=47564c54-02 ro console=ttyS0"
+        );
+    }
+
+    #[test]
+    fn kernel_cmdline_with_init_and_read_only_format() {
+        let mut options = options();
+        options.rw_root = true;
+        options.rootfs_format = RootfsFormat::Squashfs;
+        options.init = Some("/bin/app".to_string());
+        options.init_args = Some("--debug --port=80".to_string());
+        assert_eq!(
+            SkopeoSyslinuxBuilder::kernel_cmdline(&options, "/dev/vda", None),
+            "root=/dev/vda rw rootfstype=squashfs console=ttyS0 init=/bin/app -- --debug --port=80"
+        );
+    }
+
+    #[test]
+    fn kernel_cmdline_with_verity() {
+        let mut options = options();
+        options.arch = Arch::Aarch64;
+        options.rootfs_format = RootfsFormat::Erofs;
+        let verity = Verity {
+            root_hash: "ab".repeat(32),
+            salt: verity::REPRODUCIBLE_SALT.to_string(),
+            data_blocks: 1024,
+        };
+        assert_eq!(
+            SkopeoSyslinuxBuilder::kernel_cmdline(&options, "LABEL=ROOTFS", Some(&verity)),
+            format!(
+                "root=LABEL=ROOTFS ro rootfstype=erofs verity.roothash={} verity.salt={} \
+                 verity.blocks=1024 console=ttyAMA0",
+                "ab".repeat(32),
+                verity::REPRODUCIBLE_SALT
+            )
+        );
+    }
+
+    #[test]
+    fn syslinux_config_without_initramfs() {
+        assert_eq!(
+            SkopeoSyslinuxBuilder::syslinux_config("root=/dev/sda2 ro console=ttyS0", false),
+            "DEFAULT linux\n\
+             PROMPT 0\n\
+             TIMEOUT 50\n\
+             \n\
+             LABEL linux\n    \
+             LINUX /bzImage\n    \
+             APPEND root=/dev/sda2 ro console=ttyS0\n"
+        );
+    }
+
+    #[test]
+    fn syslinux_config_with_initramfs() {
+        assert_eq!(
+            SkopeoSyslinuxBuilder::syslinux_config("root=LABEL=ROOTFS ro", true),
+            "DEFAULT linux\n\
+             PROMPT 0\n\
+             TIMEOUT 50\n\
+             \n\
+             LABEL linux\n    \
+             LINUX /bzImage\n    \
+             INITRD /initramfs.img\n    \
+             APPEND root=LABEL=ROOTFS ro\n"
+        );
+    }
+
+    #[test]
+    fn latest_mia_version_skips_installer_and_prereleases() {
+        let releases = r#"[
+            {"tag_name": "mia-installer-0.2.5", "draft": false, "prerelease": false},
+            {"tag_name": "mia-0.3.0-rc1", "draft": false, "prerelease": true},
+            {"tag_name": "mia-0.4.0", "draft": true, "prerelease": false},
+            {"tag_name": "mia-0.2.10", "draft": false, "prerelease": false},
+            {"tag_name": "mia-0.2.9", "draft": false, "prerelease": false},
+            {"tag_name": "v1.0.0", "draft": false, "prerelease": false}
+        ]"#;
+        assert_eq!(
+            SkopeoSyslinuxBuilder::latest_mia_version(releases).unwrap(),
+            "0.2.10"
+        );
+    }
+
+    #[test]
+    fn latest_mia_version_requires_release() {
+        assert!(SkopeoSyslinuxBuilder::latest_mia_version("[]").is_err());
+        assert!(SkopeoSyslinuxBuilder::latest_mia_version(
+            r#"[{"tag_name": "mia-installer-0.2.5"}]"#
+        )
+        .is_err());
+        assert!(SkopeoSyslinuxBuilder::latest_mia_version(r#"{"message": "rate limit"}"#).is_err());
+    }
+
+    #[test]
+    fn explicit_mia_versions_are_kept() {
+        assert_eq!(
+            SkopeoSyslinuxBuilder::resolve_mia_version("0.2.5").unwrap(),
+            "0.2.5"
+        );
+        assert_eq!(
+            SkopeoSyslinuxBuilder::resolve_mia_version("file:/tmp/mia").unwrap(),
+            "file:/tmp/mia"
+        );
+    }
+}
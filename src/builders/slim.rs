@@ -0,0 +1,364 @@
+//! Removal of files not needed at runtime from the root filesystem with `--slim`.
+
+use std::fs;
+use std::path::{Path, PathBuf};
+
+use anyhow::{Context, Result};
+
+use crate::builders::skopeo_builder::SkopeoSyslinuxBuilder;
+
+/// Directory whose entries matching `find` tests in `filter` are removed.
+struct Cleanup {
+    dir: &'static str,
+    filter: &'static [&'static str],
+}
+
+/// Package manager caches of apt, apk, dnf, yum and pip.
+/// Lock files and `partial` directories are kept, apt fails without them.
+const CACHES: &[Cleanup] = &[
+    Cleanup {
+        dir: "var/cache/apt",
+        filter: &["-name", "*.bin"],
+    },
+    Cleanup {
+        dir: "var/cache/apt/archives",
+        filter: &["-name", "*.deb"],
+    },
+    Cleanup {
+        dir: "var/lib/apt/lists",
+        filter: &["-not", "-name", "partial", "-not", "-name", "lock"],
+    },
+    Cleanup {
+        dir: "var/cache/apk",
+        filter: &[],
+    },
+    Cleanup {
+        dir: "var/cache/dnf",
+        filter: &[],
+    },
+    Cleanup {
+        dir: "var/cache/yum",
+        filter: &[],
+    },
+    Cleanup {
+        dir: "root/.cache/pip",
+        filter: &[],
+    },
+];
+
+/// Documentation, man and info pages.
+const DOCS: &[Cleanup] = &[
+    Cleanup {
+        dir: "usr/share/doc",
+        filter: &[],
+    },
+    Cleanup {
+        dir: "usr/share/man",
+        filter: &[],
+    },
+    Cleanup {
+        dir: "usr/share/info",
+        filter: &[],
+    },
+    Cleanup {
+        dir: "usr/share/gtk-doc",
+        filter: &[],
+    },
+];
+
+/// Message translations. Files like `locale.alias` are kept.
+const LOCALES: &[Cleanup] = &[Cleanup {
+    dir: "usr/share/locale",
+    filter: &["-type", "d"],
+}];
+
+/// Directories searched for ELF binaries and libraries to strip.
+const STRIP_DIRS: &[&str] = &[
+    "bin",
+    "sbin",
+    "lib",
+    "lib64",
+    "usr/bin",
+    "usr/sbin",
+    "usr/lib",
+    "usr/lib64",
+    "usr/libexec",
+    "usr/local/bin",
+    "usr/local/sbin",
+    "usr/local/lib",
+];
+
+/// What is removed with `--slim`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct SlimProfile {
+    pub caches: bool,
+    pub docs: bool,
+    pub locales: bool,
+    pub pycache: bool,
+    /// Strip symbols from ELF binaries and libraries.
+    pub strip: bool,
+}
+
+impl SlimProfile {
+    /// Profile used by `--slim` without a value.
+    pub const STANDARD: Self = Self {
+        caches: true,
+        docs: true,
+        locales: true,
+        pycache: true,
+        strip: false,
+    };
+
+    pub const ALL: Self = Self {
+        strip: true,
+        ..Self::STANDARD
+    };
+}
+
+impl std::str::FromStr for SlimProfile {
+    type Err = &'static str;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "standard" => return Ok(Self::STANDARD),
+            "all" => return Ok(Self::ALL),
+            _ => {}
+        }
+        let mut profile = Self {
+            caches: false,
+            docs: false,
+            locales: false,
+            pycache: false,
+            strip: false,
+        };
+        for item in s.split(',') {
+            match item.trim() {
+                "caches" => profile.caches = true,
+                "docs" => profile.docs = true,
+                "locales" => profile.locales = true,
+                "pycache" => profile.pycache = true,
+                "strip" => profile.strip = true,
+                _ => return Err("invalid slim profile: expected standard, all or comma-separated list of caches, docs, locales, pycache, strip"),
+            }
+        }
+        Ok(profile)
+    }
+}
+
+impl std::fmt::Display for SlimProfile {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match *self {
+            Self::STANDARD => write!(f, "standard"),
+            Self::ALL => write!(f, "all"),
+            _ => {
+                let items = [
+                    (self.caches, "caches"),
+                    (self.docs, "docs"),
+                    (self.locales, "locales"),
+                    (self.pycache, "pycache"),
+                    (self.strip, "strip"),
+                ];
+                let items = items
+                    .iter()
+                    .filter(|(enabled, _)| *enabled)
+                    .map(|(_, name)| *name)
+                    .collect::<Vec<_>>();
+                write!(f, "{}", items.join(","))
+            }
+        }
+    }
+}
+
+/// Remove files selected by `profile` from the root filesystem at `root_dir`.
+/// ELF files are stripped with `strip` program, which must support the image architecture.
+pub fn slim(root_dir: &Path, profile: SlimProfile, strip: &str) -> Result<()> {
+    let mut cleanups = Vec::new();
+    if profile.caches {
+        cleanups.extend(CACHES);
+    }
+    if profile.docs {
+        cleanups.extend(DOCS);
+    }
+    if profile.locales {
+        cleanups.extend(LOCALES);
+    }
+    for cleanup in cleanups {
+        let Some(dir) = image_dir(root_dir, cleanup.dir) else {
+            continue;
+        };
+        let dir = dir.to_str().context("Invalid rootfs path")?;
+        let mut command = vec!["find", dir, "-mindepth", "1", "-maxdepth", "1"];
+        command.extend(cleanup.filter);
+        command.extend(["-exec", "rm", "-rf", "{}", "+"]);
+        SkopeoSyslinuxBuilder::run_command(&command, true)
+            .context(format!("Failed to clean {}", cleanup.dir))?;
+    }
+
+    let root = root_dir.to_str().context("Invalid rootfs path")?;
+    if profile.pycache {
+        SkopeoSyslinuxBuilder::run_command(
+            &[
+                "find",
+                root,
+                "-xdev",
+                "-type",
+                "d",
+                "-name",
+                "__pycache__",
+                "-prune",
+                "-exec",
+                "rm",
+                "-rf",
+                "{}",
+                "+",
+            ],
+            true,
+        )
+        .context("Failed to remove Python bytecode caches")?;
+    }
+
+    if profile.strip {
+        let dirs = STRIP_DIRS
+            .iter()
+            .filter_map(|dir| image_dir(root_dir, dir))
+            .map(|dir| dir.to_str().unwrap().to_string())
+            .collect::<Vec<_>>();
+        if dirs.is_empty() {
+            return Ok(());
+        }
+        // Files without ELF magic are skipped. Files strip can't handle are left as they are,
+        // the script prints their paths.
+        let script = format!(
+            "for f; do [ \"$(head -c 4 \"$f\" | tail -c 3)\" = ELF ] || continue; \
+             {} --strip-unneeded \"$f\" 2>/dev/null || echo \"$f\"; done",
+            strip
+        );
+        let mut command = vec!["find"];
+        command.extend(dirs.iter().map(String::as_str));
+        command.extend([
+            "-xdev", "-type", "f", "-exec", "sh", "-c", &script, "sh", "{}", "+",
+        ]);
+        let failed = SkopeoSyslinuxBuilder::run_command_output(&command, true)
+            .context("Failed to strip ELF binaries")?;
+        for path in failed.lines() {
+            let path = Path::new(path);
+            log::debug!(
+                "failed to strip {}, kept as it is",
+                path.strip_prefix(root_dir).unwrap_or(path).display()
+            );
+        }
+    }
+    Ok(())
+}
+
+/// Directory `relative` to `root_dir`, if it exists and none of its components is a symlink.
+///
+/// Symlinks in the image may be absolute or point outside of it, and commands running as root
+/// would follow them into the host filesystem.
+fn image_dir(root_dir: &Path, relative: &str) -> Option<PathBuf> {
+    let mut dir = root_dir.to_path_buf();
+    for component in Path::new(relative).components() {
+        dir.push(component);
+        let metadata = fs::symlink_metadata(&dir).ok()?;
+        if !metadata.is_dir() {
+            if metadata.is_symlink() {
+                log::debug!("{} is a symlink, skipped", relative);
+            }
+            return None;
+        }
+    }
+    Some(dir)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::os::unix::fs::symlink;
+
+    use super::*;
+    use tempdir::TempDir;
+
+    const NONE: SlimProfile = SlimProfile {
+        caches: false,
+        docs: false,
+        locales: false,
+        pycache: false,
+        strip: false,
+    };
+
+    #[test]
+    fn named_profiles() {
+        assert_eq!("standard".parse(), Ok(SlimProfile::STANDARD));
+        assert_eq!("all".parse(), Ok(SlimProfile::ALL));
+        assert_eq!(SlimProfile::STANDARD.to_string(), "standard");
+        assert_eq!(SlimProfile::ALL.to_string(), "all");
+    }
+
+    #[test]
+    fn profile_list() {
+        assert_eq!(
+            "docs, strip".parse(),
+            Ok(SlimProfile {
+                docs: true,
+                strip: true,
+                ..NONE
+            })
+        );
+        assert_eq!(
+            "caches".parse(),
+            Ok(SlimProfile {
+                caches: true,
+                ..NONE
+            })
+        );
+        // List with every item of a named profile is that profile.
+        assert_eq!(
+            "caches,docs,locales,pycache".parse(),
+            Ok(SlimProfile::STANDARD)
+        );
+        assert!("docs,binaries".parse::<SlimProfile>().is_err());
+        assert!("".parse::<SlimProfile>().is_err());
+    }
+
+    #[test]
+    fn image_dir_rejects_symlinked_components() {
+        let root = TempDir::new("slim-root").unwrap();
+        let host = TempDir::new("slim-host").unwrap();
+        fs::create_dir_all(host.path().join("cache/apt/archives")).unwrap();
+        fs::create_dir_all(root.path().join("var/lib/apt/lists")).unwrap();
+        fs::create_dir_all(root.path().join("usr/share")).unwrap();
+        // Absolute symlink in a parent directory, e.g. var/cache -> /var/cache.
+        symlink(host.path().join("cache"), root.path().join("var/cache")).unwrap();
+        // Symlinked last component.
+        symlink("/usr/share/doc", root.path().join("usr/share/doc")).unwrap();
+
+        assert_eq!(
+            image_dir(root.path(), "var/lib/apt/lists"),
+            Some(root.path().join("var/lib/apt/lists"))
+        );
+        assert_eq!(image_dir(root.path(), "var/cache/apt/archives"), None);
+        assert_eq!(image_dir(root.path(), "var/cache/apt"), None);
+        assert_eq!(image_dir(root.path(), "usr/share/doc"), None);
+        assert_eq!(image_dir(root.path(), "usr/share/man"), None);
+    }
+
+    #[test]
+    fn display_round_trip() {
+        for s in [
+            "standard",
+            "all",
+            "caches",
+            "docs,strip",
+            "locales,pycache",
+            "caches,docs,locales,strip",
+        ] {
+            let profile: SlimProfile = s.parse().unwrap();
+            assert_eq!(profile.to_string(), s);
+            assert_eq!(profile.to_string().parse(), Ok(profile));
+        }
+        // Items are printed in a fixed order.
+        assert_eq!(
+            "strip,docs".parse::<SlimProfile>().unwrap().to_string(),
+            "docs,strip"
+        );
+    }
+}
@@ -0,0 +1,338 @@
+//! Root filesystem tarballs given with `--rootfs-tar`.
+//!
+//! Tarballs are streamed through the decompressor into `tar` running as root, so they are never
+//! extracted into a temporary directory. Compression is detected from the content.
+
+use std::fs::File;
+use std::io::{self, Read, Write};
+use std::path::Path;
+use std::process::{Child, Command, ExitStatus, Stdio};
+use std::thread;
+
+use anyhow::{Context, Result};
+
+use crate::builders::size;
+
+/// `--rootfs-tar` value reading the tarball from stdin.
+pub const STDIN: &str = "-";
+
+/// Number of bytes needed to detect compression.
+const MAGIC_LEN: usize = 6;
+
+/// Block size used to estimate disk usage of the tarball content.
+const BLOCK_SIZE: u64 = 4096;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Compression {
+    None,
+    Gzip,
+    Xz,
+    Zstd,
+}
+
+impl Compression {
+    /// Detect compression from magic bytes at the start of the tarball.
+    fn detect(magic: &[u8]) -> Self {
+        if magic.starts_with(&[0x1f, 0x8b]) {
+            Self::Gzip
+        } else if magic.starts_with(&[0xfd, b'7', b'z', b'X', b'Z', 0x00]) {
+            Self::Xz
+        } else if magic.starts_with(&[0x28, 0xb5, 0x2f, 0xfd]) {
+            Self::Zstd
+        } else {
+            Self::None
+        }
+    }
+
+    /// Command decompressing stdin to stdout.
+    fn decompressor(&self) -> Option<[&'static str; 2]> {
+        match self {
+            Self::None => None,
+            Self::Gzip => Some(["gzip", "-dc"]),
+            Self::Xz => Some(["xz", "-dc"]),
+            Self::Zstd => Some(["zstd", "-dc"]),
+        }
+    }
+}
+
+impl std::fmt::Display for Compression {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::None => write!(f, "none"),
+            Self::Gzip => write!(f, "gzip"),
+            Self::Xz => write!(f, "xz"),
+            Self::Zstd => write!(f, "zstd"),
+        }
+    }
+}
+
+/// Extract tarball `source` (file or [`STDIN`]) into `target` directory as root,
+/// keeping numeric owners, permissions and extended attributes.
+pub fn extract(source: &str, target: &Path) -> Result<()> {
+    let target = target.to_str().context("Invalid target path")?;
+    pipe(
+        source,
+        &[
+            "tar",
+            "--extract",
+            "--same-permissions",
+            "--numeric-owner",
+            "--xattrs",
+            "--xattrs-include=*",
+            "--directory",
+            target,
+            "--file",
+            "-",
+        ],
+        true,
+    )
+    .context(format!("Failed to extract rootfs tarball {}", source))?;
+    Ok(())
+}
+
+/// Estimate disk usage of the tarball content from its listing.
+///
+/// The tarball is read for the listing, so it can't be [`STDIN`].
+pub fn content_usage(source: &Path) -> Result<size::ContentUsage> {
+    let source = source.to_str().context("Invalid tarball path")?;
+    if source == STDIN {
+        anyhow::bail!(
+            "Tarball from stdin is read only once and can't be listed to measure its content"
+        );
+    }
+    let listing = pipe(
+        source,
+        &[
+            "tar",
+            "--list",
+            "--verbose",
+            "--numeric-owner",
+            "--file",
+            "-",
+        ],
+        false,
+    )
+    .context(format!("Failed to list rootfs tarball {}", source))?;
+    parse_listing(&listing).context(format!("Failed to parse listing of {}", source))
+}
+
+/// Sum disk usage of entries in `tar --list --verbose` output.
+fn parse_listing(listing: &str) -> Result<size::ContentUsage> {
+    let mut usage = size::ContentUsage::default();
+    for line in listing.lines() {
+        // Lines look like `-rw-r--r-- 0/0 1234 2024-01-01 00:00 path`.
+        let mut fields = line.split_whitespace();
+        let Some(kind) = fields.next().and_then(|mode| mode.chars().next()) else {
+            continue;
+        };
+        match kind {
+            '-' => {
+                let bytes = fields
+                    .nth(1)
+                    .and_then(|size| size.parse::<u64>().ok())
+                    .context(format!("Invalid size of regular file in line: {}", line))?;
+                usage.bytes += size::align_up(bytes, BLOCK_SIZE);
+            }
+            'd' => usage.bytes += BLOCK_SIZE,
+            // Hardlinks share the inode of their target.
+            'h' => continue,
+            _ => {}
+        }
+        usage.inodes += 1;
+    }
+    Ok(usage)
+}
+
+/// Decompressor process with the thread collecting its stderr.
+struct Decompressor {
+    child: Child,
+    stderr: thread::JoinHandle<io::Result<String>>,
+}
+
+/// Feed tarball `source` through its decompressor into `command` and return its stdout.
+fn pipe(source: &str, command: &[&str], as_root: bool) -> Result<String> {
+    let mut input: Box<dyn Read + Send> = if source == STDIN {
+        Box::new(io::stdin())
+    } else {
+        Box::new(File::open(source).context("Failed to open tarball")?)
+    };
+    let mut magic = Vec::with_capacity(MAGIC_LEN);
+    (&mut input)
+        .take(MAGIC_LEN as u64)
+        .read_to_end(&mut magic)
+        .context("Failed to read tarball")?;
+    let compression = Compression::detect(&magic);
+    log::debug!("tarball {} compression: {}", source, compression);
+
+    let program = if as_root { "sudo" } else { command[0] };
+    let args = if as_root { command } else { &command[1..] };
+    log::debug!("running command: {program} {:?}", args);
+    let mut consumer = Command::new(program)
+        .args(args)
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .spawn()
+        .context("Failed to spawn command")?;
+    let consumer_stdin = consumer.stdin.take().expect("stdin is piped");
+
+    // Stderr of the decompressor is read from another thread, so it can't fill the pipe
+    // and block the decompressor while the consumer is waited for.
+    let (mut writer, decompressor): (Box<dyn Write + Send>, Option<Decompressor>) =
+        match compression.decompressor() {
+            Some([program, arg]) => {
+                let mut child = Command::new(program)
+                    .arg(arg)
+                    .stdin(Stdio::piped())
+                    .stdout(consumer_stdin)
+                    .stderr(Stdio::piped())
+                    .spawn()
+                    .context(format!("Failed to spawn {}", program))?;
+                let stdin = child.stdin.take().expect("stdin is piped");
+                let mut stderr = child.stderr.take().expect("stderr is piped");
+                let stderr = thread::spawn(move || -> io::Result<String> {
+                    let mut message = String::new();
+                    stderr.read_to_string(&mut message)?;
+                    Ok(message)
+                });
+                (Box::new(stdin), Some(Decompressor { child, stderr }))
+            }
+            None => (Box::new(consumer_stdin), None),
+        };
+
+    // Input is written from another thread, so the output of the consumer can't block it.
+    let feeder = thread::spawn(move || -> io::Result<()> {
+        writer.write_all(&magic)?;
+        io::copy(&mut input, &mut writer)?;
+        Ok(())
+    });
+
+    let output = consumer
+        .wait_with_output()
+        .context("Failed to wait for command")?;
+    let decompressor_output = decompressor
+        .map(|mut decompressor| -> Result<(ExitStatus, String)> {
+            let status = decompressor
+                .child
+                .wait()
+                .context("Failed to wait for decompressor")?;
+            let message = decompressor
+                .stderr
+                .join()
+                .expect("decompressor stderr reader panicked")
+                .context("Failed to read decompressor output")?;
+            Ok((status, message))
+        })
+        .transpose()?;
+    let fed = feeder.join().expect("tarball feeder panicked");
+
+    for line in String::from_utf8_lossy(&output.stderr).lines() {
+        log::debug!(target: command[0], "{}", line);
+    }
+    // Decompressor killed by closed pipe has no message, then the failure of the consumer matters.
+    if let Some((status, message)) = decompressor_output {
+        if !status.success() && !message.trim().is_empty() {
+            anyhow::bail!(
+                "Failed to decompress {} tarball: {}",
+                compression,
+                message.trim()
+            );
+        }
+    }
+    if !output.status.success() {
+        anyhow::bail!("Command failed with status {}", output.status);
+    }
+    match fed {
+        // Tar may stop reading after end-of-archive blocks, before padding at the end.
+        Err(e) if e.kind() == io::ErrorKind::BrokenPipe => {}
+        fed => fed.context("Failed to read tarball")?,
+    }
+
+    String::from_utf8(output.stdout).context("Failed to parse command output")
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs;
+
+    use super::*;
+    use crate::builders::skopeo_builder::SkopeoSyslinuxBuilder;
+    use tempdir::TempDir;
+
+    const LISTING: &str = "\
+drwxr-xr-x 0/0               0 2024-01-01 00:00 ./
+drwxr-xr-x 0/0               0 2024-01-01 00:00 ./etc/
+-rw-r--r-- 0/0               8 2024-01-01 00:00 ./etc/hostname
+-rwxr-xr-x 0/0            5000 2024-01-01 00:00 ./bin/app
+hrwxr-xr-x 0/0               0 2024-01-01 00:00 ./bin/app-link link to ./bin/app
+lrwxrwxrwx 0/0               0 2024-01-01 00:00 ./bin/sh -> app
+crw-rw-rw- 0/0             1,3 2024-01-01 00:00 ./dev/null
+";
+
+    #[test]
+    fn detect_compression() {
+        assert_eq!(Compression::detect(&[0x1f, 0x8b, 0x08]), Compression::Gzip);
+        assert_eq!(
+            Compression::detect(&[0xfd, b'7', b'z', b'X', b'Z', 0x00]),
+            Compression::Xz
+        );
+        assert_eq!(
+            Compression::detect(&[0x28, 0xb5, 0x2f, 0xfd, 0x04, 0x58]),
+            Compression::Zstd
+        );
+        assert_eq!(Compression::detect(b"etc/\0\0"), Compression::None);
+        // Truncated magic is not enough.
+        assert_eq!(Compression::detect(&[0xfd, b'7', b'z']), Compression::None);
+        assert_eq!(Compression::detect(&[]), Compression::None);
+    }
+
+    #[test]
+    fn parse_listing_usage() {
+        let usage = parse_listing(LISTING).unwrap();
+        // Two directories and two files of one and two blocks.
+        assert_eq!(usage.bytes, 5 * BLOCK_SIZE);
+        // Hardlink shares the inode of its target.
+        assert_eq!(usage.inodes, 6);
+        assert_eq!(parse_listing("").unwrap().bytes, 0);
+    }
+
+    #[test]
+    fn parse_listing_rejects_invalid_file_size() {
+        assert!(parse_listing("-rw-r--r-- 0/0 1K 2024-01-01 00:00 ./etc/hostname").is_err());
+        assert!(parse_listing("-rw-r--r-- 0/0").is_err());
+    }
+
+    #[test]
+    fn stdin_can_not_be_measured() {
+        assert!(content_usage(Path::new(STDIN)).is_err());
+    }
+
+    #[test]
+    fn pipe_through_decompressor() {
+        let dir = TempDir::new("tarball").unwrap();
+        let compressed = dir.path().join("data.gz");
+        let compressed = compressed.to_str().unwrap();
+        SkopeoSyslinuxBuilder::run_command(
+            &["sh", "-c", &format!("echo data | gzip -n > {}", compressed)],
+            false,
+        )
+        .unwrap();
+        assert_eq!(pipe(compressed, &["cat"], false).unwrap(), "data\n");
+
+        // Message of the failed decompressor is reported.
+        let corrupted = dir.path().join("corrupted.gz");
+        fs::write(
+            &corrupted,
+            [&[0x1f, 0x8b, 0x08][..], &[0xff; 4096]].concat(),
+        )
+        .unwrap();
+        let error = pipe(corrupted.to_str().unwrap(), &["cat"], false).unwrap_err();
+        assert!(
+            error
+                .to_string()
+                .starts_with("Failed to decompress gzip tarball: "),
+            "{}",
+            error
+        );
+    }
+}
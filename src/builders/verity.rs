@@ -0,0 +1,105 @@
+//! dm-verity protection of the root filesystem.
+//!
+//! Hash tree is appended right after the filesystem data without verity superblock,
+//! so the whole verity table is described by kernel command line parameters:
+//! `verity.roothash=<hex> verity.salt=<hex> verity.blocks=<number of data blocks>`.
+//! Initramfs creates the verity device from them before mounting root.
+
+use std::fs;
+use std::path::Path;
+
+use anyhow::{Context, Result};
+use serde::Serialize;
+
+use crate::builders::random;
+use crate::builders::size::{self, VERITY_BLOCK_SIZE};
+use crate::builders::skopeo_builder::SkopeoSyslinuxBuilder;
+
+/// Salt used instead of random one in reproducible builds.
+pub const REPRODUCIBLE_SALT: &str =
+    "47564c5447564c5447564c5447564c5447564c5447564c5447564c5447564c54";
+
+/// Parameters of the verity device protecting root filesystem.
+#[derive(Debug, Clone, Serialize)]
+pub struct Verity {
+    pub root_hash: String,
+    pub salt: String,
+    /// Number of data blocks. Hash tree starts right after them.
+    pub data_blocks: u64,
+}
+
+impl Verity {
+    /// Kernel command line parameters read by initramfs.
+    pub fn cmdline(&self) -> String {
+        format!(
+            "verity.roothash={} verity.salt={} verity.blocks={}",
+            self.root_hash, self.salt, self.data_blocks
+        )
+    }
+}
+
+/// Append dm-verity hash tree to the filesystem image at `image`.
+/// Random salt is used if `salt` is not given.
+pub fn format(image: &Path, salt: Option<&str>) -> Result<Verity> {
+    let image_str = image
+        .to_str()
+        .context("Invalid root filesystem image path")?;
+    let data_size = size::align_up(
+        fs::metadata(image)
+            .context("Failed to read root filesystem image metadata")?
+            .len(),
+        VERITY_BLOCK_SIZE,
+    );
+    let data_blocks = data_size / VERITY_BLOCK_SIZE;
+    let salt = match salt {
+        Some(salt) => salt.to_string(),
+        None => random_salt()?,
+    };
+
+    // Image must already have space for the hash tree when it is attached to a loop device.
+    SkopeoSyslinuxBuilder::run_command(
+        &[
+            "truncate",
+            "-s",
+            &(data_size + size::verity_hash_size(data_size)).to_string(),
+            image_str,
+        ],
+        true,
+    )
+    .context("Failed to extend root filesystem image for verity hash tree")?;
+
+    let output = SkopeoSyslinuxBuilder::run_command_output(
+        &[
+            "veritysetup",
+            "format",
+            "--no-superblock",
+            "--hash=sha256",
+            &format!("--data-block-size={}", VERITY_BLOCK_SIZE),
+            &format!("--hash-block-size={}", VERITY_BLOCK_SIZE),
+            &format!("--data-blocks={}", data_blocks),
+            &format!("--hash-offset={}", data_size),
+            &format!("--salt={}", salt),
+            image_str,
+            image_str,
+        ],
+        true,
+    )
+    .context("Failed to create verity hash tree")?;
+
+    let root_hash = output
+        .lines()
+        .find_map(|line| line.strip_prefix("Root hash:"))
+        .map(|hash| hash.trim().to_string())
+        .context("Root hash not found in veritysetup output")?;
+
+    Ok(Verity {
+        root_hash,
+        salt,
+        data_blocks,
+    })
+}
+
+fn random_salt() -> Result<String> {
+    let salt = random::bytes::<32>().context("Failed to generate verity salt")?;
+    Ok(salt.iter().map(|byte| format!("{:02x}", byte)).collect())
+}
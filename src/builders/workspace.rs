@@ -0,0 +1,302 @@
+//! Working directory of a single build with the loop device and mounts it owns.
+//!
+//! Every build gets its own directory under `$TMPDIR`, so several builds can run at the same
+//! time. Only the loop device attached by the build is detached and only filesystems mounted
+//! by it are unmounted. This happens when [`Workspace`] is dropped, so also on errors and after
+//! the build is interrupted with Ctrl-C.
+
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, Ordering};
+
+use anyhow::{Context, Result};
+use tempdir::TempDir;
+
+use crate::builders::skopeo_builder::SkopeoSyslinuxBuilder;
+
+static INTERRUPTED: AtomicBool = AtomicBool::new(false);
+
+/// Ask the running build to stop before its next step.
+pub fn interrupt() {
+    INTERRUPTED.store(true, Ordering::SeqCst);
+}
+
+/// Fail if the build was interrupted.
+pub fn check_interrupted() -> Result<()> {
+    if INTERRUPTED.load(Ordering::SeqCst) {
+        anyhow::bail!("Build interrupted");
+    }
+    Ok(())
+}
+
+/// Loop device attached to a disk image, detached on drop.
+#[derive(Debug)]
+pub struct LoopDevice {
+    device: String,
+}
+
+impl LoopDevice {
+    /// Attach the first free loop device to `image` and scan its partitions.
+    pub fn attach(image: &str) -> Result<Self> {
+        let device = SkopeoSyslinuxBuilder::run_command_output(
+            &["losetup", "--find", "--show", "--partscan", image],
+            true,
+        )
+        .context("Failed to set up loop device")?
+        .trim()
+        .to_string();
+        log::debug!("attached {} to {}", device, image);
+        Ok(Self { device })
+    }
+
+    pub fn device(&self) -> &str {
+        &self.device
+    }
+
+    /// Device of the partition with 1-based `number`.
+    pub fn partition(&self, number: u32) -> String {
+        format!("{}p{}", self.device, number)
+    }
+}
+
+impl Drop for LoopDevice {
+    fn drop(&mut self) {
+        match SkopeoSyslinuxBuilder::run_command(&["losetup", "-d", &self.device], true) {
+            Ok(()) => log::debug!("detached {}", self.device),
+            Err(e) => log::warn!("failed to detach {}: {:#}", self.device, e),
+        }
+    }
+}
+
+/// Per-build directory holding the root filesystem and everything attached to the build.
+#[derive(Debug)]
+pub struct Workspace {
+    dir: PathBuf,
+    loop_device: Option<LoopDevice>,
+    /// Mountpoints in the order they were mounted.
+    mounts: Vec<PathBuf>,
+    removed: bool,
+}
+
+impl Workspace {
+    pub fn new() -> Result<Self> {
+        let dir = TempDir::new("gvltctl-build")
+            .context("Failed to create build directory")?
+            .into_path();
+        log::debug!("created build directory {}", dir.display());
+        Ok(Self {
+            dir,
+            loop_device: None,
+            mounts: Vec::new(),
+            removed: false,
+        })
+    }
+
+    /// Root directory of the VM: mountpoint of the root partition or staging directory
+    /// of the generated root filesystem.
+    pub fn root_dir(&self) -> PathBuf {
+        self.dir.join("mnt")
+    }
+
+    /// `/boot` of the VM: mountpoint of the boot partition or its staging directory.
+    pub fn boot_dir(&self) -> PathBuf {
+        self.root_dir().join("boot")
+    }
+
+    /// Staged boot files are moved here while root filesystem is generated.
+    pub fn boot_aside_dir(&self) -> PathBuf {
+        self.dir.join("boot")
+    }
+
+    /// Attach loop device to the disk image.
+    pub fn attach(&mut self, image: &str) -> Result<&LoopDevice> {
+        if self.loop_device.is_some() {
+            anyhow::bail!("Loop device is already attached");
+        }
+        Ok(self.loop_device.insert(LoopDevice::attach(image)?))
+    }
+
+    pub fn loop_device(&self) -> Result<&LoopDevice> {
+        self.loop_device
+            .as_ref()
+            .context("Loop device is not attached")
+    }
+
+    pub fn mount(&mut self, device: &str, target: &Path) -> Result<()> {
+        SkopeoSyslinuxBuilder::run_command(&["mount", device, target.to_str().unwrap()], true)
+            .context(format!("Failed to mount {}", device))?;
+        self.mounts.push(target.to_path_buf());
+        Ok(())
+    }
+
+    pub fn unmount(&mut self, target: &Path) -> Result<()> {
+        SkopeoSyslinuxBuilder::run_command(&["umount", target.to_str().unwrap()], true)
+            .context(format!("Failed to unmount {}", target.display()))?;
+        self.mounts.retain(|mount| mount != target);
+        Ok(())
+    }
+
+    pub fn is_mounted(&self, target: &Path) -> bool {
+        self.mounts.iter().any(|mount| mount == target)
+    }
+
+    /// Unmount filesystems, detach loop device and remove the build directory.
+    /// Directory is kept if anything is still mounted there.
+    pub fn cleanup(&mut self) -> Result<()> {
+        if self.removed {
+            return Ok(());
+        }
+        while let Some(target) = self.mounts.pop() {
+            if let Err(e) =
+                SkopeoSyslinuxBuilder::run_command(&["umount", target.to_str().unwrap()], true)
+            {
+                log::warn!("failed to unmount {}: {:#}", target.display(), e);
+            }
+        }
+        self.loop_device = None;
+
+        // Staging directory is not empty and is owned by root.
+        // Never remove it recursively while something is still mounted there.
+        if has_mounts(&self.dir)? {
+            anyhow::bail!(
+                "{} is still mounted, leaving it in place",
+                self.dir.display()
+            );
+        }
+        SkopeoSyslinuxBuilder::run_command(&["rm", "-rf", self.dir.to_str().unwrap()], true)
+            .context(format!("Failed to remove {}", self.dir.display()))?;
+        log::debug!("removed build directory {}", self.dir.display());
+        self.removed = true;
+        Ok(())
+    }
+}
+
+impl Drop for Workspace {
+    fn drop(&mut self) {
+        if let Err(e) = self.cleanup() {
+            log::warn!("failed to clean up build directory: {:#}", e);
+        }
+    }
+}
+
+/// Check if anything is mounted at or below given path.
+pub fn has_mounts(path: &Path) -> Result<bool> {
+    let mounts = fs::read_to_string("/proc/self/mounts").context("Failed to read mounts")?;
+    // Mount targets are canonical paths.
+    let path = match fs::canonicalize(path) {
+        Ok(path) => path,
+        Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(false),
+        Err(e) => return Err(e).context(format!("Failed to resolve {}", path.display())),
+    };
+    Ok(mounted_under(&mounts, &path))
+}
+
+/// Check if any target in `mounts` table (format of `/proc/self/mounts`) is at or below `path`.
+fn mounted_under(mounts: &str, path: &Path) -> bool {
+    mounts
+        .lines()
+        .filter_map(|line| line.split_whitespace().nth(1))
+        .any(|target| Path::new(&unescape_mount_field(target)).starts_with(path))
+}
+
+/// Decode octal escapes of space, tab, newline and backslash in mount table fields,
+/// e.g. `\040` for space.
+fn unescape_mount_field(field: &str) -> String {
+    let bytes = field.as_bytes();
+    let mut unescaped = Vec::with_capacity(bytes.len());
+    let mut i = 0;
+    while i < bytes.len() {
+        let code = bytes
+            .get(i + 1..i + 4)
+            .filter(|digits| bytes[i] == b'\\' && digits.iter().all(|d| (b'0'..=b'7').contains(d)))
+            .and_then(|digits| u8::from_str_radix(std::str::from_utf8(digits).ok()?, 8).ok());
+        match code {
+            Some(code) => {
+                unescaped.push(code);
+                i += 4;
+            }
+            None => {
+                unescaped.push(bytes[i]);
+                i += 1;
+            }
+        }
+    }
+    String::from_utf8_lossy(&unescaped).into_owned()
+}
+
+/// Detach loop devices left attached to `image` by an interrupted build, which was killed
+/// before it could clean up, and unmount their partitions.
+pub fn release_stale(image: &str) -> Result<()> {
+    let output = SkopeoSyslinuxBuilder::run_command_output(&["losetup", "-j", image], true)
+        .context("Failed to list loop devices")?;
+    let mounts = fs::read_to_string("/proc/self/mounts").context("Failed to read mounts")?;
+    for device in output.lines().filter_map(|line| line.split(':').next()) {
+        // Deepest mounts go first.
+        let mut targets = mounts
+            .lines()
+            .filter_map(|line| {
+                let mut fields = line.split_whitespace();
+                let source = fields.next()?;
+                let target = fields.next()?;
+                (source == device || source.starts_with(&format!("{}p", device)))
+                    .then(|| unescape_mount_field(target))
+            })
+            .collect::<Vec<_>>();
+        targets.reverse();
+        for target in &targets {
+            SkopeoSyslinuxBuilder::run_command(&["umount", target], true)
+                .context(format!("Failed to unmount {}", target))?;
+        }
+        SkopeoSyslinuxBuilder::run_command(&["losetup", "-d", device], true)
+            .context(format!("Failed to detach {}", device))?;
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::os::unix::fs::symlink;
+
+    use super::*;
+
+    const MOUNTS: &str = "\
+proc /proc proc rw,nosuid,nodev,noexec,relatime 0 0
+/dev/loop7p2 /tmp/gvltctl\\040build.x1/root ext4 rw,relatime 0 0
+/dev/loop7p1 /tmp/gvltctl\\040build.x1/root/boot vfat rw,relatime 0 0
+";
+
+    #[test]
+    fn unescape_mount_targets() {
+        assert_eq!(unescape_mount_field("/proc"), "/proc");
+        assert_eq!(unescape_mount_field("/tmp/a\\040b"), "/tmp/a b");
+        assert_eq!(unescape_mount_field("/tmp/a\\011b\\134c"), "/tmp/a\tb\\c");
+        // Incomplete escapes are kept.
+        assert_eq!(unescape_mount_field("/tmp/a\\04"), "/tmp/a\\04");
+    }
+
+    #[test]
+    fn mounts_under_path_with_space() {
+        assert!(mounted_under(MOUNTS, Path::new("/tmp/gvltctl build.x1")));
+        assert!(mounted_under(
+            MOUNTS,
+            Path::new("/tmp/gvltctl build.x1/root/boot")
+        ));
+        assert!(!mounted_under(MOUNTS, Path::new("/tmp/gvltctl")));
+        assert!(!mounted_under(
+            MOUNTS,
+            Path::new("/tmp/gvltctl\\040build.x1")
+        ));
+    }
+
+    #[test]
+    fn mounts_through_symlink() {
+        let dir = TempDir::new("mounts").unwrap();
+        assert!(!has_mounts(dir.path()).unwrap());
+        assert!(!has_mounts(&dir.path().join("missing")).unwrap());
+        // /proc is mounted, the link is resolved to it.
+        let link = dir.path().join("proc");
+        symlink("/proc", &link).unwrap();
+        assert!(has_mounts(&link).unwrap());
+    }
+}
@@ -1,7 +1,10 @@
+use crate::builders::rootfs;
 use crate::builders::skopeo_builder::SkopeoSyslinuxBuilder;
+use crate::builders::workspace;
 use crate::builders::{BuildOptions, ImageBuilder};
 use anyhow::Result;
 use clap::{Arg, ArgGroup, ValueHint};
+use std::path::Path;
 
 pub fn get_command() -> clap::Command {
     clap::Command::new("build")
@@ -15,8 +18,12 @@ pub fn get_command() -> clap::Command {
                        - docker: Docker registry (e.g., docker://docker.io/debian:latest)\n\
                        - containers-storage: Local container storage (e.g., containers-storage:localhost/myimage:latest)\n\
                        - dir: Local directory (e.g., dir:/path/to/image)\n\
-                       - oci: OCI image layout (e.g., oci:/path/to/layout)\n\
-                       - docker-archive: Docker archive (e.g., docker-archive:/path/to/archive.tar)\n\
+                       - oci: OCI image layout (e.g., oci:/path/to/layout or oci:/path/to/layout:name)\n\
+                       - oci-archive: OCI layout in a tarball (e.g., oci-archive:/path/to/archive.tar)\n\
+                       - docker-archive: Docker archive (e.g., docker-archive:/path/to/archive.tar or docker-archive:/path/to/archive.tar:name:tag)\n\
+                       OCI layout directory (with oci-layout file) or tarball without transport is read as OCI layout\n\
+                       or docker archive, e.g. output of `podman save` or `docker save`. Local images are read without skopeo.\n\
+                       Image for --platform is selected from multi-platform images.\n\
                        Examples:\n\
                        - docker://docker.io/ubuntu:20.04\n\
                        - containers-storage:localhost/custom-image:latest")
@@ -31,6 +38,46 @@ pub fn get_command() -> clap::Command {
                        prepared root filesystem, typically extracted from a container or created manually.")
                 .required(false)
         )
+        .arg(
+            Arg::new("exclude")
+                .long("exclude")
+                .value_name("PATTERN")
+                .help("Glob pattern of paths in --rootfs-dir not copied into the image. Can be specified multiple times.")
+                .long_help("Glob pattern of paths in --rootfs-dir not copied into the image. Can be specified multiple times.\n\
+                            Pattern without '/' matches file names at any depth, e.g. '*.pyc' or '.git'.\n\
+                            Other patterns match paths relative to --rootfs-dir, e.g. '/var/cache/**' or 'usr/share/doc'.\n\
+                            '*' and '?' don't match '/', '**' matches any number of directories.\n\
+                            Content of excluded directories is skipped as well.")
+                .action(clap::ArgAction::Append)
+                .requires("rootfs_dir")
+                .required(false),
+        )
+        .arg(
+            Arg::new("owner_map")
+                .long("owner-map")
+                .value_name("FROM:TO")
+                .help("Map owner user and group ID of files copied from --rootfs-dir, e.g. 1000:0. Can be specified multiple times.")
+                .long_help("Map owner user and group ID of files copied from --rootfs-dir, e.g. 1000:0. Can be specified multiple times.\n\
+                            Useful for directories prepared without root privileges. '*:0' maps any ID.\n\
+                            The first matching mapping is used, IDs without a mapping are kept.")
+                .action(clap::ArgAction::Append)
+                .requires("rootfs_dir")
+                .required(false),
+        )
+        .arg(
+            Arg::new("rootfs_tar")
+                .long("rootfs-tar")
+                .value_name("FILE")
+                .value_hint(ValueHint::FilePath)
+                .help("Tarball containing the root filesystem to use, or '-' to read it from stdin. \
+                       Plain, gzip, xz and zstd compressed tarballs are supported.")
+                .long_help("Tarball containing the root filesystem to use, or '-' to read it from stdin.\n\
+                            Plain, gzip, xz and zstd compressed tarballs are supported, compression is detected from the content.\n\
+                            The tarball is extracted straight into the image filesystem, keeping numeric owners and permissions.\n\
+                            With --size auto the tarball is listed first to measure its content, so it is decompressed twice.\n\
+                            Tarball from stdin can be read only once, so it requires explicit --size.")
+                .required(false)
+        )
         .arg(
             Arg::new("containerfile")
                 .long("containerfile")
@@ -41,9 +88,85 @@ pub fn get_command() -> clap::Command {
                        The file will be used to build a new image which will then be used as the source.")
                 .required(false)
         )
+        .arg(
+            Arg::new("build_engine")
+                .long("engine")
+                .value_name("ENGINE")
+                .help("Tool used to build the image from --containerfile.")
+                .long_help("Tool used to build the image from --containerfile.\n\
+                            Image is built under a tag unique for the build and removed once it is extracted.\n\
+                            Images built with podman and buildah are read from containers-storage,\n\
+                            images built with docker from the docker daemon.")
+                .value_parser(["podman", "buildah", "docker"])
+                .required(false)
+                .default_value("podman"),
+        )
+        .arg(
+            Arg::new("build_context")
+                .long("context")
+                .value_name("DIR")
+                .value_hint(ValueHint::DirPath)
+                .help("Build context directory for --containerfile. Default: current directory.")
+                .requires("containerfile")
+                .required(false),
+        )
+        .arg(
+            Arg::new("build_target")
+                .long("target")
+                .value_name("STAGE")
+                .help("Target stage of multi-stage --containerfile to build.")
+                .requires("containerfile")
+                .required(false),
+        )
+        .arg(
+            Arg::new("build_arg")
+                .long("build-arg")
+                .value_name("KEY=VALUE")
+                .help("Build argument passed to the --containerfile build. Can be specified multiple times.")
+                .action(clap::ArgAction::Append)
+                .requires("containerfile")
+                .required(false),
+        )
+        .arg(
+            Arg::new("build_secret")
+                .long("secret")
+                .value_name("id=ID,src=PATH")
+                .help("Secret passed to the --containerfile build. Can be specified multiple times.")
+                .long_help("Secret passed to the --containerfile build. Can be specified multiple times.\n\
+                            Value is passed to the build engine as is, e.g. id=token,src=./token.txt.\n\
+                            Docker requires BuildKit for secrets.")
+                .action(clap::ArgAction::Append)
+                .requires("containerfile")
+                .required(false),
+        )
+        .arg(
+            Arg::new("arch")
+                .long("arch")
+                .value_name("ARCH")
+                .help("CPU architecture of the VM image.")
+                .long_help("CPU architecture of the VM image.\n\
+                            It selects the container platform, kernel defconfig and image, MIA binary and bootloader.\n\
+                            - x86_64: bzImage, booted with SYSLINUX or systemd-boot\n\
+                            - aarch64: arm64 Image, booted with systemd-boot (--boot uefi) or directly (--output-kind kernel+rootfs)\n\
+                            Kernel is cross-compiled if the host has another architecture, see --cross-compile.")
+                .value_parser(["x86_64", "aarch64"])
+                .required(false)
+                .default_value("x86_64"),
+        )
+        .arg(
+            Arg::new("platform")
+                .long("platform")
+                .value_name("OS/ARCH[/VARIANT]")
+                .help("Platform of the container image, e.g. linux/amd64. Default: derived from --arch.")
+                .long_help("Platform of the container image, e.g. linux/amd64. Default: derived from --arch.\n\
+                            It is selected from multi-platform images and passed to podman build for --containerfile.\n\
+                            The build fails if the image has no manifest for this platform.\n\
+                            Its architecture must match --arch (linux/amd64 for x86_64, linux/arm64 for aarch64).")
+                .required(false),
+        )
         .group(
             ArgGroup::new("image")
-                .args(["container_source", "rootfs_dir", "containerfile"])
+                .args(["container_source", "rootfs_dir", "rootfs_tar", "containerfile"])
                 .multiple(false)
                 .required(true)
         )
@@ -52,17 +175,80 @@ pub fn get_command() -> clap::Command {
                 .short('s')
                 .long("size")
                 .value_name("SIZE")
-                .help("Size of the disk image (e.g., 10G, 1024M). This determines the total capacity of the VM's virtual disk.")
+                .help("Size of the disk image (e.g., 10G, 1024M, auto, auto+20%). This determines the total capacity of the VM's virtual disk.")
+                .long_help("Size of the disk image (e.g., 10G, 1024M, auto, auto+20%). This determines the total capacity of the VM's virtual disk.\n\
+                            With 'auto' the size is calculated from the root filesystem content, kernel and MIA,\n\
+                            including filesystem overhead. 'auto+N%' adds N percent of free space on top of it.\n\
+                            If explicit size is too small for the content, the build fails before creating the image.")
                 .required(false)
                 .default_value("10G"),
         )
+        .arg(
+            Arg::new("boot_size")
+                .long("boot-size")
+                .value_name("SIZE")
+                .help("Size of the FAT boot partition (e.g., 200M, 1G).")
+                .long_help("Size of the FAT boot partition (e.g., 200M, 1G).\n\
+                            It holds the kernel, initramfs and bootloader files. Only used for disk images.")
+                .required(false)
+                .default_value("200M"),
+        )
+        .arg(
+            Arg::new("extra_partition")
+                .long("extra-partition")
+                .value_name("LABEL:SIZE:FS")
+                .help("Add data partition after the root partition. Example: scratch:1G:ext4")
+                .long_help("Add data partition after the root partition. Example: scratch:1G:ext4\n\
+                            Supported filesystems are ext4 and vfat. Partition is created empty with given filesystem label.\n\
+                            It can be mounted by MIA with --mount LABEL=<label>:<target>, e.g. --mount LABEL=scratch:/scratch.\n\
+                            MIA mounts it by filesystem label, which is upper case for vfat.\n\
+                            MBR partition table (--boot bios) supports at most 2 extra partitions. Only used for disk images.")
+                .action(clap::ArgAction::Append)
+                .required(false),
+        )
+        .arg(
+            Arg::new("rootfs_format")
+                .long("rootfs-format")
+                .value_name("FORMAT")
+                .help("Filesystem of the root partition.")
+                .long_help("Filesystem of the root partition.\n\
+                            - ext4: writable filesystem, populated through a loop mount\n\
+                            - squashfs: compressed read-only filesystem, generated with mksquashfs\n\
+                            - erofs: compressed read-only filesystem, generated with mkfs.erofs\n\
+                            Read-only formats are generated directly from the extracted rootfs, which makes images \
+                            smaller. They can't be used together with --rw-root.")
+                .value_parser(["ext4", "squashfs", "erofs"])
+                .required(false)
+                .default_value("ext4"),
+        )
+        .arg(
+            Arg::new("slim")
+                .long("slim")
+                .value_name("PROFILE")
+                .help("Remove package caches, docs, locales and Python bytecode caches from the root filesystem.")
+                .long_help("Remove files not needed at runtime from the root filesystem after it is installed.\n\
+                            PROFILE is 'standard' (default), 'all' or comma-separated list of:\n\
+                            - caches: apt, apk, dnf, yum and pip caches\n\
+                            - docs: /usr/share/doc, man and info pages\n\
+                            - locales: message translations in /usr/share/locale\n\
+                            - pycache: __pycache__ directories\n\
+                            - strip: strip symbols from ELF binaries and libraries (with --cross-compile toolchain for foreign --arch)\n\
+                            'standard' is everything except strip, 'all' includes strip.\n\
+                            Size before and after is printed. --size auto is calculated before slimming,\n\
+                            so with ext4 the freed space stays available in the root filesystem.")
+                .num_args(0..=1)
+                .require_equals(true)
+                .default_missing_value("standard")
+                .required(false),
+        )
         .arg(
             Arg::new("kernel_version")
                 .short('k')
                 .long("kernel")
                 .value_name("VERSION")
                 .help("Linux kernel version to use (e.g., v6.10). Use 'latest' for the most recent version. \
-                       This kernel will be compiled from source.")
+                       This kernel will be compiled from source. \
+                       Use 'prebuilt:<version>' (e.g., prebuilt:v6.12) to download a prebuilt kernel from --kernel-index instead.")
                 .required(false)
                 .default_value("v6.12"),
         )
@@ -75,15 +261,66 @@ pub fn get_command() -> clap::Command {
                 .required(false)
                 .default_value("https://github.com/torvalds/linux.git"),
         )
+        .arg(
+            Arg::new("kernel_index")
+                .long("kernel-index")
+                .value_name("URL")
+                .value_hint(ValueHint::Url)
+                .env("GEVULOT_KERNEL_INDEX")
+                .help("URL of the signed release index of prebuilt kernels. Used with --kernel prebuilt:<version>. \
+                       Signature is downloaded from the same URL with .sig suffix.")
+                .required(false),
+        )
+        .arg(
+            Arg::new("kernel_index_key")
+                .long("kernel-index-key")
+                .value_name("FILE")
+                .value_hint(ValueHint::FilePath)
+                .env("GEVULOT_KERNEL_INDEX_KEY")
+                .help("Path to a PEM public key verifying the release index signature. Used with --kernel prebuilt:<version>.")
+                .required(false),
+        )
         .arg(
             Arg::new("kernel_file")
                 .long("kernel-file")
                 .value_name("FILE")
                 .value_hint(ValueHint::FilePath)
                 .help("Path to a precompiled kernel file. Use this if you have a custom kernel or want to skip kernel compilation. \
-                       Example: /path/to/bzImage")
+                       Example: /path/to/bzImage (or arm64 Image for aarch64)")
                 .required(false),
         )
+        .arg(
+            Arg::new("kernel_defconfig")
+                .long("kernel-defconfig")
+                .value_name("FILE")
+                .value_hint(ValueHint::FilePath)
+                .help("Path to a base kernel config used instead of x86_64_defconfig (or defconfig for aarch64). \
+                       Gevulot config fragment and --kernel-config fragments are merged on top of it.")
+                .required(false)
+                .conflicts_with("kernel_file"),
+        )
+        .arg(
+            Arg::new("kernel_config")
+                .long("kernel-config")
+                .value_name("FILE")
+                .value_hint(ValueHint::FilePath)
+                .help("Kernel config fragment merged on top of the base config and Gevulot fragment. \
+                       Can be specified multiple times, later fragments take precedence. \
+                       Example: CONFIG_VIRTIO_FS=y")
+                .required(false)
+                .action(clap::ArgAction::Append)
+                .conflicts_with("kernel_file"),
+        )
+        .arg(
+            Arg::new("cross_compile")
+                .long("cross-compile")
+                .value_name("PREFIX")
+                .env("CROSS_COMPILE")
+                .help("Toolchain prefix used to build the kernel for another architecture than the host one. \
+                       Default: aarch64-linux-gnu- or x86_64-linux-gnu- if --arch differs from the host.")
+                .required(false)
+                .conflicts_with("kernel_file"),
+        )
         .arg(
             Arg::new("nvidia_drivers")
                 .long("nvidia-drivers")
@@ -102,6 +339,20 @@ pub fn get_command() -> clap::Command {
                 .conflicts_with_all(["init", "init_args"])
                 .required(false)
         )
+        .arg(
+            Arg::new("modules_install")
+                .long("kernel-modules-install")
+                .value_name("MODE")
+                .help("Kernel modules to install from the built kernel into /lib/modules.")
+                .long_help("Kernel modules to install from the built kernel into /lib/modules.\n\
+                            - all: all modules built with the kernel\n\
+                            - requested: modules given with --kernel-module and their dependencies\n\
+                            - none: don't install any modules\n\
+                            Every --kernel-module is verified to be available in the image.")
+                .value_parser(["all", "requested", "none"])
+                .required(false)
+                .default_value("requested"),
+        )
         .arg(
             Arg::new("mount")
                 .long("mount")
@@ -109,6 +360,7 @@ pub fn get_command() -> clap::Command {
                 .help("[MIA] Mount directory on startup. Example: input:/mnt/input\n\
                        These options are passed to MIA to mount before running any commands. Arguments are corresponding\n\
                        to mount syscall. If no <fstype> is specified, MIA will use 9p by default.\n\
+                       Source LABEL=<label> refers to --extra-partition with that label and uses its filesystem by default.\n\
                        MIA will mount /proc by default. If you don't want this, use --no-default-mounts.\n\
                        This option can't be used together with --init or --init-args.")
                 .action(clap::ArgAction::Append)
@@ -181,6 +433,70 @@ pub fn get_command() -> clap::Command {
                 .required(false)
                 .action(clap::ArgAction::SetTrue),
         )
+        .arg(
+            Arg::new("initramfs")
+                .long("initramfs")
+                .help("Boot through a minimal initramfs that mounts the root filesystem.")
+                .long_help("Boot through a minimal initramfs that mounts the root filesystem.\n\
+                            Initramfs loads storage and filesystem drivers built as modules, finds the root\n\
+                            device by path, LABEL= or UUID= and switches to MIA (/usr/lib/mia/mia) or --init\n\
+                            in the root filesystem.\n\
+                            The same image can then be attached both as SCSI/SATA (sda) and virtio-blk (vda) disk.\n\
+                            Requires statically linked busybox on the host.")
+                .required(false)
+                .action(clap::ArgAction::SetTrue),
+        )
+        .arg(
+            Arg::new("verity")
+                .long("verity")
+                .help("Protect root filesystem with dm-verity. Requires --initramfs.")
+                .long_help("Protect root filesystem with dm-verity. Requires --initramfs.\n\
+                            Hash tree is appended to the root filesystem and its root hash is passed on the kernel\n\
+                            command line. Initramfs sets up the verity device with dmsetup from the host before\n\
+                            mounting root, so any modification of the root filesystem makes reads fail.\n\
+                            Requires veritysetup and dmsetup on the host and dm-verity support in the kernel.")
+                .requires("initramfs")
+                .required(false)
+                .action(clap::ArgAction::SetTrue),
+        )
+        .arg(
+            Arg::new("busybox_file")
+                .long("busybox-file")
+                .value_name("FILE")
+                .value_hint(ValueHint::FilePath)
+                .help("Path to static busybox binary used in initramfs. If none provided, following paths will be tried:\n\
+                        - /usr/lib/initramfs-tools/bin/busybox\n\
+                        - /usr/bin/busybox\n\
+                        - /bin/busybox\n\
+                        - /usr/sbin/busybox")
+                .requires("initramfs")
+                .required(false),
+        )
+        .arg(
+            Arg::new("root_device")
+                .long("root-device")
+                .value_name("DEVICE")
+                .help("Root device passed to the kernel as root=. Example: /dev/vda2")
+                .long_help("Root device passed to the kernel as root=. Example: /dev/vda2\n\
+                            If none provided, root partition is referenced by PARTUUID in disk images.\n\
+                            With --initramfs, ext4 and EROFS root filesystems are referenced by LABEL=ROOTFS.\n\
+                            Initramfs resolves device paths, LABEL= and UUID=, but not PARTUUID=.\n\
+                            Otherwise /dev/sda2 is used for disk images and /dev/vda for kernel+rootfs output.")
+                .required(false),
+        )
+        .arg(
+            Arg::new("boot_mode")
+                .long("boot")
+                .value_name("MODE")
+                .help("Firmware interface the image will be bootable with.")
+                .long_help("Firmware interface the image will be bootable with.\n\
+                            - bios: MBR partition table, SYSLINUX bootloader\n\
+                            - uefi: GPT partition table, EFI system partition with systemd-boot (e.g. for OVMF)\n\
+                            - hybrid: GPT partition table, bootable with both SYSLINUX and systemd-boot\n\
+                            Default is bios for x86_64 and uefi for aarch64, which supports only uefi.")
+                .value_parser(["bios", "uefi", "hybrid"])
+                .required(false),
+        )
         .arg(
             Arg::new("mbr_file")
                 .long("mbr-file")
@@ -189,7 +505,19 @@ pub fn get_command() -> clap::Command {
                 .help("Path to MBR file. If none provided, following paths will be tried:\n\
                         - /usr/share/syslinux/mbr.bin\n\
                         - /usr/lib/syslinux/mbr/mbr.bin\n\
-                        - /usr/lib/syslinux/bios/mbr.bin")
+                        - /usr/lib/syslinux/bios/mbr.bin\n\
+                        With --boot hybrid gptmbr.bin is looked up in the same directories.")
+                .required(false),
+        )
+        .arg(
+            Arg::new("efi_loader_file")
+                .long("efi-loader-file")
+                .value_name("FILE")
+                .value_hint(ValueHint::FilePath)
+                .help("Path to systemd-boot EFI binary. Used with --boot uefi or --boot hybrid. If none provided, following paths will be tried:\n\
+                        - /usr/lib/systemd/boot/efi/systemd-bootx64.efi\n\
+                        - /usr/share/systemd/boot/efi/systemd-bootx64.efi\n\
+                        For aarch64 systemd-bootaa64.efi is looked up in the same directories.")
                 .required(false),
         )
         .arg(
@@ -198,9 +526,70 @@ pub fn get_command() -> clap::Command {
                 .long("output")
                 .value_name("FILE")
                 .value_hint(ValueHint::FilePath)
-                .help("Name of the output disk image file. This will be a bootable disk image you can use with QEMU or other VM software.")
+                .help("Name of the output disk image file. This will be a bootable disk image you can use with QEMU or other VM software.\n\
+                       With --output-kind kernel+rootfs this is a directory to write artifacts into.\n\
+                       Default: disk.img, or image directory with --output-kind kernel+rootfs.")
+                .required(false),
+        )
+        .arg(
+            Arg::new("output_kind")
+                .long("output-kind")
+                .value_name("KIND")
+                .help("Kind of build artifacts.")
+                .long_help("Kind of build artifacts.\n\
+                            - disk: partitioned disk image with bootloader\n\
+                            - kernel+rootfs: directory with kernel (bzImage or Image), root filesystem image (rootfs.img)\n\
+                              and boot descriptor (boot.json) with kernel command line. Use it with VMs launched\n\
+                              with -kernel and -append. --size applies to the root filesystem image.")
+                .value_parser(["disk", "kernel+rootfs"])
                 .required(false)
-                .default_value("disk.img"),
+                .default_value("disk"),
+        )
+        .arg(
+            Arg::new("report_file")
+                .long("report")
+                .value_name("FILE")
+                .value_hint(ValueHint::FilePath)
+                .help("Write machine-readable JSON report of the build to FILE.")
+                .long_help("Write machine-readable JSON report of the build to FILE.\n\
+                            Report contains source image digest, kernel version, commit and config hash, MIA version,\n\
+                            runtime config, partition layout, image size, sha256 and CID of output files and duration\n\
+                            of every build step. It is written for failed builds too.")
+                .required(false),
+        )
+        .arg(
+            Arg::new("sbom")
+                .long("sbom")
+                .value_name("FORMAT")
+                .help("Write software bill of materials of the image next to the output.")
+                .long_help("Write software bill of materials of the image next to the output.\n\
+                            Packages are collected from dpkg, apk and rpm databases, Python and Node.js packages\n\
+                            and lockfiles in the root filesystem, plus kernel and MIA. SBOM is written to\n\
+                            <output>.spdx.json or <output>.cdx.json, or to sbom.*.json in kernel+rootfs output directory.\n\
+                            RPM databases are read with rpm on the host.")
+                .value_parser(["spdx", "cyclonedx"])
+                .required(false),
+        )
+        .arg(
+            Arg::new("reproducible")
+                .long("reproducible")
+                .help("Build bit-for-bit reproducible image.")
+                .long_help("Build bit-for-bit reproducible image.\n\
+                            Filesystem UUIDs, labels, disk identifier and hash seeds are fixed, modification times\n\
+                            are clamped to SOURCE_DATE_EPOCH and filesystems are generated from staging directories\n\
+                            instead of loop mounts. Two builds from the same source produce the same image digest.\n\
+                            Requires mtools and syslinux (mtools-based installer) on the host.")
+                .required(false)
+                .action(clap::ArgAction::SetTrue),
+        )
+        .arg(
+            Arg::new("source_date_epoch")
+                .long("source-date-epoch")
+                .value_name("TIMESTAMP")
+                .env("SOURCE_DATE_EPOCH")
+                .help("UNIX timestamp used for all files and filesystems in reproducible build. Default: 315532800 (1980-01-01).")
+                .value_parser(clap::value_parser!(u64))
+                .required(false),
         )
         .arg(
             Arg::new("force")
@@ -221,6 +610,76 @@ pub fn get_command() -> clap::Command {
 
 pub async fn build(matches: &clap::ArgMatches) -> Result<()> {
     let options = BuildOptions::try_from(matches).map_err(|e| anyhow::anyhow!(e))?;
+
+    // Ctrl-C stops the build before its next step, so mounts and loop device get released.
+    // Running commands receive the signal as well. Second Ctrl-C exits immediately.
+    tokio::spawn(async {
+        if tokio::signal::ctrl_c().await.is_ok() {
+            eprintln!("\nInterrupted, cleaning up. Press Ctrl-C again to exit immediately.");
+            workspace::interrupt();
+            if tokio::signal::ctrl_c().await.is_ok() {
+                std::process::exit(130);
+            }
+        }
+    });
+
     let builder = SkopeoSyslinuxBuilder {};
     builder.build(&options)
 }
+
+/// Hidden command the build runs with sudo to copy `--rootfs-dir` into the mounted image.
+/// It prints copy statistics as JSON.
+pub fn get_copy_rootfs_command() -> clap::Command {
+    clap::Command::new("copy-rootfs")
+        .about("Copy root filesystem directory keeping owners, permissions and attributes")
+        .hide(true)
+        .arg(
+            Arg::new("source")
+                .value_name("SOURCE")
+                .required(true)
+                .index(1),
+        )
+        .arg(
+            Arg::new("target")
+                .value_name("TARGET")
+                .required(true)
+                .index(2),
+        )
+        .arg(
+            Arg::new("exclude")
+                .long("exclude")
+                .value_name("PATTERN")
+                .action(clap::ArgAction::Append),
+        )
+        .arg(
+            Arg::new("owner_map")
+                .long("owner-map")
+                .value_name("FROM:TO")
+                .action(clap::ArgAction::Append),
+        )
+}
+
+pub async fn copy_rootfs(matches: &clap::ArgMatches) -> Result<()> {
+    let options = rootfs::CopyOptions {
+        excludes: matches
+            .get_many::<String>("exclude")
+            .unwrap_or_default()
+            .cloned()
+            .collect(),
+        owner_map: matches
+            .get_many::<String>("owner_map")
+            .unwrap_or_default()
+            .map(|map| map.parse())
+            .collect::<Result<Vec<_>, _>>()
+            .map_err(|e| anyhow::anyhow!(e))?,
+    };
+    let source = matches
+        .get_one::<String>("source")
+        .expect("source is required");
+    let target = matches
+        .get_one::<String>("target")
+        .expect("target is required");
+    let stats = rootfs::copy_tree(Path::new(source), Path::new(target), &options)?;
+    println!("{}", serde_json::to_string(&stats)?);
+    Ok(())
+}
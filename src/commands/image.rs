@@ -0,0 +1,149 @@
+use crate::builders::image;
+use crate::builders::skopeo_builder::SkopeoSyslinuxBuilder;
+use crate::print_object;
+use clap::{Arg, ArgAction, Command, ValueHint};
+use mia_installer::runtime_config::EnvVar;
+use std::path::Path;
+
+pub fn get_command() -> clap::Command {
+    Command::new("image")
+        .about("Work with built VM images")
+        .subcommand_required(true)
+        .subcommand(
+            Command::new("inspect")
+                .about("Show partitions, kernel, kernel command line and runtime config of a VM image without mounting it")
+                .arg(
+                    Arg::new("image")
+                        .value_name("IMAGE")
+                        .value_hint(ValueHint::FilePath)
+                        .required(true)
+                        .help("Disk image or root filesystem image of direct kernel boot."),
+                )
+                .arg(
+                    Arg::new("format")
+                        .short('F')
+                        .long("format")
+                        .value_name("FORMAT")
+                        .default_value("yaml")
+                        .help("Sets the output format (yaml, json, prettyjson, toml)"),
+                ),
+        )
+        .subcommand(
+            Command::new("set-config")
+                .about("Change command, environment or mounts of a VM image in place without rebuilding it")
+                .long_about("Change command, environment or mounts of a VM image in place without rebuilding it.\n\
+                             MIA runtime config is rewritten inside the root filesystem. For images with custom init\n\
+                             only --command and --arg can be changed, they are set in the kernel command line.\n\
+                             SquashFS and EROFS root filesystems are unpacked and generated again, which requires root.\n\
+                             Images protected with dm-verity can't be modified.")
+                .arg(
+                    Arg::new("image")
+                        .value_name("IMAGE")
+                        .value_hint(ValueHint::FilePath)
+                        .required(true)
+                        .help("Disk image or root filesystem image of direct kernel boot."),
+                )
+                .arg(
+                    Arg::new("command")
+                        .long("command")
+                        .value_name("COMMAND")
+                        .help("Command to run instead of the current one."),
+                )
+                .arg(
+                    Arg::new("arg")
+                        .long("arg")
+                        .value_name("ARG")
+                        .help("Argument of the command. Replaces all current arguments. Can be specified multiple times.")
+                        .allow_hyphen_values(true)
+                        .action(ArgAction::Append),
+                )
+                .arg(
+                    Arg::new("no_args")
+                        .long("no-args")
+                        .help("Remove all arguments of the command.")
+                        .action(ArgAction::SetTrue)
+                        .conflicts_with("arg"),
+                )
+                .arg(
+                    Arg::new("env")
+                        .long("env")
+                        .value_name("KEY=VALUE")
+                        .help("Set environment variable, replacing its current value. Can be specified multiple times.")
+                        .action(ArgAction::Append),
+                )
+                .arg(
+                    Arg::new("clear_env")
+                        .long("clear-env")
+                        .help("Remove all current environment variables before setting --env.")
+                        .action(ArgAction::SetTrue),
+                )
+                .arg(
+                    Arg::new("mount")
+                        .long("mount")
+                        .value_name("source:target|source:target:fstype:options")
+                        .help("Add mount, same as for build. Can be specified multiple times.")
+                        .action(ArgAction::Append),
+                )
+                .arg(
+                    Arg::new("clear_mounts")
+                        .long("clear-mounts")
+                        .help("Remove all current mounts before adding --mount.")
+                        .action(ArgAction::SetTrue),
+                ),
+        )
+}
+
+/// Prints contents of a VM image.
+pub async fn inspect_image(matches: &clap::ArgMatches) -> Result<(), Box<dyn std::error::Error>> {
+    let path = matches
+        .get_one::<String>("image")
+        .expect("image is required");
+    let info = image::inspect(Path::new(path))?;
+    print_object(matches, &info)?;
+    Ok(())
+}
+
+/// Changes runtime config of a VM image and prints the new image digest.
+pub async fn set_image_config(
+    matches: &clap::ArgMatches,
+) -> Result<(), Box<dyn std::error::Error>> {
+    let path = matches
+        .get_one::<String>("image")
+        .expect("image is required");
+    let env = matches
+        .get_many::<String>("env")
+        .unwrap_or_default()
+        .map(|var| {
+            let (key, value) = var.split_once('=').ok_or(format!(
+                "invalid environment variable {}, expected KEY=VALUE",
+                var
+            ))?;
+            Ok(EnvVar {
+                key: key.to_string(),
+                value: value.to_string(),
+            })
+        })
+        .collect::<Result<Vec<_>, String>>()?;
+    let args = if matches.get_flag("no_args") {
+        Some(Vec::new())
+    } else {
+        matches
+            .get_many::<String>("arg")
+            .map(|args| args.cloned().collect())
+    };
+    let changes = image::ConfigChanges {
+        command: matches.get_one::<String>("command").cloned(),
+        args,
+        env,
+        clear_env: matches.get_flag("clear_env"),
+        mounts: matches
+            .get_many::<String>("mount")
+            .unwrap_or_default()
+            .map(|mount| SkopeoSyslinuxBuilder::parse_mount(mount))
+            .collect(),
+        clear_mounts: matches.get_flag("clear_mounts"),
+    };
+    let digest = image::set_config(Path::new(path), &changes)?;
+    println!("Image digest: sha256:{}", digest);
+    Ok(())
+}
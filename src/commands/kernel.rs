@@ -0,0 +1,161 @@
+use crate::builders::kernel::{self, KernelConfig};
+use crate::builders::Arch;
+use crate::print_object;
+use clap::{Arg, Command, ValueHint};
+
+pub fn get_command() -> clap::Command {
+    Command::new("kernel")
+        .about("Manage cache of Linux kernels built for VM images")
+        .subcommand_required(true)
+        .subcommand(
+            Command::new("list")
+                .about("List cached kernel builds")
+                .arg(
+                    Arg::new("format")
+                        .short('F')
+                        .long("format")
+                        .value_name("FORMAT")
+                        .default_value("yaml")
+                        .help("Sets the output format (yaml, json, prettyjson, toml)"),
+                ),
+        )
+        .subcommand(
+            Command::new("build")
+                .about("Build a kernel into the cache without building a VM image")
+                .arg(
+                    Arg::new("kernel_version")
+                        .short('k')
+                        .long("kernel")
+                        .value_name("VERSION")
+                        .help("Linux kernel version to build (e.g., v6.10). Use 'latest' for the most recent version. \
+                               Use 'prebuilt:<version>' to download a prebuilt kernel from --kernel-index instead.")
+                        .default_value("v6.12"),
+                )
+                .arg(
+                    Arg::new("arch")
+                        .long("arch")
+                        .value_name("ARCH")
+                        .help("Architecture of the kernel.")
+                        .value_parser(["x86_64", "aarch64"])
+                        .default_value("x86_64"),
+                )
+                .arg(
+                    Arg::new("cross_compile")
+                        .long("cross-compile")
+                        .value_name("PREFIX")
+                        .env("CROSS_COMPILE")
+                        .help("Toolchain prefix used to build the kernel for another architecture than the host one."),
+                )
+                .arg(
+                    Arg::new("kernel_index")
+                        .long("kernel-index")
+                        .value_name("URL")
+                        .value_hint(ValueHint::Url)
+                        .env("GEVULOT_KERNEL_INDEX")
+                        .help("URL of the signed release index of prebuilt kernels."),
+                )
+                .arg(
+                    Arg::new("kernel_index_key")
+                        .long("kernel-index-key")
+                        .value_name("FILE")
+                        .value_hint(ValueHint::FilePath)
+                        .env("GEVULOT_KERNEL_INDEX_KEY")
+                        .help("Path to a PEM public key verifying the release index signature."),
+                )
+                .arg(
+                    Arg::new("with_headers")
+                        .long("with-headers")
+                        .help("Also download kernel headers of prebuilt kernel, required for NVIDIA drivers.")
+                        .action(clap::ArgAction::SetTrue),
+                )
+                .arg(
+                    Arg::new("kernel_url")
+                        .long("kernel-url")
+                        .value_name("URL")
+                        .value_hint(ValueHint::Url)
+                        .help("URL of the Linux kernel repository to clone.")
+                        .default_value("https://github.com/torvalds/linux.git"),
+                )
+                .arg(
+                    Arg::new("kernel_defconfig")
+                        .long("kernel-defconfig")
+                        .value_name("FILE")
+                        .value_hint(ValueHint::FilePath)
+                        .help("Path to a base kernel config used instead of the defconfig of --arch."),
+                )
+                .arg(
+                    Arg::new("kernel_config")
+                        .long("kernel-config")
+                        .value_name("FILE")
+                        .value_hint(ValueHint::FilePath)
+                        .help("Kernel config fragment merged on top of the base config. Can be specified multiple times.")
+                        .action(clap::ArgAction::Append),
+                ),
+        )
+        .subcommand(
+            Command::new("prune")
+                .about("Remove incomplete and failed kernel builds. Don't run it while builds are in progress.")
+                .arg(
+                    Arg::new("all")
+                        .long("all")
+                        .help("Remove the whole cache including finished builds and kernel sources.")
+                        .action(clap::ArgAction::SetTrue),
+                ),
+        )
+}
+
+/// Lists cached kernel builds.
+pub async fn list_kernels(matches: &clap::ArgMatches) -> Result<(), Box<dyn std::error::Error>> {
+    let entries = kernel::list()?;
+    print_object(matches, &entries)?;
+    Ok(())
+}
+
+/// Builds or downloads a kernel into the cache and prints path to the kernel image.
+pub async fn build_kernel(matches: &clap::ArgMatches) -> Result<(), Box<dyn std::error::Error>> {
+    let version = matches
+        .get_one::<String>("kernel_version")
+        .expect("kernel version has a default value");
+    let url = matches
+        .get_one::<String>("kernel_url")
+        .expect("kernel URL has a default value");
+    let arch: Arch = matches
+        .get_one::<String>("arch")
+        .expect("architecture has a default value")
+        .parse()?;
+    let config = KernelConfig {
+        arch,
+        cross_compile: matches.get_one::<String>("cross_compile").cloned(),
+        defconfig: matches.get_one::<String>("kernel_defconfig").cloned(),
+        fragments: matches
+            .get_many::<String>("kernel_config")
+            .unwrap_or_default()
+            .cloned()
+            .collect(),
+    };
+    let kernel_dir = if let Some(version) = version.strip_prefix(kernel::prebuilt::VERSION_PREFIX) {
+        kernel::prebuilt::fetch(
+            version,
+            matches
+                .get_one::<String>("kernel_index")
+                .ok_or("prebuilt kernels require --kernel-index")?,
+            matches
+                .get_one::<String>("kernel_index_key")
+                .ok_or("prebuilt kernels require --kernel-index-key")?,
+            matches.get_flag("with_headers"),
+            arch,
+        )?
+    } else {
+        kernel::build(version, url, &config)?
+    };
+    println!("{}", kernel::image_path(&kernel_dir, arch).display());
+    Ok(())
+}
+
+/// Removes stale entries from the kernel cache.
+pub async fn prune_kernels(matches: &clap::ArgMatches) -> Result<(), Box<dyn std::error::Error>> {
+    for path in kernel::prune(matches.get_flag("all"))? {
+        println!("Removed {}", path.display());
+    }
+    Ok(())
+}
@@ -1,5 +1,9 @@
 #[cfg(target_os = "linux")]
 pub mod build;
+#[cfg(target_os = "linux")]
+pub mod image;
+#[cfg(target_os = "linux")]
+pub mod kernel;
 pub mod pins;
 pub mod tasks;
 pub mod workers;
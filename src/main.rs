@@ -18,6 +18,10 @@ mod commands;
 
 #[cfg(target_os = "linux")]
 use commands::build::*;
+#[cfg(target_os = "linux")]
+use commands::image::*;
+#[cfg(target_os = "linux")]
+use commands::kernel::*;
 use commands::{pins::*, sudo::*, tasks::*, workers::*};
 
 shadow_rs::shadow!(build_info);
@@ -80,6 +84,21 @@ async fn main() -> Result<(), Box<dyn std::error::Error>> {
         Some(("generate-completion", sub_m)) => generate_completion(sub_m).await?,
         #[cfg(target_os = "linux")]
         Some(("build", sub_m)) => build(sub_m).await?,
+        #[cfg(target_os = "linux")]
+        Some(("copy-rootfs", sub_m)) => copy_rootfs(sub_m).await?,
+        #[cfg(target_os = "linux")]
+        Some(("kernel", sub_m)) => match sub_m.subcommand() {
+            Some(("list", sub_m)) => list_kernels(sub_m).await?,
+            Some(("build", sub_m)) => build_kernel(sub_m).await?,
+            Some(("prune", sub_m)) => prune_kernels(sub_m).await?,
+            _ => println!("Unknown kernel command"),
+        },
+        #[cfg(target_os = "linux")]
+        Some(("image", sub_m)) => match sub_m.subcommand() {
+            Some(("inspect", sub_m)) => inspect_image(sub_m).await?,
+            Some(("set-config", sub_m)) => set_image_config(sub_m).await?,
+            _ => println!("Unknown image command"),
+        },
         _ => println!("Unknown command"),
     }
 
@@ -623,7 +642,11 @@ fn setup_command_line_args() -> Result<Command, Box<dyn std::error::Error>> {
 
     #[cfg(target_os = "linux")]
     {
-        command = command.subcommand(commands::build::get_command());
+        command = command
+            .subcommand(commands::build::get_command())
+            .subcommand(commands::build::get_copy_rootfs_command())
+            .subcommand(commands::kernel::get_command())
+            .subcommand(commands::image::get_command());
     }
 
     Ok(command)
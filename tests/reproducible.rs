@@ -0,0 +1,78 @@
+//! Reproducibility of `gvltctl build --reproducible`.
+//!
+//! Builds need root (through sudo), loop devices and network access to install MIA,
+//! so the test is ignored by default. Run it with:
+//!
+//! ```text
+//! cargo test --test reproducible -- --ignored
+//! ```
+//!
+//! Set `GVLTCTL_TEST_KERNEL_FILE` to a prebuilt kernel to skip kernel compilation.
+
+#![cfg(target_os = "linux")]
+
+use std::fs;
+use std::path::Path;
+use std::process::Command;
+
+use tempdir::TempDir;
+
+const SOURCE_DATE_EPOCH: &str = "1700000000";
+
+/// Create small root filesystem with files newer than `SOURCE_DATE_EPOCH`.
+fn create_rootfs(dir: &Path) {
+    for subdir in ["bin", "etc", "usr/share/app"] {
+        fs::create_dir_all(dir.join(subdir)).unwrap();
+    }
+    fs::write(dir.join("etc/hostname"), "gevulot\n").unwrap();
+    fs::write(dir.join("usr/share/app/data.txt"), "reproducible\n").unwrap();
+    fs::write(dir.join("bin/app"), "#!/bin/sh\necho hello\n").unwrap();
+}
+
+fn build(rootfs: &Path, output: &Path) {
+    let mut command = Command::new(env!("CARGO_BIN_EXE_gvltctl"));
+    command
+        .arg("build")
+        .arg("--rootfs-dir")
+        .arg(rootfs)
+        .args(["--init", "/bin/app", "--size", "auto", "--reproducible"])
+        .arg("--output")
+        .arg(output)
+        .env("SOURCE_DATE_EPOCH", SOURCE_DATE_EPOCH);
+    if let Some(kernel_file) = std::env::var_os("GVLTCTL_TEST_KERNEL_FILE") {
+        command.arg("--kernel-file").arg(kernel_file);
+    }
+    let status = command.status().expect("failed to run gvltctl");
+    assert!(status.success(), "gvltctl build failed with {}", status);
+}
+
+fn sha256(path: &Path) -> String {
+    let output = Command::new("sha256sum")
+        .arg(path)
+        .output()
+        .expect("failed to run sha256sum");
+    assert!(output.status.success());
+    String::from_utf8(output.stdout)
+        .unwrap()
+        .split_whitespace()
+        .next()
+        .expect("empty sha256sum output")
+        .to_string()
+}
+
+#[test]
+#[ignore = "requires root, loop devices and network access"]
+fn same_source_builds_same_image() {
+    let dir = TempDir::new("gvltctl-reproducible").unwrap();
+    let rootfs = dir.path().join("rootfs");
+    create_rootfs(&rootfs);
+
+    let first = dir.path().join("first.img");
+    let second = dir.path().join("second.img");
+    build(&rootfs, &first);
+    // Modification times of the source change between builds, they must not leak into the image.
+    fs::write(rootfs.join("etc/hostname"), "gevulot\n").unwrap();
+    build(&rootfs, &second);
+
+    assert_eq!(sha256(&first), sha256(&second));
+}